@@ -0,0 +1,73 @@
+//! Compare reading every page into a buffer against borrowing pages from a memory mapped file.
+//! The page cache is kept empty so every page is fetched again on every pass. Two things are timed:
+//! fetching each b-tree page of the database and full scans of a table, which decode every record as well
+//!
+//!     cargo run --release --example mmap_scan -- [database] [table] [passes]
+//!
+//! defaults to `companies.db`, its `companies` table and 10 passes
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use toy_sqlite::{
+    pager::{cache::CacheCapacity, page_map::PageOwner, pager::Pager},
+    query_engine::{engine::QueryEngine, schema_object::SchemaObject},
+    sql_parser::parser::{Column, SelectQuery},
+};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("companies.db", String::as_str);
+    let table = args.get(2).map_or("companies", String::as_str);
+    let passes: u32 = args.get(3).map_or(Ok(10), |passes| passes.parse())?;
+
+    println!("{} passes over {}", passes, path);
+    for (mode, mmap_size) in [("buffered reads", 0), ("memory mapped", u64::MAX)] {
+        let (fetches, scans) = time_passes(path, table, passes, mmap_size)?;
+        println!(
+            "{:>14}: {:?} to fetch every b-tree page, {:?} to scan {}",
+            mode,
+            fetches / passes,
+            scans / passes,
+            table
+        );
+    }
+    Ok(())
+}
+
+/// Time to fetch every b-tree page and time to scan the table with the pager mapping up to `mmap_size` bytes
+fn time_passes(
+    path: &str,
+    table: &str,
+    passes: u32,
+    mmap_size: u64,
+) -> Result<(Duration, Duration)> {
+    let engine = QueryEngine::new(Pager::open(path)?);
+    engine.pager.set_cache_capacity(CacheCapacity::Pages(0));
+    engine.pager.set_mmap_size(mmap_size);
+
+    let page_map = engine.pager.page_map()?;
+    let btree_pages: Vec<u32> = (1..=page_map.page_count)
+        .filter(|page| matches!(page_map.owner(*page), PageOwner::BTree(_)))
+        .collect();
+    let start = Instant::now();
+    for _ in 0..passes {
+        for page in &btree_pages {
+            engine.pager.read_page(*page)?;
+        }
+    }
+    let fetches = start.elapsed();
+
+    let schema = SchemaObject::try_from(engine.get_table_rec(table)?)?;
+    let query = SelectQuery {
+        columns: vec![Column::All],
+        table: table.to_string(),
+        where_clause: None,
+    };
+    // rows are decoded but not formatted so the time is spent fetching pages and reading records
+    let start = Instant::now();
+    for _ in 0..passes {
+        engine.table_db_scan(&schema, &query)?;
+    }
+    Ok((fetches, start.elapsed()))
+}
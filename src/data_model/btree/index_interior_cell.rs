@@ -1,49 +1,89 @@
-use crate::serialisation::{deserialize::Deserialize, varint::read_varint};
+use std::io::{Cursor, Read, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::serialisation::{
+    deserialize::DeserializeCell,
+    serialize::SerializeCell,
+    varint::{read_varint, write_varint},
+};
 
 use super::{
-    record::{HasRecord, Record},
+    overflow::{read_payload, write_payload, PayloadReader},
+    page_header::PageType,
+    record::{HasRecord, RecordView},
     serial_value::SerialValue,
 };
 
-pub struct IndexInteriorCell {
+pub struct IndexInteriorCell<'a> {
     pub left_child: u32,
     pub size: u64,
-    pub record: Record,
+    pub record: RecordView<'a>,
+    pub overflow_page: Option<u32>,
 }
 
-impl Deserialize for IndexInteriorCell {
-    fn deserialize<T: std::io::Read>(reader: &mut T) -> Self {
+impl<'a> DeserializeCell<'a> for IndexInteriorCell<'a> {
+    fn deserialize_cell<O: PayloadReader>(
+        page_number: u32,
+        reader: &mut Cursor<&'a [u8]>,
+        payload_reader: &O,
+    ) -> Result<Self> {
         let mut buf = [0u8; 4];
         reader
-            .read(&mut buf)
-            .expect("Failed to read 4 bytes for left child pointer");
+            .read_exact(&mut buf)
+            .context("Failed to read 4 bytes for left child pointer")?;
         let left_child = u32::from_be_bytes(buf);
 
-        let (size, _) = read_varint(reader);
-        let record = Record::deserialize(reader);
+        let (size, _) = read_varint(reader)?;
+        let (payload, overflow_page) =
+            read_payload(reader, PageType::IndexInterior, size, payload_reader)?;
+        let record = RecordView::new(page_number, payload, payload_reader.text_encoding())?;
 
-        Self {
+        Ok(Self {
             left_child,
             size,
             record,
-        }
+            overflow_page,
+        })
+    }
+}
+
+impl SerializeCell for IndexInteriorCell<'_> {
+    fn serialize_cell<T: Write, O: PayloadReader>(
+        &self,
+        writer: &mut T,
+        payload_reader: &O,
+    ) -> Result<()> {
+        let payload = self.record.payload();
+        ensure!(
+            self.size == payload.len() as u64,
+            "cell says the record is {} bytes but it is {}",
+            self.size,
+            payload.len()
+        );
+        writer
+            .write_all(&self.left_child.to_be_bytes())
+            .context("Failed to write left child pointer")?;
+        write_varint(writer, self.size)?;
+        write_payload(
+            writer,
+            PageType::IndexInterior,
+            payload,
+            self.overflow_page,
+            payload_reader,
+        )
     }
 }
 
-impl HasRecord for IndexInteriorCell {
-    fn record(&self) -> &Record {
+impl HasRecord for IndexInteriorCell<'_> {
+    fn record(&self) -> &RecordView<'_> {
         &self.record
     }
 
-    fn row_id(&self) -> u64 {
-        match self
-            .record
-            .values
-            .last()
-            .expect("Index record should end with row id")
-        {
-            SerialValue::Int(row_id) => *row_id as u64,
-            _ => panic!("Final value in index cell should be row_id as Integer"),
+    fn row_id(&self) -> Result<u64> {
+        match self.record.last()? {
+            Some(SerialValue::Int(row_id)) => Ok(row_id as u64),
+            _ => bail!("index entry should end with the row id as an integer"),
         }
     }
 }
@@ -1,38 +1,104 @@
-use crate::serialisation::{deserialize::Deserialize, varint::read_varint};
+use std::io::{Cursor, Write};
+
+use anyhow::{bail, ensure, Result};
+
+use crate::{
+    pager::cache::PageBuffer,
+    serialisation::{
+        deserialize::DeserializeCell,
+        serialize::SerializeCell,
+        varint::{read_varint, write_varint},
+    },
+};
 
 use super::{
-    record::{HasRecord, Record},
+    index_interior_cell::IndexInteriorCell,
+    overflow::{read_payload, write_payload, PayloadReader},
+    page_header::PageType,
+    record::{HasRecord, RecordView},
     serial_value::SerialValue,
 };
 
 #[derive(Clone)]
-pub struct IndexLeafCell {
+pub struct IndexLeafCell<'a> {
     pub size: u64,
-    pub record: Record, // pub overflow_page: u32,
+    pub record: RecordView<'a>,
+    pub overflow_page: Option<u32>,
+}
+
+impl IndexLeafCell<'_> {
+    /// Keep the page the record is borrowed from alive so the entry outlives the borrow
+    pub fn retain(self, page: &PageBuffer) -> IndexLeafCell<'static> {
+        IndexLeafCell {
+            size: self.size,
+            record: self.record.retain(page),
+            overflow_page: self.overflow_page,
+        }
+    }
 }
 
-impl Deserialize for IndexLeafCell {
-    fn deserialize<T: std::io::Read>(reader: &mut T) -> Self {
-        let (size, _) = read_varint(reader);
-        let record = Record::deserialize(reader);
+impl<'a> DeserializeCell<'a> for IndexLeafCell<'a> {
+    fn deserialize_cell<O: PayloadReader>(
+        page_number: u32,
+        reader: &mut Cursor<&'a [u8]>,
+        payload_reader: &O,
+    ) -> Result<Self> {
+        let (size, _) = read_varint(reader)?;
+        let (payload, overflow_page) =
+            read_payload(reader, PageType::IndexLeaf, size, payload_reader)?;
+        let record = RecordView::new(page_number, payload, payload_reader.text_encoding())?;
 
-        Self { size, record }
+        Ok(Self {
+            size,
+            record,
+            overflow_page,
+        })
+    }
+}
+
+impl SerializeCell for IndexLeafCell<'_> {
+    fn serialize_cell<T: Write, O: PayloadReader>(
+        &self,
+        writer: &mut T,
+        payload_reader: &O,
+    ) -> Result<()> {
+        let payload = self.record.payload();
+        ensure!(
+            self.size == payload.len() as u64,
+            "cell says the record is {} bytes but it is {}",
+            self.size,
+            payload.len()
+        );
+        write_varint(writer, self.size)?;
+        write_payload(
+            writer,
+            PageType::IndexLeaf,
+            payload,
+            self.overflow_page,
+            payload_reader,
+        )
+    }
+}
+
+/// Interior cells of an index b-tree hold entries as well, this drops the child pointer to treat them like leaf entries
+impl<'a> From<IndexInteriorCell<'a>> for IndexLeafCell<'a> {
+    fn from(cell: IndexInteriorCell<'a>) -> Self {
+        Self {
+            size: cell.size,
+            record: cell.record,
+            overflow_page: cell.overflow_page,
+        }
     }
 }
 
-impl HasRecord for IndexLeafCell {
-    fn record(&self) -> &Record {
+impl HasRecord for IndexLeafCell<'_> {
+    fn record(&self) -> &RecordView<'_> {
         &self.record
     }
-    fn row_id(&self) -> u64 {
-        match self
-            .record
-            .values
-            .last()
-            .expect("Index record should end with row id")
-        {
-            SerialValue::Int(row_id) => *row_id as u64,
-            _ => panic!("Final value in index cell should be row_id as Integer"),
+    fn row_id(&self) -> Result<u64> {
+        match self.record.last()? {
+            Some(SerialValue::Int(row_id)) => Ok(row_id as u64),
+            _ => bail!("index entry should end with the row id as an integer"),
         }
     }
 }
@@ -0,0 +1,145 @@
+use std::cmp::Ordering;
+
+use crate::{
+    data_model::{
+        btree::serial_value::{encode_text, SerialValue},
+        db_header::TextEncoding,
+    },
+    query_engine::column::ColumnDefinition,
+};
+
+/// How an index orders one column of its keys
+#[derive(Clone, Copy, PartialEq)]
+pub enum Collation {
+    Binary,
+    NoCase,
+    Rtrim,
+}
+
+#[derive(Clone, Copy)]
+pub struct KeyColumn {
+    pub collation: Collation,
+    pub descending: bool,
+}
+
+impl KeyColumn {
+    pub const DEFAULT: KeyColumn = KeyColumn {
+        collation: Collation::Binary,
+        descending: false,
+    };
+
+    /// Read the `COLLATE` and `DESC` clauses of a column definition, the collation falls back to
+    /// the one declared on the table column
+    pub fn from_definition(type_def: &str, table_column: Option<&ColumnDefinition>) -> Self {
+        let collation = |type_def: &str| {
+            let words: Vec<&str> = type_def.split_whitespace().collect();
+            words
+                .windows(2)
+                .find(|words| words[0] == "collate")
+                .map(|words| match words[1] {
+                    "nocase" => Collation::NoCase,
+                    "rtrim" => Collation::Rtrim,
+                    _ => Collation::Binary,
+                })
+        };
+        KeyColumn {
+            collation: collation(type_def)
+                .or_else(|| table_column.and_then(|column| collation(&column.type_def)))
+                .unwrap_or(Collation::Binary),
+            descending: type_def.split_whitespace().any(|word| word == "desc"),
+        }
+    }
+}
+
+/// Order two index keys column by column the way sqlite sorts records,
+/// NULL before numbers before text before blobs
+pub fn compare_keys(
+    a: &[SerialValue],
+    b: &[SerialValue],
+    key_columns: &[KeyColumn],
+    encoding: TextEncoding,
+) -> Ordering {
+    for (column, (a, b)) in a.iter().zip(b).enumerate() {
+        let key_column = key_columns.get(column).unwrap_or(&KeyColumn::DEFAULT);
+        let ordering = compare_values(a, b, key_column.collation, encoding);
+        let ordering = if key_column.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+pub fn compare_values(
+    a: &SerialValue,
+    b: &SerialValue,
+    collation: Collation,
+    encoding: TextEncoding,
+) -> Ordering {
+    let rank = |value: &SerialValue| match value {
+        SerialValue::Null => 0,
+        SerialValue::Int(_) | SerialValue::Float(_) => 1,
+        SerialValue::Text(_) => 2,
+        SerialValue::Blob(_) => 3,
+    };
+    match (a, b) {
+        (SerialValue::Int(a), SerialValue::Int(b)) => a.cmp(b),
+        (SerialValue::Int(a), SerialValue::Float(b)) => (*a as f64).total_cmp(b),
+        (SerialValue::Float(a), SerialValue::Int(b)) => a.total_cmp(&(*b as f64)),
+        (SerialValue::Float(a), SerialValue::Float(b)) => a.total_cmp(b),
+        (SerialValue::Text(a), SerialValue::Text(b)) => match collation {
+            // BINARY compares the stored bytes, the other built in collations compare UTF-8
+            Collation::Binary => encode_text(a, encoding).cmp(&encode_text(b, encoding)),
+            Collation::NoCase => a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()),
+            Collation::Rtrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+        },
+        (SerialValue::Blob(a), SerialValue::Blob(b)) => a.cmp(b),
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod key_tests {
+    use super::*;
+
+    #[test]
+    fn test_index_key_order() {
+        let keys = [KeyColumn::DEFAULT];
+        let descending = [KeyColumn {
+            collation: Collation::NoCase,
+            descending: true,
+        }];
+        let text = |text: &'static str| SerialValue::Text(text.into());
+
+        assert_eq!(
+            compare_keys(
+                &[SerialValue::Null],
+                &[SerialValue::Int(0)],
+                &keys,
+                TextEncoding::Utf8
+            ),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_keys(
+                &[SerialValue::Float(2.5)],
+                &[SerialValue::Int(2)],
+                &keys,
+                TextEncoding::Utf8
+            ),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_keys(&[text("B")], &[text("a")], &keys, TextEncoding::Utf8),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_keys(&[text("B")], &[text("a")], &descending, TextEncoding::Utf8),
+            Ordering::Less
+        );
+    }
+}
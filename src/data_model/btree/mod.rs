@@ -1,5 +1,7 @@
 pub mod index_interior_cell;
 pub mod index_leaf_cell;
+pub mod key;
+pub mod overflow;
 pub mod page;
 pub mod page_header;
 pub mod record;
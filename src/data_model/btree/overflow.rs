@@ -0,0 +1,154 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{Cursor, Read, Write},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::data_model::db_header::TextEncoding;
+
+use super::page_header::PageType;
+
+/// Database level state a cell needs to decode its payload
+pub trait PayloadReader {
+    /// Page size minus the reserved bytes at the end of every page
+    fn usable_size(&self) -> usize;
+    /// Encoding of every TEXT value in the database
+    fn text_encoding(&self) -> TextEncoding;
+    /// Read the raw bytes of an overflow page passing in 1-indexed page number
+    fn read_overflow_page(&self, page_number: u32) -> Result<Vec<u8>>;
+}
+
+/// Number of payload bytes stored on the b-tree page itself, the rest lives on overflow pages
+/// https://www.sqlite.org/fileformat2.html#b_tree_pages
+pub fn local_payload_size(page_type: PageType, payload_size: u64, usable_size: usize) -> u64 {
+    let usable_size = usable_size as u64;
+    // maximum amount of payload that can be stored directly on the b-tree page
+    let max_local = match page_type {
+        PageType::TableLeaf => usable_size - 35,
+        _ => ((usable_size - 12) * 64 / 255) - 23,
+    };
+    // minimum amount of payload that must be stored on the b-tree page before spilling
+    let min_local = ((usable_size - 12) * 32 / 255) - 23;
+
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let local = min_local + ((payload_size - min_local) % (usable_size - 4));
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+/// Read a cell's payload of `payload_size` bytes following the overflow page chain when it doesn't fit on the page.
+/// Returns the full payload, borrowed from the page when it has no overflow pages, and the first overflow page if there is one
+pub fn read_payload<'a, O: PayloadReader>(
+    reader: &mut Cursor<&'a [u8]>,
+    page_type: PageType,
+    payload_size: u64,
+    payload_reader: &O,
+) -> Result<(Cow<'a, [u8]>, Option<u32>)> {
+    let usable_size = payload_reader.usable_size();
+    let local_size = local_payload_size(page_type, payload_size, usable_size);
+
+    let page: &'a [u8] = reader.get_ref();
+    let start = reader.position() as usize;
+    let end = start + local_size as usize;
+    let local = page
+        .get(start..end)
+        .context("failed to read local payload")?;
+    reader.set_position(end as u64);
+
+    if local_size == payload_size {
+        return Ok((Cow::Borrowed(local), None));
+    }
+    let mut payload = local.to_vec();
+
+    let mut buf = [0u8; 4];
+    reader
+        .read_exact(&mut buf)
+        .context("failed to read first overflow page number")?;
+    let first_overflow_page = u32::from_be_bytes(buf);
+
+    // The first four bytes of each overflow page are the next page in the chain, 0 marks the last page
+    let mut next_page = first_overflow_page;
+    let mut visited: HashSet<u32> = HashSet::new();
+    while (payload.len() as u64) < payload_size {
+        if next_page == 0 {
+            bail!(
+                "overflow chain ended after {} of {} payload bytes",
+                payload.len(),
+                payload_size
+            );
+        }
+        // a corrupt chain that loops would otherwise be followed until the claimed payload size is read
+        if !visited.insert(next_page) {
+            bail!("overflow chain loops back to page {}", next_page);
+        }
+        let page = payload_reader.read_overflow_page(next_page)?;
+        next_page = u32::from_be_bytes(page[0..4].try_into()?);
+
+        let remaining = payload_size as usize - payload.len();
+        let content_size = remaining.min(usable_size - 4);
+        payload.extend_from_slice(&page[4..4 + content_size]);
+    }
+
+    Ok((Cow::Owned(payload), Some(first_overflow_page)))
+}
+
+/// Write the part of a cell's payload stored on the page followed by the first overflow page when it doesn't fit.
+/// The rest of the payload has to be written to the overflow pages separately
+pub fn write_payload<W: Write, O: PayloadReader>(
+    writer: &mut W,
+    page_type: PageType,
+    payload: &[u8],
+    overflow_page: Option<u32>,
+    payload_reader: &O,
+) -> Result<()> {
+    let local_size = local_payload_size(
+        page_type,
+        payload.len() as u64,
+        payload_reader.usable_size(),
+    ) as usize;
+    writer
+        .write_all(&payload[..local_size])
+        .context("failed to write local payload")?;
+
+    match overflow_page {
+        Some(page_number) if local_size < payload.len() => writer
+            .write_all(&page_number.to_be_bytes())
+            .context("failed to write first overflow page number"),
+        None if local_size == payload.len() => Ok(()),
+        Some(_) => bail!("payload of {} bytes fits on the page", payload.len()),
+        None => bail!("payload of {} bytes needs an overflow page", payload.len()),
+    }
+}
+
+#[cfg(test)]
+mod overflow_tests {
+    use super::*;
+
+    #[test]
+    fn test_small_payload_is_local() {
+        assert_eq!(local_payload_size(PageType::TableLeaf, 100, 4096), 100);
+        assert_eq!(local_payload_size(PageType::IndexLeaf, 100, 4096), 100);
+    }
+
+    #[test]
+    fn test_large_table_payload_spills() {
+        // U = 512: X = 477, M = 39, K = 39 + (2000 - 39) % 508 = 476
+        assert_eq!(local_payload_size(PageType::TableLeaf, 2000, 512), 476);
+        // K = 39 + (1000 - 39) % 508 = 492 which exceeds X so only M is stored locally
+        assert_eq!(local_payload_size(PageType::TableLeaf, 1000, 512), 39);
+    }
+
+    #[test]
+    fn test_large_index_payload_spills() {
+        // U = 512: X = 102, M = 39, K = 39 + (420 - 39) % 508 = 420
+        assert_eq!(local_payload_size(PageType::IndexInterior, 420, 512), 39);
+        assert_eq!(local_payload_size(PageType::IndexLeaf, 102, 512), 102);
+    }
+}
@@ -1,8 +1,31 @@
-use std::io::Read;
+use std::io::{Cursor, Read, Write};
 
-use crate::serialisation::deserialize::Deserialize;
+use anyhow::{ensure, Context, Result};
 
-use super::page_header::PageHeader;
+use crate::{
+    error::Error,
+    serialisation::{deserialize::Deserialize, serialize::Serialize, varint::read_varint},
+};
+
+use super::{
+    overflow::local_payload_size,
+    page_header::{PageHeader, PageType},
+};
+
+/// sqlite gives up on b-trees deeper than this, following a corrupt child pointer back up the tree would never end
+pub const MAX_BTREE_DEPTH: usize = 20;
+
+/// Fail with corruption of `page_number` when a traversal has gone `depth` pages down without reaching a leaf
+pub fn ensure_depth(page_number: u32, depth: usize) -> Result<()> {
+    ensure!(
+        depth < MAX_BTREE_DEPTH,
+        Error::corrupt_page(
+            page_number,
+            format!("b-tree is more than {} pages deep", MAX_BTREE_DEPTH)
+        )
+    );
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct Page {
@@ -11,24 +34,101 @@ pub struct Page {
 }
 
 impl Deserialize for Page {
-    fn deserialize<T: Read>(reader: &mut T) -> Self {
-        let header = PageHeader::deserialize(reader);
-        let cell_pointers = read_cell_pointer(reader, header.cell_count);
-        Page {
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Self> {
+        let header = PageHeader::deserialize(reader)?;
+        let cell_pointers = read_cell_pointer(reader, header.cell_count)?;
+        Ok(Page {
             header,
             cell_pointers,
+        })
+    }
+}
+
+impl Serialize for Page {
+    /// Writes the page header and the cell pointer array, the cells are written at the offsets they point to
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        self.header.serialize(writer)?;
+        for cell_pointer in &self.cell_pointers {
+            writer
+                .write_all(&cell_pointer.to_be_bytes())
+                .context("failed to write cell pointer")?;
         }
+        Ok(())
     }
 }
 
-fn read_cell_pointer<T: Read>(reader: &mut T, cell_count: u16) -> Vec<u16> {
+fn read_cell_pointer<T: Read>(reader: &mut T, cell_count: u16) -> Result<Vec<u16>> {
     let mut cell_pointers: Vec<u16> = vec![];
     for _ in 0..cell_count {
         let mut buf = [0; 2];
         reader
             .read_exact(&mut buf)
-            .expect("failed to read cell pointer");
+            .context("failed to read cell pointer")?;
         cell_pointers.push(u16::from_be_bytes(buf));
     }
-    cell_pointers
+    Ok(cell_pointers)
+}
+
+/// Where a cell and its payload sit on a b-tree page
+pub struct CellLayout {
+    pub left_child: Option<u32>,
+    pub row_id: Option<i64>,
+    pub payload_size: u64,
+    pub local_payload: (usize, usize),
+    pub overflow_page: Option<u32>,
+    // bytes used on the page, sqlite never allocates less than 4
+    pub size: usize,
+}
+
+/// Work out where a cell's fields are without decoding its record.
+/// `page` may also be a single cell copied off its page, with an offset of 0
+pub fn parse_cell(
+    page: &[u8],
+    offset: usize,
+    page_type: PageType,
+    usable_size: usize,
+) -> Result<CellLayout> {
+    let page_end = usable_size.min(page.len());
+    ensure!(offset < page_end, "cell starts off the end of the page");
+    let mut reader = Cursor::new(&page[offset..page_end]);
+    let left_child = match page_type {
+        PageType::TableInterior | PageType::IndexInterior => {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf)?;
+            Some(u32::from_be_bytes(buf))
+        }
+        PageType::TableLeaf | PageType::IndexLeaf => None,
+    };
+    let payload_size = match page_type {
+        PageType::TableInterior => 0,
+        _ => read_varint(&mut reader)?.0,
+    };
+    let row_id = match page_type {
+        PageType::TableInterior | PageType::TableLeaf => Some(read_varint(&mut reader)?.0 as i64),
+        PageType::IndexInterior | PageType::IndexLeaf => None,
+    };
+
+    let start = offset + reader.position() as usize;
+    let local_size = match page_type {
+        PageType::TableInterior => 0,
+        _ => local_payload_size(page_type, payload_size, usable_size) as usize,
+    };
+    let end = start + local_size;
+    let overflow_page = if (local_size as u64) < payload_size {
+        ensure!(end + 4 <= page_end, "overflow page number is off the page");
+        Some(u32::from_be_bytes(page[end..end + 4].try_into()?))
+    } else {
+        None
+    };
+    let cell_end = end + if overflow_page.is_some() { 4 } else { 0 };
+    ensure!(cell_end <= page_end, "cell extends off the end of the page");
+
+    Ok(CellLayout {
+        left_child,
+        row_id,
+        payload_size,
+        local_payload: (start, end),
+        overflow_page,
+        size: (cell_end - offset).max(4),
+    })
 }
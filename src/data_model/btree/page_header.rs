@@ -1,6 +1,11 @@
-use std::{fmt::Display, io::Read};
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
 
-use crate::serialisation::deserialize::Deserialize;
+use anyhow::{bail, Context, Result};
+
+use crate::serialisation::{deserialize::Deserialize, serialize::Serialize};
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum PageType {
@@ -32,17 +37,17 @@ pub struct PageHeader {
 }
 
 impl Deserialize for PageHeader {
-    fn deserialize<T: Read>(reader: &mut T) -> PageHeader {
+    fn deserialize<T: Read>(reader: &mut T) -> Result<PageHeader> {
         let mut buf = [0; 1];
         reader
             .read_exact(&mut buf)
-            .expect("failed to read BTreePageType");
+            .context("failed to read BTreePageType")?;
         let page_type: PageType = match buf[0] {
             0x02 => PageType::IndexInterior,
             0x05 => PageType::TableInterior,
             0x0a => PageType::IndexLeaf,
             0x0d => PageType::TableLeaf,
-            _ => panic!("Invalid B-Tree Page Type"),
+            page_type => bail!("Invalid B-Tree Page Type {:#04x}", page_type),
         };
 
         let remaining_header_size = match page_type {
@@ -53,27 +58,59 @@ impl Deserialize for PageHeader {
         let mut buf = vec![0; remaining_header_size];
         reader
             .read_exact(&mut buf)
-            .expect("failed to read BTreePageHeader");
+            .context("failed to read BTreePageHeader")?;
 
         // The four-byte page number at offset 8 is the right-most pointer.
         // This value appears in the header of interior b-tree pages only and is omitted from all other pages.
         let rightmost_pointer = match page_type {
             PageType::IndexInterior | PageType::TableInterior => Some(u32::from_be_bytes(
-                buf[remaining_header_size - 4..remaining_header_size]
-                    .try_into()
-                    .expect("incorrect range size"),
+                buf[remaining_header_size - 4..remaining_header_size].try_into()?,
             )),
             PageType::IndexLeaf | PageType::TableLeaf => None,
         };
 
-        PageHeader {
+        Ok(PageHeader {
             page_type,
             first_free_block: u16::from_be_bytes([buf[0], buf[1]]),
             cell_count: u16::from_be_bytes([buf[2], buf[3]]),
             cell_content_offset: u16::from_be_bytes([buf[4], buf[5]]),
             fragmented_free_bytes: u8::from_be_bytes([buf[6]]),
             rightmost_pointer,
+        })
+    }
+}
+
+impl From<PageType> for u8 {
+    fn from(page_type: PageType) -> Self {
+        match page_type {
+            PageType::IndexInterior => 0x02,
+            PageType::TableInterior => 0x05,
+            PageType::IndexLeaf => 0x0a,
+            PageType::TableLeaf => 0x0d,
+        }
+    }
+}
+
+impl Serialize for PageHeader {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let mut buf = vec![u8::from(self.page_type)];
+        buf.extend_from_slice(&self.first_free_block.to_be_bytes());
+        buf.extend_from_slice(&self.cell_count.to_be_bytes());
+        buf.extend_from_slice(&self.cell_content_offset.to_be_bytes());
+        buf.push(self.fragmented_free_bytes);
+        match (self.page_type, self.rightmost_pointer) {
+            (PageType::IndexInterior | PageType::TableInterior, Some(pointer)) => {
+                buf.extend_from_slice(&pointer.to_be_bytes())
+            }
+            (PageType::IndexLeaf | PageType::TableLeaf, None) => {}
+            (page_type, _) => bail!(
+                "only interior pages have a right most pointer, this is a {} page",
+                page_type
+            ),
         }
+        writer
+            .write_all(&buf)
+            .context("failed to write BTreePageHeader")
     }
 }
 
@@ -83,13 +120,13 @@ mod parse_btreeheader_tests {
 
     use crate::{
         data_model::btree::page_header::{PageHeader, PageType},
-        serialisation::deserialize::Deserialize,
+        serialisation::{deserialize::Deserialize, serialize::Serialize},
     };
 
     #[test]
     fn test_deserializing_leaftable_header() {
         let mut reader = Cursor::new(vec![0x0d, 0x0, 0x0, 0x0, 0x3, 0x0, 0x0, 0x0]);
-        let page_header = PageHeader::deserialize(&mut reader);
+        let page_header = PageHeader::deserialize(&mut reader).unwrap();
         assert_eq!(page_header.page_type, PageType::TableLeaf);
         assert_eq!(page_header.cell_count, 3);
         assert_eq!(page_header.rightmost_pointer, None);
@@ -100,9 +137,22 @@ mod parse_btreeheader_tests {
         let mut reader = Cursor::new(vec![
             0x05, 0x0, 0x0, 0x0, 0x3, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1,
         ]);
-        let page_header = PageHeader::deserialize(&mut reader);
+        let page_header = PageHeader::deserialize(&mut reader).unwrap();
         assert_eq!(page_header.page_type, PageType::TableInterior);
         assert_eq!(page_header.cell_count, 3);
         assert_eq!(page_header.rightmost_pointer, Some(1));
     }
+
+    #[test]
+    fn test_serializing_headers() {
+        for bytes in [
+            vec![0x0d, 0x0, 0x0, 0x0, 0x3, 0x0f, 0x8a, 0x0],
+            vec![0x05, 0x0, 0x0, 0x0, 0x3, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x1],
+        ] {
+            let page_header = PageHeader::deserialize(&mut Cursor::new(bytes.clone())).unwrap();
+            let mut written = vec![];
+            page_header.serialize(&mut written).unwrap();
+            assert_eq!(written, bytes);
+        }
+    }
 }
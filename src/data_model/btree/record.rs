@@ -1,30 +1,344 @@
-use crate::serialisation::deserialize::Deserialize;
+use std::{
+    borrow::Cow,
+    io::{Cursor, Read, Write},
+    ops::Deref,
+};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::{
+    data_model::db_header::TextEncoding,
+    error::Error,
+    pager::cache::PageBuffer,
+    serialisation::{deserialize::Deserialize, serialize::Serialize},
+};
 
 use super::{
     record_header::RecordHeader,
-    serial_value::{deserialize_value, SerialValue},
+    serial_type::SerialType,
+    serial_value::{decode_value, deserialize_value, SerialValue},
 };
 
+/// A record with every value decoded
 #[derive(Clone)]
 pub struct Record {
     pub header: RecordHeader,
-    pub values: Vec<SerialValue>,
+    pub values: Vec<SerialValue<'static>>,
 }
 
-impl Deserialize for Record {
-    fn deserialize<T: std::io::Read>(reader: &mut T) -> Self {
-        let header = RecordHeader::deserialize(reader);
+impl Record {
+    /// A record of the values with a header that stores each of them in the fewest bytes
+    pub fn new(values: Vec<SerialValue<'static>>, encoding: TextEncoding) -> Self {
+        let column_types = values
+            .iter()
+            .map(|value| value.serial_type(encoding))
+            .collect();
+        Record {
+            header: RecordHeader::new(column_types),
+            values,
+        }
+    }
+
+    /// Deserialize a record whose TEXT values are stored in the given encoding
+    pub fn read<T: Read>(reader: &mut T, encoding: TextEncoding) -> Result<Self> {
+        let header = RecordHeader::deserialize(reader)?;
         let values = header
             .column_types
             .iter()
-            .map(|serial_type| deserialize_value(reader, serial_type.clone()))
+            .map(|serial_type| deserialize_value(reader, serial_type.clone(), encoding))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { header, values })
+    }
+}
+
+impl Deserialize for Record {
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Self> {
+        Record::read(reader, TextEncoding::Utf8)
+    }
+}
+
+impl Record {
+    /// Serialize a record storing its TEXT values in the given encoding
+    pub fn write<T: Write>(&self, writer: &mut T, encoding: TextEncoding) -> Result<()> {
+        ensure!(
+            self.header.column_types.len() == self.values.len(),
+            "record header has {} columns but there are {} values",
+            self.header.column_types.len(),
+            self.values.len()
+        );
+        for (serial_type, value) in self.header.column_types.iter().zip(&self.values) {
+            ensure!(
+                *serial_type == value.serial_type(encoding),
+                "{:?} can't be stored as {:?}",
+                value,
+                serial_type
+            );
+        }
+        self.header.serialize(writer)?;
+        for value in &self.values {
+            value.write(writer, encoding)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        self.write(writer, TextEncoding::Utf8)
+    }
+}
+
+/// Bytes of a record, borrowed from its page, kept alive with the page or copied when the record overflowed
+#[derive(Clone)]
+enum Payload<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+    Page {
+        page: PageBuffer,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl Deref for Payload<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Borrowed(bytes) => bytes,
+            Payload::Owned(bytes) => bytes,
+            Payload::Page { page, start, end } => &page[*start..*end],
+        }
+    }
+}
+
+/// A record over the bytes of its payload, usually borrowed from the page the cell is on.
+/// The header is parsed up front and a value is only decoded when it's asked for
+#[derive(Clone)]
+pub struct RecordView<'a> {
+    pub header: RecordHeader,
+    /// Page of the cell, a value that can't be decoded makes it corrupt
+    page_number: u32,
+    /// Where the bytes of each value start in the payload
+    offsets: Vec<usize>,
+    payload: Payload<'a>,
+    encoding: TextEncoding,
+}
+
+impl<'a> RecordView<'a> {
+    pub fn new(page_number: u32, payload: Cow<'a, [u8]>, encoding: TextEncoding) -> Result<Self> {
+        let header = RecordHeader::deserialize(&mut Cursor::new(&payload[..]))?;
+
+        let mut offsets = Vec::with_capacity(header.column_types.len());
+        let mut end = header.size as usize;
+        for serial_type in &header.column_types {
+            offsets.push(end);
+            end = end.saturating_add(SerialType::size(serial_type.clone()));
+        }
+        ensure!(
+            end <= payload.len(),
+            "values of the record end at byte {} of a {} byte payload",
+            end,
+            payload.len()
+        );
+
+        let payload = match payload {
+            Cow::Borrowed(bytes) => Payload::Borrowed(bytes),
+            Cow::Owned(bytes) => Payload::Owned(bytes),
+        };
+        Ok(Self {
+            header,
+            page_number,
+            offsets,
+            payload,
+            encoding,
+        })
+    }
+
+    /// Number of values in the record
+    pub fn len(&self) -> usize {
+        self.header.column_types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decode the value of a column, None when the record has fewer columns
+    pub fn value(&self, column: usize) -> Result<Option<SerialValue<'_>>> {
+        let (Some(serial_type), Some(start)) = (
+            self.header.column_types.get(column),
+            self.offsets.get(column),
+        ) else {
+            return Ok(None);
+        };
+        let end = start + SerialType::size(serial_type.clone());
+        let value = decode_value(
+            &self.payload[*start..end],
+            serial_type.clone(),
+            self.encoding,
+        )
+        .map_err(|e| Error::on_page(self.page_number, e))?;
+        Ok(Some(value))
+    }
+
+    /// Decode the value of the last column
+    pub fn last(&self) -> Result<Option<SerialValue<'_>>> {
+        match self.len() {
+            0 => Ok(None),
+            len => self.value(len - 1),
+        }
+    }
+
+    /// Decode every value of the record
+    pub fn values(&self) -> Result<Vec<SerialValue<'_>>> {
+        (0..self.len())
+            .map(|column| Ok(self.value(column)?.unwrap_or(SerialValue::Null)))
+            .collect()
+    }
+
+    /// A view of the given columns in the given order, every column has to be in the record
+    pub fn select_columns(self, columns: &[usize]) -> Self {
+        let column_types = columns
+            .iter()
+            .map(|column| self.header.column_types[*column].clone())
             .collect();
+        let offsets = columns.iter().map(|column| self.offsets[*column]).collect();
+        Self {
+            header: RecordHeader {
+                size: self.header.size,
+                column_types,
+            },
+            page_number: self.page_number,
+            offsets,
+            payload: self.payload,
+            encoding: self.encoding,
+        }
+    }
+
+    /// The bytes of the record, its header followed by the values
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Copy the payload if it's borrowed so the record outlives the page it was read from
+    pub fn into_owned(self) -> RecordView<'static> {
+        let payload = match self.payload {
+            Payload::Borrowed(bytes) => Payload::Owned(bytes.to_vec()),
+            Payload::Owned(bytes) => Payload::Owned(bytes),
+            Payload::Page { page, start, end } => Payload::Page { page, start, end },
+        };
+        RecordView {
+            header: self.header,
+            page_number: self.page_number,
+            offsets: self.offsets,
+            payload,
+            encoding: self.encoding,
+        }
+    }
 
-        Self { header, values }
+    /// Keep the page the payload is borrowed from alive rather than copying the payload,
+    /// a payload that isn't on the page is copied
+    pub fn retain(self, page: &PageBuffer) -> RecordView<'static> {
+        let Payload::Borrowed(bytes) = self.payload else {
+            return self.into_owned();
+        };
+        let start = (bytes.as_ptr() as usize).wrapping_sub(page.as_ptr() as usize);
+        if start > page.len() || bytes.len() > page.len() - start {
+            return self.into_owned();
+        }
+        RecordView {
+            header: self.header,
+            page_number: self.page_number,
+            offsets: self.offsets,
+            payload: Payload::Page {
+                page: page.clone(),
+                start,
+                end: start + bytes.len(),
+            },
+            encoding: self.encoding,
+        }
+    }
+}
+
+impl Serialize for RecordView<'_> {
+    /// Writes the payload it was read from unchanged, the values are never decoded
+    /// and columns picked with `select_columns` are written in their stored order
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        writer
+            .write_all(&self.payload)
+            .context("failed to write record")
     }
 }
 
 pub trait HasRecord {
-    fn record(&self) -> &Record;
-    fn row_id(&self) -> u64;
+    fn record(&self) -> &RecordView<'_>;
+    fn row_id(&self) -> Result<u64>;
+}
+
+#[cfg(test)]
+mod record_view_tests {
+    use super::*;
+
+    // ('Italian', 7.5, 2) after a NULL rowid alias
+    static SANDWICH: [u8; 21] = [
+        0x05, 0x00, 0x1b, 0x07, 0x01, 0x49, 0x74, 0x61, 0x6c, 0x69, 0x61, 0x6e, 0x40, 0x1e, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+    ];
+
+    #[test]
+    fn test_values_are_decoded_on_demand() {
+        let record = RecordView::new(2, Cow::Borrowed(&SANDWICH[..]), TextEncoding::Utf8).unwrap();
+        assert_eq!(record.len(), 4);
+        assert_eq!(record.value(3).unwrap(), Some(SerialValue::Int(2)));
+        assert_eq!(record.value(4).unwrap(), None);
+        assert_eq!(
+            record.values().unwrap(),
+            vec![
+                SerialValue::Null,
+                SerialValue::Text("Italian".into()),
+                SerialValue::Float(7.5),
+                SerialValue::Int(2)
+            ]
+        );
+        // utf-8 text points into the payload rather than being copied
+        let Some(SerialValue::Text(Cow::Borrowed(text))) = record.value(1).unwrap() else {
+            panic!("text should be borrowed from the payload");
+        };
+        assert_eq!(text.as_ptr(), SANDWICH[5..].as_ptr());
+    }
+
+    #[test]
+    fn test_only_the_asked_for_value_is_decoded() {
+        let mut payload = SANDWICH;
+        // text that isn't utf-8 only fails when the text is read
+        payload[5] = 0xff;
+        let record = RecordView::new(2, Cow::Borrowed(&payload[..]), TextEncoding::Utf8).unwrap();
+        assert_eq!(record.value(2).unwrap(), Some(SerialValue::Float(7.5)));
+        let error = record.value(1).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(Error::CorruptPage { page: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_selecting_columns() {
+        let record = RecordView::new(2, Cow::Borrowed(&SANDWICH[..]), TextEncoding::Utf8).unwrap();
+        let record = record.select_columns(&[3, 1]).into_owned();
+        assert_eq!(
+            record.values().unwrap(),
+            vec![SerialValue::Int(2), SerialValue::Text("Italian".into())]
+        );
+        assert_eq!(
+            record.header.column_types,
+            vec![SerialType::Int8, SerialType::Text(7)]
+        );
+    }
+
+    #[test]
+    fn test_values_past_the_end_of_the_payload() {
+        assert!(RecordView::new(2, Cow::Borrowed(&SANDWICH[..20]), TextEncoding::Utf8).is_err());
+    }
 }
@@ -1,8 +1,14 @@
-use std::io::Read;
+use std::io::{Read, Write};
+
+use anyhow::{ensure, Result};
 
 use crate::{
     data_model::btree::serial_type::SerialType,
-    serialisation::{deserialize::Deserialize, varint::read_varint},
+    serialisation::{
+        deserialize::Deserialize,
+        serialize::Serialize,
+        varint::{read_varint, varint_size, write_varint},
+    },
 };
 
 #[derive(Clone)]
@@ -11,20 +17,73 @@ pub struct RecordHeader {
     pub column_types: Vec<SerialType>,
 }
 
+impl RecordHeader {
+    pub fn new(column_types: Vec<SerialType>) -> Self {
+        let types_size: usize = column_types
+            .iter()
+            .map(|serial_type| varint_size(serial_type.into()))
+            .sum();
+        // the size counts the varint it is written in as well
+        let mut size = types_size + 1;
+        while types_size + varint_size(size as u64) != size {
+            size = types_size + varint_size(size as u64);
+        }
+        RecordHeader {
+            size: size as u64,
+            column_types,
+        }
+    }
+}
+
 impl Deserialize for RecordHeader {
-    fn deserialize<T: Read>(reader: &mut T) -> Self {
-        let (size, b) = read_varint(reader);
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Self> {
+        let (size, b) = read_varint(reader)?;
         let mut bytes_read = b;
         let mut column_types: Vec<SerialType> = vec![];
 
         while bytes_read < size {
-            let (type_encoding, b) = read_varint(reader);
+            let (type_encoding, b) = read_varint(reader)?;
             bytes_read += b;
-            column_types.push(SerialType::from(type_encoding));
+            column_types.push(SerialType::try_from(type_encoding)?);
+        }
+
+        ensure!(
+            bytes_read == size,
+            "record header is {} bytes but its serial types take {}",
+            size,
+            bytes_read
+        );
+
+        Ok(RecordHeader { size, column_types })
+    }
+}
+
+impl Serialize for RecordHeader {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        let mut bytes_written = write_varint(writer, self.size)?;
+        for serial_type in &self.column_types {
+            bytes_written += write_varint(writer, serial_type.into())?;
         }
+        ensure!(
+            bytes_written == self.size,
+            "record header is {} bytes but {} were written",
+            self.size,
+            bytes_written
+        );
+        Ok(())
+    }
+}
 
-        assert_eq!(bytes_read, size);
+#[cfg(test)]
+mod record_header_tests {
+    use super::*;
 
-        return RecordHeader { size, column_types };
+    #[test]
+    fn test_header_size_counts_itself() {
+        assert_eq!(RecordHeader::new(vec![]).size, 1);
+        assert_eq!(RecordHeader::new(vec![SerialType::Int8; 126]).size, 127);
+        // 127 types need a 2 byte size, making the header 129 bytes
+        assert_eq!(RecordHeader::new(vec![SerialType::Int8; 127]).size, 129);
+        assert_eq!(RecordHeader::new(vec![SerialType::Text(100)]).size, 3);
     }
 }
@@ -1,3 +1,5 @@
+use anyhow::bail;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum SerialType {
     Null,
@@ -25,17 +27,37 @@ impl SerialType {
             SerialType::Int48 => 6,
             SerialType::Int64 => 8,
             SerialType::Float64 => 8,
-            SerialType::Zero | SerialType::One => 0,
+            SerialType::Null | SerialType::Zero | SerialType::One => 0,
             SerialType::Blob(size) => size,
             SerialType::Text(size) => size,
-            _ => panic!("Invalid serial type"),
         }
     }
 }
 
-impl From<u64> for SerialType {
-    fn from(value: u64) -> Self {
-        match value {
+impl From<&SerialType> for u64 {
+    fn from(serial_type: &SerialType) -> Self {
+        match serial_type {
+            SerialType::Null => 0,
+            SerialType::Int8 => 1,
+            SerialType::Int16 => 2,
+            SerialType::Int24 => 3,
+            SerialType::Int32 => 4,
+            SerialType::Int48 => 5,
+            SerialType::Int64 => 6,
+            SerialType::Float64 => 7,
+            SerialType::Zero => 8,
+            SerialType::One => 9,
+            SerialType::Blob(size) => *size as u64 * 2 + 12,
+            SerialType::Text(size) => *size as u64 * 2 + 13,
+        }
+    }
+}
+
+impl TryFrom<u64> for SerialType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        let serial_type = match value {
             0 => SerialType::Null,
             1 => SerialType::Int8,
             2 => SerialType::Int16,
@@ -46,16 +68,17 @@ impl From<u64> for SerialType {
             7 => SerialType::Float64,
             8 => SerialType::Zero,
             9 => SerialType::One,
-            10 | 11 => panic!("Serial type for internal use"),
+            10 | 11 => bail!("Serial type {} is reserved for internal use", value),
             value => {
                 if value >= 12 && value % 2 == 0 {
                     SerialType::Blob(((value - 12) / 2) as usize)
                 } else if value >= 13 && value % 2 == 1 {
                     SerialType::Text(((value - 13) / 2) as usize)
                 } else {
-                    panic!("Invalid serial type encoding {}", value);
+                    bail!("Invalid serial type encoding {}", value);
                 }
             }
-        }
+        };
+        Ok(serial_type)
     }
 }
@@ -1,16 +1,81 @@
-use std::{cmp::min, fmt::Display, io::Read};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    io::{Read, Write},
+};
 
-use crate::data_model::btree::serial_type::SerialType;
+use anyhow::{ensure, Context, Result};
 
+use crate::{
+    data_model::{btree::serial_type::SerialType, db_header::TextEncoding},
+    serialisation::serialize::Serialize,
+};
+
+/// A value of a record, TEXT and BLOB values can borrow their bytes from the page they were read from
 #[derive(PartialEq, Debug, Clone)]
-pub enum SerialValue {
+pub enum SerialValue<'a> {
     Null,
     Int(i64),
     Float(f64),
-    Text(String),
-    Blob(Vec<u8>),
+    Text(Cow<'a, str>),
+    Blob(Cow<'a, [u8]>),
+}
+
+impl SerialValue<'_> {
+    /// Copy borrowed text or bytes so the value outlives the page it was read from
+    pub fn into_owned(self) -> SerialValue<'static> {
+        match self {
+            SerialValue::Null => SerialValue::Null,
+            SerialValue::Int(value) => SerialValue::Int(value),
+            SerialValue::Float(value) => SerialValue::Float(value),
+            SerialValue::Text(value) => SerialValue::Text(Cow::Owned(value.into_owned())),
+            SerialValue::Blob(value) => SerialValue::Blob(Cow::Owned(value.into_owned())),
+        }
+    }
 }
-impl Display for SerialValue {
+
+impl SerialValue<'_> {
+    /// The serial type the value is stored as, integers take the fewest bytes that hold them
+    pub fn serial_type(&self, encoding: TextEncoding) -> SerialType {
+        match self {
+            SerialValue::Null => SerialType::Null,
+            SerialValue::Int(0) => SerialType::Zero,
+            SerialValue::Int(1) => SerialType::One,
+            SerialValue::Int(value) => match value {
+                -0x80..=0x7f => SerialType::Int8,
+                -0x8000..=0x7fff => SerialType::Int16,
+                -0x80_0000..=0x7f_ffff => SerialType::Int24,
+                -0x8000_0000..=0x7fff_ffff => SerialType::Int32,
+                -0x8000_0000_0000..=0x7fff_ffff_ffff => SerialType::Int48,
+                _ => SerialType::Int64,
+            },
+            SerialValue::Float(_) => SerialType::Float64,
+            SerialValue::Text(text) => SerialType::Text(encode_text(text, encoding).len()),
+            SerialValue::Blob(blob) => SerialType::Blob(blob.len()),
+        }
+    }
+
+    /// Write the bytes of the value as its serial type with TEXT in the given encoding
+    pub fn write<T: Write>(&self, writer: &mut T, encoding: TextEncoding) -> Result<()> {
+        let size = SerialType::size(self.serial_type(encoding));
+        let result = match self {
+            SerialValue::Null => Ok(()),
+            SerialValue::Int(value) => writer.write_all(&value.to_be_bytes()[8 - size..]),
+            SerialValue::Float(value) => writer.write_all(&value.to_be_bytes()),
+            SerialValue::Text(text) => writer.write_all(&encode_text(text, encoding)),
+            SerialValue::Blob(blob) => writer.write_all(blob),
+        };
+        result.context("failed to write value")
+    }
+}
+
+impl Serialize for SerialValue<'_> {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        self.write(writer, TextEncoding::Utf8)
+    }
+}
+
+impl Display for SerialValue<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SerialValue::Null => write!(f, ""),
@@ -22,41 +87,92 @@ impl Display for SerialValue {
     }
 }
 
-pub fn deserialize_value<T: Read>(reader: &mut T, serial_type: SerialType) -> SerialValue {
-    match serial_type {
+pub fn deserialize_value<T: Read>(
+    reader: &mut T,
+    serial_type: SerialType,
+    encoding: TextEncoding,
+) -> Result<SerialValue<'static>> {
+    let bytes = read_bytes(reader, SerialType::size(serial_type.clone()))?;
+    Ok(decode_value(&bytes, serial_type, encoding)?.into_owned())
+}
+
+/// Decode a value from exactly the bytes the serial type says it takes,
+/// UTF-8 text and blobs borrow `bytes` rather than being copied
+pub fn decode_value(
+    bytes: &[u8],
+    serial_type: SerialType,
+    encoding: TextEncoding,
+) -> Result<SerialValue<'_>> {
+    ensure!(
+        bytes.len() == SerialType::size(serial_type.clone()),
+        "{:?} value can't be {} bytes",
+        serial_type,
+        bytes.len()
+    );
+    let value = match serial_type {
         SerialType::Int8
         | SerialType::Int16
         | SerialType::Int24
         | SerialType::Int32
         | SerialType::Int48
         | SerialType::Int64 => {
-            const MAX_SIZE: usize = 8;
-            let buffer_size = min(SerialType::size(serial_type), MAX_SIZE);
-            let mut buf = vec![0u8; buffer_size];
-            let _ = reader.read_exact(&mut buf);
-            let mut byte_array = [0u8; MAX_SIZE];
-            byte_array[MAX_SIZE - buffer_size..MAX_SIZE].copy_from_slice(&buf[..buffer_size]);
+            // sign extend the big-endian bytes
+            let fill = if bytes[0] & 0x80 != 0 { 0xff } else { 0 };
+            let mut byte_array = [fill; 8];
+            byte_array[8 - bytes.len()..].copy_from_slice(bytes);
             SerialValue::Int(i64::from_be_bytes(byte_array))
         }
         SerialType::Zero => SerialValue::Int(0),
         SerialType::One => SerialValue::Int(1),
-        SerialType::Float64 => {
-            let mut buf = [0u8; 8];
-            let _ = reader.read_exact(&mut buf);
-            SerialValue::Float(f64::from_be_bytes(buf))
-        }
-        SerialType::Blob(size) => {
-            let mut buf = vec![0u8; size];
-            let _ = reader.read_exact(&mut buf);
-            SerialValue::Blob(buf)
-        }
-        SerialType::Text(size) => {
-            let mut buf = vec![0u8; size];
-            let _ = reader.read_exact(&mut buf);
-            let out_str = String::from_utf8(buf).expect("failed to parse Text to string");
-            SerialValue::Text(out_str)
-        }
+        SerialType::Float64 => SerialValue::Float(f64::from_be_bytes(bytes.try_into()?)),
+        SerialType::Blob(_) => SerialValue::Blob(Cow::Borrowed(bytes)),
+        SerialType::Text(_) => SerialValue::Text(decode_text(bytes, encoding)?),
         SerialType::Null => SerialValue::Null,
+    };
+    Ok(value)
+}
+
+/// Read the bytes of a value, a corrupt record can claim any size so the buffer only grows as bytes are read
+fn read_bytes<T: Read>(reader: &mut T, size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    reader
+        .take(size as u64)
+        .read_to_end(&mut buf)
+        .context("failed to read value")?;
+    ensure!(
+        buf.len() == size,
+        "value of {} bytes runs past the end of the record",
+        size
+    );
+    Ok(buf)
+}
+
+/// Decode the bytes of a TEXT value stored in the database's text encoding, UTF-8 text is borrowed
+pub fn decode_text(bytes: &[u8], encoding: TextEncoding) -> Result<Cow<'_, str>> {
+    let code_units = bytes.chunks_exact(2).map(|pair| [pair[0], pair[1]]);
+    let text = match encoding {
+        TextEncoding::Utf8 => {
+            Cow::Borrowed(std::str::from_utf8(bytes).context("failed to parse Text to string")?)
+        }
+        TextEncoding::Utf16le => Cow::Owned(
+            String::from_utf16(&code_units.map(u16::from_le_bytes).collect::<Vec<u16>>())
+                .context("failed to parse UTF-16le Text to string")?,
+        ),
+        TextEncoding::Utf16be => Cow::Owned(
+            String::from_utf16(&code_units.map(u16::from_be_bytes).collect::<Vec<u16>>())
+                .context("failed to parse UTF-16be Text to string")?,
+        ),
+    };
+    Ok(text)
+}
+
+/// Encode a string the way a TEXT value is stored in the database,
+/// the BINARY collation compares these bytes rather than the decoded characters
+pub fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    match encoding {
+        TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        TextEncoding::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
     }
 }
 
@@ -70,41 +186,103 @@ mod parse_values_tests {
     #[test]
     fn test_parse_value_int8() {
         let mut reader = Cursor::new(vec![0x01]);
-        let value = deserialize_value(&mut reader, SerialType::Int8);
+        let value = deserialize_value(&mut reader, SerialType::Int8, TextEncoding::Utf8).unwrap();
         assert_eq!(value, SerialValue::Int(1));
     }
     #[test]
     fn test_parse_value_int64() {
         let mut reader = Cursor::new(vec![127, 255, 255, 255, 255, 255, 255, 255]);
-        let value = deserialize_value(&mut reader, SerialType::Int64);
+        let value = deserialize_value(&mut reader, SerialType::Int64, TextEncoding::Utf8).unwrap();
         assert_eq!(value, SerialValue::Int(9223372036854775807));
     }
 
+    #[test]
+    fn test_parse_value_negative_int() {
+        let mut reader = Cursor::new(vec![0xff, 0x38]);
+        let value = deserialize_value(&mut reader, SerialType::Int16, TextEncoding::Utf8).unwrap();
+        assert_eq!(value, SerialValue::Int(-200));
+    }
+
     #[test]
     fn test_parse_value_float() {
         let mut reader = Cursor::new(vec![0x40, 0x29, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-        let value = deserialize_value(&mut reader, SerialType::Float64);
+        let value =
+            deserialize_value(&mut reader, SerialType::Float64, TextEncoding::Utf8).unwrap();
         assert_eq!(value, SerialValue::Float(12.5));
     }
 
     #[test]
     fn test_parse_value_blob() {
         let mut reader = Cursor::new(vec![0x01, 0x02, 0x03, 0x04]);
-        let value = deserialize_value(&mut reader, SerialType::Blob(4));
-        assert_eq!(value, SerialValue::Blob(vec![0x01, 0x02, 0x03, 0x04]));
+        let value =
+            deserialize_value(&mut reader, SerialType::Blob(4), TextEncoding::Utf8).unwrap();
+        assert_eq!(
+            value,
+            SerialValue::Blob(vec![0x01, 0x02, 0x03, 0x04].into())
+        );
     }
 
     #[test]
     fn test_parse_value_text() {
         let mut reader = Cursor::new(vec![0x48, 0x65, 0x6c, 0x6c, 0x6f]);
-        let value = deserialize_value(&mut reader, SerialType::Text(5));
-        assert_eq!(value, SerialValue::Text("Hello".to_string()));
+        let value =
+            deserialize_value(&mut reader, SerialType::Text(5), TextEncoding::Utf8).unwrap();
+        assert_eq!(value, SerialValue::Text("Hello".into()));
     }
 
     #[test]
     fn test_parse_value_null() {
         let mut reader = Cursor::new(vec![]);
-        let value = deserialize_value(&mut reader, SerialType::Null);
+        let value = deserialize_value(&mut reader, SerialType::Null, TextEncoding::Utf8).unwrap();
         assert_eq!(value, SerialValue::Null);
     }
+
+    #[test]
+    fn test_parse_value_utf16_text() {
+        let mut reader = Cursor::new(vec![0x48, 0x00, 0xe9, 0x00, 0x2c, 0x67]);
+        let value =
+            deserialize_value(&mut reader, SerialType::Text(6), TextEncoding::Utf16le).unwrap();
+        assert_eq!(value, SerialValue::Text("Hé本".into()));
+
+        let mut reader = Cursor::new(vec![0x00, 0x48, 0x00, 0xe9, 0x67, 0x2c]);
+        let value =
+            deserialize_value(&mut reader, SerialType::Text(6), TextEncoding::Utf16be).unwrap();
+        assert_eq!(value, SerialValue::Text("Hé本".into()));
+    }
+
+    #[test]
+    fn test_integers_take_the_fewest_bytes() {
+        for (value, serial_type) in [
+            (0, SerialType::Zero),
+            (1, SerialType::One),
+            (2, SerialType::Int8),
+            (-1, SerialType::Int8),
+            (-129, SerialType::Int16),
+            (32768, SerialType::Int24),
+            (-8388609, SerialType::Int32),
+            (2147483648, SerialType::Int48),
+            (i64::MIN, SerialType::Int64),
+        ] {
+            let value = SerialValue::Int(value);
+            assert_eq!(value.serial_type(TextEncoding::Utf8), serial_type);
+
+            let mut buf = vec![];
+            value.serialize(&mut buf).unwrap();
+            let mut reader = Cursor::new(buf);
+            let read = deserialize_value(&mut reader, serial_type, TextEncoding::Utf8).unwrap();
+            assert_eq!(read, value);
+        }
+    }
+
+    #[test]
+    fn test_encode_text_round_trips() {
+        for encoding in [
+            TextEncoding::Utf8,
+            TextEncoding::Utf16le,
+            TextEncoding::Utf16be,
+        ] {
+            let bytes = encode_text("Żółw 𝄞", encoding);
+            assert_eq!(decode_text(&bytes, encoding).unwrap(), "Żółw 𝄞");
+        }
+    }
 }
@@ -1,19 +1,48 @@
-use crate::serialisation::{deserialize::Deserialize, varint::read_varint};
+use std::io::{Cursor, Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::serialisation::{
+    deserialize::DeserializeCell,
+    serialize::SerializeCell,
+    varint::{read_varint, write_varint},
+};
+
+use super::overflow::PayloadReader;
 
 pub struct TableInteriorCell {
     pub left_child: u32, // page number of the left subtree
     pub row_id: u64,     // all keys in the subtree are less than this key
 }
 
-impl Deserialize for TableInteriorCell {
-    fn deserialize<T: std::io::Read>(reader: &mut T) -> Self {
+impl DeserializeCell<'_> for TableInteriorCell {
+    // table interior cells carry no payload so they never overflow
+    fn deserialize_cell<O: PayloadReader>(
+        _page_number: u32,
+        reader: &mut Cursor<&[u8]>,
+        _payload_reader: &O,
+    ) -> Result<Self> {
         let mut buf: [u8; 4] = [0; 4];
         reader
-            .read(&mut buf)
-            .expect("Failed to read 4 bytes for left child pointer");
+            .read_exact(&mut buf)
+            .context("Failed to read 4 bytes for left child pointer")?;
         let left_child = u32::from_be_bytes(buf);
-        let (row_id, _) = read_varint(reader);
+        let (row_id, _) = read_varint(reader)?;
+
+        Ok(TableInteriorCell { left_child, row_id })
+    }
+}
 
-        TableInteriorCell { left_child, row_id }
+impl SerializeCell for TableInteriorCell {
+    fn serialize_cell<T: Write, O: PayloadReader>(
+        &self,
+        writer: &mut T,
+        _payload_reader: &O,
+    ) -> Result<()> {
+        writer
+            .write_all(&self.left_child.to_be_bytes())
+            .context("Failed to write left child pointer")?;
+        write_varint(writer, self.row_id)?;
+        Ok(())
     }
 }
@@ -1,8 +1,21 @@
-use std::io::Read;
+use std::io::{Cursor, Read, Write};
 
-use crate::serialisation::{deserialize::Deserialize, varint::read_varint};
+use anyhow::{ensure, Result};
 
-use super::record::{HasRecord, Record};
+use crate::{
+    pager::cache::PageBuffer,
+    serialisation::{
+        deserialize::{Deserialize, DeserializeCell},
+        serialize::{Serialize, SerializeCell},
+        varint::{read_varint, write_varint},
+    },
+};
+
+use super::{
+    overflow::{read_payload, write_payload, PayloadReader},
+    page_header::PageType,
+    record::{HasRecord, RecordView},
+};
 
 #[derive(Clone)]
 pub struct RowHeader {
@@ -12,34 +25,88 @@ pub struct RowHeader {
 }
 
 impl Deserialize for RowHeader {
-    fn deserialize<T: Read>(reader: &mut T) -> Self {
-        let (size, _) = read_varint(reader);
-        let (row_id, _) = read_varint(reader);
-        RowHeader { size, row_id }
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Self> {
+        let (size, _) = read_varint(reader)?;
+        let (row_id, _) = read_varint(reader)?;
+        Ok(RowHeader { size, row_id })
+    }
+}
+
+impl Serialize for RowHeader {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        write_varint(writer, self.size)?;
+        write_varint(writer, self.row_id)?;
+        Ok(())
     }
 }
 
 #[derive(Clone)]
-pub struct TableLeafCell {
+pub struct TableLeafCell<'a> {
     pub row_header: RowHeader,
-    pub record: Record,
+    pub record: RecordView<'a>,
+    pub overflow_page: Option<u32>, // first page of the overflow chain when the record doesn't fit on the page
+}
+
+impl TableLeafCell<'_> {
+    /// Keep the page the record is borrowed from alive so the cell outlives the borrow
+    pub fn retain(self, page: &PageBuffer) -> TableLeafCell<'static> {
+        TableLeafCell {
+            row_header: self.row_header,
+            record: self.record.retain(page),
+            overflow_page: self.overflow_page,
+        }
+    }
+}
+
+impl<'a> DeserializeCell<'a> for TableLeafCell<'a> {
+    fn deserialize_cell<O: PayloadReader>(
+        page_number: u32,
+        reader: &mut Cursor<&'a [u8]>,
+        payload_reader: &O,
+    ) -> Result<Self> {
+        let row_header = RowHeader::deserialize(reader)?;
+        let (payload, overflow_page) =
+            read_payload(reader, PageType::TableLeaf, row_header.size, payload_reader)?;
+        let record = RecordView::new(page_number, payload, payload_reader.text_encoding())?;
+        Ok(TableLeafCell {
+            row_header,
+            record,
+            overflow_page,
+        })
+    }
 }
 
-impl Deserialize for TableLeafCell {
-    fn deserialize<T: Read>(reader: &mut T) -> Self {
-        let row_header = RowHeader::deserialize(reader);
-        let record = Record::deserialize(reader);
-        TableLeafCell { row_header, record }
+impl SerializeCell for TableLeafCell<'_> {
+    fn serialize_cell<T: Write, O: PayloadReader>(
+        &self,
+        writer: &mut T,
+        payload_reader: &O,
+    ) -> Result<()> {
+        let payload = self.record.payload();
+        ensure!(
+            self.row_header.size == payload.len() as u64,
+            "row header says the record is {} bytes but it is {}",
+            self.row_header.size,
+            payload.len()
+        );
+        self.row_header.serialize(writer)?;
+        write_payload(
+            writer,
+            PageType::TableLeaf,
+            payload,
+            self.overflow_page,
+            payload_reader,
+        )
     }
 }
 
-impl HasRecord for TableLeafCell {
-    fn record(&self) -> &Record {
+impl HasRecord for TableLeafCell<'_> {
+    fn record(&self) -> &RecordView<'_> {
         &self.record
     }
 
-    fn row_id(&self) -> u64 {
-        self.row_header.row_id
+    fn row_id(&self) -> Result<u64> {
+        Ok(self.row_header.row_id)
     }
 }
 
@@ -47,33 +114,53 @@ impl HasRecord for TableLeafCell {
 mod parse_record_tests {
     use std::io::Cursor;
 
+    use anyhow::{bail, Result};
+
     use crate::data_model::btree::{
-        serial_type::SerialType, serial_value::SerialValue, table_leaf_cell::TableLeafCell,
+        overflow::PayloadReader, serial_type::SerialType, serial_value::SerialValue,
+        table_leaf_cell::TableLeafCell,
     };
-    use crate::serialisation::deserialize::Deserialize;
+    use crate::data_model::db_header::TextEncoding;
+    use crate::serialisation::deserialize::DeserializeCell;
+
+    struct NoOverflow;
+
+    impl PayloadReader for NoOverflow {
+        fn usable_size(&self) -> usize {
+            4096
+        }
+
+        fn text_encoding(&self) -> TextEncoding {
+            TextEncoding::Utf8
+        }
+
+        fn read_overflow_page(&self, _page_number: u32) -> Result<Vec<u8>> {
+            bail!("record should fit on the page")
+        }
+    }
 
     #[test]
     fn test_record_parsing() {
         // INSERT INTO sandwiches (name, length, count) VALUES ('Italian', 7.5, 2)
-        let mut reader = Cursor::new(vec![
+        let bytes = [
             0x15, 0x01, 0x05, 0x00, 0x1b, 0x07, 0x01, 0x49, 0x74, 0x61, 0x6c, 0x69, 0x61, 0x6e,
             0x40, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
-        ]);
-        let cell = TableLeafCell::deserialize(&mut reader);
+        ];
+        let mut reader = Cursor::new(&bytes[..]);
+        let cell = TableLeafCell::deserialize_cell(2, &mut reader, &NoOverflow).unwrap();
         assert_eq!(cell.row_header.size, 21);
         assert_eq!(cell.row_header.row_id, 1);
         assert_eq!(cell.record.header.size, 5);
+        assert_eq!(cell.overflow_page, None);
         // When an SQL table includes an INTEGER PRIMARY KEY column (which aliases the rowid) then that column appears in the record as a NULL value.
         assert_eq!(cell.record.header.column_types[0], SerialType::Null);
         assert_eq!(cell.record.header.column_types[1], SerialType::Text(7));
         assert_eq!(cell.record.header.column_types[2], SerialType::Float64);
         assert_eq!(cell.record.header.column_types[3], SerialType::Int8);
-        assert_eq!(cell.record.values[0], SerialValue::Null);
-        assert_eq!(
-            cell.record.values[1],
-            SerialValue::Text("Italian".to_string())
-        );
-        assert_eq!(cell.record.values[2], SerialValue::Float(7.5));
-        assert_eq!(cell.record.values[3], SerialValue::Int(2));
+        let values = cell.record.values().unwrap();
+        assert_eq!(values[0], SerialValue::Null);
+        assert_eq!(values[1], SerialValue::Text("Italian".into()));
+        assert_eq!(values[2], SerialValue::Float(7.5));
+        assert_eq!(values[3], SerialValue::Int(2));
     }
 }
@@ -1,20 +1,295 @@
-use std::io::Read;
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+};
 
-use crate::serialisation::deserialize::Deserialize;
+use anyhow::{bail, Context, Result};
 
+use crate::{
+    error::Error,
+    serialisation::{deserialize::Deserialize, serialize::Serialize},
+};
+
+pub const DB_HEADER_SIZE: usize = 100;
+pub const MAGIC_HEADER_STRING: &[u8; 16] = b"SQLite format 3\0";
+
+/// The first 100 bytes of the database file
+/// https://www.sqlite.org/fileformat2.html#the_database_header
+#[derive(Clone)]
 pub struct Dbheader {
-    pub page_size: u16,
+    pub page_size: u32,     // stored as 1 in the file when the page size is 65536
+    pub write_version: u8,  // 1 for legacy, 2 for WAL
+    pub read_version: u8,   // 1 for legacy, 2 for WAL
+    pub reserved_bytes: u8, // unused space at the end of each page
+    pub max_payload_fraction: u8, // must be 64
+    pub min_payload_fraction: u8, // must be 32
+    pub leaf_payload_fraction: u8, // must be 32
+    pub file_change_counter: u32,
+    pub database_size: u32,             // in pages
+    pub first_freelist_trunk_page: u32, // 0 when the freelist is empty
+    pub freelist_page_count: u32,
+    pub schema_cookie: u32,
+    pub schema_format: u32, // 1, 2, 3 or 4
+    pub default_cache_size: u32,
+    pub largest_root_page: u32, // non-zero only in auto-vacuum and incremental-vacuum modes
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub incremental_vacuum: u32, // non-zero for incremental-vacuum mode
+    pub application_id: u32,
+    pub version_valid_for: u32, // change counter value when sqlite_version was stored
+    pub sqlite_version: u32,    // SQLITE_VERSION_NUMBER of the library that last wrote the file
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TryFrom<u32> for TextEncoding {
+    type Error = anyhow::Error;
+
+    /// sqlite leaves the encoding at 0 until the first schema object is created, which means UTF-8
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 | 1 => Ok(TextEncoding::Utf8),
+            2 => Ok(TextEncoding::Utf16le),
+            3 => Ok(TextEncoding::Utf16be),
+            _ => bail!(Error::UnsupportedFormat(format!(
+                "invalid text encoding {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl Display for TextEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Utf8 => write!(f, "utf8"),
+            Self::Utf16le => write!(f, "utf16le"),
+            Self::Utf16be => write!(f, "utf16be"),
+        }
+    }
+}
+
+impl FromStr for TextEncoding {
+    type Err = anyhow::Error;
+
+    /// The names sqlite's `PRAGMA encoding` accepts, with or without the dash
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(TextEncoding::Utf8),
+            "utf16le" | "utf-16le" => Ok(TextEncoding::Utf16le),
+            "utf16be" | "utf-16be" => Ok(TextEncoding::Utf16be),
+            _ => bail!(Error::UnsupportedFormat(format!(
+                "invalid text encoding {}",
+                name
+            ))),
+        }
+    }
+}
+
+impl Dbheader {
+    /// Usable size of a page, the region cells and overflow content can occupy
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.reserved_bytes as usize
+    }
+}
+
+impl TryFrom<&[u8; DB_HEADER_SIZE]> for Dbheader {
+    type Error = anyhow::Error;
+
+    fn try_from(buf: &[u8; DB_HEADER_SIZE]) -> Result<Self> {
+        if &buf[0..16] != MAGIC_HEADER_STRING {
+            bail!(Error::UnsupportedFormat(
+                "file is not a database, missing 'SQLite format 3' header string".into()
+            ));
+        }
+
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                buf[offset],
+                buf[offset + 1],
+                buf[offset + 2],
+                buf[offset + 3],
+            ])
+        };
+
+        let page_size = match u16::from_be_bytes([buf[16], buf[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            bail!(Error::UnsupportedFormat(format!(
+                "invalid page size {}",
+                page_size
+            )));
+        }
+
+        Ok(Dbheader {
+            page_size,
+            write_version: buf[18],
+            read_version: buf[19],
+            reserved_bytes: buf[20],
+            max_payload_fraction: buf[21],
+            min_payload_fraction: buf[22],
+            leaf_payload_fraction: buf[23],
+            file_change_counter: read_u32(24),
+            database_size: read_u32(28),
+            first_freelist_trunk_page: read_u32(32),
+            freelist_page_count: read_u32(36),
+            schema_cookie: read_u32(40),
+            schema_format: read_u32(44),
+            default_cache_size: read_u32(48),
+            largest_root_page: read_u32(52),
+            text_encoding: TextEncoding::try_from(read_u32(56))?,
+            user_version: read_u32(60),
+            incremental_vacuum: read_u32(64),
+            application_id: read_u32(68),
+            version_valid_for: read_u32(92),
+            sqlite_version: read_u32(96),
+        })
+    }
 }
-pub const DB_HEADER_SIZE: usize = 100;
 
 impl Deserialize for Dbheader {
-    fn deserialize<T: Read>(reader: &mut T) -> Dbheader {
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Dbheader> {
         let mut buf = [0; DB_HEADER_SIZE];
         reader
             .read_exact(&mut buf)
-            .expect("failed to read Dbheader");
-        Dbheader {
-            page_size: u16::from_be_bytes([buf[16], buf[17]]),
+            .context("failed to read Dbheader")?;
+        Dbheader::try_from(&buf)
+    }
+}
+
+impl From<TextEncoding> for u32 {
+    fn from(encoding: TextEncoding) -> Self {
+        match encoding {
+            TextEncoding::Utf8 => 1,
+            TextEncoding::Utf16le => 2,
+            TextEncoding::Utf16be => 3,
         }
     }
 }
+
+impl From<&Dbheader> for [u8; DB_HEADER_SIZE] {
+    fn from(header: &Dbheader) -> Self {
+        let mut buf = [0u8; DB_HEADER_SIZE];
+        buf[0..16].copy_from_slice(MAGIC_HEADER_STRING);
+        let page_size = match header.page_size {
+            65536 => 1,
+            size => size as u16,
+        };
+        buf[16..18].copy_from_slice(&page_size.to_be_bytes());
+        buf[18] = header.write_version;
+        buf[19] = header.read_version;
+        buf[20] = header.reserved_bytes;
+        buf[21] = header.max_payload_fraction;
+        buf[22] = header.min_payload_fraction;
+        buf[23] = header.leaf_payload_fraction;
+
+        let mut write_u32 = |offset: usize, value: u32| {
+            buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        };
+        write_u32(24, header.file_change_counter);
+        write_u32(28, header.database_size);
+        write_u32(32, header.first_freelist_trunk_page);
+        write_u32(36, header.freelist_page_count);
+        write_u32(40, header.schema_cookie);
+        write_u32(44, header.schema_format);
+        write_u32(48, header.default_cache_size);
+        write_u32(52, header.largest_root_page);
+        write_u32(56, header.text_encoding.into());
+        write_u32(60, header.user_version);
+        write_u32(64, header.incremental_vacuum);
+        write_u32(68, header.application_id);
+        // bytes 72 to 91 are reserved for expansion and must be zero
+        write_u32(92, header.version_valid_for);
+        write_u32(96, header.sqlite_version);
+
+        buf
+    }
+}
+
+impl Serialize for Dbheader {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()> {
+        writer
+            .write_all(&<[u8; DB_HEADER_SIZE]>::from(self))
+            .context("failed to write Dbheader")
+    }
+}
+
+#[cfg(test)]
+mod db_header_tests {
+    use super::*;
+
+    fn sample_header() -> [u8; DB_HEADER_SIZE] {
+        let mut buf = [0u8; DB_HEADER_SIZE];
+        std::fs::File::open("sample.db")
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_deserializing_header() {
+        let header = Dbheader::try_from(&sample_header()).unwrap();
+        assert_eq!(header.page_size, 4096);
+        assert_eq!(header.write_version, 1);
+        assert_eq!(header.read_version, 1);
+        assert_eq!(header.reserved_bytes, 0);
+        assert_eq!(header.max_payload_fraction, 64);
+        assert_eq!(header.file_change_counter, 5);
+        assert_eq!(header.database_size, 4);
+        assert_eq!(header.freelist_page_count, 0);
+        assert_eq!(header.schema_cookie, 2);
+        assert_eq!(header.schema_format, 4);
+        assert_eq!(header.text_encoding, TextEncoding::Utf8);
+        assert_eq!(header.version_valid_for, 5);
+        assert_eq!(header.sqlite_version, 3034000);
+    }
+
+    #[test]
+    fn test_page_size_of_one_is_65536() {
+        let mut buf = sample_header();
+        buf[16..18].copy_from_slice(&1u16.to_be_bytes());
+        let header = Dbheader::try_from(&buf).unwrap();
+        assert_eq!(header.page_size, 65536);
+    }
+
+    #[test]
+    fn test_encoding_of_zero_is_utf8() {
+        let mut buf = sample_header();
+        buf[56..60].copy_from_slice(&[0; 4]);
+        let header = Dbheader::try_from(&buf).unwrap();
+        assert_eq!(header.text_encoding, TextEncoding::Utf8);
+        assert_eq!(<[u8; DB_HEADER_SIZE]>::from(&header)[56..60], [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_serializing_header() {
+        let buf = sample_header();
+        let header = Dbheader::try_from(&buf).unwrap();
+        let mut written = vec![];
+        header.serialize(&mut written).unwrap();
+        assert_eq!(written, buf);
+
+        let mut buf = sample_header();
+        buf[16..18].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(
+            <[u8; DB_HEADER_SIZE]>::from(&Dbheader::try_from(&buf).unwrap()),
+            buf
+        );
+    }
+
+    #[test]
+    fn test_rejects_missing_magic_string() {
+        let mut buf = sample_header();
+        buf[0..6].copy_from_slice(b"MySQL ");
+        assert!(Dbheader::try_from(&buf).is_err());
+    }
+}
@@ -1,6 +1,12 @@
+use std::{fmt::Display, io::Cursor};
+
+use anyhow::{bail, ensure, Result};
+
 use crate::{
-    data_model::btree::{serial_value::SerialValue, table_leaf_cell::TableLeafCell},
-    serialisation::deserialize::Deserialize,
+    data_model::btree::{
+        overflow::PayloadReader, serial_value::SerialValue, table_leaf_cell::TableLeafCell,
+    },
+    serialisation::deserialize::DeserializeCell,
 };
 
 // The sqlite_schema table contains one record for each table, index, view, and trigger (collectively "objects") in the database schema,
@@ -22,47 +28,93 @@ pub enum DbObject {
     Trigger,
 }
 
-impl Deserialize for SchemaRecord {
-    fn deserialize<T: std::io::Read>(reader: &mut T) -> Self {
-        SchemaRecord::from(TableLeafCell::deserialize(reader))
+impl SchemaRecord {
+    /// The values of the object's row in `sqlite_schema`, automatic indexes have NULL for their sql
+    pub fn values(&self) -> Vec<SerialValue<'static>> {
+        let sql = match self.sql.as_str() {
+            "" => SerialValue::Null,
+            sql => SerialValue::Text(sql.to_string().into()),
+        };
+        vec![
+            SerialValue::Text(self.db_object.to_string().into()),
+            SerialValue::Text(self.name.clone().into()),
+            SerialValue::Text(self.tbl_name.clone().into()),
+            SerialValue::Int(self.rootpage as i64),
+            sql,
+        ]
     }
 }
 
-impl From<TableLeafCell> for SchemaRecord {
-    fn from(cell: TableLeafCell) -> Self {
-        assert_eq!(cell.record.values.len(), 5);
-        let db_object = match &cell.record.values[0] {
-            SerialValue::Text(t) => match t.as_str() {
+impl Display for DbObject {
+    /// The type of the object as it's stored in `sqlite_schema`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbObject::Table => write!(f, "table"),
+            DbObject::Index => write!(f, "index"),
+            DbObject::View => write!(f, "view"),
+            DbObject::Trigger => write!(f, "trigger"),
+        }
+    }
+}
+
+impl<'a> DeserializeCell<'a> for SchemaRecord {
+    fn deserialize_cell<O: PayloadReader>(
+        page_number: u32,
+        reader: &mut Cursor<&'a [u8]>,
+        payload_reader: &O,
+    ) -> Result<Self> {
+        SchemaRecord::try_from(TableLeafCell::deserialize_cell(
+            page_number,
+            reader,
+            payload_reader,
+        )?)
+    }
+}
+
+impl TryFrom<TableLeafCell<'_>> for SchemaRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(cell: TableLeafCell) -> Result<Self> {
+        let values = cell.record.values()?;
+        ensure!(
+            values.len() == 5,
+            "expected 5 columns in a schema record, found {}",
+            values.len()
+        );
+        let db_object = match &values[0] {
+            SerialValue::Text(t) => match t.as_ref() {
                 "table" => DbObject::Table,
                 "index" => DbObject::Index,
                 "view" => DbObject::View,
                 "trigger" => DbObject::Trigger,
-                _ => panic!("Unknow DbObject type"),
+                t => bail!("Unknown DbObject type {}", t),
             },
-            _ => panic!("expected column value[0] to be of type Text"),
+            _ => bail!("expected column value[0] to be of type Text"),
         };
-        let name = match &cell.record.values[1] {
-            SerialValue::Text(name) => name.to_owned(),
-            _ => panic!("expected column value[0] to be of type Text"),
+        let name = match &values[1] {
+            SerialValue::Text(name) => name.to_string(),
+            _ => bail!("expected column value[1] to be of type Text"),
         };
-        let tbl_name = match &cell.record.values[2] {
-            SerialValue::Text(tbl_name) => tbl_name.to_owned(),
-            _ => panic!("expected column value[0] to be of type Text"),
+        let tbl_name = match &values[2] {
+            SerialValue::Text(tbl_name) => tbl_name.to_string(),
+            _ => bail!("expected column value[2] to be of type Text"),
         };
-        let rootpage = match &cell.record.values[3] {
+        let rootpage = match &values[3] {
             SerialValue::Int(rootpage) => rootpage.to_owned() as u32,
-            _ => panic!("expected column value[3] to be of type Int"),
+            _ => bail!("expected column value[3] to be of type Int"),
         };
-        let sql = match &cell.record.values[4] {
-            SerialValue::Text(sql) => sql.to_owned(),
-            _ => panic!("expected column value[4] to be of type Text"),
+        let sql = match &values[4] {
+            SerialValue::Text(sql) => sql.to_string(),
+            // automatic indexes created for UNIQUE and PRIMARY KEY constraints have no sql
+            SerialValue::Null => String::new(),
+            _ => bail!("expected column value[4] to be of type Text"),
         };
-        SchemaRecord {
+        Ok(SchemaRecord {
             db_object,
             name,
             tbl_name,
             rootpage,
             sql,
-        }
+        })
     }
 }
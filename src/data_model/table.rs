@@ -1,54 +1,64 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Cursor;
 
-use anyhow::Error;
+use anyhow::Result;
 
 use crate::{
+    error::Error,
     query_engine::{filter::create_record_filter, set::Set},
-    serialisation::deserialize::Deserialize,
+    serialisation::deserialize::DeserializeCell,
     sql_parser::parser::Comparison,
 };
 
-use super::btree::record::HasRecord;
+use super::btree::{overflow::PayloadReader, record::HasRecord, serial_value::SerialValue};
 
-pub struct Table<T: Deserialize> {
+pub struct Table<T> {
     pub cells: Vec<T>,
     pub columns: Option<Set<String>>,
 }
 
-impl<T: Deserialize> Table<T> {
+impl<'a, T: DeserializeCell<'a>> Table<T> {
     /// Generic function to use cell pointers to deserialize a collection of cells on a page
-    /// i.e IndexInteriorCells,TableLeafCells etc.
-    pub fn new<R: Seek + Read>(reader: &mut R, cell_pointers: &Vec<u16>) -> Self {
+    /// i.e IndexInteriorCells,TableLeafCells etc. A cell that can't be read makes the page corrupt.
+    /// Cells borrow their payloads from the page when they can
+    pub fn new<O: PayloadReader>(
+        page_number: u32,
+        page: &'a [u8],
+        cell_pointers: &[u16],
+        payload_reader: &O,
+    ) -> Result<Self> {
+        let mut reader = Cursor::new(page);
         let cells = cell_pointers
             .iter()
             .map(|cell_ptr| {
-                let seek_pos = SeekFrom::Start(*cell_ptr as u64);
-                reader
-                    .seek(seek_pos)
-                    .expect("failed to seek to cell pointer");
-                T::deserialize(reader)
+                reader.set_position(*cell_ptr as u64);
+                T::deserialize_cell(page_number, &mut reader, payload_reader)
             })
-            .collect();
-        Table {
+            .collect::<Result<Vec<T>>>()
+            .map_err(|e| Error::on_page(page_number, e))?;
+        Ok(Table {
             cells,
             columns: None,
-        }
+        })
     }
 }
 
-impl<T: Deserialize + HasRecord + Clone> Table<T> {
+impl<T: HasRecord> Table<T> {
+    /// Take the cells whose record matches the comparison, only the compared column of each record is decoded.
+    /// `defaults` stand in for values missing from the end of a record
     pub fn filter_cells(
-        &self,
-        ordered_column_names: &Vec<String>,
+        self,
+        ordered_column_names: &[String],
+        defaults: &[SerialValue<'static>],
         comparison: &Comparison,
-    ) -> Result<Vec<T>, Error> {
-        let record_predicate = create_record_filter(ordered_column_names, comparison)?;
+    ) -> Result<Vec<T>> {
+        let record_predicate = create_record_filter(ordered_column_names, defaults, comparison)?;
 
-        Ok(self
-            .cells
-            .iter()
-            .filter(|item| record_predicate(item.record()))
-            .cloned()
-            .collect())
+        let mut cells = vec![];
+        for cell in self.cells {
+            if record_predicate(cell.record())? {
+                cells.push(cell);
+            }
+        }
+        Ok(cells)
     }
 }
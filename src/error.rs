@@ -0,0 +1,107 @@
+use std::{fmt::Display, io};
+
+/// Kinds of failure a caller may want to handle differently.
+/// Errors are passed around as `anyhow::Error` with context added on the way up,
+/// the kind can be recovered with `error.downcast_ref::<Error>()`
+#[derive(Debug)]
+pub enum Error {
+    /// A page of the database file, its header or a cell on it doesn't make sense
+    CorruptPage {
+        page: u32,
+        reason: String,
+    },
+    /// The file isn't a database or uses a feature of the file format that isn't supported
+    UnsupportedFormat(String),
+    /// SQL that can't be parsed, `position` is the offset in bytes of what was found instead
+    ParseError {
+        position: usize,
+        expected: String,
+        found: String,
+    },
+    NoSuchTable(String),
+    NoSuchColumn(String),
+    NoSuchIndex(String),
+    /// A value compared with a column that can't hold it
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    /// A valid query that the query engine can't run yet
+    Unsupported(String),
+    /// A change that would break the schema's rules, i.e a second row with the same UNIQUE value.
+    /// `constraint` is the kind of rule and `columns` the `table.column`s it is on
+    Constraint {
+        constraint: String,
+        columns: String,
+    },
+    /// A schema object is created with the name of one that's already there, `object` is the kind of that one
+    AlreadyExists {
+        object: String,
+        name: String,
+    },
+    /// Another connection holds a lock that conflicts with the one asked for
+    Locked,
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn corrupt_page(page: u32, reason: impl Into<String>) -> Self {
+        Error::CorruptPage {
+            page,
+            reason: reason.into(),
+        }
+    }
+
+    /// Report a failure to decode a page as corruption of that page.
+    /// Failures that already have a kind, such as a read that failed, keep it
+    pub fn on_page(page: u32, error: anyhow::Error) -> anyhow::Error {
+        if error.downcast_ref::<Error>().is_some() {
+            return error;
+        }
+        Error::corrupt_page(page, format!("{:#}", error)).into()
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CorruptPage { page, reason } => write!(f, "page {} is corrupt: {}", page, reason),
+            Self::UnsupportedFormat(reason) => write!(f, "unsupported file format: {}", reason),
+            Self::ParseError {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "syntax error at offset {}: expected {} but found {}",
+                position, expected, found
+            ),
+            Self::NoSuchTable(table) => write!(f, "no such table: {}", table),
+            Self::NoSuchColumn(column) => write!(f, "no such column: {}", column),
+            Self::NoSuchIndex(index) => write!(f, "no such index: {}", index),
+            Self::TypeMismatch { expected, found } => {
+                write!(
+                    f,
+                    "type mismatch: expected {} but found {}",
+                    expected, found
+                )
+            }
+            Self::Unsupported(feature) => write!(f, "{} isn't supported", feature),
+            Self::Constraint {
+                constraint,
+                columns,
+            } => write!(f, "{} constraint failed: {}", constraint, columns),
+            Self::AlreadyExists { object, name } => write!(f, "{} {} already exists", object, name),
+            Self::Locked => write!(f, "database is locked"),
+            Self::Io(error) => write!(f, "disk I/O error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
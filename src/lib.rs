@@ -1,5 +1,7 @@
 pub mod data_model;
+pub mod error;
 pub mod pager;
 pub mod query_engine;
 pub mod serialisation;
 pub mod sql_parser;
+pub mod vfs;
@@ -3,14 +3,9 @@ use itertools::Itertools;
 use toy_sqlite::data_model::btree::page::Page;
 use toy_sqlite::data_model::table::Table;
 use toy_sqlite::data_model::{db_header::Dbheader, schema_record::SchemaRecord};
-
-use std::fs::File;
-use toy_sqlite::pager::pager::Pager;
+use toy_sqlite::pager::{create::CreateOptions, pager::Pager};
 use toy_sqlite::query_engine::engine::QueryEngine;
-use toy_sqlite::sql_parser::{
-    lexer::lexer,
-    parser::{Parser, SelectQuery},
-};
+use toy_sqlite::sql_parser::parser::{Parser, Statement};
 
 fn main() -> Result<()> {
     // Parse arguments
@@ -24,16 +19,32 @@ fn main() -> Result<()> {
     // Parse command and act accordingly
     let command = args[2].as_str();
 
-    let mut file = File::open(&args[1])?;
-    let pager = Pager::new(&mut file)?;
+    // the database doesn't exist yet so it can't be opened first
+    if command == ".create" {
+        let pager = Pager::create(&args[1], create_options(&args[3..])?)?;
+        dbinfo(&pager.db_header, &pager.root_page);
+        return Ok(());
+    }
+
+    // several statements separated by semicolons run one after the other, i.e a transaction
+    let is_sql = !command.is_empty() && !command.starts_with('.');
+    let pager = if is_sql && changes_database(command)? {
+        Pager::open_for_writing(&args[1])?
+    } else {
+        Pager::open(&args[1])?
+    };
 
     match command {
-        ".dbinfo" => dbinfo(pager.db_header, pager.root_page),
-        ".tables" => tables(pager.schema_table),
-        cmd if !cmd.is_empty() => {
-            let mut query_engine = QueryEngine::new(pager);
-            let result = query_engine.run_query(parse_sql(cmd)).unwrap();
-            println!("{}", result);
+        ".dbinfo" => dbinfo(&pager.db_header, &pager.root_page),
+        ".tables" => tables(&pager.schema_table),
+        ".freelist" => freelist(pager)?,
+        ".integrity_check" => integrity_check(pager)?,
+        _ if is_sql => {
+            // a transaction that's left open is rolled back when the database is closed
+            let result = QueryEngine::new(pager).execute_sql(command)?;
+            if !result.is_empty() {
+                println!("{}", result);
+            }
         }
         _ => bail!("Missing or invalid command passed: {}", command),
     }
@@ -41,12 +52,27 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn dbinfo(db_header: Dbheader, root_page: Page) {
+fn dbinfo(db_header: &Dbheader, root_page: &Page) {
     println!("database page size: {}", db_header.page_size);
+    println!("write format: {}", db_header.write_version);
+    println!("read format: {}", db_header.read_version);
+    println!("reserved bytes: {}", db_header.reserved_bytes);
+    println!("file change counter: {}", db_header.file_change_counter);
+    println!("database page count: {}", db_header.database_size);
+    println!("freelist page count: {}", db_header.freelist_page_count);
+    println!("schema cookie: {}", db_header.schema_cookie);
+    println!("schema format: {}", db_header.schema_format);
+    println!("default cache size: {}", db_header.default_cache_size);
+    println!("autovacuum top root: {}", db_header.largest_root_page);
+    println!("incremental vacuum: {}", db_header.incremental_vacuum);
+    println!("text encoding: {}", db_header.text_encoding);
+    println!("user version: {}", db_header.user_version);
+    println!("application id: {}", db_header.application_id);
+    println!("software version: {}", db_header.sqlite_version);
     println!("number of tables: {}", root_page.header.cell_count);
 }
 
-fn tables(schema_table: Table<SchemaRecord>) {
+fn tables(schema_table: &Table<SchemaRecord>) {
     let table_names = schema_table
         .cells
         .iter()
@@ -58,8 +84,79 @@ fn tables(schema_table: Table<SchemaRecord>) {
     println!("{}", table_names);
 }
 
-fn parse_sql(query: &str) -> SelectQuery {
-    let tokens = lexer(query);
-    let mut parser = Parser::new(tokens);
-    parser.parse()
+/// Report the freelist and which b-tree, freelist or special purpose every page is used for
+fn freelist(pager: Pager) -> Result<()> {
+    let freelist = pager.freelist()?;
+    println!(
+        "freelist trunk pages: {}",
+        freelist.trunk_pages.iter().join(" ")
+    );
+    println!(
+        "freelist leaf pages: {}",
+        freelist.leaf_pages.iter().join(" ")
+    );
+
+    let page_map = pager.page_map()?;
+    println!();
+    println!("pages:");
+    for (first, last, owner) in page_map.ranges() {
+        if first == last {
+            println!("{}: {}", first, owner);
+        } else {
+            println!("{}-{}: {}", first, last, owner);
+        }
+    }
+
+    println!();
+    println!("usage:");
+    for (owner, page_count) in page_map.summary() {
+        let percentage = page_count as f64 * 100.0 / page_map.page_count as f64;
+        println!("{}: {} pages ({:.1}%)", owner, page_count, percentage);
+    }
+
+    for (page_number, owner) in page_map.conflicts {
+        println!("page {} is also claimed by {}", page_number, owner);
+    }
+    Ok(())
+}
+
+/// Print sqlite's `PRAGMA integrity_check` report, `ok` when no problems are found
+fn integrity_check(pager: Pager) -> Result<()> {
+    let problems = pager.integrity_check()?;
+    if problems.is_empty() {
+        println!("ok");
+        return Ok(());
+    }
+    println!("*** in database main ***");
+    for problem in problems {
+        println!("{}", problem);
+    }
+    Ok(())
+}
+
+/// Options of `.create` given as `name=value` arguments, anything left out gets sqlite's default
+fn create_options(args: &[String]) -> Result<CreateOptions> {
+    let mut options = CreateOptions::default();
+    for arg in args {
+        let Some((name, value)) = arg.split_once('=') else {
+            bail!("Expected <option>=<value> but got {}", arg);
+        };
+        match name {
+            "page_size" => options.page_size = value.parse()?,
+            "reserved_bytes" => options.reserved_bytes = value.parse()?,
+            "encoding" => options.text_encoding = value.parse()?,
+            "user_version" => options.user_version = value.parse()?,
+            "application_id" => options.application_id = value.parse()?,
+            _ => bail!("Unknown option {}", name),
+        }
+    }
+    Ok(options)
+}
+
+/// Whether any of the statements isn't a query, every other statement changes the database
+fn changes_database(sql: &str) -> Result<bool> {
+    let statements = Parser::from_sql(sql)?.parse_statements()?;
+    Ok(statements
+        .iter()
+        .any(|statement| !matches!(statement, Statement::Select(_))))
 }
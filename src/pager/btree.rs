@@ -0,0 +1,1137 @@
+use std::{cmp::Ordering, io::Cursor};
+
+use anyhow::{bail, ensure, Result};
+
+use crate::{
+    data_model::{
+        btree::{
+            key::{compare_keys, KeyColumn},
+            overflow::{local_payload_size, read_payload, write_payload},
+            page::{ensure_depth, parse_cell, Page, MAX_BTREE_DEPTH},
+            page_header::{PageHeader, PageType},
+            record::Record,
+            serial_value::SerialValue,
+        },
+        db_header::DB_HEADER_SIZE,
+    },
+    error::Error,
+    serialisation::{
+        deserialize::Deserialize,
+        serialize::Serialize,
+        varint::{read_varint, write_varint},
+    },
+};
+
+use super::pager::Pager;
+
+/// A b-tree page taken apart into its cells so they can be moved around, the page is rebuilt from them when it's written
+#[derive(Clone)]
+struct Node {
+    page_type: PageType,
+    // the bytes of each cell in key order, as they are stored on the page
+    cells: Vec<Vec<u8>>,
+    rightmost_pointer: Option<u32>,
+}
+
+/// One page on the way from the root to where a key belongs
+struct Step {
+    page_number: u32,
+    node: Node,
+    // the child followed on an interior page or where the key goes on a leaf
+    index: usize,
+}
+
+/// What a b-tree is ordered by
+enum Key<'k> {
+    RowId(i64),
+    /// An index entry or a WITHOUT ROWID row, compared on its first `len` values
+    Entry {
+        values: &'k [SerialValue<'k>],
+        key_columns: &'k [KeyColumn],
+        len: usize,
+    },
+}
+
+/// Space a cell takes on its page, its cell pointer included. sqlite never allocates less than 4 bytes for a cell
+fn cell_footprint(cell: &[u8]) -> usize {
+    2 + cell.len().max(4)
+}
+
+fn header_offset(page_number: u32) -> usize {
+    if page_number == 1 {
+        DB_HEADER_SIZE
+    } else {
+        0
+    }
+}
+
+fn is_leaf(page_type: PageType) -> bool {
+    matches!(page_type, PageType::TableLeaf | PageType::IndexLeaf)
+}
+
+fn interior_type(page_type: PageType) -> PageType {
+    match page_type {
+        PageType::TableLeaf | PageType::TableInterior => PageType::TableInterior,
+        PageType::IndexLeaf | PageType::IndexInterior => PageType::IndexInterior,
+    }
+}
+
+fn left_child(cell: &[u8]) -> u32 {
+    u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]])
+}
+
+impl Node {
+    fn header_size(&self) -> usize {
+        if is_leaf(self.page_type) {
+            8
+        } else {
+            12
+        }
+    }
+
+    /// Space the cells can take on a page
+    fn capacity(&self, page_number: u32, usable_size: usize) -> usize {
+        usable_size - header_offset(page_number) - self.header_size()
+    }
+
+    fn used(&self) -> usize {
+        self.cells.iter().map(|cell| cell_footprint(cell)).sum()
+    }
+
+    fn fits(&self, page_number: u32, usable_size: usize) -> bool {
+        self.used() <= self.capacity(page_number, usable_size)
+    }
+
+    /// sqlite balances a page when more than two thirds of it is free
+    fn is_underfull(&self, page_number: u32, usable_size: usize) -> bool {
+        let free = self
+            .capacity(page_number, usable_size)
+            .saturating_sub(self.used());
+        free * 3 > usable_size * 2
+    }
+
+    /// The child page at `index`, the rightmost pointer follows the last cell
+    fn child(&self, index: usize) -> Result<u32> {
+        match self.cells.get(index) {
+            Some(cell) => Ok(left_child(cell)),
+            None => self
+                .rightmost_pointer
+                .ok_or_else(|| anyhow::anyhow!("leaf pages don't have children")),
+        }
+    }
+
+    fn set_child(&mut self, index: usize, page_number: u32) {
+        match self.cells.get_mut(index) {
+            Some(cell) => cell[0..4].copy_from_slice(&page_number.to_be_bytes()),
+            None => self.rightmost_pointer = Some(page_number),
+        }
+    }
+}
+
+impl Pager {
+    fn read_node(&self, page_number: u32) -> Result<Node> {
+        let page = self.read_raw_page(page_number)?;
+        let usable_size = self.db_header.usable_size();
+        let node = (|| {
+            let offset = header_offset(page_number);
+            let header = Page::deserialize(&mut &page[offset..])?;
+            let cells = header
+                .cell_pointers
+                .iter()
+                .map(|pointer| {
+                    let start = *pointer as usize;
+                    ensure!(
+                        start < usable_size,
+                        "cell pointer {} is off the page",
+                        pointer
+                    );
+                    let layout = parse_cell(&page, start, header.header.page_type, usable_size)?;
+                    Ok(page[start..(start + layout.size).min(usable_size)].to_vec())
+                })
+                .collect::<Result<_>>()?;
+            Ok(Node {
+                page_type: header.header.page_type,
+                cells,
+                rightmost_pointer: header.header.rightmost_pointer,
+            })
+        })();
+        node.map_err(|e| Error::on_page(page_number, e))
+    }
+
+    /// Lay the cells out from the end of the page without gaps between them, so the page has no freeblocks
+    fn write_node(&mut self, page_number: u32, node: &Node) -> Result<()> {
+        let page_size = self.db_header.page_size as usize;
+        let usable_size = self.db_header.usable_size();
+        ensure!(
+            node.fits(page_number, usable_size),
+            "{} cells don't fit on page {}",
+            node.cells.len(),
+            page_number
+        );
+        let mut page = vec![0u8; page_size];
+        let offset = header_offset(page_number);
+        if page_number == 1 {
+            page[..DB_HEADER_SIZE].copy_from_slice(&self.read_raw_page(1)?[..DB_HEADER_SIZE]);
+        }
+
+        let mut content_start = usable_size;
+        let mut cell_pointers = Vec::with_capacity(node.cells.len());
+        for cell in &node.cells {
+            content_start -= cell.len().max(4);
+            page[content_start..content_start + cell.len()].copy_from_slice(cell);
+            cell_pointers.push(content_start as u16);
+        }
+        let header = Page {
+            header: PageHeader {
+                page_type: node.page_type,
+                first_free_block: 0,
+                cell_count: node.cells.len() as u16,
+                // an empty 65536 byte page starts its content at 65536 which is stored as 0
+                cell_content_offset: content_start as u16,
+                fragmented_free_bytes: 0,
+                rightmost_pointer: node.rightmost_pointer,
+            },
+            cell_pointers,
+        };
+        header.serialize(&mut &mut page[offset..])?;
+        self.write_page(page_number, page)
+    }
+
+    /// Build a leaf cell for the payload, the part of it that doesn't fit on the page is written to a chain
+    /// of new overflow pages. Table leaf cells have the rowid between the payload size and the payload
+    fn leaf_cell(&mut self, row_id: Option<i64>, payload: &[u8]) -> Result<Vec<u8>> {
+        let page_type = match row_id {
+            Some(_) => PageType::TableLeaf,
+            None => PageType::IndexLeaf,
+        };
+        let usable_size = self.db_header.usable_size();
+        let local_size = local_payload_size(page_type, payload.len() as u64, usable_size) as usize;
+
+        let chunks: Vec<&[u8]> = payload[local_size..].chunks(usable_size - 4).collect();
+        let overflow_pages = chunks
+            .iter()
+            .map(|_| self.allocate_page())
+            .collect::<Result<Vec<u32>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            // each overflow page starts with the next page of the chain, 0 on the last one
+            let next = overflow_pages.get(i + 1).copied().unwrap_or(0);
+            let mut page = vec![0u8; self.db_header.page_size as usize];
+            page[0..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+            self.write_page(overflow_pages[i], page)?;
+        }
+
+        let mut cell = vec![];
+        write_varint(&mut cell, payload.len() as u64)?;
+        if let Some(row_id) = row_id {
+            write_varint(&mut cell, row_id as u64)?;
+        }
+        write_payload(
+            &mut cell,
+            page_type,
+            payload,
+            overflow_pages.first().copied(),
+            &*self,
+        )?;
+        Ok(cell)
+    }
+
+    /// Give the pages of an overflow chain back to the freelist
+    fn free_overflow_chain(&mut self, page_type: PageType, cell: &[u8]) -> Result<()> {
+        let layout = parse_cell(cell, 0, page_type, self.db_header.usable_size())?;
+        let payload_size = layout.payload_size as usize;
+        let mut stored = layout.local_payload.1 - layout.local_payload.0;
+        let mut next_page = layout.overflow_page.unwrap_or(0);
+        while next_page != 0 && stored < payload_size {
+            let page = self.read_raw_page(next_page)?;
+            self.free_page(next_page)?;
+            stored += self.db_header.usable_size() - 4;
+            next_page = u32::from_be_bytes(page[0..4].try_into()?);
+        }
+        Ok(())
+    }
+
+    /// The rowid of a table b-tree cell
+    fn cell_row_id(&self, page_type: PageType, cell: &[u8]) -> Result<i64> {
+        let mut reader = Cursor::new(cell);
+        match page_type {
+            PageType::TableLeaf => {
+                read_varint(&mut reader)?;
+            }
+            PageType::TableInterior => reader.set_position(4),
+            _ => bail!("{} pages don't have rowids", page_type),
+        }
+        Ok(read_varint(&mut reader)?.0 as i64)
+    }
+
+    /// The values of the record of an index b-tree cell, read from its overflow pages when it doesn't fit on the page
+    fn cell_values(&self, page_type: PageType, cell: &[u8]) -> Result<Vec<SerialValue<'static>>> {
+        let mut reader = Cursor::new(cell);
+        if page_type == PageType::IndexInterior {
+            reader.set_position(4);
+        }
+        let (payload_size, _) = read_varint(&mut reader)?;
+        let (payload, _) = read_payload(&mut reader, page_type, payload_size, self)?;
+        let record = Record::read(&mut Cursor::new(&payload[..]), self.db_header.text_encoding)?;
+        Ok(record.values)
+    }
+
+    /// Order a cell against the key being looked for
+    fn compare_cell(&self, page_type: PageType, cell: &[u8], key: &Key) -> Result<Ordering> {
+        match (page_type, key) {
+            (PageType::TableLeaf | PageType::TableInterior, Key::RowId(row_id)) => {
+                Ok(self.cell_row_id(page_type, cell)?.cmp(row_id))
+            }
+            (
+                PageType::IndexLeaf | PageType::IndexInterior,
+                Key::Entry {
+                    values,
+                    key_columns,
+                    len,
+                },
+            ) => {
+                let cell_values = self.cell_values(page_type, cell)?;
+                let len = (*len).min(values.len());
+                Ok(compare_keys(
+                    &cell_values[..len.min(cell_values.len())],
+                    &values[..len],
+                    key_columns,
+                    self.db_header.text_encoding,
+                ))
+            }
+            (page_type, _) => bail!("{} page in the wrong kind of b-tree", page_type),
+        }
+    }
+
+    /// Index of the first cell that isn't ordered before the key, and whether it's equal to the key
+    fn lower_bound(&self, node: &Node, key: &Key) -> Result<(usize, bool)> {
+        let (mut low, mut high) = (0, node.cells.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.compare_cell(node.page_type, &node.cells[middle], key)? {
+                Ordering::Less => low = middle + 1,
+                _ => high = middle,
+            }
+        }
+        let found = match node.cells.get(low) {
+            Some(cell) => self.compare_cell(node.page_type, cell, key)? == Ordering::Equal,
+            None => false,
+        };
+        Ok((low, found))
+    }
+
+    /// Walk from the root to the leaf the key belongs on. Returns the pages on the way and whether the key
+    /// is already in the b-tree, an index entry equal to the key can be found on an interior page
+    fn descend(&self, root: u32, key: &Key) -> Result<(Vec<Step>, bool)> {
+        let mut path: Vec<Step> = vec![];
+        let mut page_number = root;
+        loop {
+            ensure_depth(root, path.len())?;
+            let node = self.read_node(page_number)?;
+            let (index, found) = self.lower_bound(&node, key)?;
+            // interior cells of a table b-tree repeat the largest rowid of their left child
+            let found_entry = found && node.page_type != PageType::TableInterior;
+            let next = (!is_leaf(node.page_type) && !found_entry)
+                .then(|| node.child(index))
+                .transpose()?;
+            path.push(Step {
+                page_number,
+                node,
+                index,
+            });
+            match next {
+                Some(child) => page_number = child,
+                None => return Ok((path, found)),
+            }
+        }
+    }
+
+    /// Put a cell on the leaf at the end of the path, splitting pages up to the root as they fill up
+    fn insert_cell(&mut self, mut path: Vec<Step>, cell: Vec<u8>) -> Result<()> {
+        // the cell goes after every other cell of the b-tree
+        let appended = path.iter().all(|step| step.index == step.node.cells.len());
+        let step = path.last_mut().expect("a path starts at the root");
+        step.node.cells.insert(step.index, cell);
+        let depth = path.len() - 1;
+        self.balance(path, depth, appended)
+    }
+
+    /// Write the pages of the path from the leaf up, as far as the page at depth `changed` which is the highest
+    /// one that was modified. Like sqlite's balance() overfull pages are split and pages less than a third full
+    /// are merged with a sibling or take cells from it, which can change their parents in turn
+    fn balance(&mut self, mut path: Vec<Step>, mut changed: usize, appended: bool) -> Result<()> {
+        let usable_size = self.db_header.usable_size();
+        while let Some(step) = path.pop() {
+            let Some(parent) = path.pop() else {
+                return self.balance_root(step, appended);
+            };
+            let depth = path.len() + 1;
+            let parent = if !step.node.fits(step.page_number, usable_size) {
+                self.split(step, parent, appended)?
+            } else if step.node.is_underfull(step.page_number, usable_size)
+                && !parent.node.cells.is_empty()
+            {
+                self.merge(step, parent)?
+            } else {
+                self.write_node(step.page_number, &step.node)?;
+                if changed >= depth {
+                    return Ok(());
+                }
+                parent
+            };
+            changed = changed.min(depth - 1);
+            path.push(parent);
+        }
+        Ok(())
+    }
+
+    /// The root keeps its page number, so it moves down into a new child when it's overfull and takes
+    /// the place of its only child when it's left without cells
+    fn balance_root(&mut self, mut step: Step, appended: bool) -> Result<()> {
+        let usable_size = self.db_header.usable_size();
+        if !step.node.fits(step.page_number, usable_size) {
+            let child = self.allocate_page()?;
+            let root = Step {
+                page_number: step.page_number,
+                node: Node {
+                    page_type: interior_type(step.node.page_type),
+                    cells: vec![],
+                    rightmost_pointer: Some(child),
+                },
+                index: 0,
+            };
+            step.page_number = child;
+            let root = self.split(step, root, appended)?;
+            return self.balance_root(root, appended);
+        }
+        if let (true, Some(child)) = (step.node.cells.is_empty(), step.node.rightmost_pointer) {
+            let node = self.read_node(child)?;
+            // page 1 has less room than the child because of the database header
+            if node.fits(step.page_number, usable_size) {
+                self.free_page(child)?;
+                step.node = node;
+            }
+        }
+        self.write_node(step.page_number, &step.node)
+    }
+
+    /// Share the cells of an overfull page out over itself and new pages, adding the keys that divide
+    /// them to the parent. Returns the parent, which may be overfull in turn
+    fn split(&mut self, step: Step, mut parent: Step, appended: bool) -> Result<Step> {
+        let usable_size = self.db_header.usable_size();
+        let node = step.node;
+        let capacity = node.capacity(0, usable_size);
+        let sizes: Vec<usize> = node.cells.iter().map(|cell| cell_footprint(cell)).collect();
+        let total: usize = sizes.iter().sum();
+        // table leaves copy the largest rowid of each page up, other pages move a cell up to the parent
+        let promote = node.page_type != PageType::TableLeaf;
+
+        let (groups, promoted) = if appended && node.page_type == PageType::TableLeaf {
+            // like sqlite, rows added in rowid order leave the full page as it is and start a new one
+            let last = node.cells.len() - 1;
+            (vec![(0..last), (last..last + 1)], vec![])
+        } else {
+            (2..=node.cells.len())
+                .find_map(|pages| {
+                    let target = total.div_ceil(pages).min(capacity);
+                    divide(&sizes, target, capacity, promote)
+                })
+                .ok_or_else(|| {
+                    anyhow::anyhow!("the cells of page {} can't be split", step.page_number)
+                })?
+        };
+
+        // the first group stays on the page, the others go on new pages
+        let mut page_numbers = vec![step.page_number];
+        for _ in 1..groups.len() {
+            page_numbers.push(self.allocate_page()?);
+        }
+
+        let mut dividers = vec![];
+        for (i, group) in groups.iter().enumerate() {
+            let page_number = page_numbers[i];
+            let mut page = Node {
+                page_type: node.page_type,
+                cells: node.cells[group.clone()].to_vec(),
+                rightmost_pointer: None,
+            };
+            let last = i + 1 == groups.len();
+            if promote {
+                if let Some(divider) = promoted.get(i).map(|index| &node.cells[*index]) {
+                    let mut cell = page_number.to_be_bytes().to_vec();
+                    if is_leaf(node.page_type) {
+                        cell.extend_from_slice(divider);
+                    } else {
+                        // the divider's left child becomes the rightmost child of the page before it
+                        page.rightmost_pointer = Some(left_child(divider));
+                        cell.extend_from_slice(&divider[4..]);
+                    }
+                    dividers.push(cell);
+                }
+                if last {
+                    page.rightmost_pointer = node.rightmost_pointer;
+                }
+            } else if !last {
+                let last_cell = &node.cells[group.end - 1];
+                let mut cell = page_number.to_be_bytes().to_vec();
+                write_varint(
+                    &mut cell,
+                    self.cell_row_id(node.page_type, last_cell)? as u64,
+                )?;
+                dividers.push(cell);
+            }
+            self.write_node(page_number, &page)?;
+        }
+
+        // the parent's pointer to the page now leads to the last of the pages, the dividers lead to the others
+        let index = parent.index;
+        parent
+            .node
+            .set_child(index, *page_numbers.last().expect("at least one page"));
+        parent.node.cells.splice(index..index, dividers);
+        Ok(parent)
+    }
+
+    /// Put the cells of an underfull page together with those of its sibling to the left, or to the right
+    /// for a first child, along with the divider between them. They go on one page when they fit,
+    /// otherwise they're shared out again. Returns the parent, which has lost a divider or had it replaced
+    fn merge(&mut self, step: Step, mut parent: Step) -> Result<Step> {
+        let usable_size = self.db_header.usable_size();
+        let index = parent.index;
+        let divider_index = index.saturating_sub(1);
+        let ((left_page, left), (right_page, right)) = if index > 0 {
+            let sibling = parent.node.child(divider_index)?;
+            (
+                (sibling, self.read_node(sibling)?),
+                (step.page_number, step.node),
+            )
+        } else {
+            let sibling = parent.node.child(index + 1)?;
+            (
+                (step.page_number, step.node),
+                (sibling, self.read_node(sibling)?),
+            )
+        };
+        ensure!(
+            left.page_type == right.page_type,
+            Error::corrupt_page(
+                parent.page_number,
+                format!(
+                    "children are {} and {} pages",
+                    left.page_type, right.page_type
+                )
+            )
+        );
+
+        // table leaves don't need the divider, it only repeats a rowid
+        let divider = parent.node.cells.remove(divider_index);
+        let mut cells = left.cells;
+        match left.page_type {
+            PageType::TableLeaf => {}
+            PageType::IndexLeaf => cells.push(divider[4..].to_vec()),
+            PageType::TableInterior | PageType::IndexInterior => {
+                let mut cell = left
+                    .rightmost_pointer
+                    .ok_or_else(|| {
+                        Error::corrupt_page(left_page, "interior page without a rightmost pointer")
+                    })?
+                    .to_be_bytes()
+                    .to_vec();
+                cell.extend_from_slice(&divider[4..]);
+                cells.push(cell);
+            }
+        }
+        cells.extend(right.cells);
+        let node = Node {
+            page_type: left.page_type,
+            cells,
+            rightmost_pointer: right.rightmost_pointer,
+        };
+
+        // the pointer that led to the right page now leads to the left one
+        parent.node.set_child(divider_index, left_page);
+        parent.index = divider_index;
+        self.free_page(right_page)?;
+        if node.fits(left_page, usable_size) {
+            self.write_node(left_page, &node)?;
+            return Ok(parent);
+        }
+        let step = Step {
+            page_number: left_page,
+            node,
+            index: 0,
+        };
+        self.split(step, parent, false)
+    }
+
+    /// Take the cell at the end of the path out of its b-tree. A leaf that is still well filled afterwards
+    /// only loses the cell, its space goes on the page's freeblock list
+    fn delete_cell(&mut self, mut path: Vec<Step>) -> Result<()> {
+        let usable_size = self.db_header.usable_size();
+        let depth = path.len() - 1;
+        let step = path.last_mut().expect("a path starts at the root");
+        let page_type = step.node.page_type;
+        let cell = step.node.cells.remove(step.index);
+        self.free_overflow_chain(page_type, &cell)?;
+
+        if is_leaf(page_type) {
+            let step = path.last().expect("a path starts at the root");
+            if depth == 0 || !step.node.is_underfull(step.page_number, usable_size) {
+                return self.drop_cell(step.page_number, step.index);
+            }
+            return self.balance(path, depth, false);
+        }
+
+        // an index entry on an interior page is replaced by the entry before it, the last one of its left subtree
+        let mut page_number = left_child(&cell);
+        loop {
+            ensure_depth(page_number, path.len())?;
+            let node = self.read_node(page_number)?;
+            let leaf = is_leaf(node.page_type);
+            let index =
+                match leaf {
+                    true => node.cells.len().checked_sub(1).ok_or_else(|| {
+                        Error::corrupt_page(page_number, "leaf page without cells")
+                    })?,
+                    false => node.cells.len(),
+                };
+            let next = (!leaf).then(|| node.child(index)).transpose()?;
+            path.push(Step {
+                page_number,
+                node,
+                index,
+            });
+            match next {
+                Some(child) => page_number = child,
+                None => break,
+            }
+        }
+        let leaf = path.last_mut().expect("the path reaches a leaf");
+        let predecessor = leaf.node.cells.remove(leaf.index);
+        let mut replacement = left_child(&cell).to_be_bytes().to_vec();
+        replacement.extend_from_slice(&predecessor);
+        let step = &mut path[depth];
+        step.node.cells.insert(step.index, replacement);
+        self.balance(path, depth, false)
+    }
+
+    /// Remove a cell pointer from a page and give the cell's bytes back to the page's free space, the way
+    /// sqlite's freeSpace() does. Freeblocks next to the cell are joined to it, along with gaps between
+    /// them of less than 4 bytes which were counted as fragmented bytes
+    fn drop_cell(&mut self, page_number: u32, index: usize) -> Result<()> {
+        let usable_size = self.db_header.usable_size();
+        let mut page = self.read_raw_page(page_number)?;
+        let hdr = header_offset(page_number);
+        let get = |page: &[u8], at: usize| u16::from_be_bytes([page[at], page[at + 1]]) as usize;
+        let put = |page: &mut [u8], at: usize, value: usize| {
+            page[at..at + 2].copy_from_slice(&(value as u16).to_be_bytes())
+        };
+        let corrupt = |message: &str| Error::corrupt_page(page_number, message.to_string());
+
+        let header = Page::deserialize(&mut &page[hdr..])?;
+        let page_type = header.header.page_type;
+        let cell_count = header.cell_pointers.len();
+        ensure!(
+            index < cell_count,
+            corrupt("cell index is past the last cell")
+        );
+        let mut start = header.cell_pointers[index] as usize;
+        let size = parse_cell(&page, start, page_type, usable_size)?.size;
+        let mut end = start + size;
+
+        let pointers = hdr + if is_leaf(page_type) { 8 } else { 12 };
+        page.copy_within(
+            pointers + 2 * (index + 1)..pointers + 2 * cell_count,
+            pointers + 2 * index,
+        );
+        put(&mut page, pointers + 2 * (cell_count - 1), 0);
+        put(&mut page, hdr + 3, cell_count - 1);
+        if cell_count == 1 {
+            // an empty page starts over with all of its space in one piece
+            put(&mut page, hdr + 1, 0);
+            put(&mut page, hdr + 5, usable_size);
+            page[hdr + 7] = 0;
+            return self.write_page(page_number, page);
+        }
+
+        // find the freeblocks either side of the cell, the list is in order of offset
+        let mut previous = hdr + 1;
+        let mut next = get(&page, previous);
+        while next != 0 && next < start {
+            ensure!(next > previous, corrupt("freeblocks are out of order"));
+            previous = next;
+            next = get(&page, previous);
+        }
+        ensure!(
+            next <= usable_size - 4,
+            corrupt("freeblock is off the page")
+        );
+        let mut fragments = 0;
+        if next != 0 && end + 3 >= next {
+            ensure!(end <= next, corrupt("cell overlaps a freeblock"));
+            fragments += next - end;
+            end = next + get(&page, next + 2);
+            ensure!(end <= usable_size, corrupt("freeblock is off the page"));
+            next = get(&page, next);
+        }
+        if previous > hdr + 1 {
+            let previous_end = previous + get(&page, previous + 2);
+            if previous_end + 3 >= start {
+                ensure!(previous_end <= start, corrupt("cell overlaps a freeblock"));
+                fragments += start - previous_end;
+                start = previous;
+            }
+        }
+        ensure!(
+            fragments <= page[hdr + 7] as usize,
+            corrupt("more fragmented bytes than the header counts")
+        );
+        page[hdr + 7] -= fragments as u8;
+
+        let content_start = match get(&page, hdr + 5) {
+            0 => 65536,
+            offset => offset,
+        };
+        if start <= content_start {
+            // the space is at the start of the cell content area, which just gets smaller
+            ensure!(
+                start == content_start && previous == hdr + 1,
+                corrupt("cell is before the cell content area")
+            );
+            put(&mut page, hdr + 1, next);
+            put(&mut page, hdr + 5, end);
+        } else {
+            put(&mut page, previous, start);
+            put(&mut page, start, next);
+            put(&mut page, start + 2, end - start);
+        }
+        self.write_page(page_number, page)
+    }
+
+    /// Start a new empty b-tree, a table when `page_type` is `TableLeaf` or an index when it's `IndexLeaf`.
+    /// Returns its root page
+    pub fn create_btree(&mut self, page_type: PageType) -> Result<u32> {
+        let page_number = self.allocate_page()?;
+        let root = Node {
+            page_type,
+            cells: vec![],
+            rightmost_pointer: None,
+        };
+        self.write_node(page_number, &root)?;
+        Ok(page_number)
+    }
+
+    /// Give every page of the b-tree at `root` back to the freelist, along with the overflow pages of its cells
+    pub fn drop_btree(&mut self, root: u32) -> Result<()> {
+        self.free_btree_pages(root, 0)
+    }
+
+    fn free_btree_pages(&mut self, page_number: u32, depth: usize) -> Result<()> {
+        ensure_depth(page_number, depth)?;
+        let node = self.read_node(page_number)?;
+        for cell in &node.cells {
+            if !is_leaf(node.page_type) {
+                self.free_btree_pages(left_child(cell), depth + 1)?;
+            }
+            self.free_overflow_chain(node.page_type, cell)?;
+        }
+        if let Some(child) = node.rightmost_pointer {
+            self.free_btree_pages(child, depth + 1)?;
+        }
+        self.free_page(page_number)
+    }
+
+    /// Add a row to the table b-tree at `root`. When a row with the same rowid is there it is replaced
+    /// if `replace` is set, otherwise nothing changes and false is returned
+    pub fn insert_row(
+        &mut self,
+        root: u32,
+        row_id: i64,
+        record: &Record,
+        replace: bool,
+    ) -> Result<bool> {
+        let (mut path, found) = self.descend(root, &Key::RowId(row_id))?;
+        if found {
+            if !replace {
+                return Ok(false);
+            }
+            let step = path.last_mut().expect("a path starts at the root");
+            let old = step.node.cells.remove(step.index);
+            self.free_overflow_chain(PageType::TableLeaf, &old)?;
+        }
+
+        let mut payload = vec![];
+        record.write(&mut payload, self.db_header.text_encoding)?;
+        let cell = self.leaf_cell(Some(row_id), &payload)?;
+        self.insert_cell(path, cell)?;
+        Ok(true)
+    }
+
+    /// Add an entry to an index b-tree, or a row to a WITHOUT ROWID table, ordered by `key_columns`.
+    /// When an entry that is equal on its first `key_len` values is there, nothing changes and false is returned
+    pub fn insert_entry(
+        &mut self,
+        root: u32,
+        record: &Record,
+        key_columns: &[KeyColumn],
+        key_len: usize,
+    ) -> Result<bool> {
+        let key = Key::Entry {
+            values: &record.values,
+            key_columns,
+            len: key_len,
+        };
+        let (path, found) = self.descend(root, &key)?;
+        if found {
+            return Ok(false);
+        }
+
+        let mut payload = vec![];
+        record.write(&mut payload, self.db_header.text_encoding)?;
+        let cell = self.leaf_cell(None, &payload)?;
+        self.insert_cell(path, cell)?;
+        Ok(true)
+    }
+
+    /// Remove the row with the rowid from the table b-tree at `root`, false when there isn't one
+    pub fn delete_row(&mut self, root: u32, row_id: i64) -> Result<bool> {
+        let (path, found) = self.descend(root, &Key::RowId(row_id))?;
+        if found {
+            self.delete_cell(path)?;
+        }
+        Ok(found)
+    }
+
+    /// Remove the entry whose first `key_len` values are equal to those of `values` from the index b-tree
+    /// at `root`, or the row of a WITHOUT ROWID table. False when there isn't one
+    pub fn delete_entry(
+        &mut self,
+        root: u32,
+        values: &[SerialValue],
+        key_columns: &[KeyColumn],
+        key_len: usize,
+    ) -> Result<bool> {
+        let key = Key::Entry {
+            values,
+            key_columns,
+            len: key_len,
+        };
+        let (path, found) = self.descend(root, &key)?;
+        if found {
+            self.delete_cell(path)?;
+        }
+        Ok(found)
+    }
+
+    /// Whether the table b-tree at `root` has a row with the rowid
+    pub fn has_row(&self, root: u32, row_id: i64) -> Result<bool> {
+        Ok(self.descend(root, &Key::RowId(row_id))?.1)
+    }
+
+    /// Whether the index b-tree at `root` has an entry starting with the values of `prefix`
+    pub fn has_entry(
+        &self,
+        root: u32,
+        prefix: &[SerialValue],
+        key_columns: &[KeyColumn],
+    ) -> Result<bool> {
+        let key = Key::Entry {
+            values: prefix,
+            key_columns,
+            len: prefix.len(),
+        };
+        Ok(self.descend(root, &key)?.1)
+    }
+
+    /// Largest rowid in the table b-tree at `root`, 0 when it's empty
+    pub fn max_row_id(&self, root: u32) -> Result<i64> {
+        let mut page_number = root;
+        for _ in 0..MAX_BTREE_DEPTH {
+            let node = self.read_node(page_number)?;
+            match node.rightmost_pointer {
+                Some(child) => page_number = child,
+                None => {
+                    return match node.cells.last() {
+                        Some(cell) => self.cell_row_id(node.page_type, cell),
+                        None => Ok(0),
+                    }
+                }
+            }
+        }
+        bail!(Error::corrupt_page(root, "b-tree is too deep"))
+    }
+}
+
+/// Share cells out into pages of about `target` bytes each, none over `capacity`.
+/// When cells are promoted the one after each page moves up to the parent instead of starting the next page.
+/// Returns the range of cells on each page and the cells that are promoted, or None when they don't divide that way
+fn divide(
+    sizes: &[usize],
+    target: usize,
+    capacity: usize,
+    promote: bool,
+) -> Option<(Vec<std::ops::Range<usize>>, Vec<usize>)> {
+    let mut groups = vec![];
+    let mut promoted = vec![];
+    let mut start = 0;
+    let mut used = 0;
+    let mut i = 0;
+    while i < sizes.len() {
+        let remaining = sizes.len() - i;
+        // a promoted cell needs a page after it
+        let can_end = i > start && (!promote || remaining > 1);
+        if can_end && used + sizes[i] > target {
+            groups.push(start..i);
+            if promote {
+                promoted.push(i);
+                i += 1;
+            }
+            start = i;
+            used = 0;
+            continue;
+        }
+        used += sizes[i];
+        if used > capacity {
+            return None;
+        }
+        i += 1;
+    }
+    if start == sizes.len() {
+        return None;
+    }
+    groups.push(start..sizes.len());
+    (groups.len() > 1).then_some((groups, promoted))
+}
+
+#[cfg(test)]
+mod btree_tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        data_model::db_header::TextEncoding,
+        query_engine::{engine::QueryEngine, index_key::key_columns},
+        sql_parser::parser::{Column, SelectQuery},
+        vfs::{memory::MemoryVfs, vfs::OpenMode},
+    };
+
+    fn text(text: String) -> SerialValue<'static> {
+        SerialValue::Text(text.into())
+    }
+
+    fn select_all(pager: Pager, table: &str) -> String {
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: table.into(),
+            where_clause: None,
+        };
+        QueryEngine::new(pager).run_query(query).unwrap()
+    }
+
+    #[test]
+    fn test_inserting_rows_splits_pages() {
+        let vfs = MemoryVfs::new();
+        let mut pager = QueryEngine::open_fixture(&vfs, "sample.db").pager;
+        let encoding = TextEncoding::Utf8;
+        // rows added after the last one fill each page before starting the next
+        for row_id in 5..2005 {
+            let record = Record::new(
+                vec![
+                    SerialValue::Null,
+                    text(format!("apple {}", row_id)),
+                    text("Green".into()),
+                ],
+                encoding,
+            );
+            assert!(pager.insert_row(2, row_id, &record, false).unwrap());
+        }
+        // rows added out of order split pages in the middle of the b-tree
+        for i in 0..2000i64 {
+            let row_id = (i * 7919) % 2000 + 10;
+            let record = Record::new(
+                vec![
+                    SerialValue::Null,
+                    text(format!("orange {}", row_id)),
+                    SerialValue::Null,
+                ],
+                encoding,
+            );
+            assert!(pager.insert_row(4, row_id, &record, false).unwrap());
+        }
+        let record = Record::new(vec![SerialValue::Null], encoding);
+        assert!(!pager.insert_row(2, 1000, &record, false).unwrap());
+        assert_eq!(pager.max_row_id(2).unwrap(), 2004);
+        assert!(pager.has_row(4, 1500).unwrap());
+        assert!(!pager.has_row(4, 2500).unwrap());
+        pager.commit().unwrap();
+        assert!(pager.integrity_check().unwrap().is_empty());
+
+        let pager = Pager::open_with(&vfs, "sample.db").unwrap();
+        assert!(pager.page_count().unwrap() > 20);
+        let apples = select_all(pager, "apples");
+        assert_eq!(apples.lines().count(), 2004);
+        assert_eq!(apples.lines().nth(1000), Some("1001|apple 1001|Green"));
+        let oranges = select_all(Pager::open_with(&vfs, "sample.db").unwrap(), "oranges");
+        let row_ids: Vec<i64> = oranges
+            .lines()
+            .map(|line| line.split('|').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(row_ids.len(), 2006);
+        assert!(row_ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_inserting_index_entries_and_overflowing_payloads() {
+        let vfs = MemoryVfs::new();
+        let mut pager = QueryEngine::open_fixture(&vfs, "fixtures/overflow.db").pager;
+        let encoding = pager.db_header.text_encoding;
+        let schema: Vec<_> = pager.schema_table.cells.clone();
+        let table = schema.iter().find(|rec| rec.name == "documents").unwrap();
+        let index = schema
+            .iter()
+            .find(|rec| rec.name == "idx_documents_title")
+            .unwrap();
+        let key_columns = key_columns(index, &schema).unwrap();
+
+        let first_row_id = pager.max_row_id(table.rootpage).unwrap() + 1;
+        for i in 0..300 {
+            let row_id = first_row_id + i;
+            // every few titles are long enough to spill out of the index cells
+            let title = format!(
+                "title {:03} {}",
+                (i * 37) % 300,
+                "x".repeat((i % 5) as usize * 300)
+            );
+            let body = "body ".repeat(i as usize * 7);
+            let record = Record::new(
+                vec![SerialValue::Null, text(title.clone()), text(body)],
+                encoding,
+            );
+            assert!(pager
+                .insert_row(table.rootpage, row_id, &record, false)
+                .unwrap());
+            let entry = Record::new(vec![text(title), SerialValue::Int(row_id)], encoding);
+            let len = entry.values.len();
+            assert!(pager
+                .insert_entry(index.rootpage, &entry, &key_columns, len)
+                .unwrap());
+        }
+        let title = |title: &str| [text(title.into())];
+        assert!(pager
+            .has_entry(index.rootpage, &title("title 000 "), &key_columns)
+            .unwrap());
+        assert!(!pager
+            .has_entry(index.rootpage, &title("title 999 "), &key_columns)
+            .unwrap());
+        pager.commit().unwrap();
+        assert_eq!(pager.integrity_check().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_inserting_into_without_rowid_tables() {
+        let vfs = MemoryVfs::new();
+        let mut pager = QueryEngine::open_fixture(&vfs, "fixtures/without_rowid.db").pager;
+        let encoding = pager.db_header.text_encoding;
+        let schema: Vec<_> = pager.schema_table.cells.clone();
+        let table = schema.iter().find(|rec| rec.name == "countries").unwrap();
+        let key_columns = key_columns(table, &schema).unwrap();
+
+        for i in 0..500 {
+            // the primary key is stored first
+            let record = Record::new(
+                vec![
+                    text(format!("Z{:03}", (i * 13) % 500)),
+                    text(format!("country {}", i)),
+                    SerialValue::Int(i),
+                ],
+                encoding,
+            );
+            assert!(pager
+                .insert_entry(table.rootpage, &record, &key_columns, 1)
+                .unwrap());
+        }
+        let duplicate = Record::new(
+            vec![text("Z007".into()), text("again".into()), SerialValue::Null],
+            encoding,
+        );
+        assert!(!pager
+            .insert_entry(table.rootpage, &duplicate, &key_columns, 1)
+            .unwrap());
+        assert!(pager
+            .has_entry(table.rootpage, &[text("Z499".into())], &key_columns)
+            .unwrap());
+        pager.commit().unwrap();
+        assert!(pager.integrity_check().unwrap().is_empty());
+        assert!(Path::new("fixtures/without_rowid.db").exists());
+    }
+
+    #[test]
+    fn test_deleting_rows_leaves_freeblocks() {
+        let vfs = MemoryVfs::new();
+        let mut pager = QueryEngine::open_fixture(&vfs, "sample.db").pager;
+        // oranges is a single leaf, rows in the middle of its cell content area become freeblocks
+        assert!(pager.delete_row(4, 2).unwrap());
+        assert!(pager.delete_row(4, 3).unwrap());
+        assert!(!pager.delete_row(4, 3).unwrap());
+        pager.commit().unwrap();
+
+        let (page, _) = pager.read_page(4).unwrap();
+        assert_eq!(page.header.cell_count, 4);
+        assert_ne!(page.header.first_free_block, 0);
+        assert!(pager.integrity_check().unwrap().is_empty());
+        assert_eq!(
+            select_all(pager, "oranges").lines().next(),
+            Some("1|Mandarin|great for snacking")
+        );
+    }
+
+    #[test]
+    fn test_deleting_rows_merges_pages() {
+        let vfs = MemoryVfs::new();
+        let mut pager = QueryEngine::open_fixture(&vfs, "fixtures/overflow.db").pager;
+        let schema: Vec<_> = pager.schema_table.cells.clone();
+        let table = schema.iter().find(|rec| rec.name == "documents").unwrap();
+        let index = schema
+            .iter()
+            .find(|rec| rec.name == "idx_documents_title")
+            .unwrap();
+        let key_columns = key_columns(index, &schema).unwrap();
+        let free_pages = pager.freelist().unwrap().page_count();
+
+        let rows = QueryEngine::new(
+            Pager::open_with_mode(&vfs, "fixtures/overflow.db", OpenMode::ReadOnly).unwrap(),
+        )
+        .table_db_scan(
+            &table.clone().try_into().unwrap(),
+            &SelectQuery {
+                columns: vec![Column::All],
+                table: "documents".into(),
+                where_clause: None,
+            },
+        )
+        .unwrap();
+        for row in rows.iter().skip(1) {
+            let row_id = row.row_header.row_id as i64;
+            let title = row.record.value(1).unwrap().unwrap();
+            assert!(pager.delete_row(table.rootpage, row_id).unwrap());
+            assert!(pager
+                .delete_entry(
+                    index.rootpage,
+                    &[title, SerialValue::Int(row_id)],
+                    &key_columns,
+                    2
+                )
+                .unwrap());
+        }
+        pager.commit().unwrap();
+
+        // both b-trees are back to a single leaf and everything else is on the freelist
+        let (root, _) = pager.read_page(table.rootpage).unwrap();
+        assert_eq!(root.header.page_type, PageType::TableLeaf);
+        assert_eq!(root.header.cell_count, 1);
+        let (root, _) = pager.read_page(index.rootpage).unwrap();
+        assert_eq!(root.header.page_type, PageType::IndexLeaf);
+        assert!(pager.freelist().unwrap().page_count() > free_pages);
+        assert!(pager.integrity_check().unwrap().is_empty());
+    }
+}
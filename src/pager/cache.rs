@@ -0,0 +1,300 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Deref,
+    sync::Arc,
+};
+
+use crate::{data_model::btree::page::Page, vfs::mmap::Mmap};
+
+/// Bytes of a page shared between the cache and every reader of the page
+#[derive(Clone)]
+pub enum PageBuffer {
+    /// Read into memory
+    Owned(Arc<[u8]>),
+    /// Borrowed from the memory mapped database file without copying
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Deref for PageBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageBuffer::Owned(bytes) => bytes,
+            PageBuffer::Mapped { map, offset, len } => &map[*offset..*offset + *len],
+        }
+    }
+}
+
+impl AsRef<[u8]> for PageBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for PageBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        PageBuffer::Owned(bytes.into())
+    }
+}
+
+/// How much the page cache may hold before it evicts the least recently used page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheCapacity {
+    Pages(usize),
+    Bytes(usize),
+}
+
+impl Default for CacheCapacity {
+    /// sqlite's default cache size of 2000 KiB
+    fn default() -> Self {
+        CacheCapacity::Bytes(2000 * 1024)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct CacheEntry {
+    page: Arc<Page>,
+    data: PageBuffer,
+    // value of the cache's clock when the page was last read
+    last_used: u64,
+}
+
+/// Parsed b-tree pages kept in memory, evicting the least recently used page once full
+#[derive(Default)]
+pub struct PageCache {
+    capacity: CacheCapacity,
+    entries: HashMap<u32, CacheEntry>,
+    // page numbers ordered from least to most recently used
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+    bytes: usize,
+    pub stats: CacheStats,
+    // file change counter of the database when the cached pages were read
+    pub change_counter: u32,
+}
+
+impl PageCache {
+    pub fn new(capacity: CacheCapacity) -> Self {
+        PageCache {
+            capacity,
+            ..PageCache::default()
+        }
+    }
+
+    pub fn capacity(&self) -> CacheCapacity {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting pages until the cache fits
+    pub fn set_capacity(&mut self, capacity: CacheCapacity) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Size of the cached page buffers in bytes
+    pub fn size(&self) -> usize {
+        self.bytes
+    }
+
+    /// Look up a page marking it as the most recently used
+    pub fn get(&mut self, page_number: u32) -> Option<(Arc<Page>, PageBuffer)> {
+        self.clock += 1;
+        let Some(entry) = self.entries.get_mut(&page_number) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.clock, page_number);
+        entry.last_used = self.clock;
+        Some((entry.page.clone(), entry.data.clone()))
+    }
+
+    pub fn insert(&mut self, page_number: u32, page: Arc<Page>, data: PageBuffer) {
+        self.clock += 1;
+        self.bytes += data.len();
+        let entry = CacheEntry {
+            page,
+            data,
+            last_used: self.clock,
+        };
+        if let Some(replaced) = self.entries.insert(page_number, entry) {
+            self.bytes -= replaced.data.len();
+            self.recency.remove(&replaced.last_used);
+        }
+        self.recency.insert(self.clock, page_number);
+        self.evict();
+    }
+
+    /// Drop a page that has been changed
+    pub fn remove(&mut self, page_number: u32) {
+        if let Some(removed) = self.entries.remove(&page_number) {
+            self.bytes -= removed.data.len();
+            self.recency.remove(&removed.last_used);
+        }
+    }
+
+    /// Drop every page, i.e when another process has changed the database file
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+
+    fn is_full(&self) -> bool {
+        match self.capacity {
+            CacheCapacity::Pages(pages) => self.entries.len() > pages,
+            CacheCapacity::Bytes(bytes) => self.bytes > bytes,
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.is_full() {
+            let Some((_, page_number)) = self.recency.pop_first() else {
+                return;
+            };
+            if let Some(evicted) = self.entries.remove(&page_number) {
+                self.bytes -= evicted.data.len();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod page_cache_tests {
+    use super::*;
+    use crate::{
+        data_model::btree::page_header::{PageHeader, PageType},
+        pager::pager::Pager,
+        query_engine::engine::QueryEngine,
+        sql_parser::parser::{Column, SelectQuery},
+    };
+
+    fn page() -> Arc<Page> {
+        Arc::new(Page {
+            header: PageHeader {
+                page_type: PageType::TableLeaf,
+                first_free_block: 0,
+                cell_count: 0,
+                cell_content_offset: 0,
+                fragmented_free_bytes: 0,
+                rightmost_pointer: None,
+            },
+            cell_pointers: vec![],
+        })
+    }
+
+    fn buffer(size: usize) -> PageBuffer {
+        vec![0u8; size].into()
+    }
+
+    #[test]
+    fn test_least_recently_used_page_is_evicted() {
+        let mut cache = PageCache::new(CacheCapacity::Pages(2));
+        cache.insert(1, page(), buffer(512));
+        cache.insert(2, page(), buffer(512));
+        assert!(cache.get(1).is_some());
+        cache.insert(3, page(), buffer(512));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(3).is_some());
+        assert_eq!(
+            cache.stats,
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                evictions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_capacity_in_bytes() {
+        let mut cache = PageCache::new(CacheCapacity::Bytes(1024));
+        for page_number in 1..=4 {
+            cache.insert(page_number, page(), buffer(512));
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), 1024);
+
+        cache.set_capacity(CacheCapacity::Pages(0));
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.stats.evictions, 4);
+    }
+
+    #[test]
+    fn test_hits_share_the_buffer() {
+        let mut cache = PageCache::new(CacheCapacity::default());
+        let data = buffer(4096);
+        cache.insert(7, page(), data.clone());
+        let (_, hit) = cache.get(7).unwrap();
+        assert_eq!(data.as_ptr(), hit.as_ptr());
+    }
+
+    #[test]
+    fn test_scanning_within_capacity() {
+        let pager = Pager::open("companies.db").unwrap();
+        let engine = QueryEngine::new(pager);
+        engine.pager.set_cache_capacity(CacheCapacity::Pages(16));
+
+        let query = SelectQuery {
+            table: "companies".into(),
+            columns: vec![Column::Regular("name".into())],
+            where_clause: None,
+        };
+        engine.run_query(query).unwrap();
+
+        let stats = engine.pager.cache_stats();
+        assert!(stats.misses > 16);
+        assert_eq!(stats.evictions, stats.misses - 16);
+    }
+
+    #[test]
+    fn test_cache_is_dropped_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("cache_refresh_{}.db", std::process::id()));
+        std::fs::copy("sample.db", &path).unwrap();
+        let mut pager = Pager::open(&path).unwrap();
+        pager.read_page(2).unwrap();
+        assert!(!pager.refresh().unwrap());
+        assert!(pager.read_page(2).is_ok());
+        assert_eq!(pager.cache_stats().hits, 1);
+
+        // another process committing a transaction bumps the file change counter
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[24..28].copy_from_slice(&(pager.db_header.file_change_counter + 1).to_be_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(pager.validate_cache().unwrap());
+        assert!(!pager.validate_cache().unwrap());
+        pager.read_page(2).unwrap();
+        assert_eq!(pager.cache_stats().hits, 1);
+
+        // the schema is only reloaded by a refresh
+        assert!(pager.refresh().unwrap());
+        assert!(!pager.refresh().unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
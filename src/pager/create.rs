@@ -0,0 +1,236 @@
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    data_model::{
+        btree::page_header::{PageHeader, PageType},
+        db_header::{Dbheader, TextEncoding, DB_HEADER_SIZE},
+    },
+    error::Error,
+    serialisation::serialize::Serialize,
+    vfs::{
+        os::OsVfs,
+        vfs::{LockLevel, OpenMode, Vfs, VfsFile},
+    },
+};
+
+use super::pager::Pager;
+
+/// The sqlite release whose file format new databases are written in, stored in the header like sqlite does
+pub const SQLITE_VERSION_NUMBER: u32 = 3_045_000;
+
+/// Settings of a new database that can't be changed once it's created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CreateOptions {
+    /// A power of two from 512 to 65536
+    pub page_size: u32,
+    /// Space at the end of every page kept for extensions, at least 480 bytes of each page have to be left
+    pub reserved_bytes: u8,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub application_id: u32,
+}
+
+impl Default for CreateOptions {
+    /// sqlite's defaults
+    fn default() -> Self {
+        Self {
+            page_size: 4096,
+            reserved_bytes: 0,
+            text_encoding: TextEncoding::Utf8,
+            user_version: 0,
+            application_id: 0,
+        }
+    }
+}
+
+impl CreateOptions {
+    /// Header of a database with one page and nothing in its schema
+    fn db_header(&self) -> Result<Dbheader> {
+        let header = Dbheader {
+            page_size: self.page_size,
+            write_version: 1,
+            read_version: 1,
+            reserved_bytes: self.reserved_bytes,
+            max_payload_fraction: 64,
+            min_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 1,
+            database_size: 1,
+            first_freelist_trunk_page: 0,
+            freelist_page_count: 0,
+            schema_cookie: 0,
+            schema_format: 4,
+            default_cache_size: 0,
+            largest_root_page: 0,
+            text_encoding: self.text_encoding,
+            user_version: self.user_version,
+            incremental_vacuum: 0,
+            application_id: self.application_id,
+            version_valid_for: 1,
+            sqlite_version: SQLITE_VERSION_NUMBER,
+        };
+        // reading the header back checks the page size the way opening the database would
+        Dbheader::try_from(&<[u8; DB_HEADER_SIZE]>::from(&header))?;
+        ensure!(
+            header.usable_size() >= 480,
+            Error::UnsupportedFormat(format!(
+                "{} reserved bytes leave less than 480 bytes of a {} byte page",
+                self.reserved_bytes, self.page_size
+            ))
+        );
+        Ok(header)
+    }
+
+    /// Page 1 of an empty database, the database header followed by the empty `sqlite_schema` table leaf
+    fn first_page(&self) -> Result<Vec<u8>> {
+        let db_header = self.db_header()?;
+        let usable_size = db_header.usable_size();
+        let schema_header = PageHeader {
+            page_type: PageType::TableLeaf,
+            first_free_block: 0,
+            cell_count: 0,
+            // the cell content area starts at the end of the usable space, 65536 is stored as 0
+            cell_content_offset: usable_size as u16,
+            fragmented_free_bytes: 0,
+            rightmost_pointer: None,
+        };
+
+        let mut page = Vec::with_capacity(self.page_size as usize);
+        db_header.serialize(&mut page)?;
+        schema_header.serialize(&mut page)?;
+        page.resize(self.page_size as usize, 0);
+        Ok(page)
+    }
+}
+
+impl Pager {
+    /// Create an empty database at `path` on disk, see `create_with`
+    pub fn create(path: impl AsRef<Path>, options: CreateOptions) -> Result<Self> {
+        Self::create_with(&OsVfs, path, options)
+    }
+
+    /// Create a database with an empty schema at `path` and open it.
+    /// Like sqlite an empty file can become a database, a file that has anything in it is left alone
+    pub fn create_with(
+        vfs: &dyn Vfs,
+        path: impl AsRef<Path>,
+        options: CreateOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let first_page = options.first_page()?;
+
+        let file = vfs.open(path, OpenMode::Create)?;
+        file.lock(LockLevel::Exclusive)?;
+        let written = write_first_page(file.as_ref(), &first_page)
+            .with_context(|| format!("couldn't create a database at {}", path.display()));
+        file.unlock(LockLevel::None)?;
+        written?;
+
+        Self::new(file)
+    }
+}
+
+fn write_first_page(file: &dyn VfsFile, first_page: &[u8]) -> Result<()> {
+    if file.size()? != 0 {
+        bail!("the file isn't empty");
+    }
+    file.write_at(first_page, 0)?;
+    file.sync()
+}
+
+#[cfg(test)]
+mod create_tests {
+    use super::*;
+    use crate::{query_engine::engine::QueryEngine, vfs::memory::MemoryVfs};
+
+    #[test]
+    fn test_creating_an_empty_database() {
+        let vfs = MemoryVfs::new();
+        let options = CreateOptions {
+            page_size: 1024,
+            reserved_bytes: 8,
+            text_encoding: TextEncoding::Utf16be,
+            user_version: 7,
+            application_id: 0x5a5a,
+        };
+        Pager::create_with(&vfs, "new.db", options).unwrap();
+
+        let bytes = vfs.read(Path::new("new.db")).unwrap();
+        assert_eq!(bytes.len(), 1024);
+
+        let pager = Pager::open_with(&vfs, "new.db").unwrap();
+        assert_eq!(pager.db_header.page_size, 1024);
+        assert_eq!(pager.db_header.reserved_bytes, 8);
+        assert_eq!(pager.db_header.text_encoding, TextEncoding::Utf16be);
+        assert_eq!(pager.db_header.user_version, 7);
+        assert_eq!(pager.db_header.application_id, 0x5a5a);
+        assert_eq!(pager.db_header.database_size, 1);
+        assert_eq!(pager.root_page.header.page_type, PageType::TableLeaf);
+        assert_eq!(pager.root_page.header.cell_content_offset, 1016);
+        assert!(pager.schema_table.cells.is_empty());
+        assert!(pager.integrity_check().unwrap().is_empty());
+
+        let engine = QueryEngine::new(pager);
+        assert!(engine.get_table_rec("apples").is_err());
+    }
+
+    #[test]
+    fn test_largest_page_size() {
+        let vfs = MemoryVfs::new();
+        let options = CreateOptions {
+            page_size: 65536,
+            ..CreateOptions::default()
+        };
+        let pager = Pager::create_with(&vfs, "new.db", options).unwrap();
+        assert_eq!(pager.db_header.page_size, 65536);
+        // the end of the page doesn't fit in the header so it's stored as 0
+        assert_eq!(pager.root_page.header.cell_content_offset, 0);
+        assert!(pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let vfs = MemoryVfs::new();
+        for options in [
+            CreateOptions {
+                page_size: 1000,
+                ..CreateOptions::default()
+            },
+            CreateOptions {
+                page_size: 256,
+                ..CreateOptions::default()
+            },
+            CreateOptions {
+                page_size: 512,
+                reserved_bytes: 33,
+                ..CreateOptions::default()
+            },
+        ] {
+            let Err(error) = Pager::create_with(&vfs, "new.db", options) else {
+                panic!("{:?} should be rejected", options);
+            };
+            assert!(
+                matches!(error.downcast_ref(), Some(Error::UnsupportedFormat(_))),
+                "{:#}",
+                error
+            );
+        }
+        // nothing is created when the options are wrong
+        assert!(!vfs.exists(Path::new("new.db")).unwrap());
+    }
+
+    #[test]
+    fn test_existing_databases_are_left_alone() {
+        let vfs = MemoryVfs::new();
+        let sample = std::fs::read("sample.db").unwrap();
+        vfs.insert("sample.db", sample.clone());
+        assert!(Pager::create_with(&vfs, "sample.db", CreateOptions::default()).is_err());
+        assert_eq!(vfs.read(Path::new("sample.db")).unwrap(), sample);
+
+        // an empty file becomes a database
+        vfs.insert("empty.db", vec![]);
+        Pager::create_with(&vfs, "empty.db", CreateOptions::default()).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{bail, ensure, Result};
+
+use super::{page_map::lock_byte_page, pager::Pager};
+use crate::error::Error;
+
+/// Unused pages of the database file
+/// https://www.sqlite.org/fileformat2.html#the_freelist
+#[derive(Debug, Default)]
+pub struct Freelist {
+    // trunk pages hold the page numbers of the leaf pages and the next trunk page
+    pub trunk_pages: Vec<u32>,
+    pub leaf_pages: Vec<u32>,
+}
+
+impl Freelist {
+    pub fn page_count(&self) -> usize {
+        self.trunk_pages.len() + self.leaf_pages.len()
+    }
+}
+
+impl Pager {
+    /// Walk the freelist from the first trunk page in the database header
+    pub fn freelist(&self) -> Result<Freelist> {
+        let mut freelist = Freelist::default();
+        let expected_pages = self.db_header.freelist_page_count as usize;
+        let page_count = self.page_count()?;
+
+        let mut trunk_page = self.db_header.first_freelist_trunk_page;
+        while trunk_page != 0 {
+            if trunk_page > page_count {
+                bail!(Error::corrupt_page(
+                    trunk_page,
+                    "freelist trunk page is beyond the end of the database"
+                ));
+            }
+            if freelist.trunk_pages.contains(&trunk_page) {
+                bail!(Error::corrupt_page(
+                    trunk_page,
+                    "freelist trunk page is part of a cycle"
+                ));
+            }
+            freelist.trunk_pages.push(trunk_page);
+
+            let page = self.read_raw_page(trunk_page)?;
+            let next_trunk_page = u32::from_be_bytes(page[0..4].try_into()?);
+            let leaf_count = u32::from_be_bytes(page[4..8].try_into()?) as usize;
+            // a trunk page can't hold more leaf page numbers than fit after its 8 byte header
+            if leaf_count > (self.db_header.usable_size() - 8) / 4 {
+                bail!(Error::corrupt_page(
+                    trunk_page,
+                    format!("freelist trunk page claims {} leaf pages", leaf_count)
+                ));
+            }
+
+            freelist.leaf_pages.extend(
+                page[8..8 + leaf_count * 4]
+                    .chunks_exact(4)
+                    .map(|leaf| u32::from_be_bytes([leaf[0], leaf[1], leaf[2], leaf[3]])),
+            );
+            if freelist.page_count() > expected_pages {
+                bail!(Error::corrupt_page(
+                    1,
+                    format!(
+                        "freelist holds more than the {} pages in the database header",
+                        expected_pages
+                    )
+                ));
+            }
+            trunk_page = next_trunk_page;
+        }
+
+        Ok(freelist)
+    }
+}
+
+impl Pager {
+    /// A page for the current write to fill in, taken from the freelist when it has any,
+    /// otherwise added after the last page of the file. Its old bytes have to be overwritten
+    pub fn allocate_page(&mut self) -> Result<u32> {
+        self.begin_write()?;
+        let trunk_page = self.db_header.first_freelist_trunk_page;
+        if trunk_page == 0 {
+            let mut page_number = self.db_header.database_size + 1;
+            if page_number == lock_byte_page(self.db_header.page_size) {
+                page_number += 1;
+            }
+            self.db_header.database_size = page_number;
+            return Ok(page_number);
+        }
+
+        ensure!(
+            trunk_page <= self.db_header.database_size,
+            Error::corrupt_page(
+                trunk_page,
+                "freelist trunk page is beyond the end of the database"
+            )
+        );
+        let mut trunk = self.read_raw_page(trunk_page)?;
+        let leaf_count = u32::from_be_bytes(trunk[4..8].try_into()?) as usize;
+        ensure!(
+            leaf_count <= (self.db_header.usable_size() - 8) / 4,
+            Error::corrupt_page(
+                trunk_page,
+                format!("freelist trunk page claims {} leaf pages", leaf_count)
+            )
+        );
+        // leaves are taken from the end of the trunk, a trunk without leaves is used itself
+        let page_number = match leaf_count.checked_sub(1) {
+            Some(last) => {
+                let leaf = u32::from_be_bytes(trunk[8 + last * 4..12 + last * 4].try_into()?);
+                trunk[4..8].copy_from_slice(&(last as u32).to_be_bytes());
+                self.write_page(trunk_page, trunk)?;
+                leaf
+            }
+            None => {
+                self.db_header.first_freelist_trunk_page =
+                    u32::from_be_bytes(trunk[0..4].try_into()?);
+                trunk_page
+            }
+        };
+        ensure!(
+            (2..=self.db_header.database_size).contains(&page_number),
+            Error::corrupt_page(
+                trunk_page,
+                format!("freelist leaf page {} isn't in the database", page_number)
+            )
+        );
+        self.db_header.freelist_page_count = self
+            .db_header
+            .freelist_page_count
+            .checked_sub(1)
+            .ok_or_else(|| Error::corrupt_page(1, "freelist is longer than the header says"))?;
+        Ok(page_number)
+    }
+}
+
+impl Pager {
+    /// Give a page that is no longer used to the freelist. It becomes a leaf of the first trunk page
+    /// while that has room, otherwise the new first trunk page
+    pub fn free_page(&mut self, page_number: u32) -> Result<()> {
+        self.begin_write()?;
+        ensure!(
+            (2..=self.db_header.database_size).contains(&page_number),
+            Error::corrupt_page(page_number, "freed page isn't in the database")
+        );
+        let trunk_page = self.db_header.first_freelist_trunk_page;
+        if trunk_page != 0 {
+            let mut trunk = self.read_raw_page(trunk_page)?;
+            let leaf_count = u32::from_be_bytes(trunk[4..8].try_into()?) as usize;
+            // like sqlite the last few slots are left empty, old versions of sqlite would call the trunk corrupt
+            if leaf_count < self.db_header.usable_size() / 4 - 8 {
+                trunk[8 + leaf_count * 4..12 + leaf_count * 4]
+                    .copy_from_slice(&page_number.to_be_bytes());
+                trunk[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                self.write_page(trunk_page, trunk)?;
+                self.db_header.freelist_page_count += 1;
+                return Ok(());
+            }
+        }
+
+        let mut trunk = vec![0u8; self.db_header.page_size as usize];
+        trunk[0..4].copy_from_slice(&trunk_page.to_be_bytes());
+        self.write_page(page_number, trunk)?;
+        self.db_header.first_freelist_trunk_page = page_number;
+        self.db_header.freelist_page_count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod freelist_tests {
+
+    use super::*;
+
+    #[test]
+    fn test_walking_freelist() {
+        let pager = Pager::open("fixtures/freelist.db").unwrap();
+
+        let freelist = pager.freelist().unwrap();
+        assert_eq!(freelist.trunk_pages.len(), 1);
+        assert_eq!(freelist.page_count(), 53);
+    }
+
+    #[test]
+    fn test_empty_freelist() {
+        let pager = Pager::open("sample.db").unwrap();
+
+        let freelist = pager.freelist().unwrap();
+        assert_eq!(freelist.page_count(), 0);
+    }
+
+    #[test]
+    fn test_allocating_pages() {
+        let vfs = crate::vfs::memory::MemoryVfs::new();
+        vfs.insert(
+            "free_pages.db",
+            std::fs::read("fixtures/free_pages.db").unwrap(),
+        );
+        let mut pager =
+            Pager::open_with_mode(&vfs, "free_pages.db", crate::vfs::vfs::OpenMode::ReadWrite)
+                .unwrap();
+        let page_count = pager.page_count().unwrap();
+        let mut free_pages = pager.freelist().unwrap();
+        free_pages.leaf_pages.append(&mut free_pages.trunk_pages);
+
+        let mut allocated = vec![];
+        for _ in 0..free_pages.page_count() {
+            let page_number = pager.allocate_page().unwrap();
+            pager
+                .write_page(page_number, vec![0; pager.db_header.page_size as usize])
+                .unwrap();
+            allocated.push(page_number);
+        }
+        allocated.sort();
+        free_pages.leaf_pages.sort();
+        assert_eq!(allocated, free_pages.leaf_pages);
+        assert_eq!(pager.db_header.freelist_page_count, 0);
+        assert_eq!(pager.db_header.first_freelist_trunk_page, 0);
+
+        // once the freelist is used up the file grows
+        assert_eq!(pager.allocate_page().unwrap(), page_count + 1);
+        pager.rollback().unwrap();
+        assert_eq!(pager.freelist().unwrap().page_count(), 75);
+    }
+}
@@ -0,0 +1,673 @@
+use std::{cmp::Ordering, collections::HashMap, io::Cursor};
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use crate::{
+    data_model::{
+        btree::{
+            key::{compare_keys, KeyColumn},
+            page::{parse_cell, CellLayout},
+            page_header::{PageHeader, PageType},
+            record::Record,
+            serial_value::SerialValue,
+        },
+        db_header::{TextEncoding, DB_HEADER_SIZE},
+        schema_record::{DbObject, SchemaRecord},
+    },
+    query_engine::{
+        column::is_without_rowid,
+        index_key::{key_columns, IndexKey},
+    },
+    serialisation::deserialize::Deserialize,
+};
+
+use super::{
+    page_map::{is_pointer_map_page, lock_byte_page},
+    pager::Pager,
+};
+
+/// Like sqlite, stop reporting once this many problems have been found
+pub const MAX_PROBLEMS: usize = 100;
+
+impl Pager {
+    /// Walk every b-tree, the freelist and every index, returning a sqlite style message for each problem found.
+    /// An intact database has no problems.
+    /// https://www.sqlite.org/pragma.html#pragma_integrity_check
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut check = IntegrityCheck::new(self)?;
+        check.check_freelist();
+
+        let schema_records = check.pager.schema_table.cells.clone();
+        let mut entries: HashMap<u32, Vec<Entry>> = HashMap::new();
+        let mut schema_tree = Tree::table(1);
+        check.check_tree(&mut schema_tree);
+        for schema_record in schema_records.iter().filter(|rec| rec.rootpage != 0) {
+            let mut tree = Tree::for_object(schema_record, &schema_records);
+            check.check_tree(&mut tree);
+            entries.insert(schema_record.rootpage, tree.entries);
+        }
+
+        check.check_unused_pages();
+        for index in schema_records
+            .iter()
+            .filter(|rec| rec.db_object == DbObject::Index)
+        {
+            check.check_index(index, &schema_records, &entries);
+        }
+
+        Ok(check.problems)
+    }
+}
+
+/// A table row as (rowid, values) or an index entry with a rowid of 0
+type Entry = (i64, Vec<SerialValue<'static>>);
+
+/// State of the in-order walk of one b-tree
+struct Tree {
+    root: u32,
+    // table b-trees are keyed by rowid, index b-trees (and WITHOUT ROWID tables) by their records
+    is_table: bool,
+    // ordering of index keys, None when it can't be worked out from the schema
+    key_columns: Option<Vec<KeyColumn>>,
+    last_row_id: Option<i64>,
+    last_key: Option<Vec<SerialValue<'static>>>,
+    entries: Vec<Entry>,
+}
+
+impl Tree {
+    fn table(root: u32) -> Self {
+        Tree {
+            root,
+            is_table: true,
+            key_columns: None,
+            last_row_id: None,
+            last_key: None,
+            entries: vec![],
+        }
+    }
+
+    fn index(root: u32, key_columns: Option<Vec<KeyColumn>>) -> Self {
+        Tree {
+            is_table: false,
+            key_columns,
+            ..Tree::table(root)
+        }
+    }
+
+    fn for_object(schema_record: &SchemaRecord, schema_records: &[SchemaRecord]) -> Self {
+        let root = schema_record.rootpage;
+        match schema_record.db_object {
+            DbObject::Table if !is_without_rowid(&schema_record.sql) => Tree::table(root),
+            _ => Tree::index(root, key_columns(schema_record, schema_records)),
+        }
+    }
+}
+
+struct IntegrityCheck<'p> {
+    pager: &'p Pager,
+    page_count: u32,
+    usable_size: usize,
+    encoding: TextEncoding,
+    // indexed by page number, page 0 doesn't exist
+    referenced: Vec<bool>,
+    problems: Vec<String>,
+}
+
+impl<'p> IntegrityCheck<'p> {
+    fn new(pager: &'p Pager) -> Result<Self> {
+        let page_count = pager.page_count()?;
+        let mut check = IntegrityCheck {
+            page_count,
+            usable_size: pager.db_header.usable_size(),
+            encoding: pager.db_header.text_encoding,
+            referenced: vec![false; page_count as usize + 1],
+            problems: vec![],
+            pager,
+        };
+
+        // pages that are never part of a b-tree or the freelist, referring to them is a second reference
+        let lock_byte_page = lock_byte_page(check.pager.db_header.page_size);
+        if lock_byte_page <= page_count {
+            check.referenced[lock_byte_page as usize] = true;
+        }
+        if check.pager.db_header.largest_root_page != 0 {
+            for page_number in 2..=page_count {
+                if is_pointer_map_page(page_number, check.usable_size) {
+                    check.referenced[page_number as usize] = true;
+                }
+            }
+        }
+        Ok(check)
+    }
+
+    fn problem(&mut self, message: String) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(message);
+        }
+    }
+
+    /// Mark a page as in use, returns false when the page doesn't exist or is already used elsewhere
+    fn reference_page(&mut self, page_number: u32, context: &str) -> bool {
+        if page_number == 0 || page_number > self.page_count {
+            self.problem(format!("{}invalid page number {}", context, page_number));
+            return false;
+        }
+        if self.referenced[page_number as usize] {
+            self.problem(format!("{}2nd reference to page {}", context, page_number));
+            return false;
+        }
+        self.referenced[page_number as usize] = true;
+        true
+    }
+
+    fn read_page(&mut self, page_number: u32, context: &str) -> Option<Vec<u8>> {
+        match self.pager.read_raw_page(page_number) {
+            Ok(page) => Some(page),
+            Err(err) => {
+                self.problem(format!("{}unable to get the page: {:#}", context, err));
+                None
+            }
+        }
+    }
+
+    /// Every page on the freelist has to exist, be used once, and add up to the count in the header
+    fn check_freelist(&mut self) {
+        let context = "Freelist: ";
+        let expected_pages = self.pager.db_header.freelist_page_count;
+        let max_leaf_count = (self.usable_size - 8) / 4;
+        let mut page_count = 0;
+
+        let mut trunk_page = self.pager.db_header.first_freelist_trunk_page;
+        while trunk_page != 0 && self.reference_page(trunk_page, context) {
+            page_count += 1;
+            let Some(page) = self.read_page(trunk_page, context) else {
+                break;
+            };
+            let next_trunk_page = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+            let leaf_count = u32::from_be_bytes([page[4], page[5], page[6], page[7]]) as usize;
+            if leaf_count > max_leaf_count {
+                self.problem(format!(
+                    "{}freelist leaf count too big on page {}",
+                    context, trunk_page
+                ));
+                break;
+            }
+            for leaf in page[8..8 + leaf_count * 4].chunks_exact(4) {
+                let leaf_page = u32::from_be_bytes([leaf[0], leaf[1], leaf[2], leaf[3]]);
+                if self.reference_page(leaf_page, context) {
+                    page_count += 1;
+                }
+            }
+            trunk_page = next_trunk_page;
+        }
+
+        if page_count != expected_pages {
+            self.problem(format!(
+                "{}size is {} but should be {}",
+                context, page_count, expected_pages
+            ));
+        }
+    }
+
+    fn check_tree(&mut self, tree: &mut Tree) {
+        let context = format!("Tree {} page {}: ", tree.root, tree.root);
+        self.check_tree_page(tree, tree.root, &context);
+    }
+
+    /// Check a b-tree page and its children in key order, returns the depth of the subtree when it could be read
+    fn check_tree_page(&mut self, tree: &mut Tree, page_number: u32, context: &str) -> Option<u32> {
+        if !self.reference_page(page_number, context) {
+            return None;
+        }
+        let page_context = format!("Tree {} page {}: ", tree.root, page_number);
+        let page = self.read_page(page_number, &page_context)?;
+
+        let header_offset = if page_number == 1 { DB_HEADER_SIZE } else { 0 };
+        let header = match PageHeader::deserialize(&mut Cursor::new(&page[header_offset..])) {
+            Ok(header) if is_table_page(header.page_type) == tree.is_table => header,
+            _ => {
+                self.problem(format!(
+                    "{}btreeInitPage() returns error code 11",
+                    page_context
+                ));
+                return None;
+            }
+        };
+        let is_interior = header.rightmost_pointer.is_some();
+        let header_size = if is_interior { 12 } else { 8 };
+
+        // the cell pointer array follows the page header and the cell content area runs to the end of the usable space
+        let cell_pointers_start = header_offset + header_size;
+        let cell_pointers_end = cell_pointers_start + header.cell_count as usize * 2;
+        let content_start = match header.cell_content_offset {
+            0 => 65536,
+            offset => offset as usize,
+        };
+        if cell_pointers_end > content_start || content_start > self.usable_size {
+            self.problem(format!(
+                "{}btreeInitPage() returns error code 11",
+                page_context
+            ));
+            return None;
+        }
+
+        // byte ranges of the page used by cells and freeblocks
+        let mut used: Vec<(usize, usize)> = vec![];
+        let mut depth: Option<u32> = None;
+        for cell in 0..header.cell_count as usize {
+            let cell_context = format!("Tree {} page {} cell {}: ", tree.root, page_number, cell);
+            let pointer = cell_pointers_start + cell * 2;
+            let offset = u16::from_be_bytes([page[pointer], page[pointer + 1]]) as usize;
+            if offset < content_start || offset > self.usable_size - 4 {
+                self.problem(format!(
+                    "{}Offset {} out of range {}..{}",
+                    cell_context,
+                    offset,
+                    content_start,
+                    self.usable_size - 4
+                ));
+                continue;
+            }
+            let Ok(layout) = parse_cell(&page, offset, header.page_type, self.usable_size) else {
+                self.problem(format!("{}Extends off end of page", cell_context));
+                continue;
+            };
+            used.push((offset, offset + layout.size));
+
+            if let Some(left_child) = layout.left_child {
+                let child_depth = self.check_tree_page(tree, left_child, &cell_context);
+                self.check_depth(&mut depth, child_depth, &cell_context);
+            }
+
+            // table interior cells only hold a key that divides their children
+            if header.page_type == PageType::TableInterior {
+                let row_id = layout.row_id.unwrap_or_default();
+                if tree.last_row_id.is_some_and(|last| row_id < last) {
+                    self.problem(format!("{}Rowid {} out of order", cell_context, row_id));
+                }
+                tree.last_row_id = Some(row_id);
+                continue;
+            }
+
+            let Some(payload) = self.read_cell_payload(&page, &layout, &cell_context) else {
+                continue;
+            };
+            let record = match Record::read(&mut Cursor::new(payload), self.encoding) {
+                Ok(record) => record,
+                Err(err) => {
+                    self.problem(format!("{}malformed record: {}", cell_context, err));
+                    continue;
+                }
+            };
+
+            match layout.row_id {
+                Some(row_id) => {
+                    if tree.last_row_id.is_some_and(|last| row_id <= last) {
+                        self.problem(format!("{}Rowid {} out of order", cell_context, row_id));
+                    }
+                    tree.last_row_id = Some(row_id);
+                    tree.entries.push((row_id, record.values));
+                }
+                None => {
+                    if let (Some(key_columns), Some(last_key)) = (&tree.key_columns, &tree.last_key)
+                    {
+                        if compare_keys(last_key, &record.values, key_columns, self.encoding)
+                            != Ordering::Less
+                        {
+                            self.problem(format!("{}Index entry out of order", cell_context));
+                        }
+                    }
+                    tree.last_key = Some(record.values.clone());
+                    tree.entries.push((0, record.values));
+                }
+            }
+        }
+
+        if let Some(rightmost_pointer) = header.rightmost_pointer {
+            let right_context = format!("Tree {} page {} right child: ", tree.root, page_number);
+            let child_depth = self.check_tree_page(tree, rightmost_pointer, &right_context);
+            self.check_depth(&mut depth, child_depth, &right_context);
+        }
+
+        self.check_free_space(
+            &page,
+            page_number,
+            &header,
+            &page_context,
+            cell_pointers_end,
+            used,
+        );
+        Some(depth.unwrap_or(0) + 1)
+    }
+
+    /// Every child of an interior page has to be the same distance from the leaves
+    fn check_depth(&mut self, depth: &mut Option<u32>, child_depth: Option<u32>, context: &str) {
+        let Some(child_depth) = child_depth else {
+            return;
+        };
+        match depth {
+            Some(depth) if *depth != child_depth => {
+                self.problem(format!("{}Child page depth differs", context))
+            }
+            Some(_) => (),
+            None => *depth = Some(child_depth),
+        }
+    }
+
+    /// Cells and freeblocks must not share bytes,
+    /// and whatever they don't cover in the cell content area is the page's fragmented bytes
+    fn check_free_space(
+        &mut self,
+        page: &[u8],
+        page_number: u32,
+        header: &PageHeader,
+        context: &str,
+        cell_pointers_end: usize,
+        mut used: Vec<(usize, usize)>,
+    ) {
+        let mut free_block = header.first_free_block as usize;
+        while free_block != 0 {
+            let valid = free_block >= cell_pointers_end && free_block + 4 <= self.usable_size;
+            let size = if valid {
+                u16::from_be_bytes([page[free_block + 2], page[free_block + 3]]) as usize
+            } else {
+                0
+            };
+            if !valid || size < 4 || free_block + size > self.usable_size {
+                self.problem(format!("{}free space corruption", context));
+                return;
+            }
+            used.push((free_block, free_block + size));
+
+            // freeblocks are kept in ascending order
+            let next = u16::from_be_bytes([page[free_block], page[free_block + 1]]) as usize;
+            if next != 0 && next <= free_block + size {
+                self.problem(format!("{}free space corruption", context));
+                return;
+            }
+            free_block = next;
+        }
+
+        used.sort();
+        for pair in used.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                self.problem(format!(
+                    "Multiple uses for byte {} of page {}",
+                    pair[1].0, page_number
+                ));
+                return;
+            }
+        }
+
+        let content_start = match header.cell_content_offset {
+            0 => 65536,
+            offset => offset as usize,
+        };
+        let used_bytes: usize = used.iter().map(|(start, end)| end - start).sum();
+        let fragmented = (self.usable_size - content_start).saturating_sub(used_bytes);
+        if fragmented != header.fragmented_free_bytes as usize {
+            self.problem(format!(
+                "Fragmentation of {} bytes reported as {} on page {}",
+                fragmented, header.fragmented_free_bytes, page_number
+            ));
+        }
+    }
+
+    /// Assemble a cell's payload, checking the overflow chain holds exactly the pages it needs
+    fn read_cell_payload(
+        &mut self,
+        page: &[u8],
+        layout: &CellLayout,
+        context: &str,
+    ) -> Option<Vec<u8>> {
+        let (start, end) = layout.local_payload;
+        let mut payload = page[start..end].to_vec();
+        let Some(first_overflow_page) = layout.overflow_page else {
+            return Some(payload);
+        };
+
+        let overflow_size = self.usable_size - 4;
+        let remaining = layout.payload_size as usize - payload.len();
+        let expected_pages = remaining.div_ceil(overflow_size);
+
+        let mut overflow_pages = 0;
+        let mut next_page = first_overflow_page;
+        while next_page != 0 {
+            if !self.reference_page(next_page, context) {
+                return None;
+            }
+            let overflow_page = self.read_page(next_page, context)?;
+            overflow_pages += 1;
+            payload.extend_from_slice(&overflow_page[4..self.usable_size]);
+            next_page = u32::from_be_bytes([
+                overflow_page[0],
+                overflow_page[1],
+                overflow_page[2],
+                overflow_page[3],
+            ]);
+        }
+
+        if overflow_pages != expected_pages {
+            self.problem(format!(
+                "{}overflow list length is {} but should be {}",
+                context, overflow_pages, expected_pages
+            ));
+            return None;
+        }
+        payload.truncate(layout.payload_size as usize);
+        Some(payload)
+    }
+
+    /// Pages that aren't part of a b-tree, the freelist or reserved by sqlite are lost
+    fn check_unused_pages(&mut self) {
+        for page_number in 1..=self.page_count {
+            if !self.referenced[page_number as usize] {
+                self.problem(format!("Page {}: never used", page_number));
+            }
+        }
+    }
+
+    /// Every row of the table needs exactly one entry in the index and every entry has to belong to a row
+    fn check_index(
+        &mut self,
+        index: &SchemaRecord,
+        schema_records: &[SchemaRecord],
+        entries: &HashMap<u32, Vec<Entry>>,
+    ) {
+        // partial indexes can't be rebuilt from the rows
+        let lowercase = index.sql.to_lowercase();
+        if lowercase[lowercase.rfind(')').unwrap_or(0)..].contains("where") {
+            return;
+        }
+        let Some(table) = schema_records
+            .iter()
+            .find(|rec| rec.db_object == DbObject::Table && rec.name == index.tbl_name)
+        else {
+            return;
+        };
+        let (Some(rows), Some(index_entries)) =
+            (entries.get(&table.rootpage), entries.get(&index.rootpage))
+        else {
+            return;
+        };
+        let Some(expected_key) = IndexKey::new(index, table) else {
+            return;
+        };
+
+        // index entries that haven't been matched to a row yet
+        let mut unmatched: HashMap<String, usize> = HashMap::new();
+        for (_, values) in index_entries {
+            *unmatched.entry(format!("{:?}", values)).or_default() += 1;
+        }
+        for (row, (row_id, values)) in rows.iter().enumerate() {
+            let key = format!("{:?}", expected_key.entry_for(*row_id, values));
+            match unmatched.get_mut(&key) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => self.problem(format!("row {} missing from index {}", row + 1, index.name)),
+            }
+        }
+
+        if index_entries.len() != rows.len() {
+            self.problem(format!("wrong # of entries in index {}", index.name));
+        }
+        for (_, values) in index_entries {
+            let key = format!("{:?}", values);
+            if let Some(count) = unmatched.get_mut(&key).filter(|count| **count > 0) {
+                *count -= 1;
+                self.problem(format!(
+                    "entry ({}) in index {} has no matching row",
+                    values.iter().map(|value| format!("{:?}", value)).join(", "),
+                    index.name
+                ));
+            }
+        }
+    }
+}
+
+fn is_table_page(page_type: PageType) -> bool {
+    matches!(page_type, PageType::TableInterior | PageType::TableLeaf)
+}
+
+#[cfg(test)]
+mod integrity_check_tests {
+    use std::fs;
+
+    use super::*;
+    use crate::pager::page_map::PageOwner;
+
+    const NOTES_LEAF_PAGE: usize = 5;
+
+    fn check(path: &str) -> Vec<String> {
+        let pager = Pager::open(path).unwrap();
+        pager.integrity_check().unwrap()
+    }
+
+    /// Check a copy of a fixture with some of its bytes changed, passing the page size to `corrupt`
+    fn check_corrupted(
+        fixture: &str,
+        name: &str,
+        corrupt: impl FnOnce(&mut [u8], usize),
+    ) -> Vec<String> {
+        let mut bytes = fs::read(fixture).unwrap();
+        let page_size = u16::from_be_bytes([bytes[16], bytes[17]]) as usize;
+        corrupt(&mut bytes, page_size);
+
+        let path = std::env::temp_dir().join(format!(
+            "integrity_check_{}_{}.db",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, bytes).unwrap();
+        let problems = check(path.to_str().unwrap());
+        fs::remove_file(path).unwrap();
+        problems
+    }
+
+    #[test]
+    fn test_intact_databases_are_ok() {
+        for path in [
+            "sample.db",
+            "fixtures/overflow.db",
+            "fixtures/utf16le.db",
+            "fixtures/utf16be.db",
+            "fixtures/without_rowid.db",
+            "fixtures/freelist.db",
+        ] {
+            assert_eq!(check(path), Vec::<String>::new(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_invalid_page_type() {
+        let problems = check_corrupted("fixtures/freelist.db", "page_type", |bytes, page_size| {
+            bytes[(NOTES_LEAF_PAGE - 1) * page_size] = 0x07;
+        });
+        assert_eq!(
+            problems[0],
+            "Tree 3 page 5: btreeInitPage() returns error code 11"
+        );
+        assert!(problems.contains(&"wrong # of entries in index idx_notes_topic".to_string()));
+    }
+
+    #[test]
+    fn test_rowids_out_of_order() {
+        let problems = check_corrupted("fixtures/freelist.db", "rowids", |bytes, page_size| {
+            let cell_pointers = (NOTES_LEAF_PAGE - 1) * page_size + 8;
+            bytes.copy_within(cell_pointers..cell_pointers + 2, cell_pointers + 4);
+            bytes.copy_within(cell_pointers + 2..cell_pointers + 4, cell_pointers);
+            bytes.copy_within(cell_pointers + 4..cell_pointers + 6, cell_pointers + 2);
+        });
+        assert_eq!(problems, vec!["Tree 3 page 5 cell 1: Rowid 1 out of order"]);
+    }
+
+    #[test]
+    fn test_overlapping_cells() {
+        let problems = check_corrupted("fixtures/freelist.db", "overlap", |bytes, page_size| {
+            let cell_pointers = (NOTES_LEAF_PAGE - 1) * page_size + 8;
+            bytes.copy_within(cell_pointers..cell_pointers + 2, cell_pointers + 2);
+        });
+        assert!(problems.contains(&"Multiple uses for byte 334 of page 5".to_string()));
+        assert!(problems.contains(&"row 2 missing from index idx_notes_topic".to_string()));
+    }
+
+    #[test]
+    fn test_page_missing_from_freelist() {
+        let problems = check_corrupted("fixtures/freelist.db", "freelist", |bytes, page_size| {
+            let trunk_page = u32::from_be_bytes(bytes[32..36].try_into().unwrap()) as usize;
+            let leaf_count = (trunk_page - 1) * page_size + 4;
+            bytes[leaf_count + 3] -= 1;
+        });
+        assert_eq!(
+            problems,
+            vec![
+                "Freelist: size is 52 but should be 53",
+                "Page 166: never used"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncated_overflow_chain() {
+        let pager = Pager::open("fixtures/overflow.db").unwrap();
+        let page_map = pager.page_map().unwrap();
+        // the first overflow page that isn't the last page of its chain
+        let overflow_page = (1..=page_map.page_count)
+            .filter(|page| *page_map.owner(*page) == PageOwner::Overflow("documents".into()))
+            .find(|page| pager.read_raw_page(*page).unwrap()[0..4] != [0; 4])
+            .unwrap();
+        let next_page = u32::from_be_bytes(
+            pager.read_raw_page(overflow_page).unwrap()[0..4]
+                .try_into()
+                .unwrap(),
+        );
+
+        let problems = check_corrupted("fixtures/overflow.db", "overflow", |bytes, page_size| {
+            let page_start = (overflow_page as usize - 1) * page_size;
+            bytes[page_start..page_start + 4].fill(0);
+        });
+        assert!(problems[0].ends_with("overflow list length is 1 but should be 5"));
+        assert!(problems.contains(&format!("Page {}: never used", next_page)));
+        assert!(problems.contains(&"wrong # of entries in index idx_documents_title".to_string()));
+    }
+
+    #[test]
+    fn test_index_entry_without_row() {
+        let problems = check_corrupted("fixtures/freelist.db", "index", |bytes, page_size| {
+            let page_start = (NOTES_LEAF_PAGE - 1) * page_size;
+            let page = &bytes[page_start..page_start + page_size];
+            let topic = page
+                .windows(7)
+                .position(|window| window == b"topic 1")
+                .unwrap();
+            bytes[page_start + topic + 6] = b'X';
+        });
+        assert_eq!(
+            problems,
+            vec![
+                "row 1 missing from index idx_notes_topic",
+                "entry (Text(\"topic 1\"), Int(1)) in index idx_notes_topic has no matching row",
+            ]
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::vfs::vfs::{LockLevel, OpenMode, Vfs, VfsFile};
+
+/// Every segment of a rollback journal starts with these bytes, a zeroed header marks a committed transaction
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+pub const JOURNAL_HEADER_SIZE: usize = 28;
+/// The header of a journal this pager writes is padded out to this many bytes, the smallest sector sqlite assumes
+pub const JOURNAL_SECTOR_SIZE: u32 = 512;
+
+/// Header written at the start of each segment of a rollback journal, padded out to a sector
+/// https://www.sqlite.org/fileformat2.html#the_rollback_journal
+#[derive(Debug, Clone, Copy)]
+pub struct JournalHeader {
+    // number of page records in the segment, -1 when it has to be worked out from the journal size
+    pub record_count: u32,
+    // random value the page checksums start from
+    pub nonce: u32,
+    // size of the database in pages before the transaction started
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+impl JournalHeader {
+    fn parse(buf: &[u8; JOURNAL_HEADER_SIZE]) -> Option<Self> {
+        if buf[..8] != JOURNAL_MAGIC {
+            return None;
+        }
+        let field = |offset: usize| u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let header = JournalHeader {
+            record_count: field(8),
+            nonce: field(12),
+            initial_size: field(16),
+            sector_size: field(20),
+            page_size: field(24),
+        };
+        let valid_size =
+            |size: u32, min: u32| size.is_power_of_two() && (min..=65536).contains(&size);
+        (valid_size(header.sector_size, 32) && valid_size(header.page_size, 512)).then_some(header)
+    }
+
+    /// The header padded out to its sector
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        for field in [
+            self.record_count,
+            self.nonce,
+            self.initial_size,
+            self.sector_size,
+            self.page_size,
+        ] {
+            bytes.extend(field.to_be_bytes());
+        }
+        bytes.resize(self.sector_size as usize, 0);
+        bytes
+    }
+
+    /// sqlite's checksum of a journaled page, the nonce plus every 200th byte counting back from the end
+    pub fn page_checksum(&self, page: &[u8]) -> u32 {
+        (1..=(page.len() - 1) / 200)
+            .map(|step| page[page.len() - step * 200] as u32)
+            .fold(self.nonce, u32::wrapping_add)
+    }
+}
+
+/// Original contents of the pages an unfinished transaction was changing.
+/// When the journal is hot the database file may hold some of the transaction's writes
+pub struct Journal {
+    file: Box<dyn VfsFile>,
+    // header of the first segment, None when the journal isn't hot
+    pub header: Option<JournalHeader>,
+    // offset of the page image of the first record for each page, later records of a page aren't the original
+    pages: HashMap<u32, u64>,
+}
+
+impl Journal {
+    /// Read the page records of every segment, stopping at the first record whose checksum doesn't
+    /// match since sqlite hadn't finished writing the journal past that point.
+    /// Whether another connection still holds a lock on the database isn't checked
+    pub fn new(file: Box<dyn VfsFile>) -> Result<Self> {
+        let journal_size = file.size()?;
+        let mut first_header: Option<JournalHeader> = None;
+        let mut pages: HashMap<u32, u64> = HashMap::new();
+
+        let mut segment_offset = 0;
+        'segments: while segment_offset + JOURNAL_HEADER_SIZE as u64 <= journal_size {
+            let mut header_buf = [0u8; JOURNAL_HEADER_SIZE];
+            file.read_at(&mut header_buf, segment_offset)?;
+            let Some(header) = JournalHeader::parse(&header_buf) else {
+                break;
+            };
+            let first_header = *first_header.get_or_insert(header);
+            if header.page_size != first_header.page_size {
+                break;
+            }
+
+            let page_size = header.page_size as u64;
+            let record_size = 4 + page_size + 4;
+            let records_offset = segment_offset + header.sector_size as u64;
+            let record_count = match header.record_count {
+                u32::MAX => journal_size.saturating_sub(records_offset) / record_size,
+                record_count => record_count as u64,
+            };
+
+            let mut record = vec![0u8; record_size as usize];
+            for record_number in 0..record_count {
+                let record_offset = records_offset + record_number * record_size;
+                if file.read_at(&mut record, record_offset).is_err() {
+                    break 'segments;
+                }
+                let page_number = u32::from_be_bytes(record[0..4].try_into()?);
+                let page = &record[4..4 + page_size as usize];
+                let checksum = u32::from_be_bytes(record[4 + page_size as usize..].try_into()?);
+                if page_number == 0 || checksum != header.page_checksum(page) {
+                    break 'segments;
+                }
+                pages.entry(page_number).or_insert(record_offset + 4);
+            }
+
+            // the next segment starts on a sector boundary
+            let records_end = records_offset + record_count * record_size;
+            segment_offset = records_end.next_multiple_of(header.sector_size as u64);
+        }
+
+        Ok(Journal {
+            file,
+            header: first_header,
+            pages,
+        })
+    }
+
+    /// A journal with a valid header belongs to a transaction that never finished
+    pub fn is_hot(&self) -> bool {
+        self.header.is_some()
+    }
+
+    /// Size of the database in pages once the transaction is rolled back
+    pub fn initial_size(&self) -> Option<u32> {
+        self.header.map(|header| header.initial_size)
+    }
+
+    /// Whether the transaction changed the page
+    pub fn has_page(&self, page_number: u32) -> bool {
+        self.header.is_some() && self.pages.contains_key(&page_number)
+    }
+
+    /// Contents of a page from before the transaction, None when the transaction didn't change it
+    pub fn read_page(&self, page_number: u32) -> Result<Option<Vec<u8>>> {
+        let (Some(header), Some(offset)) = (self.header, self.pages.get(&page_number)) else {
+            return Ok(None);
+        };
+        let mut page = vec![0u8; header.page_size as usize];
+        self.file
+            .read_at(&mut page, *offset)
+            .with_context(|| format!("couldn't read page {} from the journal", page_number))?;
+        Ok(Some(page))
+    }
+
+    /// Roll the database file back to how it was before the transaction, then empty the journal so it is no longer hot.
+    /// Both files have to be open for writing
+    pub fn play_back(self, db_file: &dyn VfsFile) -> Result<()> {
+        let Some(header) = self.header else {
+            return Ok(());
+        };
+        let page_size = header.page_size as u64;
+        // nobody else may read the file while it is part way through being rolled back
+        db_file.lock(LockLevel::Exclusive)?;
+
+        for page_number in self.pages.keys() {
+            let page = self.read_page(*page_number)?.context("journaled page")?;
+            db_file
+                .write_at(&page, (page_number - 1) as u64 * page_size)
+                .with_context(|| format!("couldn't restore page {}", page_number))?;
+        }
+        db_file.set_len(header.initial_size as u64 * page_size)?;
+        db_file.sync()?;
+
+        self.file.set_len(0)?;
+        self.file.sync()?;
+        db_file.unlock(LockLevel::None)
+    }
+}
+
+/// The rollback journal of this connection's writes. The original contents of a page are added before the page
+/// is first changed, so a write that fails or crashes part way through writing the database file can be undone.
+/// Like sqlite's `journal_mode=DELETE` the journal is created when a write starts and deleted once it commits
+pub struct JournalWriter {
+    vfs: Box<dyn Vfs>,
+    path: PathBuf,
+    // the journal of the current write, None when no write has created it
+    file: Option<Box<dyn VfsFile>>,
+    // header of the current write's journal, None when no write has started
+    header: Option<JournalHeader>,
+    // pages whose original contents are in the journal, in the order they were added
+    pages: Vec<u32>,
+    journaled: HashSet<u32>,
+}
+
+impl JournalWriter {
+    pub fn new(vfs: Box<dyn Vfs>, path: PathBuf) -> Self {
+        JournalWriter {
+            vfs,
+            path,
+            file: None,
+            header: None,
+            pages: vec![],
+            journaled: HashSet::new(),
+        }
+    }
+
+    /// Whether a write has started a journal that hasn't been finished or played back
+    pub fn is_active(&self) -> bool {
+        self.header.is_some()
+    }
+
+    /// Start the journal of a write to a database of `initial_size` pages. The header says it has no records
+    /// until `sync`, before then the database file hasn't been written so there's nothing to play back
+    pub fn begin(&mut self, initial_size: u32, page_size: u32) -> Result<()> {
+        let header = JournalHeader {
+            record_count: 0,
+            nonce: nonce(),
+            initial_size,
+            sector_size: JOURNAL_SECTOR_SIZE,
+            page_size,
+        };
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => self.vfs.open(&self.path, OpenMode::Create)?,
+        };
+        let file = self.file.insert(file);
+        file.set_len(0)?;
+        file.write_at(&header.to_bytes(), 0)
+            .context("couldn't write the journal header")?;
+        self.header = Some(header);
+        self.pages.clear();
+        self.journaled.clear();
+        Ok(())
+    }
+
+    /// Whether the original contents of the page have to be journaled before it's changed.
+    /// Pages the write adds past the end of the database are cut off again when it's played back
+    pub fn needs(&self, page_number: u32) -> bool {
+        self.header
+            .is_some_and(|header| page_number <= header.initial_size)
+            && !self.journaled.contains(&page_number)
+    }
+
+    /// Add a page record holding the original contents of a page
+    pub fn journal_page(&mut self, page_number: u32, page: &[u8]) -> Result<()> {
+        let header = self.header.context("no write has started a journal")?;
+        let mut record = Vec::with_capacity(page.len() + 8);
+        record.extend(page_number.to_be_bytes());
+        record.extend(page);
+        record.extend(header.page_checksum(page).to_be_bytes());
+        self.file()?
+            .write_at(&record, self.record_offset(&header, self.pages.len()))
+            .with_context(|| format!("couldn't journal page {}", page_number))?;
+        self.pages.push(page_number);
+        self.journaled.insert(page_number);
+        Ok(())
+    }
+
+    fn file(&self) -> Result<&dyn VfsFile> {
+        self.file
+            .as_deref()
+            .context("no write has created the journal")
+    }
+
+    fn record_offset(&self, header: &JournalHeader, record_number: usize) -> u64 {
+        header.sector_size as u64 + record_number as u64 * (header.page_size as u64 + 8)
+    }
+
+    /// Make the journal durable before the database file is written. Like sqlite the page records
+    /// are synced, then their number is written to the header and synced again
+    pub fn sync(&mut self) -> Result<()> {
+        let Some(header) = self.header.as_mut() else {
+            return Ok(());
+        };
+        header.record_count = self.pages.len() as u32;
+        let file = self
+            .file
+            .as_deref()
+            .context("no write has created the journal")?;
+        file.sync()?;
+        file.write_at(&header.record_count.to_be_bytes(), 8)
+            .context("couldn't write the journal header")?;
+        file.sync()
+    }
+
+    /// Delete the journal once the database file has been written and synced, the write commits
+    /// when the journal is gone
+    pub fn finish(&mut self) -> Result<()> {
+        if self.header.is_none() {
+            return Ok(());
+        }
+        if self.file.take().is_some() {
+            self.vfs
+                .delete(&self.path)
+                .context("couldn't delete the journal")?;
+        }
+        self.header = None;
+        self.pages.clear();
+        self.journaled.clear();
+        Ok(())
+    }
+
+    /// Put the original pages back into a database file that was written part way, then delete the journal
+    pub fn play_back(&mut self, db_file: &dyn VfsFile) -> Result<()> {
+        let Some(header) = self.header else {
+            return Ok(());
+        };
+        let page_size = header.page_size as u64;
+        let mut page = vec![0u8; header.page_size as usize];
+        for (record_number, page_number) in self.pages.iter().enumerate() {
+            self.file()?
+                .read_at(&mut page, self.record_offset(&header, record_number) + 4)
+                .with_context(|| format!("couldn't read page {} from the journal", page_number))?;
+            db_file
+                .write_at(&page, (page_number - 1) as u64 * page_size)
+                .with_context(|| format!("couldn't restore page {}", page_number))?;
+        }
+        db_file.set_len(header.initial_size as u64 * page_size)?;
+        db_file.sync()?;
+        self.finish()
+    }
+}
+
+/// Value the checksums of a journal's pages start from, it only has to differ from the last journal's.
+/// Also the random salt of a new write-ahead log
+pub fn nonce() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    nanos ^ process::id().rotate_left(16)
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        pager::pager::Pager,
+        query_engine::engine::QueryEngine,
+        sql_parser::parser::{Column, SelectQuery},
+        vfs::{
+            memory::MemoryFile,
+            os::OsVfs,
+            vfs::{OpenMode, Vfs},
+        },
+    };
+
+    fn select_names(pager: Pager) -> String {
+        let engine = QueryEngine::new(pager);
+        let query = SelectQuery {
+            table: "items".into(),
+            columns: vec![Column::Regular("name".into())],
+            where_clause: None,
+        };
+        engine.run_query(query).unwrap()
+    }
+
+    #[test]
+    fn test_reading_journal_segments() {
+        let bytes = fs::read("fixtures/journal.db-journal").unwrap();
+        let journal = Journal::new(Box::new(MemoryFile::from(bytes))).unwrap();
+        assert!(journal.is_hot());
+        assert_eq!(journal.initial_size(), Some(6));
+        let mut pages: Vec<u32> = journal.pages.keys().copied().collect();
+        pages.sort();
+        assert_eq!(pages, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_records_after_a_bad_checksum_are_ignored() {
+        let mut bytes = fs::read("fixtures/journal.db-journal").unwrap();
+        // the second segment starts at sector 7 and journals page 6
+        bytes[7 * 512 + 512 + 4 + 312] ^= 0xff;
+
+        let journal = Journal::new(Box::new(MemoryFile::from(bytes))).unwrap();
+        assert_eq!(journal.pages.len(), 5);
+        assert!(!journal.pages.contains_key(&6));
+    }
+
+    #[test]
+    fn test_reading_through_a_hot_journal() {
+        let pager = Pager::open("fixtures/journal.db").unwrap();
+        assert_eq!(pager.page_count().unwrap(), 6);
+
+        let names = select_names(pager);
+        assert_eq!(names.lines().count(), 100);
+        assert_eq!(names.lines().next(), Some("item 001"));
+        assert_eq!(names.lines().last(), Some("item 100"));
+    }
+
+    #[test]
+    fn test_playing_back_a_hot_journal() {
+        let dir = std::env::temp_dir();
+        let db_path = dir.join(format!("journal_play_back_{}.db", std::process::id()));
+        let journal_path = dir.join(format!(
+            "journal_play_back_{}.db-journal",
+            std::process::id()
+        ));
+        fs::copy("fixtures/journal.db", &db_path).unwrap();
+        fs::copy("fixtures/journal.db-journal", &journal_path).unwrap();
+
+        let open = |path| OsVfs.open(path, OpenMode::ReadWrite).unwrap();
+        let pager = Pager::with_journal(open(&db_path), open(&journal_path), true).unwrap();
+        let names = select_names(pager);
+
+        assert_eq!(names.lines().count(), 100);
+        assert_eq!(fs::metadata(&db_path).unwrap().len(), 6 * 512);
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), 0);
+
+        // the rolled back file reads the same now that the journal is empty
+        let pager = Pager::open(&db_path).unwrap();
+        assert!(pager.integrity_check().unwrap().is_empty());
+        assert_eq!(select_names(pager), names);
+
+        fs::remove_file(db_path).unwrap();
+        fs::remove_file(journal_path).unwrap();
+    }
+}
@@ -1 +1,10 @@
+pub mod btree;
+pub mod cache;
+pub mod create;
+pub mod freelist;
+pub mod integrity_check;
+pub mod journal;
+pub mod page_map;
+#[allow(clippy::module_inception)]
 pub mod pager;
+pub mod wal;
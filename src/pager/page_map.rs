@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use anyhow::{bail, Result};
+
+use crate::data_model::{
+    btree::{
+        index_interior_cell::IndexInteriorCell, index_leaf_cell::IndexLeafCell,
+        page_header::PageType, table_interior_cell::TableInteriorCell,
+        table_leaf_cell::TableLeafCell,
+    },
+    table::Table,
+};
+
+use super::pager::Pager;
+use crate::error::Error;
+
+/// The byte offset the lock-byte page starts at, sqlite never stores data on it
+pub const LOCK_BYTE_OFFSET: u64 = 0x4000_0000;
+
+/// What a page of the database file is used for
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageOwner {
+    BTree(String),    // a page of the named table or index
+    Overflow(String), // holds payload spilled from a cell of the named table or index
+    FreelistTrunk,
+    FreelistLeaf,
+    PointerMap, // only present in auto-vacuum and incremental-vacuum databases
+    LockByte,
+    Orphaned, // not referenced from anywhere
+}
+
+impl Display for PageOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BTree(name) => write!(f, "{}", name),
+            Self::Overflow(name) => write!(f, "{} (overflow)", name),
+            Self::FreelistTrunk => write!(f, "freelist trunk"),
+            Self::FreelistLeaf => write!(f, "freelist leaf"),
+            Self::PointerMap => write!(f, "pointer map"),
+            Self::LockByte => write!(f, "lock-byte page"),
+            Self::Orphaned => write!(f, "orphaned"),
+        }
+    }
+}
+
+/// Owner of every page in the database file
+pub struct PageMap {
+    pub page_count: u32,
+    owners: BTreeMap<u32, PageOwner>,
+    // claims of pages that were already owned or don't exist, keeping the page and the rejected claim
+    pub conflicts: Vec<(u32, PageOwner)>,
+}
+
+impl PageMap {
+    fn new(page_count: u32) -> Self {
+        Self {
+            page_count,
+            owners: BTreeMap::new(),
+            conflicts: vec![],
+        }
+    }
+
+    /// Record the owner of a page, returns false if the page was already claimed or doesn't exist
+    fn claim(&mut self, page_number: u32, owner: PageOwner) -> bool {
+        if page_number == 0 || page_number > self.page_count {
+            self.conflicts.push((page_number, owner));
+            return false;
+        }
+        if self.owners.contains_key(&page_number) {
+            self.conflicts.push((page_number, owner));
+            return false;
+        }
+        self.owners.insert(page_number, owner);
+        true
+    }
+
+    /// Owner of a 1-indexed page, pages nothing refers to are orphaned
+    pub fn owner(&self, page_number: u32) -> &PageOwner {
+        self.owners
+            .get(&page_number)
+            .unwrap_or(&PageOwner::Orphaned)
+    }
+
+    /// Consecutive runs of pages with the same owner as (first page, last page, owner)
+    pub fn ranges(&self) -> Vec<(u32, u32, &PageOwner)> {
+        let mut ranges: Vec<(u32, u32, &PageOwner)> = vec![];
+        for page_number in 1..=self.page_count {
+            let owner = self.owner(page_number);
+            match ranges.last_mut() {
+                Some((_, last, range_owner)) if *range_owner == owner => *last = page_number,
+                _ => ranges.push((page_number, page_number, owner)),
+            }
+        }
+        ranges
+    }
+
+    /// Number of pages used by each owner
+    pub fn summary(&self) -> Vec<(&PageOwner, u32)> {
+        let mut summary: Vec<(&PageOwner, u32)> = vec![];
+        for page_number in 1..=self.page_count {
+            let owner = self.owner(page_number);
+            match summary.iter_mut().find(|(o, _)| *o == owner) {
+                Some((_, count)) => *count += 1,
+                None => summary.push((owner, 1)),
+            }
+        }
+        summary
+    }
+}
+
+/// Page that holds the byte at offset 2^30, it only exists in databases larger than 1GiB
+pub fn lock_byte_page(page_size: u32) -> u32 {
+    (LOCK_BYTE_OFFSET / page_size as u64) as u32 + 1
+}
+
+/// Pointer-map pages start at page 2 and each one describes the `usable_size / 5` pages following it
+/// https://www.sqlite.org/fileformat2.html#pointer_map_or_ptrmap_pages
+pub fn is_pointer_map_page(page_number: u32, usable_size: usize) -> bool {
+    let entries_per_page = (usable_size / 5) as u32;
+    page_number >= 2 && (page_number - 2).is_multiple_of(entries_per_page + 1)
+}
+
+impl Pager {
+    /// Label every page of the database with the b-tree, freelist or special purpose it serves
+    pub fn page_map(&self) -> Result<PageMap> {
+        let mut page_map = PageMap::new(self.page_count()?);
+
+        let lock_byte_page = lock_byte_page(self.db_header.page_size);
+        if lock_byte_page <= page_map.page_count {
+            page_map.claim(lock_byte_page, PageOwner::LockByte);
+        }
+
+        // databases with a largest root page are auto-vacuum databases with pointer-map pages
+        if self.db_header.largest_root_page != 0 {
+            let usable_size = self.db_header.usable_size();
+            for page_number in 2..=page_map.page_count {
+                if is_pointer_map_page(page_number, usable_size) && page_number != lock_byte_page {
+                    page_map.claim(page_number, PageOwner::PointerMap);
+                }
+            }
+        }
+
+        let freelist = self.freelist()?;
+        for trunk_page in freelist.trunk_pages {
+            page_map.claim(trunk_page, PageOwner::FreelistTrunk);
+        }
+        for leaf_page in freelist.leaf_pages {
+            page_map.claim(leaf_page, PageOwner::FreelistLeaf);
+        }
+
+        self.claim_btree(&mut page_map, 1, "sqlite_schema")?;
+        let objects: Vec<(u32, String)> = self
+            .schema_table
+            .cells
+            .iter()
+            .filter(|rec| rec.rootpage != 0) // views and triggers have no b-tree
+            .map(|rec| (rec.rootpage, rec.name.clone()))
+            .collect();
+        for (rootpage, name) in objects {
+            self.claim_btree(&mut page_map, rootpage, &name)?;
+        }
+
+        Ok(page_map)
+    }
+
+    /// Claim every page of a b-tree and the overflow pages its cells point to
+    fn claim_btree(&self, page_map: &mut PageMap, root_page: u32, name: &str) -> Result<()> {
+        let mut pages = vec![root_page];
+        while let Some(page_number) = pages.pop() {
+            // a page that is already claimed or out of range is never descended into so cycles terminate
+            if !page_map.claim(page_number, PageOwner::BTree(name.to_string())) {
+                continue;
+            }
+
+            let (page, buf) = self.read_page(page_number)?;
+            let overflow_pages: Vec<Option<u32>> = match page.header.page_type {
+                PageType::TableInterior => {
+                    let table = Table::<TableInteriorCell>::new(
+                        page_number,
+                        buf.get_ref(),
+                        &page.cell_pointers,
+                        self,
+                    )?;
+                    pages.extend(table.cells.iter().map(|cell| cell.left_child));
+                    vec![]
+                }
+                PageType::TableLeaf => Table::<TableLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    self,
+                )?
+                .cells
+                .iter()
+                .map(|cell| cell.overflow_page)
+                .collect(),
+                PageType::IndexInterior => {
+                    let table = Table::<IndexInteriorCell>::new(
+                        page_number,
+                        buf.get_ref(),
+                        &page.cell_pointers,
+                        self,
+                    )?;
+                    pages.extend(table.cells.iter().map(|cell| cell.left_child));
+                    table.cells.iter().map(|cell| cell.overflow_page).collect()
+                }
+                PageType::IndexLeaf => Table::<IndexLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    self,
+                )?
+                .cells
+                .iter()
+                .map(|cell| cell.overflow_page)
+                .collect(),
+            };
+            pages.extend(page.header.rightmost_pointer);
+
+            for first_overflow_page in overflow_pages.into_iter().flatten() {
+                self.claim_overflow_chain(page_map, first_overflow_page, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn claim_overflow_chain(
+        &self,
+        page_map: &mut PageMap,
+        first_page: u32,
+        name: &str,
+    ) -> Result<()> {
+        let mut page_number = first_page;
+        while page_number != 0 {
+            if !page_map.claim(page_number, PageOwner::Overflow(name.to_string())) {
+                bail!(Error::corrupt_page(
+                    page_number,
+                    format!("overflow chain of {} runs into a page already in use", name)
+                ));
+            }
+            let page = self.read_raw_page(page_number)?;
+            page_number = u32::from_be_bytes(page[0..4].try_into()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod page_map_tests {
+
+    use super::*;
+
+    #[test]
+    fn test_every_page_has_an_owner() {
+        let pager = Pager::open("fixtures/freelist.db").unwrap();
+
+        let page_map = pager.page_map().unwrap();
+        assert_eq!(page_map.page_count, 166);
+        assert!(page_map.conflicts.is_empty());
+
+        let count = |owner: PageOwner| {
+            (1..=page_map.page_count)
+                .filter(|page| *page_map.owner(*page) == owner)
+                .count()
+        };
+        assert_eq!(count(PageOwner::BTree("sqlite_schema".into())), 1);
+        assert_eq!(count(PageOwner::BTree("notes".into())), 28);
+        assert_eq!(count(PageOwner::Overflow("notes".into())), 79);
+        assert_eq!(count(PageOwner::BTree("idx_notes_topic".into())), 3);
+        assert_eq!(count(PageOwner::FreelistTrunk), 1);
+        assert_eq!(count(PageOwner::FreelistLeaf), 52);
+        assert_eq!(count(PageOwner::PointerMap), 2);
+        assert_eq!(count(PageOwner::Orphaned), 0);
+    }
+
+    #[test]
+    fn test_special_pages() {
+        assert_eq!(lock_byte_page(4096), 262145);
+        assert!(is_pointer_map_page(2, 512));
+        assert!(!is_pointer_map_page(3, 512));
+        assert!(is_pointer_map_page(105, 512));
+    }
+}
@@ -1,58 +1,1103 @@
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, ensure, Context, Ok, Result};
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Cursor, Read, Seek, SeekFrom},
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    },
 };
 
 use crate::data_model::{
-    btree::page::Page, db_header::Dbheader, schema_record::SchemaRecord, table::Table,
+    btree::{
+        overflow::PayloadReader,
+        page::{ensure_depth, Page},
+        page_header::PageType,
+        table_interior_cell::TableInteriorCell,
+    },
+    db_header::{Dbheader, TextEncoding, DB_HEADER_SIZE},
+    schema_record::SchemaRecord,
+    table::Table,
 };
+use crate::error::Error;
 use crate::serialisation::deserialize::Deserialize;
+use crate::vfs::{
+    mmap::Mmap,
+    os::OsVfs,
+    vfs::{LockLevel, OpenMode, Vfs, VfsFile},
+};
+
+use super::{
+    cache::{CacheCapacity, CacheStats, PageBuffer, PageCache},
+    create::SQLITE_VERSION_NUMBER,
+    journal::{Journal, JournalWriter},
+    wal::{Checkpoint, CheckpointMode, Wal},
+};
 
 /// Abstract fetching pages from disk
-pub struct Pager<'a> {
-    file: &'a mut File,
+pub struct Pager {
+    file: Box<dyn VfsFile>,
     pub db_header: Dbheader,
     pub root_page: Page,
     pub schema_table: Table<SchemaRecord>,
-    // TODO: implement cache invalidation
-    cache: HashMap<u32, (Page, Cursor<Vec<u8>>)>,
+    cache: Mutex<PageCache>,
+    // largest number of bytes of the file to memory map, 0 reads every page with `read_at`
+    mmap_size: AtomicU64,
+    map: RwLock<Option<Arc<Mmap>>>,
+    // committed pages that haven't been checkpointed into the database file yet, None unless in WAL mode
+    wal: RwLock<Option<Wal>>,
+    // reads running on this pager, see `begin_read`
+    reads: Mutex<Reads>,
+    // where the database was opened from, None when the pager was given its file
+    location: Option<Location>,
+    // original pages of a transaction that didn't finish, read instead of the file's torn copies
+    journal: Option<Journal>,
+    // pages changed by the current write that haven't been written to the file, see `commit`
+    dirty: BTreeMap<u32, Vec<u8>>,
+    // where a write puts the original pages before changing them, None when the pager can't create a journal
+    journal_writer: Option<JournalWriter>,
+    // commit has started writing pages to the file, rolling back has to play the journal back
+    writing_file: bool,
+    // this connection holds a reserved lock or more on the file, see `lock_writer`
+    file_locked: bool,
+    // false between BEGIN and COMMIT or ROLLBACK, otherwise every write commits on its own
+    autocommit: bool,
+    // contents of the pages from when each open savepoint started, innermost last
+    savepoints: Vec<Savepoint>,
 }
 
-impl<'a> Pager<'a> {
-    pub fn new(file: &'a mut File) -> Result<Self> {
-        let db_header = Dbheader::deserialize(file);
-        let root_page = Page::deserialize(file);
-        let schema_table = Table::<SchemaRecord>::new(file, &root_page.cell_pointers);
-        let cache = HashMap::new();
-        Ok(Self {
+/// Where a database was opened from, so its log can be opened, created and deleted as the journal mode changes
+struct Location {
+    vfs: Box<dyn Vfs>,
+    wal_path: PathBuf,
+    mode: OpenMode,
+}
+
+/// Reads running at the same time share one snapshot of the log and one shared lock on the file
+#[derive(Default)]
+struct Reads {
+    running: usize,
+    locked: bool,
+}
+
+/// A read of the database started by `Pager::begin_read`, it ends when the guard is dropped
+pub struct ReadGuard<'a> {
+    pager: &'a Pager,
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        let mut reads = self.pager.reads();
+        reads.running -= 1;
+        if reads.running == 0 && reads.locked {
+            reads.locked = false;
+            let _ = self.pager.file.unlock(LockLevel::None);
+        }
+    }
+}
+
+/// What a savepoint needs to undo the changes made after it started. Only the pages changed since then are kept,
+/// holding what they were in `dirty` at the time, None for pages the write hadn't changed yet
+struct Savepoint {
+    pages: BTreeMap<u32, Option<Vec<u8>>>,
+    db_header: Dbheader,
+}
+
+impl Pager {
+    /// Open the database at `path` on disk for reading, see `open_with`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(&OsVfs, path)
+    }
+
+    /// Open the database at `path` on disk for reading and writing, see `open_with_mode`
+    pub fn open_for_writing(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_mode(&OsVfs, path, OpenMode::ReadWrite)
+    }
+
+    /// Open the database at `path` for reading, see `open_with_mode`
+    pub fn open_with(vfs: &dyn Vfs, path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_mode(vfs, path, OpenMode::ReadOnly)
+    }
+
+    /// Open the database at `path`. A hot journal (`<db>-journal`) means the file may be half way
+    /// through a transaction, a writer plays it back while a reader reads the file as it was before.
+    /// Otherwise committed pages are read from the write-ahead log (`<db>-wal`) when there is one.
+    /// A writer deletes the journal once it's played back, and creates it again for each of its own writes.
+    /// The pager keeps a handle to the VFS for the log, see `set_wal_mode`
+    pub fn open_with_mode(vfs: &dyn Vfs, path: impl AsRef<Path>, mode: OpenMode) -> Result<Self> {
+        let path = path.as_ref();
+        let file = vfs.open(path, mode)?;
+        let sibling = |suffix: &str| {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(suffix);
+            PathBuf::from(sibling)
+        };
+
+        let journal_path = sibling("-journal");
+        let wal_path = sibling("-wal");
+        let mut pager = if vfs.exists(&journal_path)? {
+            let journal_file = vfs.open(&journal_path, mode)?;
+            let pager = Self::with_journal(file, journal_file, mode.writable())?;
+            if mode.writable() {
+                vfs.delete(&journal_path)?;
+            }
+            pager
+        } else if vfs.exists(&wal_path)? {
+            Self::with_wal(file, Some(vfs.open(&wal_path, mode)?))?
+        } else {
+            Self::new(file)?
+        };
+        if mode.writable() {
+            pager.journal_writer = Some(JournalWriter::new(vfs.share(), journal_path));
+        }
+        pager.location = Some(Location {
+            vfs: vfs.share(),
+            wal_path,
+            mode,
+        });
+        // the log of a database in WAL mode is opened once the pager knows where to find it
+        pager.refresh()?;
+        Ok(pager)
+    }
+
+    pub fn new(file: Box<dyn VfsFile>) -> Result<Self> {
+        Self::from_parts(file, None, None)
+    }
+
+    /// Open a database whose newest committed pages may be in its write-ahead log (`<db>-wal`)
+    pub fn with_wal(file: Box<dyn VfsFile>, wal_file: Option<Box<dyn VfsFile>>) -> Result<Self> {
+        let wal = wal_file.map(Wal::new).transpose()?;
+        Self::from_parts(file, wal, None)
+    }
+
+    /// Open a database that has a rollback journal (`<db>-journal`) next to it.
+    /// When the journal is hot the file is either played back, which needs both files open for writing,
+    /// or left alone with reads served from the journal so they see the database from before the transaction
+    pub fn with_journal(
+        file: Box<dyn VfsFile>,
+        journal_file: Box<dyn VfsFile>,
+        play_back: bool,
+    ) -> Result<Self> {
+        let journal = Journal::new(journal_file)?;
+        if !journal.is_hot() {
+            return Self::new(file);
+        }
+        if play_back {
+            journal.play_back(file.as_ref())?;
+            return Self::new(file);
+        }
+        Self::from_parts(file, None, Some(journal))
+    }
+
+    fn from_parts(
+        file: Box<dyn VfsFile>,
+        wal: Option<Wal>,
+        journal: Option<Journal>,
+    ) -> Result<Self> {
+        let mut header_buf = [0u8; DB_HEADER_SIZE];
+        file.read_at(&mut header_buf, 0)
+            .context("couldn't read database header")?;
+        let db_header = Dbheader::try_from(&header_buf)?;
+        let mut first_page = vec![0u8; db_header.page_size as usize];
+        file.read_at(&mut first_page, 0)
+            .context("couldn't read page 1")?;
+        let root_page = Page::deserialize(&mut &first_page[DB_HEADER_SIZE..])
+            .map_err(|e| Error::on_page(1, e))?;
+        let mut pager = Self {
             file,
             db_header,
             root_page,
-            schema_table,
-            cache,
+            schema_table: Table {
+                cells: vec![],
+                columns: None,
+            },
+            cache: Mutex::new(PageCache::new(CacheCapacity::default())),
+            mmap_size: AtomicU64::new(0),
+            map: RwLock::new(None),
+            wal: RwLock::new(wal),
+            reads: Mutex::default(),
+            location: None,
+            journal,
+            dirty: BTreeMap::new(),
+            journal_writer: None,
+            writing_file: false,
+            file_locked: false,
+            autocommit: true,
+            savepoints: vec![],
+        };
+
+        // the log or the journal hold the current copy of the database header when page 1 has changed
+        pager.db_header = pager.read_db_header()?;
+        pager.cache().change_counter = pager.db_header.file_change_counter;
+
+        // schema records can spill onto overflow pages so they are read once the pager is usable
+        pager.load_schema()?;
+        Ok(pager)
+    }
+
+    fn read_db_header(&self) -> Result<Dbheader> {
+        let first_page = self.read_raw_page(1)?;
+        Dbheader::try_from(
+            &first_page[..DB_HEADER_SIZE]
+                .try_into()
+                .context("couldn't read database header")?,
+        )
+    }
+
+    /// Read `sqlite_schema` again, i.e once a statement inside a transaction has changed it
+    pub fn load_schema(&mut self) -> Result<()> {
+        let (root_page, buf) = self.read_page(1)?;
+        let mut cells = vec![];
+        self.read_schema_records(1, &root_page, buf.get_ref(), &mut cells, 0)?;
+        self.schema_table = Table {
+            cells,
+            columns: None,
+        };
+        self.root_page = Page::clone(&root_page);
+        Ok(())
+    }
+
+    /// Collect the rows of `sqlite_schema`, which spills from page 1 into a deeper b-tree once it has many objects
+    fn read_schema_records(
+        &self,
+        page_number: u32,
+        page: &Page,
+        buf: &[u8],
+        records: &mut Vec<SchemaRecord>,
+        depth: usize,
+    ) -> Result<()> {
+        ensure_depth(page_number, depth)?;
+        match page.header.page_type {
+            PageType::TableLeaf => {
+                let leaf = Table::<SchemaRecord>::new(page_number, buf, &page.cell_pointers, self)?;
+                records.extend(leaf.cells);
+                Ok(())
+            }
+            PageType::TableInterior => {
+                let interior =
+                    Table::<TableInteriorCell>::new(page_number, buf, &page.cell_pointers, self)?;
+                let rightmost_pointer = page.header.rightmost_pointer.ok_or_else(|| {
+                    Error::corrupt_page(page_number, "interior page without a right most pointer")
+                })?;
+                let children = interior.cells.iter().map(|cell| cell.left_child);
+                for child in children.chain([rightmost_pointer]) {
+                    let (page, buf) = self.read_page(child)?;
+                    self.read_schema_records(child, &page, buf.get_ref(), records, depth + 1)?;
+                }
+                Ok(())
+            }
+            page_type => bail!(Error::corrupt_page(
+                page_number,
+                format!("{} page found in the sqlite_schema b-tree", page_type)
+            )),
+        }
+    }
+
+    /// Drop the cached pages when the file change counter has moved since they were read, like sqlite does
+    /// when a read transaction starts. Returns whether another process had written to the database.
+    /// The header and schema are kept as they were when the database was opened, `refresh` reloads them
+    pub fn validate_cache(&self) -> Result<bool> {
+        let file_change_counter = self.read_db_header()?.file_change_counter;
+        self.remap();
+        let mut cache = self.cache();
+        if cache.change_counter == file_change_counter {
+            return Ok(false);
+        }
+        cache.clear();
+        cache.change_counter = file_change_counter;
+        Ok(true)
+    }
+
+    /// Re-read the database header, when its file change counter has moved another process has written
+    /// to the database so cached pages and the schema are dropped. Returns whether the database had changed
+    pub fn refresh(&mut self) -> Result<bool> {
+        // sqlite doesn't move the change counter of a database in WAL mode, new frames in the log are the change
+        let wal_changed = self.refresh_wal()?;
+        let db_header = self.read_db_header()?;
+        if !wal_changed && db_header.file_change_counter == self.db_header.file_change_counter {
+            return Ok(false);
+        }
+
+        self.db_header = db_header;
+        if wal_changed {
+            self.cache().clear();
+        }
+        self.validate_cache()?;
+        self.load_schema()?;
+        Ok(true)
+    }
+
+    /// Start reading, like a sqlite read transaction. A shared lock keeps other connections from writing
+    /// the file under the read, which in WAL mode means checkpoints, and the transactions other connections
+    /// have committed to the log since the last read are picked up. Reads on other threads that overlap share
+    /// that snapshot and lock. Cached pages that have changed are dropped, see `validate_cache`
+    pub fn begin_read(&self) -> Result<ReadGuard<'_>> {
+        let mut reads = self.reads();
+        if reads.running == 0 {
+            // the lock comes before the log is read so a checkpoint can't copy newer frames than the snapshot's.
+            // A connection that's writing already keeps others off the file, unlocking after the read would let go of that
+            if !self.file_locked {
+                self.file.lock(LockLevel::Shared)?;
+                reads.locked = true;
+            }
+            let refreshed = self.refresh_wal();
+            if reads.locked && refreshed.is_err() {
+                self.file.unlock(LockLevel::None)?;
+                reads.locked = false;
+            }
+            if refreshed? {
+                self.cache().clear();
+            }
+        }
+        reads.running += 1;
+        drop(reads);
+        let guard = ReadGuard { pager: self };
+        self.validate_cache()?;
+        Ok(guard)
+    }
+
+    /// Catch up with the log: read the transactions other connections have committed to it, open it once
+    /// the database has been switched to WAL mode and let it go once it has been switched back.
+    /// Returns whether the pages read through the log may have changed
+    fn refresh_wal(&self) -> Result<bool> {
+        // reads next to a hot journal see the database from before its transaction, which wasn't in the log
+        if self.journal.is_some() {
+            return Ok(false);
+        }
+        // the mode is in the file's header, the copy of page 1 in the log can only say WAL mode
+        let mut header_buf = [0u8; DB_HEADER_SIZE];
+        self.file
+            .read_at(&mut header_buf, 0)
+            .context("couldn't read database header")?;
+        let wal_mode = Dbheader::try_from(&header_buf)?.write_version == 2;
+
+        let mut wal = self.wal.write().unwrap_or_else(PoisonError::into_inner);
+        match wal.as_mut() {
+            Some(log) => {
+                let changed = log.refresh()?;
+                if !wal_mode && log.last_commit_frame == 0 {
+                    *wal = None;
+                }
+                Ok(changed)
+            }
+            None if wal_mode => {
+                *wal = self.open_wal()?;
+                Ok(wal.as_ref().is_some_and(|log| log.last_commit_frame != 0))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The log of a database in WAL mode, a writable pager creates it when it isn't there
+    fn open_wal(&self) -> Result<Option<Wal>> {
+        let Some(location) = &self.location else {
+            return Ok(None);
+        };
+        let mode = match location.mode {
+            OpenMode::ReadOnly if !location.vfs.exists(&location.wal_path)? => return Ok(None),
+            OpenMode::ReadOnly => OpenMode::ReadOnly,
+            _ => OpenMode::Create,
+        };
+        Ok(Some(Wal::new(
+            location.vfs.open(&location.wal_path, mode)?,
+        )?))
+    }
+
+    fn wal(&self) -> RwLockReadGuard<'_, Option<Wal>> {
+        self.wal.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wal_mut(&mut self) -> &mut Option<Wal> {
+        self.wal.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn reads(&self) -> MutexGuard<'_, Reads> {
+        self.reads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether writes go to a write-ahead log rather than straight to the file
+    pub fn wal_mode(&self) -> bool {
+        self.wal().is_some()
+    }
+
+    // a panic while the cache was locked can't leave it holding a page that doesn't match its buffer
+    fn cache(&self) -> MutexGuard<'_, PageCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set_cache_capacity(&self, capacity: CacheCapacity) {
+        self.cache().set_capacity(capacity);
+    }
+
+    /// Hits, misses and evictions of the page cache since the database was opened
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats
+    }
+
+    /// Like sqlite's `PRAGMA mmap_size`, memory map up to `size` bytes of the database file so pages
+    /// are borrowed from the mapping rather than read into a buffer. Pages past the mapped part of the file,
+    /// or every page when the file can't be mapped, are read as usual. 0 turns memory mapping off
+    pub fn set_mmap_size(&self, size: u64) {
+        self.mmap_size.store(size, Ordering::Relaxed);
+        self.remap();
+    }
+
+    /// Map as much of the file as the mmap size allows, remapping when the file has grown or shrunk since it was mapped
+    fn remap(&self) {
+        let mmap_size = self.mmap_size.load(Ordering::Relaxed);
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        if mmap_size == 0 {
+            *map = None;
+            return;
+        }
+        let page_size = self.db_header.page_size as u64;
+        // a file whose size can't be read isn't mapped, reading its pages will report the error
+        let len = self.file.size().unwrap_or(0).min(mmap_size) / page_size * page_size;
+        if map.as_ref().map(|map| map.len() as u64) == Some(len) {
+            return;
+        }
+        *map = self.file.map(len).ok().flatten().map(Arc::new);
+    }
+
+    /// The page borrowed from the mapped file, None when it isn't mapped
+    fn mapped_page(&self, page_number: u32) -> Option<PageBuffer> {
+        let map = self.map.read().unwrap_or_else(PoisonError::into_inner);
+        let map = map.as_ref()?;
+        let len = self.db_header.page_size as usize;
+        let offset = page_number.checked_sub(1)? as usize * len;
+        (offset + len <= map.len()).then(|| PageBuffer::Mapped {
+            map: map.clone(),
+            offset,
+            len,
         })
     }
 
     /// Read a page passing in 1-indexed page number
-    /// Returns the Page struct and the byte array of the page data
-    pub fn read_page(&mut self, page_number: u32) -> Result<(Page, Cursor<Vec<u8>>)> {
-        let Some(page) = self.cache.get(&page_number) else {
-            let page_location = (page_number - 1) as u64 * self.db_header.page_size as u64;
+    /// Returns the Page struct and the byte array of the page data, both shared with the page cache
+    pub fn read_page(&self, page_number: u32) -> Result<(Arc<Page>, Cursor<PageBuffer>)> {
+        // the cache isn't locked while the page is read so other threads aren't held up by the disk
+        let cached = self.cache().get(page_number);
+        let (page, data) = match cached {
+            Some(cached) => cached,
+            None => {
+                let data = self.read_page_buffer(page_number)?;
+                let mut reader = Cursor::new(data.clone());
+                // The b-tree header of page 1 comes after the database header
+                if page_number == 1 {
+                    reader.set_position(DB_HEADER_SIZE as u64);
+                }
+                let page =
+                    Page::deserialize(&mut reader).map_err(|e| Error::on_page(page_number, e))?;
+                let page = Arc::new(page);
+                self.cache().insert(page_number, page.clone(), data.clone());
+                (page, data)
+            }
+        };
+
+        let mut reader = Cursor::new(data);
+        if page_number == 1 {
+            reader.set_position(DB_HEADER_SIZE as u64);
+        }
+        Ok((page, reader))
+    }
+
+    /// Number of pages in the database file. The size in the header is only trusted when
+    /// it was written by a version of sqlite that keeps it up to date with the change counter
+    pub fn page_count(&self) -> Result<u32> {
+        // a write keeps the header's size up to date as it allocates pages
+        if !self.dirty.is_empty() {
+            return Ok(self.db_header.database_size);
+        }
+        if let Some(initial_size) = self.journal.as_ref().and_then(Journal::initial_size) {
+            return Ok(initial_size);
+        }
+        if let Some(wal) = self.wal().as_ref().filter(|wal| wal.last_commit_frame != 0) {
+            return Ok(wal.database_size);
+        }
+        let header = &self.db_header;
+        let file_pages = (self.file.size()? / header.page_size as u64) as u32;
+        if header.database_size != 0 && header.version_valid_for == header.file_change_counter {
+            // like sqlite a file shorter than its header says is corrupt rather than missing pages
+            ensure!(
+                header.database_size <= file_pages,
+                Error::corrupt_page(
+                    1,
+                    format!(
+                        "database header says the file has {} pages but it has {}",
+                        header.database_size, file_pages
+                    )
+                )
+            );
+            return Ok(header.database_size);
+        }
+        Ok(file_pages)
+    }
+
+    fn read_page_buffer(&self, page_number: u32) -> Result<PageBuffer> {
+        if self.dirty.contains_key(&page_number) {
+            return Ok(self.read_raw_page(page_number)?.into());
+        }
+        // the journal and the log hold older or newer copies of some pages than the mapped file
+        let in_journal = self
+            .journal
+            .as_ref()
+            .is_some_and(|j| j.has_page(page_number));
+        let in_wal = self
+            .wal()
+            .as_ref()
+            .is_some_and(|wal| wal.find_frame(page_number).is_some());
+        match self.mapped_page(page_number) {
+            Some(page) if !in_journal && !in_wal => Ok(page),
+            _ => Ok(self.read_raw_page(page_number)?.into()),
+        }
+    }
 
+    /// Read the bytes of a page passing in 1-indexed page number without interpreting them.
+    /// Pages committed to the write-ahead log are read from their newest frame like a sqlite reader would,
+    /// pages the current write has changed are read as they are now
+    pub fn read_raw_page(&self, page_number: u32) -> Result<Vec<u8>> {
+        ensure!(
+            page_number != 0,
+            Error::corrupt_page(0, "page numbers start at 1")
+        );
+        if let Some(page) = self.dirty.get(&page_number) {
+            return Ok(page.clone());
+        }
+        if let Some(journal) = &self.journal {
+            if let Some(page) = journal.read_page(page_number)? {
+                return Ok(page);
+            }
+        }
+        if let Some(wal) = self.wal().as_ref() {
+            if let Some(frame_number) = wal.find_frame(page_number) {
+                return wal.read_frame(frame_number);
+            }
+        }
+        let page_location = (page_number - 1) as u64 * self.db_header.page_size as u64;
+
+        let mut page_buff: Vec<u8> = vec![0; self.db_header.page_size as usize];
+        self.file
+            .read_at(&mut page_buff, page_location)
+            .with_context(|| format!("couldn't read page {}", page_number))?;
+        Ok(page_buff)
+    }
+}
+
+impl Pager {
+    /// Fail unless the database can be changed. Writing next to a hot journal that's being read rather than
+    /// played back, and keeping the pointer map of an auto-vacuum database up to date aren't supported yet
+    fn ensure_writable(&self) -> Result<()> {
+        let header = &self.db_header;
+        if header.write_version == 2 && self.wal().is_none() && self.location.is_none() {
+            bail!(Error::Unsupported(
+                "writing to a database in WAL mode without its log".into()
+            ));
+        }
+        if self.journal.is_some() {
+            bail!(Error::Unsupported(
+                "writing to a database while another transaction's journal is read".into()
+            ));
+        }
+        if header.largest_root_page != 0 {
+            bail!(Error::Unsupported(
+                "writing to an auto-vacuum database".into()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Start changing the database unless a write has already started. Like sqlite a reserved lock keeps
+    /// other writers out, and the journal is created with the size the database has before the write.
+    /// In WAL mode the log is locked instead and there's no journal.
+    /// The size in the header is brought up to date so new pages can be added after the last one
+    pub fn begin_write(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            self.ensure_writable()?;
+            self.lock_writer(LockLevel::Reserved)?;
+            self.db_header.database_size = self.page_count()?;
+            // a savepoint that was rolled back may have left the write's journal started
+            let wal_mode = self.wal_mode();
+            if let Some(journal) = self
+                .journal_writer
+                .as_mut()
+                .filter(|j| !j.is_active() && !wal_mode)
+            {
+                journal.begin(self.db_header.database_size, self.db_header.page_size)?;
+            }
+            // commit rewrites the header on page 1, having it dirty from the start marks the write as begun
+            let first_page = self.read_raw_page(1)?;
+            self.journal_page(1, &first_page)?;
+            self.dirty.insert(1, first_page);
+        }
+        Ok(())
+    }
+
+    fn journal_page(&mut self, page_number: u32, original: &[u8]) -> Result<()> {
+        if let Some(journal) = self.journal_writer.as_mut() {
+            if journal.needs(page_number) {
+                journal.journal_page(page_number, original)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the schema cookie on, which tells other connections to read the schema again
+    pub fn schema_changed(&mut self) -> Result<()> {
+        self.begin_write()?;
+        self.db_header.schema_cookie = self.db_header.schema_cookie.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Replace the bytes of a page. The page is read back as it is now but only reaches the file on `commit`,
+    /// its original contents are journaled first
+    pub fn write_page(&mut self, page_number: u32, data: Vec<u8>) -> Result<()> {
+        ensure!(
+            page_number != 0,
+            Error::corrupt_page(0, "page numbers start at 1")
+        );
+        ensure!(
+            data.len() == self.db_header.page_size as usize,
+            "page {} is {} bytes rather than the page size",
+            page_number,
+            data.len()
+        );
+        self.begin_write()?;
+        if !self.dirty.contains_key(&page_number)
+            && self
+                .journal_writer
+                .as_ref()
+                .is_some_and(|journal| journal.needs(page_number))
+        {
+            let original = self.read_raw_page(page_number)?;
+            self.journal_page(page_number, &original)?;
+        }
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint
+                .pages
+                .entry(page_number)
+                .or_insert_with(|| self.dirty.get(&page_number).cloned());
+        }
+        self.db_header.database_size = self.db_header.database_size.max(page_number);
+        self.cache().remove(page_number);
+        self.dirty.insert(page_number, data);
+        Ok(())
+    }
+
+    /// False inside a transaction started with `begin_transaction`, writes are only committed at its end
+    pub fn autocommit(&self) -> bool {
+        self.autocommit
+    }
+
+    /// Start a transaction that lasts until `commit` or `rollback`. The lock is taken straight away, like
+    /// `BEGIN IMMEDIATE` or `BEGIN EXCLUSIVE`, `LockLevel::None` waits for the first write like `BEGIN DEFERRED`
+    pub fn begin_transaction(&mut self, lock: LockLevel) -> Result<()> {
+        if !self.autocommit {
+            bail!("cannot start a transaction within a transaction");
+        }
+        if lock != LockLevel::None {
+            self.ensure_writable()?;
+            self.lock_writer(lock)?;
+        }
+        self.autocommit = false;
+        Ok(())
+    }
+
+    /// Keep other writers out, in WAL mode by locking the log so readers of the file carry on
+    fn lock_writer(&mut self, level: LockLevel) -> Result<()> {
+        if let Some(wal) = self.wal_mut() {
+            return wal.lock_writer();
+        }
+        self.file.lock(level)?;
+        self.file_locked = true;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        if let Some(wal) = self.wal().as_ref() {
+            wal.unlock_writer()?;
+        }
+        self.file_locked = false;
+        self.file.unlock(LockLevel::None)
+    }
+
+    /// Write the changed pages to the file, ending the transaction if one was started. Like sqlite the journal
+    /// is synced before the file is written and the write commits once the file is synced and the journal deleted.
+    /// Writing the file takes an exclusive lock, while other connections are reading it fails with `Error::Locked`
+    /// and the transaction is left open so it can be committed again. A commit that fails after that is rolled back.
+    /// In WAL mode the pages are appended to the log instead and the write commits once it's synced.
+    /// The file change counter is moved on so other connections drop their cached pages, and the size
+    /// in the header is marked as up to date
+    pub fn commit(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            // savepoints that were rolled back can leave a journal without changes, BEGIN can leave a lock
+            if let Some(journal) = self.journal_writer.as_mut() {
+                journal.finish()?;
+            }
+            self.autocommit = true;
+            self.savepoints.clear();
+            return self.unlock();
+        }
+        // the lock is taken before anything changes, so a commit readers kept out of the file can be tried again
+        if !self.wal_mode() {
+            self.file.lock(LockLevel::Exclusive)?;
+        }
+        if let Err(error) = self.write_transaction() {
+            self.rollback()?;
+            return Err(error);
+        }
+        self.autocommit = true;
+        self.savepoints.clear();
+        self.unlock()?;
+
+        self.dirty.clear();
+        self.cache().change_counter = self.db_header.file_change_counter;
+        self.remap();
+        self.load_schema()
+    }
+
+    /// Bring the header on page 1 up to date and write the changed pages to the log or, through the journal, the file
+    fn write_transaction(&mut self) -> Result<()> {
+        let header = &mut self.db_header;
+        header.file_change_counter = header.file_change_counter.wrapping_add(1);
+        header.version_valid_for = header.file_change_counter;
+        header.sqlite_version = SQLITE_VERSION_NUMBER;
+        let mut first_page = self.read_raw_page(1)?;
+        first_page[..DB_HEADER_SIZE]
+            .copy_from_slice(&<[u8; DB_HEADER_SIZE]>::from(&self.db_header));
+        self.write_page(1, first_page)?;
+
+        let database_size = self.db_header.database_size;
+        let dirty = &self.dirty;
+        if let Some(wal) = self.wal.get_mut().unwrap_or_else(PoisonError::into_inner) {
+            return wal.append(dirty, database_size);
+        }
+        if let Some(journal) = self.journal_writer.as_mut() {
+            journal.sync()?;
+        }
+        self.writing_file = true;
+        self.write_dirty_pages()?;
+        if let Some(journal) = self.journal_writer.as_mut() {
+            journal.finish()?;
+        }
+        self.writing_file = false;
+        Ok(())
+    }
+
+    fn write_dirty_pages(&self) -> Result<()> {
+        let page_size = self.db_header.page_size as u64;
+        for (page_number, page) in &self.dirty {
             self.file
-                .seek(SeekFrom::Start(page_location))
-                .context("couldn't find page in file")?;
+                .write_at(page, (page_number - 1) as u64 * page_size)
+                .with_context(|| format!("couldn't write page {}", page_number))?;
+        }
+        self.file.sync()
+    }
+
+    /// Throw away the changes that haven't been committed, ending the transaction if one was started.
+    /// The file is only written by `commit`, when that failed part way the journal puts the original pages back
+    pub fn rollback(&mut self) -> Result<()> {
+        self.autocommit = true;
+        self.savepoints.clear();
+        let dirty = std::mem::take(&mut self.dirty);
+        let mut cache = self.cache();
+        for page_number in dirty.keys() {
+            cache.remove(*page_number);
+        }
+        drop(cache);
+        if let Some(journal) = self.journal_writer.as_mut() {
+            if self.writing_file {
+                journal.play_back(self.file.as_ref())?;
+                self.remap();
+            } else {
+                journal.finish()?;
+            }
+        }
+        self.writing_file = false;
+        self.unlock()?;
+        self.db_header = self.read_db_header()?;
+        self.load_schema()
+    }
+
+    /// Copy the pages committed to the log into the database file like `PRAGMA wal_checkpoint`, None when the
+    /// database isn't in WAL mode. Readers have to finish first, as the file's copies of pages are what they
+    /// read when the log doesn't have them, and every mode but passive keeps writers out while frames are copied.
+    /// Restarting the log also needs both, a checkpoint that couldn't get them does what a passive one can
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<Option<Checkpoint>> {
+        if !self.autocommit || !self.dirty.is_empty() {
+            bail!("database table is locked");
+        }
+        self.refresh()?;
+        if !self.wal_mode() {
+            return Ok(None);
+        }
+        let writer_locked =
+            mode != CheckpointMode::Passive && self.lock_writer(LockLevel::Reserved).is_ok();
+        let readers_done = self.file.lock(LockLevel::Exclusive).is_ok();
+
+        let file = self.file.as_ref();
+        let wal = self.wal.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let wal = wal.as_mut().context("the log was closed")?;
+        let log_frames = wal.last_commit_frame;
+        let mut result = Checkpoint {
+            busy: mode != CheckpointMode::Passive && !(writer_locked && readers_done),
+            log_frames,
+            checkpointed: 0,
+        };
+        // the locks are let go of even when copying fails part way, the frames are still in the log
+        let copied = readers_done.then(|| wal.checkpoint(file)).transpose();
+        let restarted = copied.and_then(|copied| {
+            if copied.is_some() {
+                result.checkpointed = log_frames;
+            }
+            let restart = matches!(mode, CheckpointMode::Restart | CheckpointMode::Truncate);
+            if restart && !result.busy {
+                wal.restart(mode == CheckpointMode::Truncate)?;
+            }
+            if mode == CheckpointMode::Truncate && !result.busy {
+                (result.log_frames, result.checkpointed) = (0, 0);
+            }
+            Ok(())
+        });
+        if writer_locked {
+            wal.unlock_writer()?;
+        }
+        self.file.unlock(LockLevel::None)?;
+        restarted?;
+        self.remap();
+        Ok(Some(result))
+    }
+
+    /// Switch between a rollback journal and a write-ahead log like `PRAGMA journal_mode`. The mode is kept in
+    /// the header, written by a rollback journal write, and the log is created once the database is in WAL mode.
+    /// Leaving WAL mode checkpoints the log and deletes it, which fails while other connections are using it
+    pub fn set_wal_mode(&mut self, wal_mode: bool) -> Result<()> {
+        self.refresh()?;
+        if wal_mode == self.wal_mode() {
+            return Ok(());
+        }
+        if !self.autocommit {
+            bail!(
+                "cannot change {} wal mode from within a transaction",
+                if wal_mode { "into" } else { "out of" }
+            );
+        }
+        if self.location.is_none() {
+            bail!(Error::Unsupported(
+                "changing the journal mode of a database that wasn't opened from a VFS".into()
+            ));
+        }
+
+        if wal_mode {
+            self.write_file_format(2)?;
+            *self.wal_mut() = self.open_wal()?;
+            return Ok(());
+        }
+        let checkpoint = self.checkpoint(CheckpointMode::Truncate)?;
+        ensure!(
+            checkpoint.is_some_and(|checkpoint| !checkpoint.busy),
+            Error::Locked
+        );
+        *self.wal_mut() = None;
+        self.write_file_format(1)?;
+        if let Some(location) = &self.location {
+            location.vfs.delete(&location.wal_path)?;
+        }
+        Ok(())
+    }
+
+    /// Set the read and write versions in the header, 2 for WAL mode and 1 for a rollback journal
+    fn write_file_format(&mut self, version: u8) -> Result<()> {
+        let written = self.begin_write().and_then(|()| {
+            self.db_header.write_version = version;
+            self.db_header.read_version = version;
+            self.commit()
+        });
+        if written.is_err() {
+            self.rollback()?;
+        }
+        written
+    }
+
+    /// Start a savepoint inside the current write, `rollback_savepoint` undoes the changes made after it
+    pub fn open_savepoint(&mut self) {
+        self.savepoints.push(Savepoint {
+            pages: BTreeMap::new(),
+            db_header: self.db_header.clone(),
+        });
+    }
+
+    /// Keep the changes of the innermost savepoint, they become part of the one it was opened in
+    pub fn release_savepoint(&mut self) {
+        let Some(savepoint) = self.savepoints.pop() else {
+            return;
+        };
+        if let Some(parent) = self.savepoints.last_mut() {
+            for (page_number, page) in savepoint.pages {
+                parent.pages.entry(page_number).or_insert(page);
+            }
+        }
+    }
 
-            let mut page_buff: Vec<u8> = vec![0; self.db_header.page_size as usize];
-            self.file.read(&mut page_buff)?;
-            let mut reader = Cursor::new(page_buff);
-            let page = (Page::deserialize(&mut reader), reader);
-            self.cache.insert(page_number, page.clone());
-            return Ok(page);
+    /// Undo the changes made since the innermost savepoint was opened and close it
+    pub fn rollback_savepoint(&mut self) -> Result<()> {
+        let Some(savepoint) = self.savepoints.pop() else {
+            return Ok(());
         };
+        for (page_number, page) in savepoint.pages {
+            self.cache().remove(page_number);
+            match page {
+                Some(page) => self.dirty.insert(page_number, page),
+                None => self.dirty.remove(&page_number),
+            };
+        }
+        self.db_header = savepoint.db_header;
+        self.load_schema()
+    }
+}
+
+impl Drop for Pager {
+    /// Like closing a sqlite connection, a transaction that wasn't committed is rolled back
+    fn drop(&mut self) {
+        let journaling = self
+            .journal_writer
+            .as_ref()
+            .is_some_and(JournalWriter::is_active);
+        if journaling || !self.autocommit {
+            let _ = self.rollback();
+        }
+    }
+}
+
+impl PayloadReader for Pager {
+    fn usable_size(&self) -> usize {
+        self.db_header.usable_size()
+    }
+
+    fn text_encoding(&self) -> TextEncoding {
+        self.db_header.text_encoding
+    }
+
+    fn read_overflow_page(&self, page_number: u32) -> Result<Vec<u8>> {
+        self.read_raw_page(page_number)
+    }
+}
+
+#[cfg(test)]
+mod pager_tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        query_engine::engine::QueryEngine,
+        sql_parser::parser::{Column, Comparison, Operator, SelectQuery},
+    };
+
+    #[test]
+    fn test_pager_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Pager>();
+        assert_send_sync::<QueryEngine>();
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let engine = QueryEngine::new(Pager::open("companies.db").unwrap());
+        engine.pager.set_cache_capacity(CacheCapacity::Pages(32));
+        let query = |country: &str| SelectQuery {
+            columns: vec![Column::Regular("name".into())],
+            table: "companies".into(),
+            where_clause: Some(Comparison {
+                column: "country".into(),
+                operator: Operator::Equals,
+                value: country.into(),
+            }),
+        };
+
+        let expected = engine.run_query(query("rwanda")).unwrap();
+        thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| engine.run_query(query("rwanda")).unwrap()))
+                .collect();
+            for reader in readers {
+                assert_eq!(reader.join().unwrap(), expected);
+            }
+        });
+        assert_eq!(expected.lines().count(), 288);
+    }
+
+    #[test]
+    fn test_opening_a_missing_file() {
+        let error = Pager::open("fixtures/missing.db").err().unwrap();
+        assert_eq!(error.to_string(), "couldn't open fixtures/missing.db");
+    }
+
+    fn select_all(engine: &QueryEngine, table: &str) -> String {
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: table.into(),
+            where_clause: None,
+        };
+        engine.run_query(query).unwrap()
+    }
+
+    fn is_mapped(pager: &Pager, page_number: u32) -> bool {
+        let (_, buf) = pager.read_page(page_number).unwrap();
+        matches!(buf.get_ref(), PageBuffer::Mapped { .. })
+    }
+
+    #[test]
+    fn test_memory_mapped_reads() {
+        let engine = QueryEngine::new(Pager::open("fixtures/overflow.db").unwrap());
+        engine.pager.set_cache_capacity(CacheCapacity::Pages(0));
+        let expected = select_all(&engine, "documents");
+
+        engine.pager.set_mmap_size(u64::MAX);
+        assert!(is_mapped(&engine.pager, 2));
+        assert_eq!(select_all(&engine, "documents"), expected);
+
+        engine.pager.set_mmap_size(0);
+        assert!(!is_mapped(&engine.pager, 2));
+    }
+
+    #[test]
+    fn test_pages_past_the_mapping_are_read_from_the_file() {
+        let engine = QueryEngine::new(Pager::open("sample.db").unwrap());
+        let expected = select_all(&engine, "oranges");
+
+        let pager = Pager::open("sample.db").unwrap();
+        pager.set_mmap_size(2 * 4096 + 100);
+        assert!(is_mapped(&pager, 2));
+        assert!(!is_mapped(&pager, 3));
+        assert_eq!(select_all(&QueryEngine::new(pager), "oranges"), expected);
+
+        // files in memory can't be mapped
+        let vfs = crate::vfs::memory::MemoryVfs::new();
+        vfs.insert("sample.db", std::fs::read("sample.db").unwrap());
+        let pager = Pager::open_with(&vfs, "sample.db").unwrap();
+        pager.set_mmap_size(u64::MAX);
+        assert!(!is_mapped(&pager, 2));
+        assert_eq!(select_all(&QueryEngine::new(pager), "oranges"), expected);
+    }
+
+    #[test]
+    fn test_remapping_when_the_file_changes_size() {
+        let path = std::env::temp_dir().join(format!("mmap_remap_{}.db", std::process::id()));
+        std::fs::copy("sample.db", &path).unwrap();
+        let pager = Pager::open(&path).unwrap();
+        pager.set_mmap_size(u64::MAX);
+        let mapped_len = |pager: &Pager| pager.map.read().unwrap().as_ref().map(|map| map.len());
+        assert_eq!(mapped_len(&pager), Some(4 * 4096));
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(5 * 4096).unwrap();
+        pager.validate_cache().unwrap();
+        assert_eq!(mapped_len(&pager), Some(5 * 4096));
 
-        std::result::Result::Ok(page.clone())
+        file.set_len(3 * 4096).unwrap();
+        pager.validate_cache().unwrap();
+        assert_eq!(mapped_len(&pager), Some(3 * 4096));
+        assert!(pager.read_page(4).is_err());
+        std::fs::remove_file(path).unwrap();
     }
 }
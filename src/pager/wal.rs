@@ -0,0 +1,777 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use super::journal::nonce;
+use crate::{
+    error::Error,
+    vfs::vfs::{LockLevel, VfsFile},
+};
+
+pub const WAL_HEADER_SIZE: usize = 32;
+pub const WAL_FRAME_HEADER_SIZE: usize = 24;
+// the low bit of the magic number says whether checksums are computed on big-endian words
+const WAL_MAGIC: u32 = 0x377f0682;
+const WAL_FORMAT_VERSION: u32 = 3007000;
+
+/// The first 32 bytes of a write-ahead log
+/// https://www.sqlite.org/fileformat2.html#walformat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub format_version: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    // the salts change with every checkpoint, frames with other salts are left over from before it
+    pub salt_1: u32,
+    pub salt_2: u32,
+    pub checksum_1: u32,
+    pub checksum_2: u32,
+}
+
+impl WalHeader {
+    /// Header of a log whose checksums are computed on little-endian words, like sqlite's on x86 and ARM
+    fn new(page_size: u32, checkpoint_sequence: u32, salt_1: u32, salt_2: u32) -> Self {
+        let mut header = WalHeader {
+            magic: WAL_MAGIC,
+            format_version: WAL_FORMAT_VERSION,
+            page_size,
+            checkpoint_sequence,
+            salt_1,
+            salt_2,
+            checksum_1: 0,
+            checksum_2: 0,
+        };
+        let bytes = header.to_bytes();
+        (header.checksum_1, header.checksum_2) =
+            wal_checksum(&bytes[..24], header.big_endian_checksums(), (0, 0));
+        header
+    }
+
+    fn big_endian_checksums(&self) -> bool {
+        self.magic & 1 == 1
+    }
+
+    fn to_bytes(self) -> [u8; WAL_HEADER_SIZE] {
+        let mut bytes = [0u8; WAL_HEADER_SIZE];
+        let fields = [
+            self.magic,
+            self.format_version,
+            self.page_size,
+            self.checkpoint_sequence,
+            self.salt_1,
+            self.salt_2,
+            self.checksum_1,
+            self.checksum_2,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8; WAL_HEADER_SIZE]> for WalHeader {
+    type Error = anyhow::Error;
+
+    fn try_from(buf: &[u8; WAL_HEADER_SIZE]) -> Result<Self> {
+        let field = |offset: usize| u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let header = WalHeader {
+            magic: field(0),
+            format_version: field(4),
+            page_size: field(8),
+            checkpoint_sequence: field(12),
+            salt_1: field(16),
+            salt_2: field(20),
+            checksum_1: field(24),
+            checksum_2: field(28),
+        };
+
+        ensure!(
+            header.magic & !1 == WAL_MAGIC,
+            "invalid WAL magic number {:#x}",
+            header.magic
+        );
+        ensure!(
+            header.format_version == WAL_FORMAT_VERSION,
+            "unsupported WAL format version {}",
+            header.format_version
+        );
+        ensure!(
+            header.page_size.is_power_of_two() && (512..=65536).contains(&header.page_size),
+            "invalid WAL page size {}",
+            header.page_size
+        );
+        let checksum = wal_checksum(&buf[..24], header.big_endian_checksums(), (0, 0));
+        ensure!(
+            checksum == (header.checksum_1, header.checksum_2),
+            "WAL header checksum mismatch"
+        );
+        Ok(header)
+    }
+}
+
+/// The 24 bytes before every page image in the log
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub page_number: u32,
+    // size of the database in pages after a commit, 0 for frames that don't end a transaction
+    pub database_size: u32,
+    pub salt_1: u32,
+    pub salt_2: u32,
+    pub checksum_1: u32,
+    pub checksum_2: u32,
+}
+
+impl From<&[u8; WAL_FRAME_HEADER_SIZE]> for FrameHeader {
+    fn from(buf: &[u8; WAL_FRAME_HEADER_SIZE]) -> Self {
+        let field = |offset: usize| u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
+        FrameHeader {
+            page_number: field(0),
+            database_size: field(4),
+            salt_1: field(8),
+            salt_2: field(12),
+            checksum_1: field(16),
+            checksum_2: field(20),
+        }
+    }
+}
+
+/// sqlite's checksum over 32-bit words, continuing from the checksum of everything before `data`
+pub fn wal_checksum(data: &[u8], big_endian: bool, initial: (u32, u32)) -> (u32, u32) {
+    let (mut s1, mut s2) = initial;
+    for words in data.chunks_exact(8) {
+        let word = |bytes: &[u8]| {
+            let bytes = bytes.try_into().unwrap();
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        s1 = s1.wrapping_add(word(&words[0..4])).wrapping_add(s2);
+        s2 = s2.wrapping_add(word(&words[4..8])).wrapping_add(s1);
+    }
+    (s1, s2)
+}
+
+/// How much a checkpoint does, https://www.sqlite.org/c3ref/wal_checkpoint_v2.html
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckpointMode {
+    // copy what can be copied without waiting on readers or writers
+    Passive,
+    // keep writers out until every frame is copied
+    Full,
+    // like full, then start the log again from its first frame
+    Restart,
+    // like restart, then truncate the log to nothing
+    Truncate,
+}
+
+/// What `PRAGMA wal_checkpoint` reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    // a reader or writer kept the checkpoint from doing all it was asked to
+    pub busy: bool,
+    // frames in the log
+    pub log_frames: u32,
+    // frames copied into the database file
+    pub checkpointed: u32,
+}
+
+/// Committed pages of a write-ahead log, these are newer than the pages in the database file.
+/// Like sqlite's wal-index the frames are indexed by page, a reader keeps seeing the transactions that
+/// were committed when the log was last read until it calls `refresh`
+pub struct Wal {
+    file: Box<dyn VfsFile>,
+    // None when the log is empty or its header is invalid, sqlite ignores such a log
+    pub header: Option<WalHeader>,
+    // newest committed frame holding each page, frames are numbered from 1
+    frames: HashMap<u32, u32>,
+    // frame that ends the last valid transaction, 0 when nothing is committed
+    pub last_commit_frame: u32,
+    // size of the database in pages as of the last commit
+    pub database_size: u32,
+    // checksum of the last commit frame, or of the header before anything is committed, the next frame's follows on from it
+    checksum: (u32, u32),
+}
+
+impl Wal {
+    pub fn new(file: Box<dyn VfsFile>) -> Result<Self> {
+        let mut wal = Wal {
+            file,
+            header: None,
+            frames: HashMap::new(),
+            last_commit_frame: 0,
+            database_size: 0,
+            checksum: (0, 0),
+        };
+        wal.refresh()?;
+        Ok(wal)
+    }
+
+    /// Read the transactions committed since the log was last read, stopping at the first frame that doesn't belong
+    /// to the current checkpoint or whose checksum doesn't follow on from the frame before. A log that was started
+    /// again by a checkpoint is read from its first frame. Returns whether the log had changed
+    pub fn refresh(&mut self) -> Result<bool> {
+        let wal_size = self.file.size()?;
+        let mut header_buf = [0u8; WAL_HEADER_SIZE];
+        let header = match self.file.read_at(&mut header_buf, 0) {
+            Ok(()) => WalHeader::try_from(&header_buf).ok(),
+            Err(_) => None,
+        };
+        let mut changed = header != self.header;
+        if changed {
+            self.reset(header);
+        }
+        let Some(header) = header else {
+            return Ok(changed);
+        };
+
+        let mut frame = vec![0u8; WAL_FRAME_HEADER_SIZE + header.page_size as usize];
+        let mut checksum = self.checksum;
+        // frames of the transaction that is being read, only kept once its commit frame is valid
+        let mut pending: Vec<(u32, u32)> = vec![];
+        let mut frame_number = self.last_commit_frame + 1;
+
+        while self.frame_offset(frame_number) + frame.len() as u64 <= wal_size {
+            self.file
+                .read_at(&mut frame, self.frame_offset(frame_number))?;
+            let frame_header = FrameHeader::from(
+                &frame[..WAL_FRAME_HEADER_SIZE]
+                    .try_into()
+                    .context("frame header size")?,
+            );
+            if frame_header.salt_1 != header.salt_1 || frame_header.salt_2 != header.salt_2 {
+                break;
+            }
+            // the checksum covers the first 8 bytes of the frame header and the page image
+            checksum = wal_checksum(&frame[..8], header.big_endian_checksums(), checksum);
+            checksum = wal_checksum(
+                &frame[WAL_FRAME_HEADER_SIZE..],
+                header.big_endian_checksums(),
+                checksum,
+            );
+            if checksum != (frame_header.checksum_1, frame_header.checksum_2) {
+                break;
+            }
+
+            pending.push((frame_header.page_number, frame_number));
+            if frame_header.database_size != 0 {
+                self.frames.extend(pending.drain(..));
+                self.last_commit_frame = frame_number;
+                self.database_size = frame_header.database_size;
+                self.checksum = checksum;
+                changed = true;
+            }
+            frame_number += 1;
+        }
+
+        Ok(changed)
+    }
+
+    /// Forget every frame, the log has a new header or none
+    fn reset(&mut self, header: Option<WalHeader>) {
+        self.header = header;
+        self.frames.clear();
+        self.last_commit_frame = 0;
+        self.database_size = 0;
+        self.checksum = header.map_or((0, 0), |header| (header.checksum_1, header.checksum_2));
+    }
+
+    fn frame_offset(&self, frame_number: u32) -> u64 {
+        let page_size = self.header.map_or(0, |header| header.page_size) as u64;
+        WAL_HEADER_SIZE as u64
+            + (frame_number - 1) as u64 * (WAL_FRAME_HEADER_SIZE as u64 + page_size)
+    }
+
+    /// Newest committed frame holding the page
+    pub fn find_frame(&self, page_number: u32) -> Option<u32> {
+        self.frames.get(&page_number).copied()
+    }
+
+    /// Read the page image stored in a frame
+    pub fn read_frame(&self, frame_number: u32) -> Result<Vec<u8>> {
+        let page_size = self
+            .header
+            .context("can't read frames from an empty WAL")?
+            .page_size;
+        let mut page = vec![0u8; page_size as usize];
+        self.file
+            .read_at(
+                &mut page,
+                self.frame_offset(frame_number) + WAL_FRAME_HEADER_SIZE as u64,
+            )
+            .with_context(|| format!("couldn't read WAL frame {}", frame_number))?;
+        Ok(page)
+    }
+
+    /// Keep other connections from writing to the log, like sqlite's WAL write lock. Readers carry on.
+    /// Fails when another connection has committed since the log was last read, the write would be
+    /// based on pages that are out of date
+    pub fn lock_writer(&mut self) -> Result<()> {
+        self.file.lock(LockLevel::Exclusive)?;
+        if self.refresh()? {
+            self.unlock_writer()?;
+            bail!(Error::Locked);
+        }
+        Ok(())
+    }
+
+    pub fn unlock_writer(&self) -> Result<()> {
+        self.file.unlock(LockLevel::None)
+    }
+
+    /// Append a transaction to the log after its last commit, overwriting the frames of any transaction
+    /// that didn't finish. The last frame commits the transaction with the size of the database in pages,
+    /// the log is synced before the transaction is added to the index. An empty log is started with a new header
+    pub fn append(&mut self, pages: &BTreeMap<u32, Vec<u8>>, database_size: u32) -> Result<()> {
+        let page_size = pages.values().next().map_or(0, Vec::len) as u32;
+        let header = match self.header {
+            Some(header) => header,
+            None => self.start(WalHeader::new(page_size, 0, nonce(), nonce()))?,
+        };
+        ensure!(
+            header.page_size == page_size,
+            "WAL page size {} doesn't match the database's {}",
+            header.page_size,
+            page_size
+        );
+
+        let mut checksum = self.checksum;
+        let mut frame_number = self.last_commit_frame;
+        let mut frames = vec![];
+        for (i, (page_number, page)) in pages.iter().enumerate() {
+            frame_number += 1;
+            let commit_size = if i + 1 == pages.len() {
+                database_size
+            } else {
+                0
+            };
+            let mut frame = Vec::with_capacity(WAL_FRAME_HEADER_SIZE + page.len());
+            frame.extend(page_number.to_be_bytes());
+            frame.extend(commit_size.to_be_bytes());
+            checksum = wal_checksum(&frame, header.big_endian_checksums(), checksum);
+            checksum = wal_checksum(page, header.big_endian_checksums(), checksum);
+            for field in [header.salt_1, header.salt_2, checksum.0, checksum.1] {
+                frame.extend(field.to_be_bytes());
+            }
+            frame.extend_from_slice(page);
+            self.file
+                .write_at(&frame, self.frame_offset(frame_number))
+                .with_context(|| format!("couldn't write WAL frame {}", frame_number))?;
+            frames.push((*page_number, frame_number));
+        }
+        self.file.sync()?;
+
+        self.frames.extend(frames);
+        self.last_commit_frame = frame_number;
+        self.database_size = database_size;
+        self.checksum = checksum;
+        Ok(())
+    }
+
+    /// Write and sync a new header, forgetting the frames that were in the log
+    fn start(&mut self, header: WalHeader) -> Result<WalHeader> {
+        self.file
+            .write_at(&header.to_bytes(), 0)
+            .context("couldn't write the WAL header")?;
+        self.file.sync()?;
+        self.reset(Some(header));
+        Ok(header)
+    }
+
+    /// Copy the newest committed copy of every page into the database file, cutting the file down to the size
+    /// of the database, and sync it. The frames stay in the log until it's restarted
+    pub fn checkpoint(&self, db_file: &dyn VfsFile) -> Result<()> {
+        let Some(header) = self.header.filter(|_| self.last_commit_frame != 0) else {
+            return Ok(());
+        };
+        let page_size = header.page_size as u64;
+        let mut frames: Vec<(u32, u32)> = self
+            .frames
+            .iter()
+            .map(|(page_number, frame_number)| (*page_number, *frame_number))
+            .filter(|(page_number, _)| *page_number <= self.database_size)
+            .collect();
+        frames.sort();
+        for (page_number, frame_number) in frames {
+            db_file
+                .write_at(
+                    &self.read_frame(frame_number)?,
+                    (page_number - 1) as u64 * page_size,
+                )
+                .with_context(|| format!("couldn't checkpoint page {}", page_number))?;
+        }
+        db_file.set_len(self.database_size as u64 * page_size)?;
+        db_file.sync()
+    }
+
+    /// Start the log again once every frame is in the database file. Like sqlite the new header has the next
+    /// checkpoint sequence and new salts, so the frames left in the file no longer belong to the log.
+    /// Truncating empties the file instead, the next write starts it with a new header
+    pub fn restart(&mut self, truncate: bool) -> Result<()> {
+        if truncate {
+            self.file.set_len(0)?;
+            self.file.sync()?;
+            self.reset(None);
+            return Ok(());
+        }
+        if let Some(header) = self.header {
+            self.start(WalHeader::new(
+                header.page_size,
+                header.checkpoint_sequence.wrapping_add(1),
+                header.salt_1.wrapping_add(1),
+                nonce(),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod wal_tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{
+        pager::{create::CreateOptions, pager::Pager},
+        query_engine::engine::QueryEngine,
+        sql_parser::parser::{Column, SelectQuery},
+        vfs::{
+            fault::{Crash, FaultyVfs},
+            memory::{MemoryFile, MemoryVfs},
+            vfs::{OpenMode, Vfs},
+        },
+    };
+
+    fn load(path: &str) -> Box<dyn VfsFile> {
+        Box::new(MemoryFile::from(fs::read(path).unwrap()))
+    }
+
+    #[test]
+    fn test_finding_last_commit() {
+        let wal = Wal::new(load("fixtures/wal.db-wal")).unwrap();
+        assert_eq!(wal.header.unwrap().page_size, 512);
+        assert_eq!(wal.last_commit_frame, 17);
+        assert_eq!(wal.database_size, 14);
+        // page 3 was written by the first transaction and again by the second
+        assert_eq!(wal.find_frame(3), Some(14));
+        assert_eq!(wal.find_frame(14), Some(17));
+    }
+
+    #[test]
+    fn test_frames_after_a_bad_checksum_are_ignored() {
+        let mut bytes = fs::read("fixtures/wal.db-wal").unwrap();
+        // flip a byte in the page image of frame 15 so the third and fourth transactions are dropped
+        let frame_size = WAL_FRAME_HEADER_SIZE + 512;
+        bytes[WAL_HEADER_SIZE + 14 * frame_size + WAL_FRAME_HEADER_SIZE + 100] ^= 0xff;
+
+        let wal = Wal::new(Box::new(MemoryFile::from(bytes))).unwrap();
+        assert_eq!(wal.last_commit_frame, 14);
+        assert_eq!(wal.database_size, 13);
+        assert_eq!(wal.find_frame(14), None);
+        assert_eq!(wal.find_frame(1), Some(1));
+    }
+
+    fn select(pager: Pager, table: &str, column: &str) -> String {
+        let engine = QueryEngine::new(pager);
+        let query = SelectQuery {
+            table: table.into(),
+            columns: vec![Column::Regular(column.into())],
+            where_clause: None,
+        };
+        engine.run_query(query).unwrap()
+    }
+
+    #[test]
+    fn test_reading_pages_from_the_wal() {
+        let pager = Pager::open("fixtures/wal.db").unwrap();
+        assert_eq!(pager.page_count().unwrap(), 14);
+
+        let names = select(pager, "items", "name");
+        assert_eq!(names.lines().count(), 110);
+        assert_eq!(names.lines().next(), Some("updated"));
+
+        let pager =
+            Pager::with_wal(load("fixtures/wal.db"), Some(load("fixtures/wal.db-wal"))).unwrap();
+        assert_eq!(select(pager, "extra", "note"), "only in the wal");
+    }
+
+    #[test]
+    fn test_database_file_without_its_wal() {
+        let pager = Pager::new(load("fixtures/wal.db")).unwrap();
+
+        let names = select(pager, "items", "name");
+        assert_eq!(names.lines().count(), 10);
+        assert_eq!(names.lines().next(), Some("item 01"));
+    }
+
+    fn insert(engine: &mut QueryEngine, names: std::ops::Range<usize>) {
+        for i in names {
+            let sql = format!(
+                "INSERT INTO t (name, body) VALUES ('row {}', '{}')",
+                i,
+                "x".repeat(i * 40)
+            );
+            engine.execute_sql(&sql).unwrap();
+        }
+    }
+
+    fn row_count(engine: &QueryEngine) -> usize {
+        let query = SelectQuery {
+            table: "t".into(),
+            columns: vec![Column::Regular("name".into())],
+            where_clause: None,
+        };
+        engine.run_query(query).unwrap().lines().count()
+    }
+
+    fn wal_file(vfs: &MemoryVfs) -> Wal {
+        let bytes = vfs.read(Path::new("test.db-wal")).unwrap();
+        Wal::new(Box::new(MemoryFile::from(bytes))).unwrap()
+    }
+
+    /// A database of 512 byte pages in WAL mode with an empty table
+    fn wal_database() -> MemoryVfs {
+        let vfs = MemoryVfs::new();
+        let options = CreateOptions {
+            page_size: 512,
+            ..CreateOptions::default()
+        };
+        Pager::create_with(&vfs, "test.db", options).unwrap();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        assert_eq!(engine.execute_sql("PRAGMA journal_mode").unwrap(), "delete");
+        assert_eq!(
+            engine.execute_sql("PRAGMA journal_mode = WAL").unwrap(),
+            "wal"
+        );
+        engine
+            .execute_sql("CREATE TABLE t (name text, body text)")
+            .unwrap();
+        vfs
+    }
+
+    #[test]
+    fn test_committing_to_the_wal() {
+        let vfs = wal_database();
+        let file = vfs.read(Path::new("test.db")).unwrap();
+        assert_eq!(
+            file[18..20],
+            [2, 2],
+            "the header says the database is in WAL mode"
+        );
+
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        insert(&mut engine, 0..20);
+        assert_eq!(vfs.read(Path::new("test.db")).unwrap(), file);
+
+        let wal = wal_file(&vfs);
+        let header = wal.header.unwrap();
+        assert_eq!(header.page_size, 512);
+        assert_eq!(header.checkpoint_sequence, 0);
+        assert_eq!(wal.database_size, engine.pager.page_count().unwrap());
+        // every commit rewrites page 1 along with the pages it changed
+        assert!(wal.last_commit_frame > 21);
+        assert!(wal.find_frame(1).is_some());
+
+        let reader = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadOnly);
+        assert_eq!(row_count(&reader), 20);
+        assert!(reader.pager.integrity_check().unwrap().is_empty());
+
+        engine.execute_sql("BEGIN; DELETE FROM t").unwrap();
+        assert!(
+            !vfs.exists(Path::new("test.db-journal")).unwrap(),
+            "writes to the log aren't journaled"
+        );
+        engine.execute_sql("ROLLBACK").unwrap();
+    }
+
+    #[test]
+    fn test_readers_keep_their_snapshot() {
+        let vfs = wal_database();
+        let mut writer = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let reader = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadOnly);
+        insert(&mut writer, 0..5);
+
+        let read = reader.pager.begin_read().unwrap();
+        insert(&mut writer, 5..10);
+        writer
+            .execute_sql("DELETE FROM t WHERE name = 'row 1'")
+            .unwrap();
+        assert_eq!(row_count(&reader), 5, "the read started before the commits");
+        drop(read);
+        assert_eq!(row_count(&reader), 9);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let vfs = wal_database();
+        let mut writer = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let reader = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadOnly);
+        insert(&mut writer, 0..20);
+        let frames = wal_file(&vfs).last_commit_frame;
+
+        // a reader keeps frames from being copied
+        let read = reader.pager.begin_read().unwrap();
+        let checkpoint = |engine: &mut QueryEngine, mode: &str| {
+            engine
+                .execute_sql(&format!("PRAGMA wal_checkpoint({})", mode))
+                .unwrap()
+        };
+        assert_eq!(
+            checkpoint(&mut writer, "PASSIVE"),
+            format!("0|{}|0", frames)
+        );
+        assert_eq!(checkpoint(&mut writer, "FULL"), format!("1|{}|0", frames));
+        drop(read);
+
+        assert_eq!(
+            writer.execute_sql("PRAGMA wal_checkpoint").unwrap(),
+            format!("0|{}|{}", frames, frames)
+        );
+        let file = vfs.read(Path::new("test.db")).unwrap();
+        let without_wal = QueryEngine::new(Pager::new(Box::new(MemoryFile::from(file))).unwrap());
+        assert_eq!(row_count(&without_wal), 20);
+
+        assert_eq!(
+            checkpoint(&mut writer, "restart"),
+            format!("0|{}|{}", frames, frames)
+        );
+        insert(&mut writer, 20..21);
+        let wal = wal_file(&vfs);
+        assert_eq!(wal.header.unwrap().checkpoint_sequence, 1);
+        assert!(
+            wal.last_commit_frame < frames / 4,
+            "the log starts again from its first frame"
+        );
+
+        assert_eq!(checkpoint(&mut writer, "truncate"), "0|0|0");
+        assert_eq!(vfs.read(Path::new("test.db-wal")).unwrap().len(), 0);
+        assert_eq!(row_count(&reader), 21);
+        insert(&mut writer, 21..22);
+        assert_eq!(row_count(&reader), 22);
+        assert!(reader.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_leaving_wal_mode() {
+        let vfs = wal_database();
+        let mut writer = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let reader = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadOnly);
+        insert(&mut writer, 0..5);
+        assert_eq!(
+            writer
+                .execute_sql("BEGIN; PRAGMA journal_mode = DELETE")
+                .unwrap_err()
+                .to_string(),
+            "cannot change out of wal mode from within a transaction"
+        );
+        writer.execute_sql("ROLLBACK").unwrap();
+
+        let read = reader.pager.begin_read().unwrap();
+        assert_eq!(
+            writer
+                .execute_sql("PRAGMA journal_mode = DELETE")
+                .unwrap_err()
+                .to_string(),
+            "database is locked"
+        );
+        drop(read);
+        assert_eq!(
+            writer.execute_sql("PRAGMA journal_mode = DELETE").unwrap(),
+            "delete"
+        );
+        assert!(!vfs.exists(Path::new("test.db-wal")).unwrap());
+        assert_eq!(vfs.read(Path::new("test.db")).unwrap()[18..20], [1, 1]);
+        assert_eq!(
+            writer.execute_sql("PRAGMA wal_checkpoint").unwrap(),
+            "0|-1|-1"
+        );
+
+        insert(&mut writer, 5..6);
+        assert_eq!(row_count(&reader), 6);
+        assert!(!reader.pager.wal_mode());
+    }
+
+    #[test]
+    fn test_recovering_from_a_crash_while_committing() {
+        let vfs = wal_database();
+        insert(
+            &mut QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite),
+            0..10,
+        );
+        let files = ["test.db", "test.db-wal"].map(|path| vfs.read(Path::new(path)).unwrap());
+        let statements = [
+            "INSERT INTO t (name, body) VALUES ('a', 'new'), ('b', '".to_string()
+                + &"y".repeat(2000)
+                + "')",
+            "BEGIN; UPDATE t SET body = 'short' WHERE name = 'row 9'; \
+             DELETE FROM t WHERE name = 'row 5'; CREATE TABLE u (a unique); \
+             INSERT INTO u VALUES ('1'); COMMIT"
+                .to_string(),
+        ];
+        let fresh = || {
+            let memory = MemoryVfs::new();
+            memory.insert("test.db", files[0].clone());
+            memory.insert("test.db-wal", files[1].clone());
+            FaultyVfs::new(memory)
+        };
+        let run = |vfs: &FaultyVfs<MemoryVfs>| -> Result<()> {
+            let mut engine =
+                QueryEngine::new(Pager::open_with_mode(vfs, "test.db", OpenMode::ReadWrite)?);
+            for sql in &statements {
+                engine.execute_sql(sql)?;
+            }
+            Ok(())
+        };
+        let contents = |vfs: &dyn Vfs| {
+            let mut engine = QueryEngine::open_with_mode(vfs, "test.db", OpenMode::ReadOnly);
+            assert!(engine.pager.integrity_check().unwrap().is_empty());
+            let tables = engine.pager.schema_table.cells.len();
+            format!(
+                "{}\n{}",
+                tables,
+                engine.execute_sql("SELECT * FROM t").unwrap()
+            )
+        };
+
+        // the database after each of the statements
+        let vfs = fresh();
+        let mut states = vec![contents(&vfs)];
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        for sql in &statements {
+            engine.execute_sql(sql).unwrap();
+            states.push(contents(&vfs));
+        }
+        drop(engine);
+        let vfs = fresh();
+        run(&vfs).unwrap();
+        let writes = vfs.writes();
+
+        for write in 1..=writes {
+            for (torn, lose_unsynced) in
+                [(false, false), (true, false), (false, true), (true, true)]
+            {
+                let crash = Crash {
+                    write,
+                    torn,
+                    lose_unsynced,
+                };
+                let vfs = fresh();
+                vfs.crash(crash);
+                assert!(run(&vfs).is_err(), "{:?} didn't stop the writes", crash);
+                vfs.restart().unwrap();
+
+                // frames after the last commit frame the crash left whole are ignored
+                let state = contents(&vfs);
+                let position = states.iter().position(|s| *s == state);
+                assert!(
+                    position.is_some_and(|position| position < states.len() - 1),
+                    "{:?} left the database in a state it wasn't committed in",
+                    crash
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::data_model::btree::serial_value::SerialValue;
+
+/// The kind of value a column prefers to store, values are converted to it when they can be without losing anything
+/// https://www.sqlite.org/datatype3.html#type_affinity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+// words that start the constraints following a column's declared type
+const CONSTRAINT_KEYWORDS: [&str; 11] = [
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "collate",
+    "references",
+    "generated",
+    "as",
+];
+
+impl Affinity {
+    /// Affinity of a column from the rest of its definition, i.e `varchar(10) not null`
+    /// https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+    pub fn from_type_def(type_def: &str) -> Self {
+        let declared_type = type_def
+            .to_lowercase()
+            .split_whitespace()
+            .take_while(|word| !CONSTRAINT_KEYWORDS.contains(word))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let contains = |names: &[&str]| names.iter().any(|name| declared_type.contains(name));
+
+        if contains(&["int"]) {
+            Affinity::Integer
+        } else if contains(&["char", "clob", "text"]) {
+            Affinity::Text
+        } else if declared_type.is_empty() || contains(&["blob"]) {
+            Affinity::Blob
+        } else if contains(&["real", "floa", "doub"]) {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// Convert a value the way sqlite does before storing it in a column with this affinity
+    pub fn apply(self, value: SerialValue<'static>) -> SerialValue<'static> {
+        match (self, value) {
+            (Affinity::Text, SerialValue::Int(integer)) => {
+                SerialValue::Text(integer.to_string().into())
+            }
+            (Affinity::Text, SerialValue::Float(real)) => {
+                SerialValue::Text(format_real(real).into())
+            }
+            (Affinity::Numeric | Affinity::Integer | Affinity::Real, SerialValue::Text(text)) => {
+                match parse_numeric(&text) {
+                    Some(number) => self.apply(number),
+                    None => SerialValue::Text(text),
+                }
+            }
+            // reals that are whole numbers are stored as integers
+            (Affinity::Numeric | Affinity::Integer, SerialValue::Float(real)) => {
+                match real_to_integer(real) {
+                    Some(integer) => SerialValue::Int(integer),
+                    None => SerialValue::Float(real),
+                }
+            }
+            (Affinity::Real, SerialValue::Int(integer)) => SerialValue::Float(integer as f64),
+            (_, value) => value,
+        }
+    }
+}
+
+/// The number text holds when it is nothing but a well formed integer or real, surrounding spaces aside
+fn parse_numeric(text: &str) -> Option<SerialValue<'static>> {
+    let text = text.trim();
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    // rust also parses words like `inf` and `nan` as reals
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        || !digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || ".eE+-".contains(c))
+    {
+        return None;
+    }
+    if let Ok(integer) = text.parse::<i64>() {
+        return Some(SerialValue::Int(integer));
+    }
+    text.parse::<f64>().ok().map(SerialValue::Float)
+}
+
+fn real_to_integer(real: f64) -> Option<i64> {
+    // i64::MAX as f64 rounds up to 2^63, which doesn't fit
+    (real.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&real))
+        .then_some(real as i64)
+}
+
+/// Write a real the way sqlite turns one into text, with up to 15 significant digits and always a decimal point,
+/// i.e `1.0`, `0.1` or `1.0e+20`
+pub fn format_real(real: f64) -> String {
+    if !real.is_finite() {
+        return match real {
+            real if real.is_nan() => String::new(),
+            real if real > 0.0 => "Inf".into(),
+            _ => "-Inf".into(),
+        };
+    }
+    let scientific = format!("{:.14e}", real);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("{:e} always writes an exponent");
+    let exponent: i32 = exponent.parse().expect("a whole number exponent");
+    let with_point = |digits: String| {
+        let digits = if digits.contains('.') {
+            digits.trim_end_matches('0').to_string()
+        } else {
+            digits
+        };
+        match digits.strip_suffix('.') {
+            Some(whole) => format!("{}.0", whole),
+            None if digits.contains('.') => digits,
+            None => format!("{}.0", digits),
+        }
+    };
+    if (-4..15).contains(&exponent) {
+        let decimals = (14 - exponent).max(0) as usize;
+        with_point(format!("{:.*}", decimals, real))
+    } else {
+        format!(
+            "{}e{}{:02}",
+            with_point(mantissa.to_string()),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    }
+}
+
+#[cfg(test)]
+mod affinity_tests {
+    use super::*;
+
+    #[test]
+    fn test_affinity_of_declared_types() {
+        assert_eq!(
+            Affinity::from_type_def("integer primary key"),
+            Affinity::Integer
+        );
+        assert_eq!(
+            Affinity::from_type_def("varchar(100) not null"),
+            Affinity::Text
+        );
+        assert_eq!(Affinity::from_type_def("not null"), Affinity::Blob);
+        assert_eq!(Affinity::from_type_def("double precision"), Affinity::Real);
+        assert_eq!(Affinity::from_type_def("decimal(10, 2)"), Affinity::Numeric);
+        // INT wins over anything else in the name
+        assert_eq!(Affinity::from_type_def("floating point"), Affinity::Integer);
+    }
+
+    #[test]
+    fn test_converting_values() {
+        let text = |text: &str| SerialValue::Text(text.to_string().into());
+        assert_eq!(Affinity::Text.apply(SerialValue::Int(5)), text("5"));
+        assert_eq!(Affinity::Text.apply(SerialValue::Float(0.5)), text("0.5"));
+        assert_eq!(Affinity::Integer.apply(text(" 42 ")), SerialValue::Int(42));
+        assert_eq!(Affinity::Numeric.apply(text("3.0")), SerialValue::Int(3));
+        assert_eq!(Affinity::Numeric.apply(text("3.5e1x")), text("3.5e1x"));
+        assert_eq!(Affinity::Numeric.apply(text("inf")), text("inf"));
+        assert_eq!(Affinity::Real.apply(text("7")), SerialValue::Float(7.0));
+        assert_eq!(
+            Affinity::Integer.apply(SerialValue::Float(2.5)),
+            SerialValue::Float(2.5)
+        );
+        assert_eq!(
+            Affinity::Blob.apply(text("12")),
+            text("12"),
+            "columns without a type keep what they're given"
+        );
+    }
+
+    #[test]
+    fn test_formatting_reals() {
+        assert_eq!(format_real(1.0), "1.0");
+        assert_eq!(format_real(0.1), "0.1");
+        assert_eq!(format_real(-2.5), "-2.5");
+        assert_eq!(format_real(1e20), "1.0e+20");
+        assert_eq!(format_real(1.5e-7), "1.5e-07");
+        assert_eq!(format_real(123456.789), "123456.789");
+    }
+}
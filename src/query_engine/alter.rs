@@ -0,0 +1,573 @@
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    data_model::{
+        btree::{record::Record, serial_value::SerialValue},
+        schema_record::{DbObject, SchemaRecord},
+    },
+    error::Error,
+    sql_parser::parser::{AlterAction, AlterTableStatement, Column, Literal, SelectQuery},
+};
+
+use super::{
+    column::{
+        column_name_ranges, definition_ranges, find_column_index, get_column_defaults,
+        get_column_definitions, get_column_names, get_primary_key, get_unique_constraints,
+        is_table_constraint, name_before_definitions, quote_identifier,
+    },
+    engine::QueryEngine,
+    index_key::index_columns,
+    schema_object::SchemaObject,
+    table_writer::{parse_default, CHECK_CONSTRAINT, GENERATED_COLUMN},
+};
+
+impl QueryEngine {
+    /// Rename a table or one of its columns, add a column or drop one. The SQL stored in `sqlite_schema`
+    /// is rewritten since the columns are read from it, along with that of the table's indexes
+    pub fn alter_table(&mut self, statement: AlterTableStatement) -> Result<()> {
+        self.write(|engine| engine.change_table(statement))
+    }
+
+    fn change_table(&mut self, statement: AlterTableStatement) -> Result<()> {
+        let table = self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .find(|rec| {
+                rec.db_object == DbObject::Table && rec.name.eq_ignore_ascii_case(&statement.table)
+            })
+            .cloned()
+            .ok_or_else(|| Error::NoSuchTable(statement.table.clone()))?;
+        if table.name.to_lowercase().starts_with("sqlite_") {
+            bail!("table {} may not be altered", table.name);
+        }
+        if table.rootpage == 0 {
+            bail!(Error::Unsupported(format!(
+                "altering virtual tables like {}",
+                table.name
+            )));
+        }
+
+        match statement.action {
+            AlterAction::RenameTable(name) => self.rename_table(&table, &name)?,
+            AlterAction::RenameColumn { from, to, quoted } => {
+                self.rename_column(&table, &from, &to, quoted)?
+            }
+            AlterAction::AddColumn { name, definition } => {
+                self.add_column(&table, &name, &definition)?
+            }
+            AlterAction::DropColumn(name) => self.drop_column(&table, &name)?,
+        }
+        self.pager.schema_changed()
+    }
+
+    fn rename_table(&mut self, table: &SchemaRecord, name: &str) -> Result<()> {
+        if name.to_lowercase().starts_with("sqlite_") {
+            bail!("object name reserved for internal use: {}", name);
+        }
+        if self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .any(|rec| rec.name.eq_ignore_ascii_case(name))
+        {
+            bail!(
+                "there is already another table or index with this name: {}",
+                name
+            );
+        }
+        self.ensure_not_referenced(table, "renaming")?;
+
+        // like sqlite the new name is always quoted
+        let quoted = quote_identifier(name);
+        let renamed = SchemaRecord {
+            name: name.to_string(),
+            tbl_name: name.to_string(),
+            sql: replace_table_name(table, &quoted)?,
+            ..table.clone()
+        };
+        self.replace_schema_record(&table.name, &renamed)?;
+
+        let autoindex_prefix = format!("sqlite_autoindex_{}_", table.name);
+        for index in self.indexes_of(table) {
+            let renamed = SchemaRecord {
+                name: match index.name.strip_prefix(&autoindex_prefix) {
+                    Some(number) => format!("sqlite_autoindex_{}_{}", name, number),
+                    None => index.name.clone(),
+                },
+                tbl_name: name.to_string(),
+                sql: match index.sql.as_str() {
+                    "" => String::new(),
+                    _ => replace_table_name(&index, &quoted)?,
+                },
+                ..index.clone()
+            };
+            self.replace_schema_record(&index.name, &renamed)?;
+        }
+
+        if self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .any(|rec| rec.name == "sqlite_sequence")
+        {
+            if let (Some(row_id), seq) = self.sequence(&table.name)? {
+                self.set_sequence(name, Some(row_id), seq)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_column(
+        &mut self,
+        table: &SchemaRecord,
+        from: &str,
+        to: &str,
+        quoted: bool,
+    ) -> Result<()> {
+        let columns = get_column_names(&table.sql)?;
+        let position = find_column_index(&columns, from)?;
+        if let Some(other) = find_column_index(&columns, to)
+            .ok()
+            .filter(|other| *other != position)
+        {
+            bail!(
+                "error in table {} after rename: duplicate column name: {}",
+                table.name,
+                columns[other]
+            );
+        }
+        self.ensure_not_referenced(table, "renaming columns of")?;
+        ensure_only_named(table, "renaming columns of")?;
+        let indexes = self.indexes_of(table);
+        for index in &indexes {
+            ensure_only_named(index, "renaming columns of")?;
+        }
+
+        // the new name is quoted where the old one was, and where it has to be
+        let plain = to.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && to.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let rename = |sql: &str| -> Result<String> {
+            let ranges = column_name_ranges(sql, &columns[position])?;
+            Ok(replace_ranges(sql, &ranges, |old| {
+                if quoted || !plain || !old.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+                    quote_identifier(to)
+                } else {
+                    to.to_string()
+                }
+            }))
+        };
+
+        let renamed = SchemaRecord {
+            sql: rename(&table.sql)?,
+            ..table.clone()
+        };
+        self.replace_schema_record(&table.name, &renamed)?;
+        // automatic indexes have no SQL, their columns are read from the table's
+        for index in indexes.iter().filter(|index| !index.sql.is_empty()) {
+            let renamed = SchemaRecord {
+                sql: rename(&index.sql)?,
+                ..index.clone()
+            };
+            self.replace_schema_record(&index.name, &renamed)?;
+        }
+        Ok(())
+    }
+
+    /// Add a column to the end of the table's definition. The rows are left as they are,
+    /// their records are read as having the column's default
+    fn add_column(&mut self, table: &SchemaRecord, name: &str, definition: &str) -> Result<()> {
+        if find_column_index(&get_column_names(&table.sql)?, name).is_ok() {
+            bail!("duplicate column name: {}", name);
+        }
+        let column_sql = format!("CREATE TABLE t({})", definition);
+        let type_def = &get_column_definitions(&column_sql)?[0].type_def;
+        if type_def.contains("primary key") {
+            bail!("Cannot add a PRIMARY KEY column");
+        }
+        if type_def.contains("unique") {
+            bail!("Cannot add a UNIQUE column");
+        }
+        if GENERATED_COLUMN.is_match(definition) || CHECK_CONSTRAINT.is_match(definition) {
+            bail!(Error::Unsupported(
+                "adding generated columns or columns with CHECK constraints".into()
+            ));
+        }
+        // rows already in the table read the default, so it has to be a constant
+        let default = match &get_column_defaults(&column_sql)?[0] {
+            Some(default) => parse_default(default)
+                .map_err(|_| anyhow::anyhow!("Cannot add a column with non-constant default"))?,
+            None => Literal::Null,
+        };
+        if default == Literal::Null && type_def.contains("not null") {
+            bail!("Cannot add a NOT NULL column with default value NULL");
+        }
+
+        // like sqlite the definition goes after the last column, before the table constraints
+        let definitions = definition_ranges(&table.sql)?;
+        let first_constraint = definitions
+            .iter()
+            .position(|range| is_table_constraint(&table.sql[range.clone()]));
+        let end = match first_constraint {
+            Some(i) if i > 0 => table.sql[..definitions[i].start].rfind(','),
+            _ => definitions
+                .last()
+                .and_then(|last| table.sql[last.end..].find(')').map(|end| last.end + end)),
+        }
+        .with_context(|| format!("couldn't find the end of the columns of {}", table.name))?;
+        let altered = SchemaRecord {
+            sql: format!("{}, {}{}", &table.sql[..end], definition, &table.sql[end..]),
+            ..table.clone()
+        };
+        self.replace_schema_record(&table.name, &altered)?;
+
+        // records that stop short of a column with a default need a newer file format to be read
+        let format = if default == Literal::Null { 2 } else { 3 };
+        if self.pager.db_header.schema_format < format {
+            self.pager.begin_write()?;
+            self.pager.db_header.schema_format = format;
+        }
+        Ok(())
+    }
+
+    /// Remove a column from the table's definition and its value from every row
+    fn drop_column(&mut self, table: &SchemaRecord, name: &str) -> Result<()> {
+        let writer = self.table_writer(&table.name)?;
+        let position = find_column_index(&writer.columns, name)?;
+        let column = &writer.columns[position];
+        if writer.row_id_alias == Some(position) || get_primary_key(&table.sql)?.contains(column) {
+            bail!("cannot drop PRIMARY KEY column: \"{}\"", name);
+        }
+        if get_unique_constraints(&table.sql)?
+            .iter()
+            .any(|columns| columns.iter().any(|unique| &unique.name == column))
+        {
+            bail!("cannot drop UNIQUE column: \"{}\"", name);
+        }
+        if writer.columns.len() == 1 {
+            bail!("cannot drop column \"{}\": no other columns exist", name);
+        }
+        for index in self.indexes_of(table) {
+            let columns = index_columns(&index, table).unwrap_or_default();
+            if columns.iter().any(|indexed| &indexed.name == column) {
+                bail!(
+                    "error in index {} after drop column: no such column: {}",
+                    index.name,
+                    name
+                );
+            }
+        }
+        self.ensure_not_referenced(table, "dropping columns of")?;
+        ensure_only_named(table, "dropping columns of")?;
+
+        let object = SchemaObject::try_from(table.clone())?;
+        let record_position = find_column_index(&object.record_columns(), column)?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: table.name.clone(),
+            where_clause: None,
+        };
+        let encoding = self.pager.db_header.text_encoding;
+        // the rows are read before any are changed so the b-tree isn't changed while it's read
+        for row in self.table_db_scan(&object, &query)? {
+            let values = object.complete_row(row.record.values()?);
+            let old: Vec<SerialValue<'static>> =
+                writer.record_order(values.into_iter().map(SerialValue::into_owned).collect());
+            let mut new = old.clone();
+            new.remove(record_position);
+            match &writer.primary_key {
+                // the key stays the same, the row is written again without the value
+                Some((key, key_columns)) => {
+                    self.pager
+                        .delete_entry(writer.rootpage, &old, key_columns, key.len())?;
+                    self.pager.insert_entry(
+                        writer.rootpage,
+                        &Record::new(new, encoding),
+                        key_columns,
+                        key.len(),
+                    )?;
+                }
+                None => {
+                    let row_id = row.row_header.row_id as i64;
+                    self.pager.insert_row(
+                        writer.rootpage,
+                        row_id,
+                        &Record::new(new, encoding),
+                        true,
+                    )?;
+                }
+            }
+        }
+
+        // the definition goes with the comma after it, or before it when it's the last one
+        let definitions = definition_ranges(&table.sql)?;
+        let columns: Vec<usize> = definitions
+            .iter()
+            .enumerate()
+            .filter(|(_, range)| !is_table_constraint(&table.sql[(*range).clone()]))
+            .map(|(i, _)| i)
+            .collect();
+        let i = columns[position];
+        let removed = match definitions.get(i + 1) {
+            Some(next) => definitions[i].start..next.start,
+            None => definitions[i - 1].end..definitions[i].end,
+        };
+        let altered = SchemaRecord {
+            sql: format!(
+                "{}{}",
+                &table.sql[..removed.start],
+                &table.sql[removed.end..]
+            ),
+            ..table.clone()
+        };
+        self.replace_schema_record(&table.name, &altered)
+    }
+
+    fn indexes_of(&self, table: &SchemaRecord) -> Vec<SchemaRecord> {
+        self.pager
+            .schema_table
+            .cells
+            .iter()
+            .filter(|rec| rec.db_object == DbObject::Index && rec.tbl_name == table.name)
+            .cloned()
+            .collect()
+    }
+
+    /// Views, triggers and foreign keys refer to tables by name in SQL that isn't rewritten,
+    /// so tables they may refer to are left as they are
+    fn ensure_not_referenced(&self, table: &SchemaRecord, action: &str) -> Result<()> {
+        let name = table.name.to_lowercase();
+        let referenced = self.pager.schema_table.cells.iter().any(|rec| {
+            let sql = rec.sql.to_lowercase();
+            match rec.db_object {
+                DbObject::View | DbObject::Trigger => sql.contains(&name),
+                DbObject::Table => sql.contains("references") && sql.contains(&name),
+                DbObject::Index => false,
+            }
+        });
+        if referenced {
+            bail!(Error::Unsupported(format!(
+                "{} tables that views, triggers or foreign keys refer to, like {}",
+                action, table.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Columns can only be renamed and dropped where they're referred to by name, not in expressions
+/// like CHECK constraints, generated columns, or indexes on expressions or with a WHERE clause
+fn ensure_only_named(object: &SchemaRecord, action: &str) -> Result<()> {
+    let sql = object.sql.to_lowercase();
+    let has_expressions = match object.db_object {
+        // automatic indexes have no SQL, they're on columns of the table
+        DbObject::Index if sql.is_empty() => false,
+        DbObject::Index => {
+            sql[sql.rfind(')').unwrap_or(0)..].contains("where")
+                || get_column_names(&sql)?
+                    .iter()
+                    .any(|column| column.contains('('))
+        }
+        _ => CHECK_CONSTRAINT.is_match(&sql) || GENERATED_COLUMN.is_match(&sql),
+    };
+    if has_expressions {
+        bail!(Error::Unsupported(format!(
+            "{} tables with expressions that may use them, like {}",
+            action, object.name
+        )));
+    }
+    Ok(())
+}
+
+/// The SQL of a table, or of an index, with the name of the table replaced
+fn replace_table_name(object: &SchemaRecord, name: &str) -> Result<String> {
+    let range = name_before_definitions(&object.sql)
+        .with_context(|| format!("couldn't find the table name in the SQL of {}", object.name))?;
+    Ok(replace_ranges(&object.sql, &[range], |_| name.to_string()))
+}
+
+/// Replace the text of each range, which are in order and don't overlap, with what `replacement` gives for it
+fn replace_ranges(
+    text: &str,
+    ranges: &[Range<usize>],
+    replacement: impl Fn(&str) -> String,
+) -> String {
+    let mut replaced = String::new();
+    let mut end = 0;
+    for range in ranges {
+        replaced.push_str(&text[end..range.start]);
+        replaced.push_str(&replacement(&text[range.clone()]));
+        end = range.end;
+    }
+    replaced.push_str(&text[end..]);
+    replaced
+}
+
+#[cfg(test)]
+mod alter_tests {
+    use super::*;
+    use crate::{
+        pager::{create::CreateOptions, pager::Pager},
+        vfs::{memory::MemoryVfs, vfs::OpenMode},
+    };
+
+    fn create(vfs: &MemoryVfs) -> QueryEngine {
+        Pager::create_with(vfs, "new.db", CreateOptions::default()).unwrap();
+        QueryEngine::open_with_mode(vfs, "new.db", OpenMode::ReadWrite)
+    }
+
+    fn schema(engine: &QueryEngine) -> Vec<String> {
+        let cells = &engine.pager.schema_table.cells;
+        cells
+            .iter()
+            .map(|rec| format!("{}|{}|{}", rec.name, rec.tbl_name, rec.sql))
+            .collect()
+    }
+
+    #[test]
+    fn test_renaming_tables_and_columns() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs);
+        engine
+            .execute_sql(
+                "CREATE TABLE t (id integer primary key autoincrement, a text unique, \"b c\")",
+            )
+            .unwrap();
+        engine
+            .execute_sql("CREATE INDEX t_b ON t (\"b c\", a)")
+            .unwrap();
+        engine
+            .execute_sql("INSERT INTO t (a, \"b c\") VALUES ('x', 1), ('y', 2)")
+            .unwrap();
+
+        engine.execute_sql("ALTER TABLE t RENAME TO fruit").unwrap();
+        engine
+            .execute_sql("ALTER TABLE fruit RENAME COLUMN a TO name")
+            .unwrap();
+        engine
+            .execute_sql("ALTER TABLE fruit RENAME \"b c\" TO size")
+            .unwrap();
+
+        assert_eq!(
+            schema(&engine),
+            vec![
+                "fruit|fruit|CREATE TABLE \"fruit\" (id integer primary key autoincrement, name text unique, \"size\")",
+                "sqlite_autoindex_fruit_1|fruit|",
+                "sqlite_sequence|sqlite_sequence|CREATE TABLE sqlite_sequence(name,seq)",
+                "t_b|fruit|CREATE INDEX t_b ON \"fruit\" (\"size\", name)",
+            ]
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM fruit WHERE size = '2'")
+                .unwrap(),
+            "y"
+        );
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM sqlite_sequence").unwrap(),
+            "fruit|2"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_adding_and_dropping_columns() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs);
+        engine.execute_sql("CREATE TABLE t (a, b, c)").unwrap();
+        engine
+            .execute_sql("CREATE TABLE w (k text, v, PRIMARY KEY (k)) WITHOUT ROWID")
+            .unwrap();
+        engine
+            .execute_sql("INSERT INTO t VALUES ('1', '2', '3'), ('4', '5', '6')")
+            .unwrap();
+        engine.execute_sql("INSERT INTO w VALUES ('x', 1)").unwrap();
+
+        engine
+            .execute_sql("ALTER TABLE t ADD COLUMN d integer DEFAULT '7'")
+            .unwrap();
+        engine
+            .execute_sql("ALTER TABLE w ADD e DEFAULT 'none'")
+            .unwrap();
+        assert_eq!(
+            engine
+                .execute_sql("SELECT a, d FROM t WHERE d = '7'")
+                .unwrap(),
+            "1|7\n4|7",
+            "rows written before the column was added read its default"
+        );
+        assert_eq!(engine.execute_sql("SELECT * FROM w").unwrap(), "x|1|none");
+
+        engine.execute_sql("ALTER TABLE t DROP COLUMN b").unwrap();
+        engine.execute_sql("ALTER TABLE w DROP COLUMN v").unwrap();
+        assert_eq!(
+            schema(&engine),
+            vec![
+                "t|t|CREATE TABLE t (a, c, d integer DEFAULT '7')",
+                "w|w|CREATE TABLE w (k text, e DEFAULT 'none', PRIMARY KEY (k)) WITHOUT ROWID",
+            ]
+        );
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM t").unwrap(),
+            "1|3|7\n4|6|7"
+        );
+        assert_eq!(engine.execute_sql("SELECT * FROM w").unwrap(), "x|none");
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_changes_that_cant_be_made() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs);
+        engine
+            .execute_sql("CREATE TABLE t (id integer primary key, a unique, b)")
+            .unwrap();
+        engine.execute_sql("CREATE TABLE one (a)").unwrap();
+        engine.execute_sql("CREATE INDEX t_b ON t (b)").unwrap();
+        let error =
+            |engine: &mut QueryEngine, sql| engine.execute_sql(sql).unwrap_err().to_string();
+
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE nope RENAME TO x"),
+            "no such table: nope"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t RENAME TO ONE"),
+            "there is already another table or index with this name: ONE"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t RENAME COLUMN a TO B"),
+            "error in table t after rename: duplicate column name: b"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t ADD COLUMN c UNIQUE"),
+            "Cannot add a UNIQUE column"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t ADD COLUMN c NOT NULL"),
+            "Cannot add a NOT NULL column with default value NULL"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t DROP COLUMN id"),
+            "cannot drop PRIMARY KEY column: \"id\""
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t DROP COLUMN a"),
+            "cannot drop UNIQUE column: \"a\""
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE t DROP COLUMN b"),
+            "error in index t_b after drop column: no such column: b"
+        );
+        assert_eq!(
+            error(&mut engine, "ALTER TABLE one DROP COLUMN a"),
+            "cannot drop column \"a\": no other columns exist"
+        );
+        assert_eq!(engine.pager.db_header.schema_cookie, 3);
+    }
+}
@@ -1,7 +1,8 @@
-use anyhow::{anyhow, Context, Error, Ok, Result};
+use anyhow::{Context, Ok, Result};
 use regex::Regex;
+use std::{ops::Range, sync::LazyLock};
 
-use crate::data_model::schema_record::SchemaRecord;
+use crate::{data_model::schema_record::SchemaRecord, error::Error};
 
 pub struct ColumnDefinition {
     pub name: String,
@@ -15,16 +16,12 @@ pub fn is_integer_primary_key(table_record: &SchemaRecord, col_idx: &usize) -> R
             if col_def.type_def.contains("primary key")
                 && (col_def.type_def.contains("int") || col_def.type_def.contains("integer"))
             {
-                return Ok(true);
+                Ok(true)
             } else {
-                return Ok(false);
+                Ok(false)
             }
         }
-        _ => Err(anyhow!(
-            "No column at index {} found on table {}",
-            *col_idx,
-            table_record.name
-        )),
+        _ => Err(Error::NoSuchColumn(format!("{} of {}", col_idx, table_record.name)).into()),
     }
 }
 
@@ -99,41 +96,373 @@ mod is_integer_primary_key_tests {
 
 /// Extract the column definitions from a create table sql statement
 pub fn get_column_definitions(create_table_sql: &str) -> Result<Vec<ColumnDefinition>> {
-    // Normalize by removing extra spaces and line breaks
-    let normalized_sql = create_table_sql
-        .replace('\n', " ")
-        .replace('\t', " ")
-        .trim()
-        .to_string();
-
-    // Regular expression to find columns inside parentheses
-    let re = Regex::new(r"\((.*)\)")?;
-    let captures = re
+    // Split columns by top level commas and trim whitespace
+    let column_defintions: Vec<ColumnDefinition> = split_definitions(create_table_sql)?
+        .into_iter()
+        .filter(|col_def| !is_table_constraint(col_def))
+        .map(|col_def| {
+            let (name, type_def) = split_column_name(&col_def);
+
+            ColumnDefinition {
+                name: name.to_lowercase(),
+                type_def: type_def.to_lowercase(),
+            }
+        })
+        .collect();
+
+    Ok(column_defintions)
+}
+
+// Regular expression to find columns inside parentheses, compiled once since every query looks up columns
+static OUTER_PARENTHESES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\((.*)\)").expect("valid regex"));
+
+/// Split the text inside the outer parentheses of a create statement on commas that aren't nested in parentheses
+/// i.e `a text, b decimal(10, 2), primary key (a, b)` gives three definitions
+fn split_definitions(create_sql: &str) -> Result<Vec<String>> {
+    Ok(definition_ranges(create_sql)?
+        .into_iter()
+        .map(|range| create_sql[range].replace(['\n', '\t'], " "))
+        .collect())
+}
+
+/// Where each of the definitions `split_definitions` gives is in the create statement, without the
+/// whitespace around it, so the statement can be rewritten
+pub fn definition_ranges(create_sql: &str) -> Result<Vec<Range<usize>>> {
+    // Normalize line breaks so the pattern matches across them, each is one byte so offsets don't move
+    let normalized_sql = create_sql.replace(['\n', '\t'], " ");
+
+    let captures = OUTER_PARENTHESES
         .captures(&normalized_sql)
         .context("Invalid CREATE TABLE syntax.")?;
 
     // Extract the columns string
-    let column_definitions_str = captures.get(1).context("No columns found.")?.as_str();
-    // Split columns by commas and trim whitespace
-    let column_defintions: Vec<ColumnDefinition> = column_definitions_str
-        .split(",")
-        .map(|col_def| {
-            let mut parts = col_def.trim().splitn(2, ' ');
+    let columns = captures.get(1).context("No columns found.")?;
+
+    let trimmed = |range: Range<usize>| {
+        let text = &create_sql[range.clone()];
+        let start = range.start + text.len() - text.trim_start().len();
+        start..(range.start + text.trim_end().len()).max(start)
+    };
+    let mut definitions = vec![];
+    let mut start = columns.start();
+    let mut depth = 0;
+    for (offset, ch) in columns.as_str().char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                definitions.push(trimmed(start..columns.start() + offset));
+                start = columns.start() + offset + 1;
+            }
+            _ => (),
+        }
+    }
+    definitions.push(trimmed(start..columns.end()));
+
+    Ok(definitions)
+}
 
-            let name = parts.next().unwrap();
+/// Table constraints share the column list but don't define a column
+pub fn is_table_constraint(definition: &str) -> bool {
+    let definition = definition.to_lowercase();
+    [
+        "constraint",
+        "primary key",
+        "unique",
+        "check",
+        "foreign key",
+    ]
+    .iter()
+    .any(|keyword| definition.starts_with(keyword))
+}
+
+/// Split a column definition into its name and the rest of the definition, the name may be quoted
+fn split_column_name(col_def: &str) -> (String, String) {
+    let closing_quote = match col_def.chars().next() {
+        Some('"') => Some('"'),
+        Some('`') => Some('`'),
+        Some('[') => Some(']'),
+        _ => None,
+    };
+    match closing_quote.and_then(|quote| col_def[1..].find(quote)) {
+        Some(end) => (
+            col_def[1..end + 1].to_string(),
+            col_def[end + 2..].trim().to_string(),
+        ),
+        None => {
+            let mut parts = col_def.splitn(2, ' ');
+            let name = parts.next().unwrap_or("");
             let type_def = parts.next().unwrap_or("");
+            (name.to_string(), type_def.trim().to_string())
+        }
+    }
+}
 
-            ColumnDefinition {
-                name: name.to_lowercase().to_string(),
-                type_def: type_def.to_lowercase().to_string(),
+/// Length in bytes of the name a column definition starts with, its quotes included
+fn column_name_len(col_def: &str) -> usize {
+    let closing_quote = match col_def.chars().next() {
+        Some('"') => '"',
+        Some('`') => '`',
+        Some('[') => ']',
+        _ => return col_def.find(char::is_whitespace).unwrap_or(col_def.len()),
+    };
+    col_def[1..]
+        .find(closing_quote)
+        .map_or(col_def.len(), |end| end + 2)
+}
+
+/// Where the column is named in a create statement: by its definition, in PRIMARY KEY and UNIQUE
+/// table constraints, or in the column list of an index. Names are matched ignoring case.
+/// Other table constraints and expressions aren't looked at
+pub fn column_name_ranges(create_sql: &str, column: &str) -> Result<Vec<Range<usize>>> {
+    let mut names = vec![];
+    for definition in definition_ranges(create_sql)? {
+        let text = &create_sql[definition.clone()];
+        let lowercase = text.to_lowercase();
+        let columns = if !is_table_constraint(text) {
+            vec![definition]
+        } else if lowercase.contains("primary key") || lowercase.contains("unique") {
+            let (Some(open), Some(close)) = (text.find('('), text.rfind(')')) else {
+                continue;
+            };
+            let mut columns = vec![];
+            let mut start = open + 1;
+            let commas = text[open..close].match_indices(',');
+            for offset in commas.map(|(offset, _)| open + offset).chain([close]) {
+                let item = &text[start..offset];
+                let item_start = definition.start + start + item.len() - item.trim_start().len();
+                columns.push(item_start..definition.start + offset);
+                start = offset + 1;
+            }
+            columns
+        } else {
+            continue;
+        };
+        for range in columns {
+            let text = &create_sql[range.clone()];
+            let name = range.start..range.start + column_name_len(text);
+            let (unquoted, _) = split_column_name(text);
+            if unquoted.eq_ignore_ascii_case(column) {
+                names.push(name);
             }
+        }
+    }
+    Ok(names)
+}
+
+/// Where the name just before the definitions of a create statement is, its quotes included.
+/// That's the name of the table in `CREATE TABLE` and of the indexed table in `CREATE INDEX`
+pub fn name_before_definitions(create_sql: &str) -> Option<Range<usize>> {
+    let mut name = None;
+    let mut chars = create_sql.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        if ch == '(' {
+            return name;
+        }
+        if ch.is_whitespace() {
+            continue;
+        }
+        let closing_quote = match ch {
+            '"' => Some('"'),
+            '`' => Some('`'),
+            '[' => Some(']'),
+            _ => None,
+        };
+        let mut end = start + ch.len_utf8();
+        match closing_quote {
+            // a quote is written twice to put it in the name
+            Some(quote) => loop {
+                let (offset, _) = chars.find(|(_, c)| *c == quote)?;
+                end = offset + 1;
+                if chars.next_if(|(_, c)| *c == quote).is_none() {
+                    break;
+                }
+            },
+            None => {
+                while let Some((offset, c)) =
+                    chars.next_if(|(_, c)| !c.is_whitespace() && *c != '(')
+                {
+                    end = offset + c.len_utf8();
+                }
+            }
+        }
+        name = Some(start..end);
+    }
+    None
+}
+
+/// Write a name in double quotes so it reads back as the same name whatever it holds
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Tables declared `WITHOUT ROWID` are stored in an index b-tree keyed by their primary key
+pub fn is_without_rowid(create_table_sql: &str) -> bool {
+    let Some(closing_paren) = create_table_sql.rfind(')') else {
+        return false;
+    };
+    let table_options = create_table_sql[closing_paren + 1..].to_lowercase();
+    table_options
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .windows(2)
+        .any(|words| words == ["without", "rowid"])
+}
+
+/// Names of the primary key columns in key order,
+/// declared either as a column constraint or a `PRIMARY KEY (..)` table constraint
+pub fn get_primary_key(create_table_sql: &str) -> Result<Vec<String>> {
+    let definitions = split_definitions(create_table_sql)?;
+
+    let table_constraint = definitions.iter().find_map(|definition| {
+        let lowercase = definition.to_lowercase();
+        let key_start = lowercase.find("primary key")?;
+        if !is_table_constraint(definition) {
+            return None;
+        }
+        let open = key_start + lowercase[key_start..].find('(')?;
+        let close = open + lowercase[open..].find(')')?;
+        Some(definition[open + 1..close].to_string())
+    });
+
+    if let Some(key_columns) = table_constraint {
+        return Ok(key_columns
+            .split(',')
+            .map(|key_column| split_column_name(key_column.trim()).0.to_lowercase())
+            .collect());
+    }
+
+    Ok(get_column_definitions(create_table_sql)?
+        .into_iter()
+        .filter(|col_def| col_def.type_def.contains("primary key"))
+        .map(|col_def| col_def.name)
+        .collect())
+}
+
+/// Position of the column that is another name for the rowid: an `INTEGER PRIMARY KEY` declared on the column,
+/// or the only column of the table's `PRIMARY KEY (..)`. WITHOUT ROWID tables don't have one
+pub fn get_row_id_alias(create_table_sql: &str) -> Result<Option<usize>> {
+    if is_without_rowid(create_table_sql) {
+        return Ok(None);
+    }
+    let [key] = &get_primary_key(create_table_sql)?[..] else {
+        return Ok(None);
+    };
+    let columns = get_column_definitions(create_table_sql)?;
+    Ok(columns.iter().position(|column| {
+        &column.name == key
+            && column.type_def.split_whitespace().next() == Some("integer")
+            // a quirk sqlite keeps for compatibility, `INTEGER PRIMARY KEY DESC` isn't an alias
+            && !column.type_def.contains("primary key desc")
+    }))
+}
+
+// AUTOINCREMENT is part of a PRIMARY KEY clause, after the sort order and conflict clause
+static AUTOINCREMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\bprimary\s+key\s+(?:(?:asc|desc)\s+)?(?:on\s+conflict\s+\w+\s+)?autoincrement\b",
+    )
+    .expect("valid regex")
+});
+
+/// Position of the column declared `PRIMARY KEY AUTOINCREMENT`. The word anywhere else,
+/// i.e in a column name or a default, doesn't count
+pub fn get_autoincrement_column(create_table_sql: &str) -> Result<Option<usize>> {
+    let columns = get_column_definitions(create_table_sql)?;
+    Ok(columns
+        .iter()
+        .position(|column| AUTOINCREMENT.is_match(&column.type_def)))
+}
+
+/// Columns of the automatic indexes behind the table's PRIMARY KEY and UNIQUE constraints,
+/// in the order sqlite numbers them in `sqlite_autoindex_<table>_<n>`.
+/// Each column keeps the rest of its definition so `COLLATE` and `DESC` can be read from it.
+/// The primary key of a WITHOUT ROWID table is counted even though the table itself is its index
+pub fn get_unique_constraints(create_table_sql: &str) -> Result<Vec<Vec<ColumnDefinition>>> {
+    let row_id_alias = get_row_id_alias(create_table_sql)?;
+    let definitions = split_definitions(create_table_sql)?;
+
+    let column_constraints = get_column_definitions(create_table_sql)?
+        .into_iter()
+        .enumerate()
+        .filter(|(position, column)| {
+            column.type_def.contains("unique")
+                || (column.type_def.contains("primary key") && row_id_alias != Some(*position))
         })
-        .collect();
+        .map(|(_, column)| vec![column]);
 
-    Ok(column_defintions)
+    let table_constraints = definitions
+        .iter()
+        .filter(|definition| is_table_constraint(definition))
+        .filter_map(|definition| {
+            let lowercase = definition.to_lowercase();
+            let constraint = lowercase
+                .strip_prefix("constraint")
+                .and_then(|rest| rest.trim_start().split_once(char::is_whitespace))
+                .map_or(lowercase.as_str(), |(_, rest)| rest.trim_start());
+            let is_primary_key = constraint.starts_with("primary key");
+            if !is_primary_key && !constraint.starts_with("unique") {
+                return None;
+            }
+            if is_primary_key && row_id_alias.is_some() {
+                return None;
+            }
+            let open = lowercase.find('(')?;
+            let close = open + lowercase[open..].find(')')?;
+            let columns = definition[open + 1..close]
+                .split(',')
+                .map(|column| {
+                    let (name, type_def) = split_column_name(column.trim());
+                    ColumnDefinition {
+                        name: name.to_lowercase(),
+                        type_def: type_def.to_lowercase(),
+                    }
+                })
+                .collect();
+            Some(columns)
+        });
+
+    // a constraint on the same columns as an earlier one shares its index
+    let mut constraints: Vec<Vec<ColumnDefinition>> = vec![];
+    for columns in column_constraints.chain(table_constraints) {
+        let names = |columns: &[ColumnDefinition]| {
+            columns
+                .iter()
+                .map(|column| column.name.clone())
+                .collect::<Vec<_>>()
+        };
+        if !constraints
+            .iter()
+            .any(|existing| names(existing) == names(&columns))
+        {
+            constraints.push(columns);
+        }
+    }
+    Ok(constraints)
+}
+
+// DEFAULT as a word of its own, its value follows
+static DEFAULT_CLAUSE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bdefault\s+").expect("valid regex"));
+
+/// The text following each column's `DEFAULT`, in the case it was written in, None for columns without one.
+/// The value is left for the caller to parse along with anything after it, i.e `'x' not null`
+pub fn get_column_defaults(create_table_sql: &str) -> Result<Vec<Option<String>>> {
+    Ok(split_definitions(create_table_sql)?
+        .into_iter()
+        .filter(|col_def| !is_table_constraint(col_def))
+        .map(|col_def| {
+            let (_, type_def) = split_column_name(&col_def);
+            DEFAULT_CLAUSE
+                .find(&type_def)
+                .map(|clause| type_def[clause.end()..].to_string())
+        })
+        .collect())
 }
 
-pub fn get_column_names(create_table_sql: &str) -> Result<Vec<String>, Error> {
+pub fn get_column_names(create_table_sql: &str) -> Result<Vec<String>> {
     let defintions: Vec<ColumnDefinition> = get_column_definitions(create_table_sql)?;
     let column_names = defintions
         .iter()
@@ -143,7 +472,7 @@ pub fn get_column_names(create_table_sql: &str) -> Result<Vec<String>, Error> {
     Ok(column_names)
 }
 
-pub fn find_column_index(ordered_column_names: &Vec<String>, name: &str) -> Result<usize, Error> {
+pub fn find_column_index(ordered_column_names: &[String], name: &str) -> Result<usize> {
     // Find the index of the given column name
     for (index, column_name) in ordered_column_names.iter().enumerate() {
         if column_name.eq_ignore_ascii_case(name) {
@@ -151,7 +480,7 @@ pub fn find_column_index(ordered_column_names: &Vec<String>, name: &str) -> Resu
         }
     }
 
-    Err(anyhow!("Column '{}' not found.", name))
+    Err(Error::NoSuchColumn(name.to_string()).into())
 }
 
 #[test]
@@ -171,3 +500,104 @@ fn test_find_column_index() {
     let index = find_column_index(&columns, "name").unwrap();
     assert_eq!(index, 1);
 }
+
+#[test]
+fn test_column_definitions_skip_table_constraints() {
+    let create_table_sql = r#"
+        CREATE TABLE rates (
+            "currency code" text,
+            day text,
+            rate decimal(10, 2),
+            PRIMARY KEY (`currency code`, day)
+        ) WITHOUT ROWID
+    "#;
+
+    let columns = get_column_names(create_table_sql).unwrap();
+    assert_eq!(columns, vec!["currency code", "day", "rate"]);
+    assert_eq!(
+        get_primary_key(create_table_sql).unwrap(),
+        vec!["currency code", "day"]
+    );
+    assert!(is_without_rowid(create_table_sql));
+}
+
+#[test]
+fn test_column_primary_key() {
+    let create_table_sql = "CREATE TABLE apples (id integer primary key autoincrement, name text)";
+    assert_eq!(get_primary_key(create_table_sql).unwrap(), vec!["id"]);
+    assert!(!is_without_rowid(create_table_sql));
+}
+
+#[test]
+fn test_autoincrement_column() {
+    let column = |sql| get_autoincrement_column(sql).unwrap();
+    assert_eq!(
+        column("CREATE TABLE t (a, id INTEGER PRIMARY KEY ASC AUTOINCREMENT)"),
+        Some(1)
+    );
+    assert_eq!(
+        column("CREATE TABLE t (id integer primary key, no_autoincrement_flag, note default 'autoincrement')"),
+        None
+    );
+}
+
+#[test]
+fn test_unique_constraints_in_autoindex_order() {
+    let names = |sql: &str| -> Vec<Vec<String>> {
+        get_unique_constraints(sql)
+            .unwrap()
+            .into_iter()
+            .map(|columns| columns.into_iter().map(|column| column.name).collect())
+            .collect()
+    };
+
+    assert_eq!(
+        names("CREATE TABLE t(a unique, b text primary key, c, d int unique, unique(c, a), unique(a))"),
+        vec![vec!["a"], vec!["b"], vec!["d"], vec!["c", "a"]]
+    );
+    assert_eq!(
+        names("CREATE TABLE v(x, y UNIQUE, CONSTRAINT pk PRIMARY KEY (x, y))"),
+        vec![vec!["y"], vec!["x", "y"]]
+    );
+    // the rowid needs no index
+    assert_eq!(
+        names("CREATE TABLE u(id integer, b unique, primary key(id))"),
+        vec![vec!["b"]]
+    );
+    assert_eq!(
+        get_row_id_alias("CREATE TABLE u(name text, id INTEGER PRIMARY KEY)").unwrap(),
+        Some(1)
+    );
+    assert_eq!(
+        get_row_id_alias("CREATE TABLE u(id INT PRIMARY KEY)").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_finding_names_to_rewrite() {
+    let sql =
+        "CREATE TABLE \"my \"\"t\"\"\"(a text,\n  `B` int unique, c, primary key (c, b desc))";
+    let name = name_before_definitions(sql).unwrap();
+    assert_eq!(&sql[name], "\"my \"\"t\"\"\"");
+    let ranges = definition_ranges(sql).unwrap();
+    assert_eq!(
+        ranges
+            .iter()
+            .map(|range| &sql[range.clone()])
+            .collect::<Vec<_>>(),
+        vec!["a text", "`B` int unique", "c", "primary key (c, b desc)"]
+    );
+    let names = column_name_ranges(sql, "b").unwrap();
+    assert_eq!(
+        names
+            .iter()
+            .map(|range| &sql[range.clone()])
+            .collect::<Vec<_>>(),
+        vec!["`B`", "b"]
+    );
+
+    let sql = "CREATE INDEX i ON t(a, b COLLATE nocase)";
+    assert_eq!(&sql[name_before_definitions(sql).unwrap()], "t");
+    assert_eq!(column_name_ranges(sql, "B").unwrap(), vec![23..24]);
+}
@@ -0,0 +1,489 @@
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use crate::{
+    data_model::{
+        btree::{page_header::PageType, record::Record, serial_value::SerialValue},
+        schema_record::{DbObject, SchemaRecord},
+    },
+    error::Error,
+    sql_parser::parser::{
+        Column, Comparison, CreateIndexStatement, CreateTableStatement, Operator, SelectQuery,
+    },
+};
+
+use super::{
+    column::{
+        find_column_index, get_autoincrement_column, get_column_definitions, get_column_names,
+        get_primary_key, get_row_id_alias, get_unique_constraints, is_without_rowid,
+    },
+    engine::QueryEngine,
+    index_key::{index_columns, key_columns, IndexKey},
+    schema_object::SchemaObject,
+};
+
+// how sqlite_schema is declared, it isn't in the schema itself
+const SCHEMA_TABLE_SQL: &str =
+    "CREATE TABLE sqlite_schema(type text, name text, tbl_name text, rootpage integer, sql text)";
+
+impl QueryEngine {
+    /// Add a table with an empty b-tree to the schema, along with the automatic indexes of its
+    /// PRIMARY KEY and UNIQUE constraints. False when it's already there and IF NOT EXISTS was given
+    pub fn create_table(&mut self, statement: CreateTableStatement) -> Result<bool> {
+        self.write(|engine| engine.add_table(&statement))
+    }
+
+    /// Add an index on columns of a table, filled in from the rows the table already has.
+    /// False when it's already there and IF NOT EXISTS was given
+    pub fn create_index(&mut self, statement: CreateIndexStatement) -> Result<bool> {
+        self.write(|engine| engine.add_index(&statement))
+    }
+
+    fn add_table(&mut self, statement: &CreateTableStatement) -> Result<bool> {
+        if !self.is_new_name(&statement.name, DbObject::Table, statement.if_not_exists)? {
+            return Ok(false);
+        }
+        let mut table = SchemaRecord {
+            db_object: DbObject::Table,
+            name: statement.name.clone(),
+            tbl_name: statement.name.clone(),
+            rootpage: 0,
+            sql: statement.sql.clone(),
+        };
+        // a table whose definition can't be read couldn't be used once it's created
+        let columns = get_column_definitions(&table.sql)?;
+        if let Some(column) = columns
+            .iter()
+            .map(|column| &column.name)
+            .duplicates_by(|name| name.to_lowercase())
+            .next()
+        {
+            bail!("duplicate column name: {}", column);
+        }
+        SchemaObject::try_from(table.clone())?;
+        let autoincrement = get_autoincrement_column(&table.sql)?;
+        if autoincrement.is_some() && autoincrement != get_row_id_alias(&table.sql)? {
+            bail!("AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY");
+        }
+        let constraints = get_unique_constraints(&table.sql)?;
+
+        // rows of WITHOUT ROWID tables are kept in an index b-tree ordered by their primary key
+        let without_rowid = is_without_rowid(&table.sql);
+        let page_type = if without_rowid {
+            PageType::IndexLeaf
+        } else {
+            PageType::TableLeaf
+        };
+        table.rootpage = self.pager.create_btree(page_type)?;
+        self.add_schema_record(&table)?;
+
+        let primary_key = get_primary_key(&table.sql)?;
+        for (number, columns) in constraints.iter().enumerate() {
+            let is_primary_key = columns.len() == primary_key.len()
+                && columns
+                    .iter()
+                    .zip(&primary_key)
+                    .all(|(column, key)| column.name.eq_ignore_ascii_case(key));
+            // the table is the index of its own primary key, it still takes up a number
+            if without_rowid && is_primary_key {
+                continue;
+            }
+            let index = SchemaRecord {
+                db_object: DbObject::Index,
+                name: format!("sqlite_autoindex_{}_{}", table.name, number + 1),
+                tbl_name: table.name.clone(),
+                rootpage: self.pager.create_btree(PageType::IndexLeaf)?,
+                sql: String::new(),
+            };
+            self.add_schema_record(&index)?;
+        }
+        // the largest rowid given out by each AUTOINCREMENT table is kept in sqlite_sequence
+        let has_sequence = self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .any(|rec| rec.name == "sqlite_sequence");
+        if autoincrement.is_some() && !has_sequence {
+            let sequence = SchemaRecord {
+                db_object: DbObject::Table,
+                name: "sqlite_sequence".into(),
+                tbl_name: "sqlite_sequence".into(),
+                rootpage: self.pager.create_btree(PageType::TableLeaf)?,
+                sql: "CREATE TABLE sqlite_sequence(name,seq)".into(),
+            };
+            self.add_schema_record(&sequence)?;
+        }
+        self.pager.schema_changed()?;
+        Ok(true)
+    }
+
+    fn add_index(&mut self, statement: &CreateIndexStatement) -> Result<bool> {
+        if !self.is_new_name(&statement.name, DbObject::Index, statement.if_not_exists)? {
+            return Ok(false);
+        }
+        let schema = &self.pager.schema_table.cells;
+        let table = schema
+            .iter()
+            .find(|rec| {
+                rec.db_object == DbObject::Table && rec.name.eq_ignore_ascii_case(&statement.table)
+            })
+            .cloned()
+            .ok_or_else(|| Error::NoSuchTable(statement.table.clone()))?;
+        let mut index = SchemaRecord {
+            db_object: DbObject::Index,
+            name: statement.name.clone(),
+            tbl_name: table.name.clone(),
+            rootpage: 0,
+            sql: statement.sql.clone(),
+        };
+
+        let unsupported =
+            || Error::Unsupported(format!("indexes on expressions like {}", index.name));
+        let table_columns = get_column_names(&table.sql)?;
+        let columns = index_columns(&index, &table)
+            .ok_or_else(unsupported)?
+            .iter()
+            .map(
+                |column| match find_column_index(&table_columns, &column.name) {
+                    Err(_) if column.name.contains('(') => Err(unsupported().into()),
+                    position => position.map(|position| table_columns[position].clone()),
+                },
+            )
+            .collect::<Result<Vec<String>>>()?;
+        let key = IndexKey::new(&index, &table).ok_or_else(unsupported)?;
+        let key_columns = key_columns(&index, schema).ok_or_else(unsupported)?;
+
+        index.rootpage = self.pager.create_btree(PageType::IndexLeaf)?;
+        let table = SchemaObject::try_from(table)?;
+        // rows are read with their values in table order, entries are built from the order of the record
+        let record_order = table
+            .record_columns()
+            .iter()
+            .map(|column| find_column_index(&table.columns, column))
+            .collect::<Result<Vec<usize>>>()?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: table.name.clone(),
+            where_clause: None,
+        };
+        let encoding = self.pager.db_header.text_encoding;
+        for row in self.table_db_scan(&table, &query)? {
+            let values = table.complete_row(row.record.values()?);
+            let values: Vec<SerialValue<'static>> = record_order
+                .iter()
+                .map(|position| values[*position].clone().into_owned())
+                .collect();
+            let entry = key.entry_for(row.row_header.row_id as i64, &values);
+            // a UNIQUE index can't have two entries with the same key, NULLs are never equal to each other
+            let key_len =
+                if statement.unique && !entry[..key.column_count()].contains(&SerialValue::Null) {
+                    key.column_count()
+                } else {
+                    entry.len()
+                };
+            let record = Record::new(entry, encoding);
+            if !self
+                .pager
+                .insert_entry(index.rootpage, &record, &key_columns, key_len)?
+            {
+                bail!(Error::Constraint {
+                    constraint: "UNIQUE".into(),
+                    columns: columns
+                        .iter()
+                        .map(|column| format!("{}.{}", table.name, column))
+                        .join(", "),
+                });
+            }
+        }
+        self.add_schema_record(&index)?;
+        self.pager.schema_changed()?;
+        Ok(true)
+    }
+
+    /// Whether an object can be created with the name, which no other object can have in any case.
+    /// False when an object of the same kind has it and IF NOT EXISTS was given
+    fn is_new_name(&self, name: &str, db_object: DbObject, if_not_exists: bool) -> Result<bool> {
+        if name.to_lowercase().starts_with("sqlite_") {
+            bail!("object name reserved for internal use: {}", name);
+        }
+        let existing = self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .find(|rec| rec.name.eq_ignore_ascii_case(name));
+        match existing {
+            None => Ok(true),
+            Some(existing) if if_not_exists && existing.db_object == db_object => Ok(false),
+            Some(existing) => bail!(Error::AlreadyExists {
+                object: existing.db_object.to_string(),
+                name: existing.name.clone(),
+            }),
+        }
+    }
+
+    /// Add the row of an object to `sqlite_schema`
+    fn add_schema_record(&mut self, record: &SchemaRecord) -> Result<()> {
+        let row_id = self.pager.max_row_id(1)? + 1;
+        let record = Record::new(record.values(), self.pager.db_header.text_encoding);
+        self.pager.insert_row(1, row_id, &record, false)?;
+        Ok(())
+    }
+
+    /// Write the row of the object that was called `name` in `sqlite_schema` again, i.e after it's renamed
+    pub fn replace_schema_record(&mut self, name: &str, record: &SchemaRecord) -> Result<()> {
+        let row_id = self.schema_row_id(name)?;
+        let record = Record::new(record.values(), self.pager.db_header.text_encoding);
+        self.pager.insert_row(1, row_id, &record, true)?;
+        Ok(())
+    }
+
+    /// Remove the row of an object from `sqlite_schema`
+    pub fn delete_schema_record(&mut self, name: &str) -> Result<()> {
+        let row_id = self.schema_row_id(name)?;
+        self.pager.delete_row(1, row_id)?;
+        Ok(())
+    }
+
+    /// The rowid of an object's row in `sqlite_schema`, the schema loaded from it doesn't keep them
+    fn schema_row_id(&self, name: &str) -> Result<i64> {
+        let schema = SchemaObject::try_from(SchemaRecord {
+            db_object: DbObject::Table,
+            name: "sqlite_schema".into(),
+            tbl_name: "sqlite_schema".into(),
+            rootpage: 1,
+            sql: SCHEMA_TABLE_SQL.into(),
+        })?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: schema.name.clone(),
+            where_clause: Some(Comparison {
+                operator: Operator::Equals,
+                column: "name".into(),
+                value: name.into(),
+            }),
+        };
+        let row = self.table_db_scan(&schema, &query)?.into_iter().next();
+        let row = row.ok_or_else(|| {
+            Error::corrupt_page(1, format!("{} has no row in sqlite_schema", name))
+        })?;
+        Ok(row.row_header.row_id as i64)
+    }
+}
+
+#[cfg(test)]
+mod create_tests {
+    use super::*;
+    use crate::{
+        pager::{create::CreateOptions, pager::Pager},
+        sql_parser::parser::{Parser, Statement},
+        vfs::{memory::MemoryVfs, vfs::OpenMode},
+    };
+
+    fn create(vfs: &MemoryVfs, page_size: u32) -> QueryEngine {
+        let options = CreateOptions {
+            page_size,
+            ..CreateOptions::default()
+        };
+        Pager::create_with(vfs, "new.db", options).unwrap();
+        QueryEngine::open_with_mode(vfs, "new.db", OpenMode::ReadWrite)
+    }
+
+    fn schema(engine: &QueryEngine) -> Vec<String> {
+        let cells = &engine.pager.schema_table.cells;
+        cells
+            .iter()
+            .map(|rec| format!("{}|{}|{}|{}", rec.name, rec.tbl_name, rec.rootpage, rec.sql))
+            .collect()
+    }
+
+    #[test]
+    fn test_creating_tables_and_indexes() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs, 4096);
+        engine.execute_sql("CREATE TABLE IF NOT EXISTS users (id integer primary key, email text unique, age integer);",
+        )
+        .unwrap();
+        engine
+            .execute_sql("create table pairs (a, b, c, primary key (a, b)) without rowid")
+            .unwrap();
+        engine.execute_sql("INSERT INTO users (email, age) VALUES ('ada@example.com', 36), ('alan@example.com', 41)",
+        )
+        .unwrap();
+        engine
+            .execute_sql("INSERT INTO pairs VALUES (2, 1, 'x'), (1, 2, 'y')")
+            .unwrap();
+        engine
+            .execute_sql("CREATE INDEX users_age ON Users (age DESC)")
+            .unwrap();
+        engine
+            .execute_sql("CREATE UNIQUE INDEX pairs_c ON pairs (c)")
+            .unwrap();
+
+        assert_eq!(
+            schema(&engine),
+            vec![
+                "users|users|2|CREATE TABLE users (id integer primary key, email text unique, age integer)",
+                "sqlite_autoindex_users_1|users|3|",
+                "pairs|pairs|4|CREATE TABLE pairs (a, b, c, primary key (a, b)) without rowid",
+                "users_age|users|5|CREATE INDEX users_age ON Users (age DESC)",
+                "pairs_c|pairs|6|CREATE UNIQUE INDEX pairs_c ON pairs (c)",
+            ]
+        );
+        assert_eq!(engine.pager.db_header.schema_cookie, 4);
+        assert_eq!(
+            engine
+                .execute_sql("SELECT email FROM users WHERE age = '41'")
+                .unwrap(),
+            "alan@example.com",
+            "the new index has the rows that were already in the table"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT a, b FROM pairs WHERE c = 'y'")
+                .unwrap(),
+            "1|2"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_objects_that_cant_be_created() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs, 4096);
+        engine.execute_sql("CREATE TABLE t (a, b)").unwrap();
+        engine
+            .execute_sql("INSERT INTO t VALUES (1, 2), (1, 3)")
+            .unwrap();
+        let error =
+            |engine: &mut QueryEngine, sql| engine.execute_sql(sql).unwrap_err().to_string();
+
+        assert_eq!(
+            error(&mut engine, "CREATE TABLE T (c)"),
+            "table t already exists"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE INDEX IF NOT EXISTS t ON t (a)"),
+            "table t already exists"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE TABLE sqlite_stat1 (a)"),
+            "object name reserved for internal use: sqlite_stat1"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE TABLE d (a, A)"),
+            "duplicate column name: a"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE INDEX i ON nope (a)"),
+            "no such table: nope"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE INDEX i ON t (c)"),
+            "no such column: c"
+        );
+        assert_eq!(
+            error(&mut engine, "CREATE UNIQUE INDEX i ON t (a)"),
+            "UNIQUE constraint failed: t.a"
+        );
+        let Statement::CreateTable(create) = Parser::from_sql("CREATE TABLE IF NOT EXISTS t (c)")
+            .unwrap()
+            .parse_statement()
+            .unwrap()
+        else {
+            panic!("expected a CREATE TABLE");
+        };
+        assert!(!engine.create_table(create).unwrap());
+
+        // the failed index left nothing behind
+        assert_eq!(engine.pager.schema_table.cells.len(), 1);
+        assert_eq!(engine.pager.page_count().unwrap(), 2);
+        assert_eq!(engine.pager.db_header.schema_cookie, 1);
+    }
+
+    #[test]
+    fn test_autoincrement_tables_share_sqlite_sequence() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs, 4096);
+        engine
+            .execute_sql("CREATE TABLE a (id integer primary key autoincrement, x unique)")
+            .unwrap();
+        engine
+            .execute_sql("CREATE TABLE b (id INTEGER PRIMARY KEY AUTOINCREMENT)")
+            .unwrap();
+        assert_eq!(
+            engine
+                .execute_sql("CREATE TABLE c (id text primary key autoincrement)")
+                .unwrap_err()
+                .to_string(),
+            "AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY"
+        );
+        engine
+            .execute_sql("INSERT INTO a (x) VALUES ('one'), ('two')")
+            .unwrap();
+        engine.execute_sql("INSERT INTO b (id) VALUES (7)").unwrap();
+
+        assert_eq!(
+            schema(&engine),
+            vec![
+                "a|a|2|CREATE TABLE a (id integer primary key autoincrement, x unique)",
+                "sqlite_autoindex_a_1|a|3|",
+                "sqlite_sequence|sqlite_sequence|4|CREATE TABLE sqlite_sequence(name,seq)",
+                "b|b|5|CREATE TABLE b (id INTEGER PRIMARY KEY AUTOINCREMENT)",
+            ]
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name, seq FROM sqlite_sequence")
+                .unwrap(),
+            "a|2\nb|7"
+        );
+
+        // the word only means AUTOINCREMENT after PRIMARY KEY
+        engine
+            .execute_sql("CREATE TABLE d (id integer primary key, no_autoincrement_flag, note default 'autoincrement')")
+            .unwrap();
+        engine
+            .execute_sql("INSERT INTO d (no_autoincrement_flag) VALUES (1)")
+            .unwrap();
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name, seq FROM sqlite_sequence")
+                .unwrap(),
+            "a|2\nb|7"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schema_spanning_many_pages() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs, 512);
+        for i in 0..40 {
+            engine
+                .execute_sql(&format!(
+                    "CREATE TABLE table_{} (id integer primary key, name text unique)",
+                    i
+                ))
+                .unwrap();
+        }
+        engine
+            .execute_sql("INSERT INTO table_39 (name) VALUES ('last')")
+            .unwrap();
+
+        let engine = QueryEngine::new(Pager::open_with(&vfs, "new.db").unwrap());
+        assert_eq!(
+            engine.pager.root_page.header.page_type,
+            PageType::TableInterior
+        );
+        assert_eq!(engine.pager.schema_table.cells.len(), 80);
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: "table_39".into(),
+            where_clause: None,
+        };
+        assert_eq!(engine.run_query(query).unwrap(), "1|last");
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+}
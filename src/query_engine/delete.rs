@@ -0,0 +1,174 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    data_model::btree::serial_value::SerialValue,
+    error::Error,
+    sql_parser::parser::{Column, DeleteStatement, SelectQuery},
+};
+
+use super::{
+    engine::QueryEngine,
+    schema_object::SchemaObject,
+    table_writer::{IndexWriter, TableWriter},
+};
+
+impl QueryEngine {
+    /// Remove the rows matching the WHERE clause, or every row without one, along with their index entries.
+    /// Returns the number of rows deleted
+    pub fn delete(&mut self, statement: DeleteStatement) -> Result<usize> {
+        self.write(|engine| engine.delete_rows(statement))
+    }
+
+    fn delete_rows(&mut self, statement: DeleteStatement) -> Result<usize> {
+        let writer = self.table_writer(&statement.table)?;
+        let table = SchemaObject::try_from(self.get_table_rec(&writer.name)?)?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: writer.name.clone(),
+            where_clause: statement.where_clause,
+        };
+        // the rows are found before any are deleted so the b-trees aren't changed while they're read
+        let rows = self.matching_rows(&table, &query)?;
+        for row in &rows {
+            let values = table.complete_row(row.record.values()?);
+            self.delete_row(&writer, row.row_header.row_id as i64, values)?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Remove one row, given by its values in table order, and its index entries
+    pub fn delete_row(
+        &mut self,
+        writer: &TableWriter,
+        row_id: i64,
+        values: Vec<SerialValue>,
+    ) -> Result<()> {
+        let pager = &mut self.pager;
+        let values = writer.record_order(values);
+        let deleted = match &writer.primary_key {
+            Some((key, key_columns)) => {
+                pager.delete_entry(writer.rootpage, &values, key_columns, key.len())?
+            }
+            None => pager.delete_row(writer.rootpage, row_id)?,
+        };
+        if !deleted {
+            bail!(Error::corrupt_page(
+                writer.rootpage,
+                format!(
+                    "row {} of {} disappeared while deleting it",
+                    row_id, writer.name
+                )
+            ));
+        }
+
+        for index in &writer.indexes {
+            self.delete_index_entry(index, &index.key.entry_for(row_id, &values), row_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_index_entry(
+        &mut self,
+        index: &IndexWriter,
+        entry: &[SerialValue],
+        row_id: i64,
+    ) -> Result<()> {
+        if !self
+            .pager
+            .delete_entry(index.rootpage, entry, &index.key_columns, entry.len())?
+        {
+            bail!(Error::corrupt_page(
+                index.rootpage,
+                format!("index {} has no entry for row {}", index.name, row_id)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod delete_tests {
+    use super::*;
+    use crate::vfs::memory::MemoryVfs;
+
+    #[test]
+    fn test_deleting_rows_and_their_index_entries() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        engine
+            .execute_sql("DELETE FROM users WHERE email = 'ada@example.com'")
+            .unwrap();
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "2|alan@example.com|Alan|41"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE age = '36'")
+                .unwrap(),
+            ""
+        );
+        // the UNIQUE index no longer has the email
+        engine
+            .execute_sql("INSERT INTO users (email, name) VALUES ('ada@example.com', 'Ada')")
+            .unwrap();
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+
+        engine
+            .execute_sql("DELETE FROM users WHERE name = 'nobody'")
+            .unwrap();
+        engine.execute_sql("DELETE FROM users").unwrap();
+        assert_eq!(engine.execute_sql("SELECT * FROM users").unwrap(), "");
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deleting_by_rowid_alias() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "sample.db");
+        engine
+            .execute_sql("DELETE FROM apples WHERE id = '2'")
+            .unwrap();
+        assert_eq!(
+            engine.execute_sql("SELECT id FROM apples").unwrap(),
+            "1\n3\n4"
+        );
+
+        // ids that aren't in the table, or aren't integers, match no row
+        engine
+            .execute_sql("DELETE FROM apples WHERE id = '2'; DELETE FROM apples WHERE id = 'two'")
+            .unwrap();
+        assert_eq!(
+            engine.execute_sql("SELECT id FROM apples").unwrap(),
+            "1\n3\n4"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deleted_pages_go_on_the_freelist() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/overflow.db");
+        let page_count = engine.pager.page_count().unwrap();
+        assert_eq!(engine.pager.freelist().unwrap().page_count(), 0);
+
+        engine.execute_sql("DELETE FROM documents").unwrap();
+        // only the roots of the table and its index are left
+        let free_pages = engine.pager.freelist().unwrap().page_count();
+        assert_eq!(free_pages as u32, page_count - 3);
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+
+        // new rows take their pages from the freelist rather than growing the file
+        let body = "long ".repeat(2000);
+        engine
+            .execute_sql(&format!(
+                "INSERT INTO documents (title, body) VALUES ('again', '{}')",
+                body
+            ))
+            .unwrap();
+        assert_eq!(engine.pager.page_count().unwrap(), page_count);
+        assert!(engine.pager.freelist().unwrap().page_count() < free_pages);
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+}
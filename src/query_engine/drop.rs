@@ -0,0 +1,195 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    data_model::schema_record::{DbObject, SchemaRecord},
+    error::Error,
+    sql_parser::parser::DropStatement,
+};
+
+use super::engine::QueryEngine;
+
+impl QueryEngine {
+    /// Remove a table from the schema along with its indexes and triggers, every page of their b-trees
+    /// goes on the freelist. False when there's no such table and IF EXISTS was given
+    pub fn drop_table(&mut self, statement: DropStatement) -> Result<bool> {
+        self.write(|engine| engine.remove_table(&statement))
+    }
+
+    /// Remove an index from the schema, every page of its b-tree goes on the freelist.
+    /// False when there's no such index and IF EXISTS was given
+    pub fn drop_index(&mut self, statement: DropStatement) -> Result<bool> {
+        self.write(|engine| engine.remove_index(&statement))
+    }
+
+    fn remove_table(&mut self, statement: &DropStatement) -> Result<bool> {
+        // the schema table isn't in the schema it holds
+        if ["sqlite_master", "sqlite_schema"]
+            .iter()
+            .any(|name| statement.name.eq_ignore_ascii_case(name))
+        {
+            bail!("table {} may not be dropped", statement.name);
+        }
+        let schema = &self.pager.schema_table.cells;
+        let Some(table) = schema
+            .iter()
+            .find(|rec| {
+                matches!(rec.db_object, DbObject::Table | DbObject::View)
+                    && rec.name.eq_ignore_ascii_case(&statement.name)
+            })
+            .cloned()
+        else {
+            if statement.if_exists {
+                return Ok(false);
+            }
+            bail!(Error::NoSuchTable(statement.name.clone()));
+        };
+        if table.db_object == DbObject::View {
+            bail!("use DROP VIEW to delete view {}", table.name);
+        }
+        let lowercase = table.name.to_lowercase();
+        // statistics tables are the internal ones that can be dropped
+        if lowercase.starts_with("sqlite_") && !lowercase.starts_with("sqlite_stat") {
+            bail!("table {} may not be dropped", table.name);
+        }
+        if table.rootpage == 0 {
+            bail!(Error::Unsupported(format!(
+                "dropping virtual tables like {}",
+                table.name
+            )));
+        }
+
+        // the table's indexes and triggers go with it
+        let objects: Vec<SchemaRecord> = schema
+            .iter()
+            .filter(|rec| rec.tbl_name == table.name)
+            .cloned()
+            .collect();
+        let has_sequence = schema.iter().any(|rec| rec.name == "sqlite_sequence");
+        for object in &objects {
+            if object.rootpage != 0 {
+                self.pager.drop_btree(object.rootpage)?;
+            }
+            self.delete_schema_record(&object.name)?;
+        }
+        // so does the largest rowid of an AUTOINCREMENT table
+        if has_sequence {
+            if let (Some(row_id), _) = self.sequence(&table.name)? {
+                let root = self.sqlite_sequence()?.rootpage;
+                self.pager.delete_row(root, row_id)?;
+            }
+        }
+        self.pager.schema_changed()?;
+        Ok(true)
+    }
+
+    fn remove_index(&mut self, statement: &DropStatement) -> Result<bool> {
+        let Some(index) = self
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .find(|rec| {
+                rec.db_object == DbObject::Index && rec.name.eq_ignore_ascii_case(&statement.name)
+            })
+            .cloned()
+        else {
+            if statement.if_exists {
+                return Ok(false);
+            }
+            bail!(Error::NoSuchIndex(statement.name.clone()));
+        };
+        // automatic indexes have no SQL, they go when their table does
+        if index.sql.is_empty() {
+            bail!("index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped");
+        }
+        self.pager.drop_btree(index.rootpage)?;
+        self.delete_schema_record(&index.name)?;
+        self.pager.schema_changed()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod drop_tests {
+    use super::*;
+    use crate::{
+        pager::{create::CreateOptions, pager::Pager},
+        vfs::{memory::MemoryVfs, vfs::OpenMode},
+    };
+
+    fn create(vfs: &MemoryVfs) -> QueryEngine {
+        Pager::create_with(vfs, "new.db", CreateOptions::default()).unwrap();
+        QueryEngine::open_with_mode(vfs, "new.db", OpenMode::ReadWrite)
+    }
+
+    fn names(engine: &QueryEngine) -> Vec<String> {
+        let cells = &engine.pager.schema_table.cells;
+        cells.iter().map(|rec| rec.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_dropping_tables_and_indexes() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs);
+        engine
+            .execute_sql("CREATE TABLE t (id integer primary key autoincrement, a text unique, b)")
+            .unwrap();
+        engine.execute_sql("CREATE TABLE keep (x)").unwrap();
+        engine.execute_sql("CREATE INDEX t_b ON t (b)").unwrap();
+        let long = "x".repeat(10_000);
+        for i in 0..50 {
+            engine
+                .execute_sql(&format!(
+                    "INSERT INTO t (a, b) VALUES ('{}{}', '{}')",
+                    long, i, i
+                ))
+                .unwrap();
+        }
+        engine
+            .execute_sql("INSERT INTO keep VALUES ('kept')")
+            .unwrap();
+        let page_count = engine.pager.page_count().unwrap() as usize;
+
+        engine.execute_sql("DROP INDEX t_b").unwrap();
+        assert!(!names(&engine).contains(&"t_b".to_string()));
+        engine.execute_sql("DROP TABLE T").unwrap();
+
+        assert_eq!(names(&engine), vec!["sqlite_sequence", "keep"]);
+        assert_eq!(
+            engine
+                .execute_sql("SELECT count(*) FROM sqlite_sequence")
+                .unwrap(),
+            "0"
+        );
+        // everything but the schema, sqlite_sequence and keep is free
+        assert_eq!(
+            engine.pager.freelist().unwrap().page_count(),
+            page_count - 3
+        );
+        assert_eq!(engine.execute_sql("SELECT x FROM keep").unwrap(), "kept");
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_objects_that_cant_be_dropped() {
+        let vfs = MemoryVfs::new();
+        let mut engine = create(&vfs);
+        engine.execute_sql("CREATE TABLE t (a unique)").unwrap();
+        let error =
+            |engine: &mut QueryEngine, sql| engine.execute_sql(sql).unwrap_err().to_string();
+
+        assert_eq!(error(&mut engine, "DROP TABLE nope"), "no such table: nope");
+        assert_eq!(error(&mut engine, "DROP INDEX nope"), "no such index: nope");
+        assert_eq!(
+            error(&mut engine, "DROP TABLE sqlite_schema"),
+            "table sqlite_schema may not be dropped"
+        );
+        assert_eq!(
+            error(&mut engine, "DROP INDEX sqlite_autoindex_t_1"),
+            "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+        );
+        engine.execute_sql("DROP TABLE IF EXISTS nope").unwrap();
+        engine.execute_sql("DROP INDEX IF EXISTS nope").unwrap();
+        assert_eq!(engine.pager.db_header.schema_cookie, 1);
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{Error, Result};
+use anyhow::{bail, Result};
 use itertools::Itertools;
 
 use crate::{
@@ -6,8 +6,9 @@ use crate::{
         index_leaf_cell::IndexLeafCell, page_header::PageType, record::HasRecord,
         serial_value::SerialValue, table_leaf_cell::TableLeafCell,
     },
+    error::Error,
     pager::pager::Pager,
-    sql_parser::parser::{AggregateFn, Column, Comparison, SelectQuery},
+    sql_parser::parser::{AggregateFn, Column, Comparison, Parser, SelectQuery, Statement},
 };
 
 use super::{
@@ -16,110 +17,240 @@ use super::{
     set::Set,
 };
 
-pub struct QueryEngine<'a> {
-    pub pager: Pager<'a>,
+pub struct QueryEngine {
+    pub pager: Pager,
+    // names of the open savepoints, outermost first, each is the savepoint at the same depth of the pager's stack
+    pub savepoints: Vec<String>,
+    // the outermost savepoint started the transaction, releasing it commits
+    pub savepoint_began_transaction: bool,
 }
 
-impl<'a> QueryEngine<'a> {
-    pub fn new(pager: Pager<'a>) -> Self {
-        Self { pager }
+impl QueryEngine {
+    pub fn new(pager: Pager) -> Self {
+        Self {
+            pager,
+            savepoints: vec![],
+            savepoint_began_transaction: false,
+        }
+    }
+
+    /// Run any statement, only queries have a result to show
+    pub fn execute(&mut self, statement: Statement) -> Result<String> {
+        match statement {
+            Statement::Select(query) => self.run_query(query),
+            Statement::Insert(insert) => {
+                self.insert(insert)?;
+                Ok(String::new())
+            }
+            Statement::Delete(delete) => {
+                self.delete(delete)?;
+                Ok(String::new())
+            }
+            Statement::Update(update) => {
+                self.update(update)?;
+                Ok(String::new())
+            }
+            Statement::CreateTable(create) => {
+                self.create_table(create)?;
+                Ok(String::new())
+            }
+            Statement::CreateIndex(create) => {
+                self.create_index(create)?;
+                Ok(String::new())
+            }
+            Statement::DropTable(drop) => {
+                self.drop_table(drop)?;
+                Ok(String::new())
+            }
+            Statement::DropIndex(drop) => {
+                self.drop_index(drop)?;
+                Ok(String::new())
+            }
+            Statement::AlterTable(alter) => {
+                self.alter_table(alter)?;
+                Ok(String::new())
+            }
+            Statement::Begin(kind) => {
+                self.begin_transaction(kind)?;
+                Ok(String::new())
+            }
+            Statement::Commit => {
+                self.commit_transaction()?;
+                Ok(String::new())
+            }
+            Statement::Rollback => {
+                self.rollback_transaction()?;
+                Ok(String::new())
+            }
+            Statement::Savepoint(name) => {
+                self.savepoint(name)?;
+                Ok(String::new())
+            }
+            Statement::Release(name) => {
+                self.release_savepoint(&name)?;
+                Ok(String::new())
+            }
+            Statement::RollbackTo(name) => {
+                self.rollback_to_savepoint(&name)?;
+                Ok(String::new())
+            }
+            Statement::Pragma(pragma) => self.pragma(pragma),
+        }
     }
 
-    pub fn run_query(&mut self, query: SelectQuery) -> Result<String, Error> {
+    /// Run statements separated by semicolons one after the other, showing the results that aren't empty
+    /// on their own lines like sqlite's shell. A statement that fails stops the ones after it from running
+    pub fn execute_sql(&mut self, sql: &str) -> Result<String> {
+        let mut results = vec![];
+        for statement in Parser::from_sql(sql)?.parse_statements()? {
+            let result = self.execute(statement)?;
+            if !result.is_empty() {
+                results.push(result);
+            }
+        }
+        Ok(results.join("\n"))
+    }
+
+    pub fn run_query(&self, query: SelectQuery) -> Result<String> {
+        // like a sqlite read transaction, start by dropping cached pages another process has since changed
+        let _read = self.pager.begin_read()?;
         let table_record = self.get_table_rec(query.table.as_str())?;
-        let table: SchemaObject = SchemaObject::from(table_record.clone());
+        let table = SchemaObject::try_from(table_record.clone())?;
 
         if query
             .columns
             .contains(&Column::Aggregation(AggregateFn::CountAll))
         {
-            let (page, _) = self
-                .pager
-                .read_page(table.rootpage)
-                .expect("couldn't find page");
+            let (page, _) = self.pager.read_page(table.rootpage)?;
 
-            if page.header.page_type == PageType::TableLeaf {
-                return Ok(page.header.cell_count.to_string());
+            if matches!(
+                page.header.page_type,
+                PageType::TableLeaf | PageType::IndexLeaf
+            ) {
+                Ok(page.header.cell_count.to_string())
             } else {
-                unimplemented!("Count records on tables that span multiple pages")
+                bail!(Error::Unsupported(
+                    "counting the rows of tables that span multiple pages".into()
+                ))
             }
         } else {
             // get the array index of the queried columns
             let mut queried_col_idxs: Set<usize> = Set::new();
-            query.columns.iter().for_each(|column| match column {
-                Column::Regular(col_name) => {
-                    let idx = find_column_index(&table.columns, col_name)
-                        .expect("couldn't find column name");
-                    queried_col_idxs.push(idx);
-                }
-                Column::All => {
-                    // add the remaining columns to the col indx vec
-                    for col_name in &table.columns {
-                        let idx = find_column_index(&table.columns, &col_name)
-                            .expect("couldn't find column name");
-                        queried_col_idxs.push(idx);
+            for column in &query.columns {
+                match column {
+                    Column::Regular(col_name) => {
+                        queried_col_idxs.push(find_column_index(&table.columns, col_name)?);
                     }
+                    Column::All => {
+                        // add the remaining columns to the col indx vec
+                        for col_name in &table.columns {
+                            queried_col_idxs.push(find_column_index(&table.columns, col_name)?);
+                        }
+                    }
+                    _ => (),
                 }
-                _ => (),
-            });
-
-            let records: Vec<TableLeafCell> = match &query.where_clause {
-                Some(comparison) => match self.find_index(&query) {
-                    Some(index) => self.search_with_index(table, index, &comparison)?,
-                    None => self.table_db_scan(&table, &query)?,
-                },
-                None => self.table_db_scan(&table, &query)?,
-            };
+            }
+
+            let records = self.matching_rows(&table, &query)?;
 
-            // collect the queried columns from the records
-            let col_values = records
+            // collect the queried columns from the records, decoding only those columns
+            let rows = records
                 .iter()
                 .map(|cell| {
-                    queried_col_idxs
+                    let values = queried_col_idxs
                         .iter()
                         .map(|col_idx| {
-                            let v = cell
-                                .record
-                                .values
-                                .get(*col_idx)
-                                .expect("failed to find column index");
+                            let v = match cell.record.value(*col_idx)? {
+                                Some(v) => v,
+                                None => table.defaults[*col_idx].clone(),
+                            };
                             // When a table includes an INTEGER PRIMARY KEY column then that column appears in the record as a NULL value and aliases rowid.
-                            if *v == SerialValue::Null
-                                && is_integer_primary_key(&table_record, col_idx).unwrap()
+                            if v == SerialValue::Null
+                                && is_integer_primary_key(&table_record, col_idx)?
                             {
-                                SerialValue::Int(cell.row_id() as i64)
+                                Ok(SerialValue::Int(cell.row_header.row_id as i64))
                             } else {
-                                v.clone()
+                                Ok(v)
                             }
                         })
-                        .join("|")
+                        .collect::<Result<Vec<SerialValue>>>()?;
+                    Ok(values.iter().join("|"))
                 })
-                .join("\n");
-            return Ok(col_values);
+                .collect::<Result<Vec<String>>>()?;
+            Ok(rows.join("\n"))
+        }
+    }
+
+    /// The rows of the query's table that match its WHERE clause, found with an index when there is one on the column.
+    /// Rows are looked up by rowid when the column is the INTEGER PRIMARY KEY
+    pub fn matching_rows(
+        &self,
+        table: &SchemaObject,
+        query: &SelectQuery,
+    ) -> Result<Vec<TableLeafCell<'static>>> {
+        match &query.where_clause {
+            // secondary indexes on WITHOUT ROWID tables refer to rows by primary key rather than rowid
+            Some(_) if table.without_rowid => self.table_db_scan(table, query),
+            Some(comparison)
+                if table.row_id_alias
+                    == Some(find_column_index(&table.columns, &comparison.column)?) =>
+            {
+                self.row_id_search(table, comparison)
+            }
+            Some(comparison) => match self.find_index(query) {
+                Some(index) => self.search_with_index(table, index, comparison),
+                None => self.table_db_scan(table, query),
+            },
+            None => self.table_db_scan(table, query),
         }
     }
 
     fn search_with_index(
-        &mut self,
-        table: SchemaObject,
+        &self,
+        table: &SchemaObject,
         index: SchemaObject,
         comparison: &Comparison,
-    ) -> Result<Vec<TableLeafCell>> {
+    ) -> Result<Vec<TableLeafCell<'static>>> {
         // Binary search index
-        let mut matching_index_leaf_cells: Vec<IndexLeafCell> = vec![];
+        let mut matching_index_leaf_cells: Vec<IndexLeafCell<'static>> = vec![];
         self.index_binary_search(
             index.rootpage,
-            &comparison,
+            comparison,
             &mut matching_index_leaf_cells,
             &index,
-        );
+        )?;
 
         let rows_to_find = matching_index_leaf_cells
             .iter()
             .map(|c| c.row_id())
-            .collect();
+            .collect::<Result<_>>()
+            .map_err(|e| Error::on_page(index.rootpage, e))?;
         // Binary search table
-        return self.table_binary_search(&table, rows_to_find);
+        self.table_binary_search(table, rows_to_find)
+    }
+}
+
+/// Opening the databases tests run statements against
+#[cfg(test)]
+mod test_databases {
+    use crate::vfs::{
+        memory::MemoryVfs,
+        vfs::{OpenMode, Vfs},
+    };
+
+    use super::*;
+
+    impl QueryEngine {
+        /// Copy a fixture into `vfs` and open it for writing, so tests never change the file on disk
+        pub fn open_fixture(vfs: &MemoryVfs, path: &str) -> Self {
+            vfs.insert(path, std::fs::read(path).unwrap());
+            Self::open_with_mode(vfs, path, OpenMode::ReadWrite)
+        }
+
+        /// Open a database a test created in `vfs`
+        pub fn open_with_mode(vfs: &dyn Vfs, path: &str, mode: OpenMode) -> Self {
+            Self::new(Pager::open_with_mode(vfs, path, mode).unwrap())
+        }
     }
 }
 
@@ -127,16 +258,14 @@ impl<'a> QueryEngine<'a> {
 mod execution_engine_tests {
     use super::*;
     use crate::sql_parser::parser::{AggregateFn, Column, Operator};
-    use std::{fs::File, path::Path};
+    use std::path::Path;
 
     #[test]
     fn test_select_count() {
         let path = Path::new("sample.db");
-        let mut file = File::open(path).expect("Failed to open sample.db");
-
         // Create an instance of ExecutionEngine
-        let pager = Pager::new(&mut file).expect("Failed to initialize pager");
-        let mut engine = QueryEngine::new(pager);
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let engine = QueryEngine::new(pager);
 
         let query = SelectQuery {
             table: "apples".to_string(),
@@ -152,11 +281,9 @@ mod execution_engine_tests {
     #[test]
     fn test_select_all() {
         let path = Path::new("sample.db");
-        let mut file = File::open(path).expect("Failed to open sample.db");
-
         // Create an instance of ExecutionEngine
-        let pager = Pager::new(&mut file).expect("Failed to initialize pager");
-        let mut engine = QueryEngine::new(pager);
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let engine = QueryEngine::new(pager);
 
         let query = SelectQuery {
             table: "apples".to_string(),
@@ -174,10 +301,8 @@ mod execution_engine_tests {
     #[test]
     fn test_searching_by_index() {
         let path = Path::new("companies.db");
-        let mut file = File::open(path).expect("Failed to open companies.db");
-
-        let pager = Pager::new(&mut file).expect("Failed to initialize pager");
-        let mut engine = QueryEngine::new(pager);
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let engine = QueryEngine::new(pager);
         let query = SelectQuery {
             columns: vec![Column::All],
             table: "companies".into(),
@@ -188,13 +313,85 @@ mod execution_engine_tests {
             }),
         };
 
-        let table = SchemaObject::from(engine.get_table_rec("companies").unwrap());
-        let index = SchemaObject::from(engine.find_index(&query).unwrap());
+        let table = SchemaObject::try_from(engine.get_table_rec("companies").unwrap()).unwrap();
+        let index = engine.find_index(&query).unwrap();
 
         let matching_recs = engine
-            .search_with_index(table, index, &query.where_clause.unwrap())
+            .search_with_index(&table, index, &query.where_clause.unwrap())
             .unwrap();
 
         assert_eq!(matching_recs.len(), 288);
     }
+
+    #[test]
+    fn test_reading_overflowing_records() {
+        let path = Path::new("fixtures/overflow.db");
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let engine = QueryEngine::new(pager);
+
+        // titles spill out of the index cells and bodies span several overflow pages
+        let title = format!("document 07 {}", "abcdefghij".repeat(40));
+        let query = SelectQuery {
+            columns: vec![Column::Regular("body".into())],
+            table: "documents".into(),
+            where_clause: Some(Comparison {
+                column: "title".into(),
+                operator: Operator::Equals,
+                value: title,
+            }),
+        };
+
+        let result = engine.run_query(query).unwrap();
+        let expected_body: String = (0..400).map(|j| format!("7:{};", j)).collect();
+        assert_eq!(result, expected_body);
+    }
+
+    #[test]
+    fn test_reading_utf16_databases() {
+        for path in ["fixtures/utf16le.db", "fixtures/utf16be.db"] {
+            let pager = Pager::open(path).expect("Failed to initialize pager");
+            let engine = QueryEngine::new(pager);
+
+            // words are looked up through idx_words_word so the literal has to be ordered like the stored text
+            for (word, expected) in [
+                ("Ōkami03", "51|Ōkami03|lang1"),
+                ("apple00", "1|apple00|lang1"),
+                ("𝄞clef11", "192|𝄞clef11|lang2"),
+                ("Ａｂｃ07", "122|Ａｂｃ07|lang2"),
+            ] {
+                let query = SelectQuery {
+                    columns: vec![Column::All],
+                    table: "words".into(),
+                    where_clause: Some(Comparison {
+                        column: "word".into(),
+                        operator: Operator::Equals,
+                        value: word.into(),
+                    }),
+                };
+                assert_eq!(engine.run_query(query).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors_say_what_went_wrong() {
+        let engine = QueryEngine::new(Pager::open("sample.db").unwrap());
+        let select = |table: &str, column: &str| {
+            let query = SelectQuery {
+                columns: vec![Column::Regular(column.into())],
+                table: table.into(),
+                where_clause: None,
+            };
+            engine
+                .run_query(query)
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap()
+        };
+
+        assert!(matches!(select("pears", "name"), Error::NoSuchTable(table) if table == "pears"));
+        assert!(
+            matches!(select("apples", "size"), Error::NoSuchColumn(column) if column == "size")
+        );
+    }
 }
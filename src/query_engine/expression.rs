@@ -0,0 +1,198 @@
+use anyhow::Result;
+
+use crate::{
+    data_model::btree::serial_value::SerialValue,
+    sql_parser::parser::{ArithmeticOperator, Expression},
+};
+
+use super::{column::find_column_index, table_writer::literal_value};
+
+/// Fail on the first column the expression uses that isn't one of `columns`
+pub fn check_columns(expression: &Expression, columns: &[String]) -> Result<()> {
+    match expression {
+        Expression::Literal(_) => Ok(()),
+        Expression::Column(name) => find_column_index(columns, name).map(|_| ()),
+        Expression::Negate(operand) => check_columns(operand, columns),
+        Expression::Arithmetic(left, _, right) => {
+            check_columns(left, columns)?;
+            check_columns(right, columns)
+        }
+    }
+}
+
+/// Compute an expression for a row whose values are in the order of `columns`, with sqlite's arithmetic:
+/// NULL in gives NULL out, text and blobs count as the number they start with and integers that
+/// overflow or are divided by a real become reals. Dividing by zero gives NULL
+/// https://www.sqlite.org/lang_expr.html#operators_and_parse_affecting_attributes
+pub fn evaluate(
+    expression: &Expression,
+    columns: &[String],
+    row: &[SerialValue<'static>],
+) -> Result<SerialValue<'static>> {
+    Ok(match expression {
+        Expression::Literal(literal) => literal_value(literal.clone()),
+        Expression::Column(name) => row[find_column_index(columns, name)?].clone(),
+        Expression::Negate(operand) => match numeric(&evaluate(operand, columns, row)?) {
+            Some(SerialValue::Int(integer)) => integer
+                .checked_neg()
+                .map_or(SerialValue::Float(-(integer as f64)), SerialValue::Int),
+            Some(SerialValue::Float(real)) => SerialValue::Float(-real),
+            _ => SerialValue::Null,
+        },
+        Expression::Arithmetic(left, operator, right) => {
+            let left = numeric(&evaluate(left, columns, row)?);
+            let right = numeric(&evaluate(right, columns, row)?);
+            match (left, right) {
+                (Some(SerialValue::Int(left)), Some(SerialValue::Int(right))) => {
+                    integer_arithmetic(left, *operator, right)
+                }
+                (Some(left), Some(right)) => real_arithmetic(real(&left), *operator, real(&right)),
+                _ => SerialValue::Null,
+            }
+        }
+    })
+}
+
+fn integer_arithmetic(left: i64, operator: ArithmeticOperator, right: i64) -> SerialValue<'static> {
+    let result = match operator {
+        ArithmeticOperator::Add => left.checked_add(right),
+        ArithmeticOperator::Subtract => left.checked_sub(right),
+        ArithmeticOperator::Multiply => left.checked_mul(right),
+        ArithmeticOperator::Divide if right == 0 => return SerialValue::Null,
+        ArithmeticOperator::Divide => left.checked_div(right),
+        ArithmeticOperator::Remainder if right == 0 => return SerialValue::Null,
+        // i64::MIN % -1 overflows but is 0
+        ArithmeticOperator::Remainder => Some(left.checked_rem(right).unwrap_or(0)),
+    };
+    match result {
+        Some(integer) => SerialValue::Int(integer),
+        None => real_arithmetic(left as f64, operator, right as f64),
+    }
+}
+
+fn real_arithmetic(left: f64, operator: ArithmeticOperator, right: f64) -> SerialValue<'static> {
+    let result = match operator {
+        ArithmeticOperator::Add => left + right,
+        ArithmeticOperator::Subtract => left - right,
+        ArithmeticOperator::Multiply => left * right,
+        ArithmeticOperator::Divide if right == 0.0 => return SerialValue::Null,
+        ArithmeticOperator::Divide => left / right,
+        // the remainder of reals is taken of their integer parts
+        ArithmeticOperator::Remainder => {
+            let (left, right) = (left as i64, right as i64);
+            if right == 0 {
+                return SerialValue::Null;
+            }
+            left.checked_rem(right).unwrap_or(0) as f64
+        }
+    };
+    if result.is_nan() {
+        SerialValue::Null
+    } else {
+        SerialValue::Float(result)
+    }
+}
+
+fn real(number: &SerialValue) -> f64 {
+    match number {
+        SerialValue::Int(integer) => *integer as f64,
+        SerialValue::Float(real) => *real,
+        _ => unreachable!("numeric only gives integers and reals"),
+    }
+}
+
+/// The integer or real a value stands for in arithmetic, None for NULL
+fn numeric(value: &SerialValue) -> Option<SerialValue<'static>> {
+    match value {
+        SerialValue::Null => None,
+        SerialValue::Int(integer) => Some(SerialValue::Int(*integer)),
+        SerialValue::Float(real) => Some(SerialValue::Float(*real)),
+        SerialValue::Text(text) => Some(number_prefix(text)),
+        SerialValue::Blob(bytes) => Some(number_prefix(&String::from_utf8_lossy(bytes))),
+    }
+}
+
+/// The number at the start of text, i.e 12 for `12 apples`, or 0 when it doesn't start with one
+fn number_prefix(text: &str) -> SerialValue<'static> {
+    let text = text.trim_start();
+    let bytes = text.as_bytes();
+    let digits_from = |mut end: usize| {
+        while end < bytes.len() && bytes[end].is_ascii_digit() {
+            end += 1;
+        }
+        end
+    };
+
+    let sign = usize::from(text.starts_with(['-', '+']));
+    let mut end = digits_from(sign);
+    let mut is_integer = true;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits_from(end + 1);
+        if fraction_end > end + 1 || end > sign {
+            end = fraction_end;
+            is_integer = false;
+        }
+    }
+    if end == sign {
+        return SerialValue::Int(0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent_sign = usize::from(matches!(bytes.get(end + 1), Some(b'-' | b'+')));
+        let exponent_end = digits_from(end + 1 + exponent_sign);
+        if exponent_end > end + 1 + exponent_sign {
+            end = exponent_end;
+            is_integer = false;
+        }
+    }
+
+    let number = &text[..end];
+    match number.parse::<i64>() {
+        Ok(integer) if is_integer => SerialValue::Int(integer),
+        _ => SerialValue::Float(number.parse().unwrap_or(0.0)),
+    }
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use crate::sql_parser::parser::Parser;
+
+    use super::*;
+
+    fn evaluate_sql(sql: &str) -> SerialValue<'static> {
+        let expression = Parser::from_sql(sql).unwrap().parse_expression().unwrap();
+        let columns = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let row = vec![
+            SerialValue::Int(7),
+            SerialValue::Text("2.5 apples".into()),
+            SerialValue::Null,
+        ];
+        evaluate(&expression, &columns, &row).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(evaluate_sql("a + 1"), SerialValue::Int(8));
+        assert_eq!(evaluate_sql("a - -1 * 2"), SerialValue::Int(9));
+        assert_eq!(evaluate_sql("(a - 1) / 4"), SerialValue::Int(1));
+        assert_eq!(evaluate_sql("a % 4"), SerialValue::Int(3));
+        assert_eq!(evaluate_sql("-a"), SerialValue::Int(-7));
+        assert_eq!(evaluate_sql("a / 2.0"), SerialValue::Float(3.5));
+        assert_eq!(evaluate_sql("a * b"), SerialValue::Float(17.5));
+        assert_eq!(evaluate_sql("'x' + a"), SerialValue::Int(7));
+        assert_eq!(evaluate_sql("' 1e2x' + 0"), SerialValue::Float(100.0));
+        assert_eq!(evaluate_sql("a + c"), SerialValue::Null);
+        assert_eq!(evaluate_sql("a / 0"), SerialValue::Null);
+        assert_eq!(evaluate_sql("a % 0.5"), SerialValue::Null);
+        assert_eq!(
+            evaluate_sql("9223372036854775807 + a"),
+            SerialValue::Float(9223372036854775807.0 + 7.0)
+        );
+
+        let columns = vec!["a".to_string()];
+        let expression = Parser::from_sql("a + d")
+            .unwrap()
+            .parse_expression()
+            .unwrap();
+        assert!(check_columns(&expression, &columns).is_err());
+    }
+}
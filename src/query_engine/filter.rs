@@ -1,91 +1,134 @@
-use anyhow::{Error, Result};
+use std::{cmp::Ordering, str::FromStr};
+
+use anyhow::{bail, Result};
 
 use crate::{
-    data_model::btree::{record::Record, serial_value::SerialValue},
+    data_model::{
+        btree::{
+            record::RecordView,
+            serial_value::{encode_text, SerialValue},
+        },
+        db_header::TextEncoding,
+    },
+    error::Error,
     sql_parser::parser::{Comparison, Operator},
 };
 
 use super::column::find_column_index;
 
+/// Parse a comparison value as the type of the column's value it is compared with
+fn parse_value<T: FromStr>(comparison_value: &str, expected: &str) -> Result<T> {
+    comparison_value.parse().map_err(|_| {
+        Error::TypeMismatch {
+            expected: expected.into(),
+            found: format!("'{}'", comparison_value),
+        }
+        .into()
+    })
+}
+
 pub fn check_equality(serial_value: &SerialValue, comparison_value: &String) -> Result<bool> {
     let equality: bool = match serial_value {
         SerialValue::Null => comparison_value.eq_ignore_ascii_case("null"),
-        SerialValue::Int(val) => *val == comparison_value.parse()?,
-        SerialValue::Float(val) => *val == comparison_value.parse()?,
+        SerialValue::Int(val) => *val == parse_value(comparison_value, "an integer")?,
+        SerialValue::Float(val) => *val == parse_value(comparison_value, "a real")?,
         SerialValue::Text(val) => val == comparison_value,
-        SerialValue::Blob(_) => todo!("equality of blobs"),
+        SerialValue::Blob(_) => bail!(Error::Unsupported("comparing blobs".into())),
     };
     Ok(equality)
 }
 
-pub fn greater_than(serial_value: &SerialValue, comparison_value: &String) -> Result<bool> {
-    let greater: bool = match serial_value {
-        SerialValue::Null => false,
-        SerialValue::Int(val) => *val >= comparison_value.parse()?,
-        SerialValue::Float(val) => *val >= comparison_value.parse()?,
-        SerialValue::Text(val) => val >= comparison_value,
-        SerialValue::Blob(_) => todo!("ordering of blobs"),
+/// Order a stored value against a comparison value.
+/// Text is ordered by its encoded bytes the way sqlite's BINARY collation does
+pub fn compare(
+    serial_value: &SerialValue,
+    comparison_value: &str,
+    encoding: TextEncoding,
+) -> Result<Ordering> {
+    let ordering = match serial_value {
+        // NULL sorts before every other value
+        SerialValue::Null => Ordering::Less,
+        SerialValue::Int(val) => val.cmp(&parse_value(comparison_value, "an integer")?),
+        SerialValue::Float(val) => val
+            .partial_cmp(&parse_value(comparison_value, "a real")?)
+            .ok_or_else(|| Error::TypeMismatch {
+                expected: "a number".into(),
+                found: "NaN".into(),
+            })?,
+        SerialValue::Text(val) => {
+            encode_text(val, encoding).cmp(&encode_text(comparison_value, encoding))
+        }
+        SerialValue::Blob(_) => bail!(Error::Unsupported("ordering blobs".into())),
     };
-    Ok(greater)
+    Ok(ordering)
 }
 
-/// Get a closure that can filter records according to the comparison
+pub fn greater_than(
+    serial_value: &SerialValue,
+    comparison_value: &str,
+    encoding: TextEncoding,
+) -> Result<bool> {
+    Ok(compare(serial_value, comparison_value, encoding)? != Ordering::Less)
+}
+
+/// Get a closure that can filter records according to the comparison.
+/// Only the compared column is decoded, a value that can't be decoded is an error.
+/// A record without a value for the column is compared using its default, when it has one
 pub fn create_record_filter<'a>(
-    ordered_column_names: &'a Vec<String>,
+    ordered_column_names: &'a [String],
+    defaults: &'a [SerialValue<'static>],
     comparison: &'a Comparison,
-) -> Result<impl Fn(&Record) -> bool + 'a, Error> {
+) -> Result<impl Fn(&RecordView) -> Result<bool> + 'a> {
     // get index of column
     let idx = find_column_index(ordered_column_names, &comparison.column)?;
     // Return a closure that can be used for filtering
-    Ok(move |rec: &Record| {
-        if let Some(serial_value) = rec.values.get(idx) {
-            match comparison.operator {
-                Operator::Equals => {
-                    check_equality(serial_value, &comparison.value).unwrap_or(false)
-                }
-            }
-        } else {
-            false
-        }
+    Ok(move |rec: &RecordView| {
+        let serial_value = match (rec.value(idx)?, defaults.get(idx)) {
+            (Some(serial_value), _) => serial_value,
+            (None, Some(default)) => default.clone(),
+            (None, None) => return Ok(false),
+        };
+        let matches = match comparison.operator {
+            Operator::Equals => check_equality(&serial_value, &comparison.value).unwrap_or(false),
+        };
+        Ok(matches)
     })
 }
 
-pub fn filter_items(
-    items: &Vec<Record>,
-    ordered_column_names: &Vec<String>,
+pub fn filter_items<'a>(
+    items: &[RecordView<'a>],
+    ordered_column_names: &[String],
     comparison: &Comparison,
-) -> Result<Vec<Record>, Error> {
-    let record_predicate = create_record_filter(ordered_column_names, comparison)?;
-
-    Ok(items
-        .iter()
-        .filter(|r| record_predicate(r))
-        .cloned()
-        .collect())
+) -> Result<Vec<RecordView<'a>>> {
+    let record_predicate = create_record_filter(ordered_column_names, &[], comparison)?;
+
+    let mut matching = vec![];
+    for item in items {
+        if record_predicate(item)? {
+            matching.push(item.clone());
+        }
+    }
+    Ok(matching)
 }
 
 #[cfg(test)]
 mod apply_filter_test {
+    use std::borrow::Cow;
+
     use super::*;
-    use crate::{
-        data_model::btree::{
-            record::Record, record_header::RecordHeader, serial_type::SerialType,
-            serial_value::SerialValue,
-        },
-        sql_parser::parser::{Comparison, Operator},
-    };
+    use crate::sql_parser::parser::{Comparison, Operator};
+
+    /// A record holding the float 34
+    fn float_record() -> RecordView<'static> {
+        let payload = [0x02, 0x07, 0x40, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        RecordView::new(2, Cow::Owned(payload.to_vec()), TextEncoding::Utf8).unwrap()
+    }
 
     #[test]
     fn test_apply_filter() {
         let column_name = "numbers".to_string();
         let ordered_column_names: Vec<String> = vec![column_name.clone()];
-        let recs = vec![Record {
-            header: RecordHeader {
-                size: 2,
-                column_types: vec![SerialType::Float64],
-            },
-            values: vec![SerialValue::Float(34.)],
-        }];
+        let recs = vec![float_record()];
 
         let cmp = Comparison {
             value: "34".to_string(),
@@ -101,13 +144,7 @@ mod apply_filter_test {
     fn test_apply_filter_removes_value() {
         let column_name = "numbers".to_string();
         let ordered_column_names: Vec<String> = vec![column_name.clone()];
-        let recs = vec![Record {
-            header: RecordHeader {
-                size: 2,
-                column_types: vec![SerialType::Float64],
-            },
-            values: vec![SerialValue::Float(34.)],
-        }];
+        let recs = vec![float_record()];
 
         let cmp = Comparison {
             value: "1".to_string(),
@@ -1,18 +1,23 @@
+use anyhow::{bail, Result};
+
 use crate::{
     data_model::{
         btree::{
             index_interior_cell::IndexInteriorCell, index_leaf_cell::IndexLeafCell,
-            page_header::PageType,
+            page::ensure_depth, page_header::PageType,
         },
         schema_record::DbObject,
         table::Table,
     },
+    error::Error,
     sql_parser::parser::{Comparison, SelectQuery},
 };
 
-use super::{engine::QueryEngine, filter::greater_than, schema_object::SchemaObject};
+use std::cmp::Ordering;
+
+use super::{engine::QueryEngine, filter::compare, schema_object::SchemaObject};
 
-impl<'a> QueryEngine<'a> {
+impl QueryEngine {
     /*
     Finds an index for the columns
     For multi-column indexes querying a single column it will match for the first column in the index
@@ -27,83 +32,108 @@ impl<'a> QueryEngine<'a> {
             .schema_table
             .cells
             .iter()
-            .filter(|s_rec| s_rec.db_object == DbObject::Index && s_rec.tbl_name == query.table)
-            .map(|s_rec| SchemaObject::from(s_rec.clone()))
+            .filter(|s_rec| {
+                s_rec.db_object == DbObject::Index
+                    && s_rec.tbl_name == query.table
+                    && !s_rec.sql.is_empty()
+            })
+            // an index whose sql can't be parsed isn't used, the table is scanned instead
+            .filter_map(|s_rec| SchemaObject::try_from(s_rec.clone()).ok())
             .find(|index| queried_columns.len() == 1 && *queried_columns[0] == index.columns[0])
     }
 
-    /// collect row-ids which match the comparison on the index
+    /// collect the index entries whose first column matches the comparison,
+    /// also used to look up WITHOUT ROWID tables by their primary key
     pub fn index_binary_search(
-        &mut self,
+        &self,
+        page_number: u32,
+        comparison: &Comparison,
+        index_records: &mut Vec<IndexLeafCell<'static>>,
+        index: &SchemaObject,
+    ) -> Result<()> {
+        self.recursive_index_search(page_number, comparison, index_records, index, 0)
+    }
+
+    fn recursive_index_search(
+        &self,
         page_number: u32,
         comparison: &Comparison,
-        index_records: &mut Vec<IndexLeafCell>,
+        index_records: &mut Vec<IndexLeafCell<'static>>,
         index: &SchemaObject,
-    ) {
-        let (page, mut buf) = self
-            .pager
-            .read_page(page_number)
-            .expect("failed to read index page");
+        depth: usize,
+    ) -> Result<()> {
+        ensure_depth(page_number, depth)?;
+        let (page, buf) = self.pager.read_page(page_number)?;
+        let encoding = self.pager.db_header.text_encoding;
 
         match page.header.page_type {
             PageType::IndexInterior => {
-                let interior_table = Table::<IndexInteriorCell>::new(&mut buf, &page.cell_pointers);
-                drop(buf);
-                for cell in &interior_table.cells {
+                let interior_table = Table::<IndexInteriorCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
+                // Interior cells of an index b-tree are entries too, keys equal to the compared
+                // value can be on this page as well as in the children on either side of them
+                for cell in interior_table.cells {
                     // todo: instead of just assuming the indexed columm is the first value find it properly
-                    let serial_value = cell.record.values.first().expect("failed to get first");
-                    match greater_than(serial_value, &comparison.value) {
-                        Ok(is_greater) => {
-                            if is_greater {
-                                self.index_binary_search(
-                                    cell.left_child,
-                                    comparison,
-                                    index_records,
-                                    index,
-                                );
-                            }
-                        }
-                        Err(_) => todo!("Deal with comparison error"),
+                    let serial_value = cell.record.value(0)?.ok_or_else(|| {
+                        Error::corrupt_page(page_number, "index entry has no values")
+                    })?;
+                    let ordering = compare(&serial_value, &comparison.value, encoding)?;
+                    if ordering == Ordering::Less {
+                        continue;
+                    }
+                    self.recursive_index_search(
+                        cell.left_child,
+                        comparison,
+                        index_records,
+                        index,
+                        depth + 1,
+                    )?;
+                    if ordering == Ordering::Greater {
+                        return Ok(());
                     }
+                    index_records.push(IndexLeafCell::from(cell).retain(buf.get_ref()));
                 }
+                // every key on the page is at most the compared value so matches may continue right
                 match page.header.rightmost_pointer {
-                    Some(rightmost_pointer) => {
-                        // todo: error handling
-                        let last_key = &interior_table
-                            .cells
-                            .last()
-                            .unwrap()
-                            .record
-                            .values
-                            .first()
-                            .unwrap();
-
-                        if !greater_than(last_key, &comparison.value).unwrap() {
-                            self.index_binary_search(
-                                rightmost_pointer,
-                                comparison,
-                                index_records,
-                                index,
-                            );
-                        }
-                    }
-                    _ => panic!("Interior table page header missing right most pointer"),
+                    Some(rightmost_pointer) => self.recursive_index_search(
+                        rightmost_pointer,
+                        comparison,
+                        index_records,
+                        index,
+                        depth + 1,
+                    ),
+                    None => bail!(Error::corrupt_page(
+                        page_number,
+                        "interior index page header missing right most pointer"
+                    )),
                 }
             }
             PageType::IndexLeaf => {
-                let table = Table::<IndexLeafCell>::new(&mut buf, &page.cell_pointers);
-                index_records.append(&mut table.filter_cells(&index.columns, comparison).unwrap());
-            }
-            PageType::TableLeaf | PageType::TableInterior => {
-                panic!("Found a Table page while traversing an Index BTree")
+                let table = Table::<IndexLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
+                let cells = table.filter_cells(&index.record_columns(), &[], comparison)?;
+                index_records.extend(cells.into_iter().map(|cell| cell.retain(buf.get_ref())));
+                Ok(())
             }
+            page_type => bail!(Error::corrupt_page(
+                page_number,
+                format!("{} page found in an index b-tree", page_type)
+            )),
         }
     }
 }
 
 #[cfg(test)]
 mod query_engine_index_tests {
-    use std::{fs::File, path::Path};
+    use std::path::Path;
 
     use crate::{
         pager::pager::Pager,
@@ -115,10 +145,8 @@ mod query_engine_index_tests {
     #[test]
     fn test_index_binary_search() {
         let path = Path::new("companies.db");
-        let mut file = File::open(path).expect("Failed to open companies.db");
-
-        let pager = Pager::new(&mut file).expect("Failed to initialize pager");
-        let mut engine = QueryEngine::new(pager);
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let engine = QueryEngine::new(pager);
 
         let query = SelectQuery {
             columns: vec![Column::All],
@@ -132,13 +160,15 @@ mod query_engine_index_tests {
 
         let country_index = engine.find_index(&query).unwrap();
 
-        let mut index_records: Vec<IndexLeafCell> = vec![];
-        engine.index_binary_search(
-            country_index.rootpage,
-            &query.where_clause.unwrap(),
-            &mut index_records,
-            &country_index,
-        );
+        let mut index_records: Vec<IndexLeafCell<'static>> = vec![];
+        engine
+            .index_binary_search(
+                country_index.rootpage,
+                &query.where_clause.unwrap(),
+                &mut index_records,
+                &country_index,
+            )
+            .unwrap();
         assert_eq!(index_records.len(), 288);
     }
 }
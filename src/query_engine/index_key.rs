@@ -0,0 +1,136 @@
+use crate::data_model::{
+    btree::{key::KeyColumn, serial_value::SerialValue},
+    schema_record::{DbObject, SchemaRecord},
+};
+
+use super::column::{
+    get_column_definitions, get_primary_key, get_unique_constraints, is_integer_primary_key,
+    is_without_rowid, ColumnDefinition,
+};
+
+/// Builds the index entry a table row should have
+pub struct IndexKey {
+    // record position of each indexed column, and whether it is the INTEGER PRIMARY KEY aliasing the rowid
+    columns: Vec<(usize, bool)>,
+    // WITHOUT ROWID tables are referred to by the record position of their primary key columns instead of the rowid
+    primary_key: Option<Vec<usize>>,
+}
+
+impl IndexKey {
+    /// None when the index isn't made of plain table columns, i.e an index on expressions
+    pub fn new(index: &SchemaRecord, table: &SchemaRecord) -> Option<Self> {
+        let index_columns = index_columns(index, table)?;
+        let mut table_columns: Vec<String> = get_column_definitions(&table.sql)
+            .ok()?
+            .into_iter()
+            .map(|column| column.name)
+            .collect();
+
+        let primary_key = if is_without_rowid(&table.sql) {
+            // records of WITHOUT ROWID tables store the primary key first
+            let key = get_primary_key(&table.sql).ok()?;
+            table_columns.retain(|column| !key.contains(column));
+            table_columns.splice(0..0, key.iter().cloned());
+            Some(key)
+        } else {
+            None
+        };
+
+        let position = |name: &String| table_columns.iter().position(|column| column == name);
+        let columns = index_columns
+            .iter()
+            .map(|column| {
+                let position = position(&column.name)?;
+                let is_row_id = primary_key.is_none()
+                    && is_integer_primary_key(table, &position).unwrap_or(false);
+                Some((position, is_row_id))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // entries only repeat the primary key columns that aren't already indexed
+        let primary_key = primary_key.map(|key| {
+            key.iter()
+                .filter(|name| !index_columns.iter().any(|column| column.name == **name))
+                .filter_map(position)
+                .collect()
+        });
+        Some(IndexKey {
+            columns,
+            primary_key,
+        })
+    }
+
+    /// Number of leading entry values that come from the indexed columns, the rest identify the row
+    pub fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn entry_for<'a>(&self, row_id: i64, values: &[SerialValue<'a>]) -> Vec<SerialValue<'a>> {
+        let value = |position: usize| values.get(position).cloned().unwrap_or(SerialValue::Null);
+        let mut entry: Vec<SerialValue> = self
+            .columns
+            .iter()
+            .map(|(position, is_row_id)| match value(*position) {
+                SerialValue::Null if *is_row_id => SerialValue::Int(row_id),
+                value => value,
+            })
+            .collect();
+        match &self.primary_key {
+            Some(key) => entry.extend(key.iter().map(|position| value(*position))),
+            None => entry.push(SerialValue::Int(row_id)),
+        }
+        entry
+    }
+}
+
+/// The columns an index is on. Automatic indexes have no sql, their columns come from the
+/// table constraint their name is numbered after, i.e `sqlite_autoindex_t_2`
+pub fn index_columns(index: &SchemaRecord, table: &SchemaRecord) -> Option<Vec<ColumnDefinition>> {
+    if !index.sql.is_empty() {
+        return get_column_definitions(&index.sql).ok();
+    }
+    let number: usize = index
+        .name
+        .strip_prefix(&format!("sqlite_autoindex_{}_", index.tbl_name))?
+        .parse()
+        .ok()?;
+    get_unique_constraints(&table.sql)
+        .ok()?
+        .into_iter()
+        .nth(number.checked_sub(1)?)
+}
+
+/// How the keys of an index b-tree, or of a WITHOUT ROWID table, are ordered.
+/// None when it can't be worked out from the schema
+pub fn key_columns(
+    schema_record: &SchemaRecord,
+    schema_records: &[SchemaRecord],
+) -> Option<Vec<KeyColumn>> {
+    let table = schema_records
+        .iter()
+        .find(|rec| rec.db_object == DbObject::Table && rec.name == schema_record.tbl_name)?;
+    let table_columns = get_column_definitions(&table.sql).ok()?;
+    let table_column = |name: &str| table_columns.iter().find(|column| column.name == name);
+
+    match schema_record.db_object {
+        DbObject::Table => {
+            let key = get_primary_key(&schema_record.sql).ok()?;
+            Some(
+                key.iter()
+                    .map(|name| KeyColumn::from_definition("", table_column(name)))
+                    .collect(),
+            )
+        }
+        _ => {
+            let columns = index_columns(schema_record, table)?;
+            Some(
+                columns
+                    .iter()
+                    .map(|column| {
+                        KeyColumn::from_definition(&column.type_def, table_column(&column.name))
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    data_model::{
+        btree::{record::Record, serial_value::SerialValue},
+        schema_record::{DbObject, SchemaRecord},
+    },
+    error::Error,
+    sql_parser::parser::{Column, Comparison, InsertStatement, Literal, Operator, SelectQuery},
+};
+
+use super::{
+    column::find_column_index,
+    engine::QueryEngine,
+    schema_object::SchemaObject,
+    table_writer::{literal_value, IndexWriter, TableWriter},
+};
+
+impl TableWriter {
+    /// The value of every column of a row in table order, columns that weren't given get their default
+    fn row_values(
+        &self,
+        columns: &Option<Vec<usize>>,
+        row: &[Literal],
+    ) -> Result<Vec<SerialValue<'static>>> {
+        let mut literals = self.defaults.clone();
+        match columns {
+            Some(columns) => {
+                for (position, literal) in columns.iter().zip(row) {
+                    literals[*position] = literal.clone();
+                }
+            }
+            None if row.len() == self.columns.len() => literals = row.to_vec(),
+            None => bail!(
+                "table {} has {} columns but {} values were supplied",
+                self.name,
+                self.columns.len(),
+                row.len()
+            ),
+        }
+        Ok(literals
+            .into_iter()
+            .zip(&self.affinities)
+            .map(|(literal, affinity)| affinity.apply(literal_value(literal)))
+            .collect())
+    }
+}
+
+impl QueryEngine {
+    /// Add the rows of an INSERT, either all of them are committed or none are.
+    /// Returns the number of rows added
+    pub fn insert(&mut self, statement: InsertStatement) -> Result<usize> {
+        self.write(|engine| engine.insert_rows(&statement))
+    }
+
+    fn insert_rows(&mut self, statement: &InsertStatement) -> Result<usize> {
+        let writer = self.table_writer(&statement.table)?;
+        writer.ensure_no_checks("inserting into")?;
+        let columns = statement
+            .columns
+            .as_ref()
+            .map(|columns| {
+                columns
+                    .iter()
+                    .map(|column| find_column_index(&writer.columns, column))
+                    .collect::<Result<Vec<usize>>>()
+            })
+            .transpose()?;
+
+        // AUTOINCREMENT rowids are larger than any the table has ever had, which sqlite_sequence remembers
+        let sequence = if writer.autoincrement {
+            Some(self.sequence(&writer.name)?)
+        } else {
+            None
+        };
+        let mut largest_row_id = sequence.map(|(_, seq)| seq).unwrap_or(0);
+
+        for row in &statement.rows {
+            let values = writer.row_values(&columns, row)?;
+            let row_id = self.insert_row(&writer, values, largest_row_id)?;
+            largest_row_id = largest_row_id.max(row_id);
+        }
+
+        if let Some((sequence_row, seq)) = sequence {
+            if largest_row_id > seq {
+                self.set_sequence(&writer.name, sequence_row, largest_row_id)?;
+            }
+        }
+        Ok(statement.rows.len())
+    }
+
+    /// Add one row, given by its values in table order, and its index entries, returning its rowid
+    pub fn insert_row(
+        &mut self,
+        writer: &TableWriter,
+        mut values: Vec<SerialValue<'static>>,
+        largest_row_id: i64,
+    ) -> Result<i64> {
+        writer.ensure_not_null(&values)?;
+        let pager = &mut self.pager;
+        let row_id = match writer.row_id_alias.map(|alias| &values[alias]) {
+            // rows of WITHOUT ROWID tables are found by their primary key instead
+            None if writer.primary_key.is_some() => 0,
+            Some(SerialValue::Int(row_id)) => {
+                if pager.has_row(writer.rootpage, *row_id)? {
+                    let alias = writer.row_id_alias.expect("the rowid has an alias");
+                    bail!(writer.constraint("UNIQUE", &writer.columns[alias..=alias]));
+                }
+                *row_id
+            }
+            Some(SerialValue::Null) | None => {
+                let row_id = pager.max_row_id(writer.rootpage)?.max(largest_row_id);
+                row_id.checked_add(1).ok_or_else(|| {
+                    Error::Unsupported("choosing a rowid after the largest possible one".into())
+                })?
+            }
+            Some(value) => bail!(Error::TypeMismatch {
+                expected: "an integer rowid".into(),
+                found: format!("'{}'", value),
+            }),
+        };
+        // the INTEGER PRIMARY KEY is stored as the rowid, its column holds NULL
+        if let Some(alias) = writer.row_id_alias {
+            values[alias] = SerialValue::Null;
+        }
+
+        let values = writer.record_order(values);
+
+        let entries: Vec<Vec<SerialValue<'static>>> = writer
+            .indexes
+            .iter()
+            .map(|index| index.key.entry_for(row_id, &values))
+            .collect();
+        for (index, entry) in writer.indexes.iter().zip(&entries) {
+            self.ensure_unique(writer, index, entry)?;
+        }
+
+        let pager = &mut self.pager;
+        let encoding = pager.db_header.text_encoding;
+        match &writer.primary_key {
+            Some((key, key_columns)) => {
+                let record = Record::new(values, encoding);
+                if !pager.insert_entry(writer.rootpage, &record, key_columns, key.len())? {
+                    let columns: Vec<String> = key
+                        .iter()
+                        .map(|position| writer.columns[*position].clone())
+                        .collect();
+                    bail!(writer.constraint("UNIQUE", &columns));
+                }
+            }
+            None => {
+                let record = Record::new(values, encoding);
+                if !pager.insert_row(writer.rootpage, row_id, &record, false)? {
+                    bail!(Error::corrupt_page(
+                        writer.rootpage,
+                        format!("rowid {} is already in {}", row_id, writer.name)
+                    ));
+                }
+            }
+        }
+
+        for (index, entry) in writer.indexes.iter().zip(entries) {
+            self.insert_index_entry(index, entry, row_id)?;
+        }
+        Ok(row_id)
+    }
+
+    /// Fail with sqlite's error when a UNIQUE index already has an entry with the same key as `entry`
+    pub fn ensure_unique(
+        &self,
+        writer: &TableWriter,
+        index: &IndexWriter,
+        entry: &[SerialValue],
+    ) -> Result<()> {
+        let Some(columns) = &index.unique_columns else {
+            return Ok(());
+        };
+        // NULLs are never equal to each other so they don't break UNIQUE
+        let key = &entry[..index.key.column_count()];
+        if !key.contains(&SerialValue::Null)
+            && self
+                .pager
+                .has_entry(index.rootpage, key, &index.key_columns)?
+        {
+            bail!(writer.constraint("UNIQUE", columns));
+        }
+        Ok(())
+    }
+
+    pub fn insert_index_entry(
+        &mut self,
+        index: &IndexWriter,
+        entry: Vec<SerialValue<'static>>,
+        row_id: i64,
+    ) -> Result<()> {
+        let len = entry.len();
+        let record = Record::new(entry, self.pager.db_header.text_encoding);
+        if !self
+            .pager
+            .insert_entry(index.rootpage, &record, &index.key_columns, len)?
+        {
+            bail!(Error::corrupt_page(
+                index.rootpage,
+                format!(
+                    "index {} already has the entry of rowid {}",
+                    index.name, row_id
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    /// The rowid of the table's row in sqlite_sequence, if it has one, and the largest rowid it has handed out
+    pub fn sequence(&self, table: &str) -> Result<(Option<i64>, i64)> {
+        let sequence = SchemaObject::try_from(self.sqlite_sequence()?)?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: sequence.name.clone(),
+            where_clause: Some(Comparison {
+                operator: Operator::Equals,
+                column: "name".into(),
+                value: table.into(),
+            }),
+        };
+        let rows = self.table_db_scan(&sequence, &query)?;
+        let Some(row) = rows.first() else {
+            return Ok((None, 0));
+        };
+        let seq = match row.record.value(1)? {
+            Some(SerialValue::Int(seq)) => seq,
+            _ => 0,
+        };
+        Ok((Some(row.row_header.row_id as i64), seq))
+    }
+
+    pub fn set_sequence(&mut self, table: &str, row_id: Option<i64>, seq: i64) -> Result<()> {
+        let root = self.sqlite_sequence()?.rootpage;
+        let record = Record::new(
+            vec![
+                SerialValue::Text(table.to_string().into()),
+                SerialValue::Int(seq),
+            ],
+            self.pager.db_header.text_encoding,
+        );
+        let row_id = match row_id {
+            Some(row_id) => row_id,
+            None => self.pager.max_row_id(root)? + 1,
+        };
+        self.pager.insert_row(root, row_id, &record, true)?;
+        Ok(())
+    }
+
+    pub fn sqlite_sequence(&self) -> Result<SchemaRecord> {
+        self.pager
+            .schema_table
+            .cells
+            .iter()
+            .find(|rec| rec.db_object == DbObject::Table && rec.name == "sqlite_sequence")
+            .cloned()
+            .ok_or_else(|| {
+                Error::corrupt_page(1, "AUTOINCREMENT table without a sqlite_sequence table").into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod insert_tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{
+        sql_parser::{
+            lexer::lexer,
+            parser::{Parser, Statement},
+        },
+        vfs::memory::MemoryVfs,
+    };
+
+    #[test]
+    fn test_inserting_rows() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        let sql = "INSERT INTO users (email, age) VALUES ('grace@example.com', '85'), ('edsger@example.com', NULL)";
+        let statement = Parser::new(lexer(sql).unwrap()).parse_statement().unwrap();
+        let Statement::Insert(insert) = statement else {
+            panic!("expected an INSERT");
+        };
+        assert_eq!(engine.insert(insert).unwrap(), 2);
+        engine
+            .execute_sql("INSERT INTO users VALUES (10, 'barbara@example.com', 'Barbara', 70)")
+            .unwrap();
+        engine
+            .execute_sql("INSERT INTO users (email) VALUES ('linus@example.com')")
+            .unwrap();
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "1|ada@example.com|Ada|36\n\
+             2|alan@example.com|Alan|41\n\
+             3|grace@example.com|anonymous|85\n\
+             4|edsger@example.com|anonymous|\n\
+             10|barbara@example.com|Barbara|70\n\
+             11|linus@example.com|anonymous|"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT email FROM users WHERE age = '85'")
+                .unwrap(),
+            "grace@example.com",
+            "the index is used to find the new row"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT seq FROM sqlite_sequence WHERE name = 'users'")
+                .unwrap(),
+            "11"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_constraint_violations() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        let error = |engine: &mut QueryEngine, sql: &str| {
+            engine
+                .execute_sql(sql)
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(
+                &mut engine,
+                "INSERT INTO users (email) VALUES ('new@example.com'), ('ada@example.com')"
+            ),
+            "UNIQUE constraint failed: users.email"
+        );
+        assert_eq!(
+            error(&mut engine, "INSERT INTO users (name) VALUES ('nobody')"),
+            "NOT NULL constraint failed: users.email"
+        );
+        assert_eq!(
+            error(
+                &mut engine,
+                "INSERT INTO users (id, email) VALUES (2, 'other@example.com')"
+            ),
+            "UNIQUE constraint failed: users.id"
+        );
+        assert_eq!(
+            error(&mut engine, "INSERT INTO users (phone) VALUES ('555')"),
+            "no such column: phone"
+        );
+        assert!(engine
+            .execute_sql("INSERT INTO users VALUES ('x@example.com')")
+            .is_err());
+
+        // a statement that fails part way leaves none of its rows behind
+        assert_eq!(
+            engine.execute_sql("SELECT email FROM users").unwrap(),
+            "ada@example.com\nalan@example.com"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_inserting_long_values_into_an_indexed_table() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/overflow.db");
+        // bodies up to a few pages long, titles out of order so index pages split in the middle
+        for i in 0..40 {
+            let rows = (0..10)
+                .map(|j| {
+                    format!(
+                        "('draft {:02}', '{}')",
+                        (i * 7 + j) % 25,
+                        "body ".repeat(50 * j)
+                    )
+                })
+                .join(", ");
+            engine
+                .execute_sql(&format!(
+                    "INSERT INTO documents (title, body) VALUES {}",
+                    rows
+                ))
+                .unwrap();
+        }
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+        assert_eq!(
+            engine
+                .execute_sql("SELECT title FROM documents WHERE title = 'draft 11'")
+                .unwrap()
+                .lines()
+                .count(),
+            16
+        );
+    }
+}
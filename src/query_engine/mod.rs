@@ -1,7 +1,20 @@
+pub mod affinity;
+pub mod alter;
 pub mod column;
+pub mod create;
+pub mod delete;
+pub mod drop;
 pub mod engine;
+pub mod expression;
 pub mod filter;
 pub mod index;
+pub mod index_key;
+pub mod insert;
+pub mod pragma;
 pub mod schema_object;
 pub mod set;
 pub mod table;
+pub mod table_writer;
+pub mod transaction;
+pub mod update;
+pub mod without_rowid;
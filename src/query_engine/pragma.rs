@@ -0,0 +1,52 @@
+use anyhow::{bail, Result};
+
+use crate::{error::Error, pager::wal::CheckpointMode, sql_parser::parser::PragmaStatement};
+
+use super::engine::QueryEngine;
+
+impl QueryEngine {
+    /// Run one of the pragmas that are supported, showing what sqlite's shell would
+    pub fn pragma(&mut self, pragma: PragmaStatement) -> Result<String> {
+        let value = pragma.value.map(|value| value.to_lowercase());
+        match pragma.name.to_lowercase().as_str() {
+            "journal_mode" => self.journal_mode(value.as_deref()),
+            "wal_checkpoint" => self.wal_checkpoint(value.as_deref()),
+            _ => bail!(Error::Unsupported(format!("PRAGMA {}", pragma.name))),
+        }
+    }
+
+    /// Show the journal mode or change it. Rollback journals are always deleted once a write commits,
+    /// so the modes that use one all give DELETE
+    fn journal_mode(&mut self, mode: Option<&str>) -> Result<String> {
+        match mode {
+            None => {}
+            Some("wal") => self.pager.set_wal_mode(true)?,
+            Some("delete" | "truncate" | "persist") => self.pager.set_wal_mode(false)?,
+            Some(mode) => bail!(Error::Unsupported(format!("journal_mode={}", mode))),
+        }
+        let mode = if self.pager.wal_mode() {
+            "wal"
+        } else {
+            "delete"
+        };
+        Ok(mode.into())
+    }
+
+    /// Checkpoint the log, showing whether it was kept from finishing, the frames in the log and the frames
+    /// copied into the database file. Like sqlite a database that isn't in WAL mode gives -1 frames
+    fn wal_checkpoint(&mut self, mode: Option<&str>) -> Result<String> {
+        let mode = match mode {
+            Some("full") => CheckpointMode::Full,
+            Some("restart") => CheckpointMode::Restart,
+            Some("truncate") => CheckpointMode::Truncate,
+            _ => CheckpointMode::Passive,
+        };
+        Ok(match self.pager.checkpoint(mode)? {
+            Some(checkpoint) => format!(
+                "{}|{}|{}",
+                checkpoint.busy as u8, checkpoint.log_frames, checkpoint.checkpointed
+            ),
+            None => "0|-1|-1".into(),
+        })
+    }
+}
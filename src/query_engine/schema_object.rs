@@ -1,6 +1,18 @@
-use crate::data_model::schema_record::SchemaRecord;
+use anyhow::Result;
 
-use super::column::get_column_names;
+use crate::{
+    data_model::{btree::serial_value::SerialValue, schema_record::SchemaRecord},
+    error::Error,
+};
+
+use super::{
+    affinity::Affinity,
+    column::{
+        get_column_defaults, get_column_definitions, get_primary_key, get_row_id_alias,
+        is_without_rowid,
+    },
+    table_writer::{literal_value, parse_default},
+};
 
 /// Basically Schema Record but the sql creation field has been parsed
 pub struct SchemaObject {
@@ -9,15 +21,91 @@ pub struct SchemaObject {
     pub name: String,
     // column names are ordered
     pub columns: Vec<String>,
+    // WITHOUT ROWID tables are stored in an index b-tree keyed by their primary key
+    pub without_rowid: bool,
+    pub primary_key: Vec<String>,
+    // position of the INTEGER PRIMARY KEY, its records hold NULL and the value is the rowid
+    pub row_id_alias: Option<usize>,
+    // the value of each column in records written before it was added to the table
+    pub defaults: Vec<SerialValue<'static>>,
+}
+
+impl SchemaObject {
+    /// Column names in the order their values are stored in a record.
+    /// Records of WITHOUT ROWID tables start with the primary key followed by the remaining columns
+    pub fn record_columns(&self) -> Vec<String> {
+        if !self.without_rowid {
+            return self.columns.clone();
+        }
+        let mut record_columns = self.primary_key.clone();
+        record_columns.extend(
+            self.columns
+                .iter()
+                .filter(|column| !self.primary_key.contains(column))
+                .cloned(),
+        );
+        record_columns
+    }
+
+    /// The defaults of the columns in the order of `record_columns`
+    pub fn record_defaults(&self) -> Vec<SerialValue<'static>> {
+        self.record_columns()
+            .iter()
+            .map(|column| {
+                let position = self.columns.iter().position(|name| name == column);
+                position.map_or(SerialValue::Null, |position| {
+                    self.defaults[position].clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Give a row read in table order the defaults of the columns its record stops short of.
+    /// Records written before a column was added to the table don't have a value for it
+    pub fn complete_row<'a>(&self, mut values: Vec<SerialValue<'a>>) -> Vec<SerialValue<'a>> {
+        if values.len() < self.defaults.len() {
+            values.extend_from_slice(&self.defaults[values.len()..]);
+        }
+        values
+    }
 }
 
-impl From<SchemaRecord> for SchemaObject {
-    fn from(value: SchemaRecord) -> Self {
-        Self {
+impl TryFrom<SchemaRecord> for SchemaObject {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SchemaRecord) -> Result<Self> {
+        let without_rowid = is_without_rowid(&value.sql);
+        let unsupported = |e: anyhow::Error| {
+            Error::UnsupportedFormat(format!(
+                "couldn't parse the schema of {}: {:#}",
+                value.name, e
+            ))
+        };
+        let definitions = get_column_definitions(&value.sql).map_err(unsupported)?;
+        // only constant defaults can be given to columns that are added, others are never read
+        let defaults = get_column_defaults(&value.sql)
+            .map_err(unsupported)?
+            .into_iter()
+            .zip(&definitions)
+            .map(|(default, column)| {
+                let literal = default.and_then(|default| parse_default(&default).ok());
+                match literal {
+                    Some(literal) => {
+                        Affinity::from_type_def(&column.type_def).apply(literal_value(literal))
+                    }
+                    None => SerialValue::Null,
+                }
+            })
+            .collect();
+        Ok(Self {
+            columns: definitions.into_iter().map(|column| column.name).collect(),
+            primary_key: get_primary_key(&value.sql).map_err(unsupported)?,
+            row_id_alias: get_row_id_alias(&value.sql).map_err(unsupported)?,
             name: value.name,
             rootpage: value.rootpage,
             tbl_name: value.tbl_name,
-            columns: get_column_names(&value.sql).expect("couldn't get column names"),
-        }
+            without_rowid,
+            defaults,
+        })
     }
 }
@@ -19,6 +19,12 @@ impl<T: PartialOrd> Set<T> {
     }
 }
 
+impl<T: PartialOrd> Default for Set<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: PartialOrd> IntoIterator for Set<T> {
     type Item = T;
     type IntoIter = std::vec::IntoIter<T>;
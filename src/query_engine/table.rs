@@ -1,22 +1,23 @@
-use anyhow::{anyhow, Error, Ok, Result};
+use anyhow::{bail, ensure, Ok, Result};
 
 use crate::{
     data_model::{
         btree::{
-            page_header::PageType, record::HasRecord, table_interior_cell::TableInteriorCell,
-            table_leaf_cell::TableLeafCell,
+            page::ensure_depth, page_header::PageType, serial_value::SerialValue,
+            table_interior_cell::TableInteriorCell, table_leaf_cell::TableLeafCell,
         },
         schema_record::SchemaRecord,
         table::Table,
     },
+    error::Error,
     sql_parser::parser::{Comparison, SelectQuery},
 };
 
 use super::{engine::QueryEngine, schema_object::SchemaObject};
 
-impl<'a> QueryEngine<'a> {
+impl QueryEngine {
     /// Find the table record in the schema table
-    pub fn get_table_rec(&mut self, table_name: &str) -> Result<SchemaRecord, Error> {
+    pub fn get_table_rec(&self, table_name: &str) -> Result<SchemaRecord> {
         match self
             .pager
             .schema_table
@@ -24,45 +25,62 @@ impl<'a> QueryEngine<'a> {
             .iter()
             .find(|rec| rec.tbl_name == table_name)
         {
-            Some(rec) => return Ok(rec.clone()),
-            _ => return Err(anyhow!("Couldn't find table: {}", table_name)),
+            Some(rec) => Ok(rec.clone()),
+            _ => Err(Error::NoSuchTable(table_name.to_string()).into()),
         }
     }
 
     pub fn table_db_scan(
-        &mut self,
+        &self,
         table: &SchemaObject,
         query: &SelectQuery,
-    ) -> Result<Vec<TableLeafCell>> {
-        let mut records: Vec<TableLeafCell> = vec![];
+    ) -> Result<Vec<TableLeafCell<'static>>> {
+        if table.without_rowid {
+            return self.without_rowid_scan(table, &query.where_clause);
+        }
+
+        let mut records: Vec<TableLeafCell<'static>> = vec![];
         self.recursive_db_scan(
             table.rootpage,
             &mut records,
             &table.columns,
+            &table.defaults,
             &query.where_clause,
+            0,
         )?;
         Ok(records)
     }
 
-    /// Traverses a BTree collecting records in the leaf nodes
+    /// Traverses a BTree collecting records in the leaf nodes.
+    /// Rows keep their page alive rather than copying their records, only matching rows are kept
     fn recursive_db_scan(
-        &mut self,
+        &self,
         page_number: u32,
-        records: &mut Vec<TableLeafCell>,
+        records: &mut Vec<TableLeafCell<'static>>,
         ordered_column_names: &Vec<String>,
+        defaults: &[SerialValue<'static>],
         comparison: &Option<Comparison>,
+        depth: usize,
     ) -> Result<()> {
-        let (page, mut buf) = self.pager.read_page(page_number)?;
+        ensure_depth(page_number, depth)?;
+        let (page, buf) = self.pager.read_page(page_number)?;
         match page.header.page_type {
             PageType::TableInterior => {
-                let interior_table = Table::<TableInteriorCell>::new(&mut buf, &page.cell_pointers);
+                let interior_table = Table::<TableInteriorCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
                 drop(buf);
                 for cell in interior_table.cells {
                     self.recursive_db_scan(
                         cell.left_child,
                         records,
                         ordered_column_names,
+                        defaults,
                         comparison,
+                        depth + 1,
                     )?;
                 }
                 match page.header.rightmost_pointer {
@@ -71,52 +89,88 @@ impl<'a> QueryEngine<'a> {
                             rightmost_pointer,
                             records,
                             ordered_column_names,
+                            defaults,
                             comparison,
+                            depth + 1,
                         )?;
                     }
-                    _ => panic!("Interior table page header missing right most pointer"),
+                    _ => bail!(Error::corrupt_page(
+                        page_number,
+                        "interior table page header missing right most pointer"
+                    )),
                 }
                 Ok(())
             }
             PageType::TableLeaf => {
-                let mut table = Table::<TableLeafCell>::new(&mut buf, &page.cell_pointers);
-
-                match comparison {
-                    Some(cmp) => records.append(
-                        &mut table
-                            .filter_cells(&ordered_column_names, &cmp)
-                            .expect("filtering failed"),
-                    ),
-                    None => records.append(&mut table.cells),
+                let table = Table::<TableLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
+
+                let cells = match comparison {
+                    Some(cmp) => table.filter_cells(ordered_column_names, defaults, cmp)?,
+                    None => table.cells,
                 };
+                records.extend(cells.into_iter().map(|cell| cell.retain(buf.get_ref())));
 
                 Ok(())
             }
-            _ => panic!("Found an Index Page while traversing Table tree"),
+            page_type => bail!(Error::corrupt_page(
+                page_number,
+                format!("{} page found in a table b-tree", page_type)
+            )),
         }
     }
 
     pub fn table_binary_search(
-        &mut self,
+        &self,
         table: &SchemaObject,
         mut row_ids: Vec<u64>,
-    ) -> Result<Vec<TableLeafCell>> {
-        let mut records: Vec<TableLeafCell> = vec![];
+    ) -> Result<Vec<TableLeafCell<'static>>> {
+        let mut records: Vec<TableLeafCell<'static>> = vec![];
 
-        while row_ids.len() > 0 {
-            self.recursive_binary_search(table.rootpage, &mut row_ids, &mut records)?;
+        while !row_ids.is_empty() {
+            let remaining = row_ids.len();
+            self.recursive_binary_search(table.rootpage, &mut row_ids, &mut records, 0)?;
+            // the search always finds the first row id it is looking for when the table has it
+            ensure!(
+                row_ids.len() < remaining,
+                Error::corrupt_page(
+                    table.rootpage,
+                    format!("row {} is missing from table {}", row_ids[0], table.name)
+                )
+            );
         }
 
         Ok(records)
     }
 
-    fn recursive_binary_search<'b>(
-        &mut self,
+    /// The row a comparison on the table's INTEGER PRIMARY KEY asks for. The column is NULL in the records,
+    /// so the row is looked up by the rowid it stands for. A value that isn't an integer matches no row
+    pub fn row_id_search(
+        &self,
+        table: &SchemaObject,
+        comparison: &Comparison,
+    ) -> Result<Vec<TableLeafCell<'static>>> {
+        let Some(row_id) = comparison.value.parse::<i64>().ok() else {
+            return Ok(vec![]);
+        };
+        let mut records = vec![];
+        self.recursive_binary_search(table.rootpage, &mut vec![row_id as u64], &mut records, 0)?;
+        Ok(records)
+    }
+
+    fn recursive_binary_search(
+        &self,
         page_number: u32,
         queried_row_ids: &mut Vec<u64>,
-        records: &'b mut Vec<TableLeafCell>,
+        records: &mut Vec<TableLeafCell<'static>>,
+        depth: usize,
     ) -> Result<()> {
-        let (page, mut buf) = self.pager.read_page(page_number)?;
+        ensure_depth(page_number, depth)?;
+        let (page, buf) = self.pager.read_page(page_number)?;
 
         let Some(row_id) = queried_row_ids.first().cloned() else {
             return Ok(());
@@ -124,35 +178,54 @@ impl<'a> QueryEngine<'a> {
 
         match page.header.page_type {
             PageType::TableInterior => {
-                let interior_table = Table::<TableInteriorCell>::new(&mut buf, &page.cell_pointers);
+                let interior_table = Table::<TableInteriorCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
                 for cell in &interior_table.cells {
                     if cell.row_id >= row_id {
-                        self.recursive_binary_search(cell.left_child, queried_row_ids, records)?;
+                        self.recursive_binary_search(
+                            cell.left_child,
+                            queried_row_ids,
+                            records,
+                            depth + 1,
+                        )?;
                     }
                 }
                 match page.header.rightmost_pointer {
                     Some(rightmost_pointer) => {
-                        if interior_table.cells.last().unwrap().row_id <= row_id {
+                        let last_row_id = interior_table.cells.last().map(|cell| cell.row_id);
+                        if last_row_id.is_none_or(|last_row_id| last_row_id <= row_id) {
                             self.recursive_binary_search(
                                 rightmost_pointer,
                                 queried_row_ids,
                                 records,
+                                depth + 1,
                             )
                         } else {
                             Ok(())
                         }
                     }
-                    None => panic!("Interior table page header missing right most pointer"),
+                    None => bail!(Error::corrupt_page(
+                        page_number,
+                        "interior table page header missing right most pointer"
+                    )),
                 }
             }
             PageType::TableLeaf => {
-                let table = Table::<TableLeafCell>::new(&mut buf, &page.cell_pointers);
+                let table = Table::<TableLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
 
                 // Linear search page for row-ids
                 for r_id in queried_row_ids.clone() {
-                    match table.cells.iter().find(|c| c.row_id() == r_id) {
-                        Some(c) => records.push(c.clone()),
-                        None => (),
+                    if let Some(c) = table.cells.iter().find(|c| c.row_header.row_id == r_id) {
+                        records.push(c.clone().retain(buf.get_ref()))
                     };
                 }
                 // Remove found row ids
@@ -162,10 +235,39 @@ impl<'a> QueryEngine<'a> {
 
                 Ok(())
             }
-            _ => panic!("Found an Index page while traversing a Table B+ Tree"),
+            page_type => bail!(Error::corrupt_page(
+                page_number,
+                format!("{} page found in a table b-tree", page_type)
+            )),
         }
     }
 }
 
 #[cfg(test)]
-mod query_engine_table_tests {}
+mod query_engine_table_tests {
+    use std::path::Path;
+
+    use crate::{pager::pager::Pager, sql_parser::parser::Column};
+
+    use super::*;
+
+    #[test]
+    fn test_scanned_rows_point_into_their_page() {
+        let pager = Pager::open(Path::new("sample.db")).unwrap();
+        let engine = QueryEngine::new(pager);
+        let table = SchemaObject::try_from(engine.get_table_rec("apples").unwrap()).unwrap();
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: "apples".into(),
+            where_clause: None,
+        };
+        let rows = engine.table_db_scan(&table, &query).unwrap();
+        assert_eq!(rows.len(), 4);
+
+        let (_, buf) = engine.pager.read_page(table.rootpage).unwrap();
+        let page = buf.get_ref().as_ptr_range();
+        for row in &rows {
+            assert!(page.contains(&row.record.payload().as_ptr()));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::{
+    data_model::{
+        btree::{key::KeyColumn, serial_value::SerialValue},
+        schema_record::{DbObject, SchemaRecord},
+    },
+    error::Error,
+    sql_parser::{
+        lexer::lexer,
+        parser::{Literal, Parser},
+    },
+};
+
+use super::{
+    affinity::Affinity,
+    column::{
+        find_column_index, get_autoincrement_column, get_column_defaults, get_column_definitions,
+        get_primary_key, get_row_id_alias, is_without_rowid,
+    },
+    engine::QueryEngine,
+    index_key::{index_columns, key_columns, IndexKey},
+};
+
+pub static GENERATED_COLUMN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bas\s*\(|\bgenerated\s+always\b").expect("valid regex"));
+pub static CHECK_CONSTRAINT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bcheck\s*\(").expect("valid regex"));
+
+/// An index that has to be kept up to date with the rows of its table
+pub struct IndexWriter {
+    pub name: String,
+    pub rootpage: u32,
+    pub key: IndexKey,
+    pub key_columns: Vec<KeyColumn>,
+    // column names for the error when a UNIQUE index already has the values, None for other indexes
+    pub unique_columns: Option<Vec<String>>,
+}
+
+/// What changing the rows of a table needs to know about it, worked out once per statement
+pub struct TableWriter {
+    pub name: String,
+    pub rootpage: u32,
+    pub sql: String,
+    pub columns: Vec<String>,
+    pub affinities: Vec<Affinity>,
+    pub defaults: Vec<Literal>,
+    pub not_null: Vec<bool>,
+    pub row_id_alias: Option<usize>,
+    // rows of WITHOUT ROWID tables are keyed by their primary key, stored first in the record
+    pub primary_key: Option<(Vec<usize>, Vec<KeyColumn>)>,
+    pub autoincrement: bool,
+    pub indexes: Vec<IndexWriter>,
+}
+
+impl TableWriter {
+    pub fn new(table: &SchemaRecord, schema: &[SchemaRecord]) -> Result<Self> {
+        let unsupported =
+            |feature: &str| Error::Unsupported(format!("{} on {}", feature, table.name));
+        // generated columns aren't all stored in the record, so values wouldn't line up with columns
+        if GENERATED_COLUMN.is_match(&table.sql) {
+            bail!(unsupported("writing to tables with generated columns"));
+        }
+        if schema
+            .iter()
+            .any(|rec| rec.db_object == DbObject::Trigger && rec.tbl_name == table.name)
+        {
+            bail!(unsupported("running triggers"));
+        }
+
+        let definitions = get_column_definitions(&table.sql)?;
+        let columns: Vec<String> = definitions
+            .iter()
+            .map(|column| column.name.clone())
+            .collect();
+        let defaults = get_column_defaults(&table.sql)?
+            .into_iter()
+            .map(|default| match default {
+                Some(default) => parse_default(&default)
+                    .with_context(|| unsupported("DEFAULT values that aren't constants")),
+                None => Ok(Literal::Null),
+            })
+            .collect::<Result<_>>()?;
+
+        let primary_key = if is_without_rowid(&table.sql) {
+            let key = get_primary_key(&table.sql)?
+                .iter()
+                .map(|name| find_column_index(&columns, name))
+                .collect::<Result<Vec<usize>>>()?;
+            let key_columns =
+                key_columns(table, schema).context("couldn't work out the primary key order")?;
+            Some((key, key_columns))
+        } else {
+            None
+        };
+        let not_null = definitions
+            .iter()
+            .enumerate()
+            .map(|(position, column)| {
+                column.type_def.contains("not null")
+                    || primary_key
+                        .as_ref()
+                        .is_some_and(|(key, _)| key.contains(&position))
+            })
+            .collect();
+
+        let mut indexes = vec![];
+        for index in schema
+            .iter()
+            .filter(|rec| rec.db_object == DbObject::Index && rec.tbl_name == table.name)
+        {
+            let sql = index.sql.to_lowercase();
+            if sql[sql.rfind(')').unwrap_or(0)..].contains("where") {
+                bail!(unsupported("updating partial indexes"));
+            }
+            let key = IndexKey::new(index, table)
+                .ok_or_else(|| unsupported("updating indexes on expressions"))?;
+            let key_columns = key_columns(index, schema)
+                .ok_or_else(|| unsupported("updating indexes on expressions"))?;
+            // automatic indexes come from PRIMARY KEY and UNIQUE constraints
+            let unique = sql.is_empty() || sql.starts_with("create unique");
+            let unique_columns = unique
+                .then(|| index_columns(index, table))
+                .flatten()
+                .map(|columns| columns.into_iter().map(|column| column.name).collect());
+            indexes.push(IndexWriter {
+                name: index.name.clone(),
+                rootpage: index.rootpage,
+                key,
+                key_columns,
+                unique_columns,
+            });
+        }
+
+        let row_id_alias = get_row_id_alias(&table.sql)?;
+        // sqlite only takes AUTOINCREMENT on the INTEGER PRIMARY KEY
+        let autoincrement =
+            row_id_alias.is_some() && get_autoincrement_column(&table.sql)? == row_id_alias;
+        Ok(TableWriter {
+            name: table.name.clone(),
+            rootpage: table.rootpage,
+            sql: table.sql.clone(),
+            affinities: definitions
+                .iter()
+                .map(|column| Affinity::from_type_def(&column.type_def))
+                .collect(),
+            columns,
+            defaults,
+            not_null,
+            row_id_alias,
+            primary_key,
+            autoincrement,
+            indexes,
+        })
+    }
+
+    /// The error sqlite gives when a row breaks a constraint on the columns, i.e `UNIQUE constraint failed: t.a`
+    pub fn constraint(&self, constraint: &str, columns: &[String]) -> Error {
+        Error::Constraint {
+            constraint: constraint.into(),
+            columns: columns
+                .iter()
+                .map(|column| format!("{}.{}", self.name, column))
+                .join(", "),
+        }
+    }
+
+    /// Fail when a NOT NULL column of the row, given in table order, is NULL
+    pub fn ensure_not_null(&self, values: &[SerialValue]) -> Result<()> {
+        for (position, value) in values.iter().enumerate() {
+            // a NULL INTEGER PRIMARY KEY is given the next rowid
+            if *value == SerialValue::Null
+                && self.not_null[position]
+                && self.row_id_alias != Some(position)
+            {
+                bail!(self.constraint("NOT NULL", &self.columns[position..=position]));
+            }
+        }
+        Ok(())
+    }
+
+    /// CHECK constraints are expressions that can't be evaluated, so rows of their tables can't be written
+    pub fn ensure_no_checks(&self, action: &str) -> Result<()> {
+        if CHECK_CONSTRAINT.is_match(&self.sql) {
+            bail!(Error::Unsupported(format!(
+                "{} tables with CHECK constraints on {}",
+                action, self.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Put the values of a row in table order into the order they are stored in its record,
+    /// which for WITHOUT ROWID tables is the primary key first
+    pub fn record_order<'a>(&self, values: Vec<SerialValue<'a>>) -> Vec<SerialValue<'a>> {
+        let Some((key, _)) = &self.primary_key else {
+            return values;
+        };
+        let mut rest = vec![];
+        let mut key_values = vec![SerialValue::Null; key.len()];
+        for (position, value) in values.into_iter().enumerate() {
+            match key.iter().position(|column| *column == position) {
+                Some(i) => key_values[i] = value,
+                None => rest.push(value),
+            }
+        }
+        key_values.extend(rest);
+        key_values
+    }
+}
+
+pub fn literal_value(literal: Literal) -> SerialValue<'static> {
+    match literal {
+        Literal::Null => SerialValue::Null,
+        Literal::Integer(integer) => SerialValue::Int(integer),
+        Literal::Real(real) => SerialValue::Float(real),
+        Literal::Text(text) => SerialValue::Text(text.into()),
+        Literal::Blob(bytes) => SerialValue::Blob(bytes.into()),
+    }
+}
+
+/// The constant at the start of a DEFAULT clause, constraints may follow it
+pub fn parse_default(default: &str) -> Result<Literal> {
+    Parser::new(lexer(default)?).parse_literal()
+}
+
+impl QueryEngine {
+    /// Get ready to change the rows of a table, its name is matched ignoring case like sqlite does
+    pub fn table_writer(&self, table: &str) -> Result<TableWriter> {
+        let schema = &self.pager.schema_table.cells;
+        let record = schema
+            .iter()
+            .find(|rec| rec.db_object == DbObject::Table && rec.name.eq_ignore_ascii_case(table))
+            .ok_or_else(|| Error::NoSuchTable(table.to_string()))?;
+        TableWriter::new(record, schema)
+    }
+
+    /// Run a change to the database as one transaction, it's committed when it succeeds
+    /// and rolled back when it fails part way. Inside a transaction started with BEGIN the change
+    /// is kept until the transaction ends, failing only undoes the change itself
+    pub fn write<T>(&mut self, change: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if !self.pager.autocommit() {
+            self.pager.open_savepoint();
+            return match change(self) {
+                Ok(result) => {
+                    self.pager.release_savepoint();
+                    self.pager.load_schema()?;
+                    Ok(result)
+                }
+                Err(error) => {
+                    self.pager.rollback_savepoint()?;
+                    Err(error)
+                }
+            };
+        }
+        // another connection may have changed the schema or the rows since the database was opened
+        self.pager.refresh()?;
+        let result = change(self).and_then(|result| {
+            self.pager.commit()?;
+            Ok(result)
+        });
+        if result.is_err() {
+            self.pager.rollback()?;
+        }
+        result
+    }
+}
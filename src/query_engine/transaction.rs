@@ -0,0 +1,486 @@
+use anyhow::{bail, Result};
+
+use crate::{sql_parser::parser::TransactionKind, vfs::vfs::LockLevel};
+
+use super::engine::QueryEngine;
+
+impl QueryEngine {
+    /// Turn off autocommit until `commit_transaction` or `rollback_transaction`, the writes in between
+    /// are committed together. A failed statement inside the transaction only undoes its own changes
+    pub fn begin_transaction(&mut self, kind: TransactionKind) -> Result<()> {
+        if !self.pager.autocommit() {
+            bail!("cannot start a transaction within a transaction");
+        }
+        // another connection may have changed the database since it was opened
+        self.pager.refresh()?;
+        let lock = match kind {
+            TransactionKind::Deferred => LockLevel::None,
+            TransactionKind::Immediate => LockLevel::Reserved,
+            TransactionKind::Exclusive => LockLevel::Exclusive,
+        };
+        self.pager.begin_transaction(lock)
+    }
+
+    /// Commit the writes of the transaction. Like sqlite's SQLITE_BUSY a commit that's locked out
+    /// leaves the transaction open to be committed again, see `Pager::commit`
+    pub fn commit_transaction(&mut self) -> Result<()> {
+        if self.pager.autocommit() {
+            bail!("cannot commit - no transaction is active");
+        }
+        let committed = self.pager.commit();
+        // the pager ends the transaction once it's committed or has been rolled back
+        if self.pager.autocommit() {
+            self.close_savepoints();
+        }
+        committed
+    }
+
+    /// Throw away the writes of the transaction
+    pub fn rollback_transaction(&mut self) -> Result<()> {
+        if self.pager.autocommit() {
+            bail!("cannot rollback - no transaction is active");
+        }
+        self.close_savepoints();
+        self.pager.rollback()
+    }
+
+    /// Open a savepoint, starting a transaction when there isn't one. Savepoints nest and can share a name,
+    /// the newest one with the name is the one that's released or rolled back to
+    pub fn savepoint(&mut self, name: String) -> Result<()> {
+        if self.pager.autocommit() {
+            self.begin_transaction(TransactionKind::Deferred)?;
+            self.savepoint_began_transaction = true;
+        }
+        self.pager.open_savepoint();
+        self.savepoints.push(name);
+        Ok(())
+    }
+
+    /// Close a savepoint and the ones opened after it, their changes become part of the savepoint or
+    /// transaction around them. Releasing the savepoint that started the transaction commits it
+    pub fn release_savepoint(&mut self, name: &str) -> Result<()> {
+        let depth = self.find_savepoint(name)?;
+        if depth == 0 && self.savepoint_began_transaction {
+            return self.commit_transaction();
+        }
+        while self.savepoints.len() > depth {
+            self.savepoints.pop();
+            self.pager.release_savepoint();
+        }
+        Ok(())
+    }
+
+    /// Undo the changes made since a savepoint was opened, only the pages changed since then are put back.
+    /// The savepoints opened after it are closed, it stays open along with the transaction
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> Result<()> {
+        let depth = self.find_savepoint(name)?;
+        while self.savepoints.len() > depth + 1 {
+            self.savepoints.pop();
+            self.pager.rollback_savepoint()?;
+        }
+        self.pager.rollback_savepoint()?;
+        self.pager.open_savepoint();
+        Ok(())
+    }
+
+    /// Position of the newest open savepoint with the name in the stack
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow::anyhow!("no such savepoint: {}", name))
+    }
+
+    /// Forget the savepoints when the transaction ends, the pager drops its own
+    fn close_savepoints(&mut self) {
+        self.savepoints.clear();
+        self.savepoint_began_transaction = false;
+    }
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        pager::{create::CreateOptions, pager::Pager},
+        vfs::{
+            fault::{Crash, FaultyVfs},
+            memory::MemoryVfs,
+            vfs::{OpenMode, Vfs},
+        },
+    };
+
+    /// A database of 512 byte pages whose rows spill onto overflow pages
+    fn database() -> MemoryVfs {
+        let vfs = MemoryVfs::new();
+        let options = CreateOptions {
+            page_size: 512,
+            ..CreateOptions::default()
+        };
+        Pager::create_with(&vfs, "test.db", options).unwrap();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        engine
+            .execute_sql("CREATE TABLE t (id integer primary key, name text unique, body text)")
+            .unwrap();
+        for i in 0..20 {
+            let sql = format!(
+                "INSERT INTO t (name, body) VALUES ('row {}', '{}')",
+                i,
+                "x".repeat(i * 40)
+            );
+            engine.execute_sql(&sql).unwrap();
+        }
+        vfs
+    }
+
+    /// Rows and schema of the database, after checking its integrity. A writer plays back a hot journal first
+    fn contents(vfs: &dyn Vfs, mode: OpenMode) -> String {
+        let mut engine = QueryEngine::open_with_mode(vfs, "test.db", mode);
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+        let schema = engine
+            .pager
+            .schema_table
+            .cells
+            .iter()
+            .map(|rec| rec.sql.clone())
+            .collect::<Vec<_>>();
+        let rows = engine.execute_sql("SELECT * FROM t").unwrap();
+        format!("{}\n{}", schema.join("\n"), rows)
+    }
+
+    fn journal_size(vfs: &MemoryVfs) -> Option<usize> {
+        vfs.read(Path::new("test.db-journal"))
+            .map(|journal| journal.len())
+    }
+
+    #[test]
+    fn test_transactions_commit_together() {
+        let vfs = database();
+        let before = contents(&vfs, OpenMode::ReadWrite);
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        engine
+            .execute_sql(
+                "BEGIN; INSERT INTO t (name) VALUES ('new'); DELETE FROM t WHERE name = 'row 3'",
+            )
+            .unwrap();
+        assert_eq!(
+            engine
+                .execute_sql("INSERT INTO t (name) VALUES ('row 1')")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: t.name"
+        );
+        engine
+            .execute_sql("CREATE TABLE u (a); INSERT INTO u VALUES ('1')")
+            .unwrap();
+        assert_eq!(
+            engine
+                .execute_sql("SELECT id FROM t WHERE name = 'new'")
+                .unwrap(),
+            "21",
+            "the transaction reads its own writes"
+        );
+        assert!(journal_size(&vfs).is_some_and(|size| size > 0));
+        assert_eq!(
+            contents(&vfs, OpenMode::ReadOnly),
+            before,
+            "other connections don't see the transaction before COMMIT"
+        );
+
+        engine.execute_sql("COMMIT").unwrap();
+        assert_eq!(journal_size(&vfs), None);
+        let mut other = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        assert_eq!(other.execute_sql("SELECT a FROM u").unwrap(), "1");
+        assert_eq!(
+            other
+                .execute_sql("SELECT name FROM t")
+                .unwrap()
+                .lines()
+                .count(),
+            20,
+            "only the failed statement was undone"
+        );
+    }
+
+    #[test]
+    fn test_rolling_back() {
+        let vfs = database();
+        let before = vfs.read(Path::new("test.db")).unwrap();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        engine
+            .execute_sql(
+                "BEGIN IMMEDIATE; UPDATE t SET body = 'short'; DROP TABLE t; CREATE TABLE u (a)",
+            )
+            .unwrap();
+        engine.execute_sql("ROLLBACK").unwrap();
+
+        assert_eq!(vfs.read(Path::new("test.db")).unwrap(), before);
+        assert_eq!(journal_size(&vfs), None);
+        assert_eq!(engine.pager.schema_table.cells.len(), 2);
+        assert_eq!(
+            engine
+                .execute_sql("SELECT body FROM t WHERE name = 'row 1'")
+                .unwrap(),
+            "x".repeat(40)
+        );
+
+        // closing the database rolls back a transaction that's still open
+        engine.execute_sql("BEGIN; DELETE FROM t").unwrap();
+        drop(engine);
+        assert_eq!(vfs.read(Path::new("test.db")).unwrap(), before);
+    }
+
+    #[test]
+    fn test_transaction_errors_and_locks() {
+        let vfs = database();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let error =
+            |engine: &mut QueryEngine, sql| engine.execute_sql(sql).unwrap_err().to_string();
+        assert_eq!(
+            error(&mut engine, "COMMIT"),
+            "cannot commit - no transaction is active"
+        );
+        assert_eq!(
+            error(&mut engine, "ROLLBACK"),
+            "cannot rollback - no transaction is active"
+        );
+
+        engine.execute_sql("BEGIN EXCLUSIVE TRANSACTION").unwrap();
+        assert_eq!(
+            error(&mut engine, "BEGIN"),
+            "cannot start a transaction within a transaction"
+        );
+        let mut other = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        assert_eq!(
+            error(&mut other, "DELETE FROM t"),
+            "database is locked",
+            "BEGIN EXCLUSIVE takes the lock straight away"
+        );
+        engine.execute_sql("END").unwrap();
+        other
+            .execute_sql("DELETE FROM t WHERE name = 'row 1'")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_commit_waits_for_readers() {
+        let vfs = database();
+        let before = vfs.read(Path::new("test.db")).unwrap();
+        let mut reader = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadOnly);
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let read = reader.pager.begin_read().unwrap();
+
+        engine
+            .execute_sql("BEGIN; DELETE FROM t WHERE name = 'row 1'")
+            .unwrap();
+        assert_eq!(
+            engine.execute_sql("COMMIT").unwrap_err().to_string(),
+            "database is locked",
+            "the file can't be written under a read"
+        );
+        assert_eq!(vfs.read(Path::new("test.db")).unwrap(), before);
+        assert!(
+            !engine.pager.autocommit(),
+            "the transaction stays open to be committed again"
+        );
+
+        drop(read);
+        engine.execute_sql("COMMIT").unwrap();
+        let names = reader.execute_sql("SELECT name FROM t").unwrap();
+        assert_eq!(names.lines().count(), 19);
+        assert!(!names.lines().any(|name| name == "row 1"));
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let vfs = database();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        let body = |engine: &mut QueryEngine, name: &str| {
+            engine
+                .execute_sql(&format!("SELECT body FROM t WHERE name = '{}'", name))
+                .unwrap()
+        };
+        let row_count = |engine: &mut QueryEngine| {
+            let rows = engine.execute_sql("SELECT name FROM t").unwrap();
+            rows.lines().count()
+        };
+
+        // a savepoint outside a transaction starts one
+        engine.execute_sql("SAVEPOINT a; UPDATE t SET body = 'a' WHERE name = 'row 1'; \
+             SAVEPOINT b; UPDATE t SET body = 'b' WHERE name = 'row 19'; DELETE FROM t WHERE name = 'row 2'",
+        )
+        .unwrap();
+        assert!(!engine.pager.autocommit());
+        engine.execute_sql("ROLLBACK TO b").unwrap();
+        assert_eq!(body(&mut engine, "row 1"), "a");
+        assert_eq!(body(&mut engine, "row 19"), "x".repeat(19 * 40));
+        assert_eq!(row_count(&mut engine), 20);
+
+        // b is still open after being rolled back to, releasing it keeps what was done since
+        engine.execute_sql("INSERT INTO t (name) VALUES ('new'); SAVEPOINT c; CREATE TABLE u (a); RELEASE SAVEPOINT B",
+        )
+        .unwrap();
+        assert_eq!(
+            engine.execute_sql("RELEASE c").unwrap_err().to_string(),
+            "no such savepoint: c"
+        );
+        assert_eq!(engine.pager.schema_table.cells.len(), 3);
+        assert_eq!(
+            contents(&vfs, OpenMode::ReadOnly).lines().count(),
+            22,
+            "other connections don't see the transaction"
+        );
+
+        engine
+            .execute_sql("ROLLBACK TRANSACTION TO SAVEPOINT a")
+            .unwrap();
+        assert_eq!(engine.pager.schema_table.cells.len(), 2);
+        assert_eq!(row_count(&mut engine), 20);
+        assert_eq!(body(&mut engine, "row 1"), "x".repeat(40));
+        engine
+            .execute_sql("DELETE FROM t WHERE name = 'row 3'; RELEASE a")
+            .unwrap();
+        assert!(engine.pager.autocommit(), "releasing a commits");
+        assert_eq!(
+            row_count(&mut QueryEngine::open_with_mode(
+                &vfs,
+                "test.db",
+                OpenMode::ReadWrite
+            )),
+            19
+        );
+    }
+
+    #[test]
+    fn test_nested_savepoints() {
+        let vfs = database();
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        engine.execute_sql("BEGIN").unwrap();
+        for i in 0..50 {
+            // savepoints can share a name, the newest is used
+            let name = match i {
+                0 => "outer",
+                i if i % 2 == 0 => "even",
+                _ => "odd",
+            };
+            engine
+                .execute_sql(&format!(
+                    "SAVEPOINT {}; INSERT INTO t (name) VALUES ('new {}')",
+                    name, i
+                ))
+                .unwrap();
+        }
+        let row_count = |engine: &mut QueryEngine| {
+            let rows = engine.execute_sql("SELECT name FROM t").unwrap();
+            rows.lines().count()
+        };
+        assert_eq!(row_count(&mut engine), 70);
+        // the newest even savepoint is from before the last two inserts, rolling back to it closes the last odd one
+        engine.execute_sql("ROLLBACK TO even").unwrap();
+        assert_eq!(row_count(&mut engine), 68);
+        engine.execute_sql("ROLLBACK TO odd").unwrap();
+        assert_eq!(row_count(&mut engine), 67);
+        assert_eq!(engine.savepoints.len(), 48);
+
+        // releasing the outermost savepoint of a transaction started by BEGIN doesn't end it
+        engine.execute_sql("RELEASE even; RELEASE outer").unwrap();
+        assert!(engine.savepoints.is_empty());
+        assert!(!engine.pager.autocommit());
+        engine.execute_sql("SAVEPOINT x; COMMIT").unwrap();
+        assert_eq!(
+            engine.execute_sql("ROLLBACK TO x").unwrap_err().to_string(),
+            "no such savepoint: x"
+        );
+        assert_eq!(
+            row_count(&mut QueryEngine::open_with_mode(
+                &vfs,
+                "test.db",
+                OpenMode::ReadWrite
+            )),
+            67
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recovering_from_a_crash_at_every_write() {
+        let database = database().read(Path::new("test.db")).unwrap();
+        let statements = [
+            "INSERT INTO t (name, body) VALUES ('a', 'new'), ('b', '".to_string()
+                + &"y".repeat(2000)
+                + "')",
+            "BEGIN; UPDATE t SET body = 'short' WHERE name = 'row 19'; \
+             DELETE FROM t WHERE name = 'row 5'; CREATE TABLE u (a unique); \
+             INSERT INTO u VALUES ('1'); COMMIT"
+                .to_string(),
+        ];
+        let fresh = || {
+            let memory = MemoryVfs::new();
+            memory.insert("test.db", database.clone());
+            FaultyVfs::new(memory)
+        };
+        let run = |vfs: &FaultyVfs<MemoryVfs>| -> Result<()> {
+            let mut engine =
+                QueryEngine::new(Pager::open_with_mode(vfs, "test.db", OpenMode::ReadWrite)?);
+            for sql in &statements {
+                engine.execute_sql(sql)?;
+            }
+            Ok(())
+        };
+
+        // the database after each of the statements
+        let vfs = fresh();
+        let mut states = vec![contents(&vfs, OpenMode::ReadWrite)];
+        let mut engine = QueryEngine::open_with_mode(&vfs, "test.db", OpenMode::ReadWrite);
+        for sql in &statements {
+            engine.execute_sql(sql).unwrap();
+            states.push(contents(&vfs, OpenMode::ReadWrite));
+        }
+        drop(engine);
+        let vfs = fresh();
+        run(&vfs).unwrap();
+        let writes = vfs.writes();
+
+        let mut recovered = vec![0; states.len()];
+        for write in 1..=writes {
+            for (torn, lose_unsynced) in
+                [(false, false), (true, false), (false, true), (true, true)]
+            {
+                let crash = Crash {
+                    write,
+                    torn,
+                    lose_unsynced,
+                };
+                let vfs = fresh();
+                vfs.crash(crash);
+                assert!(run(&vfs).is_err(), "{:?} didn't stop the writes", crash);
+                vfs.restart().unwrap();
+
+                // opening the database plays back the journal the crash left behind
+                let state = contents(&vfs, OpenMode::ReadWrite);
+                let Some(position) = states.iter().position(|s| *s == state) else {
+                    panic!(
+                        "{:?} left the database in a state it was never committed in",
+                        crash
+                    );
+                };
+                assert!(
+                    position < states.len() - 1,
+                    "{:?} committed the last write",
+                    crash
+                );
+                recovered[position] += 1;
+            }
+        }
+        assert!(
+            recovered
+                .iter()
+                .take(states.len() - 1)
+                .all(|count| *count > 0),
+            "{:?}",
+            recovered
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    data_model::btree::{record::Record, serial_value::SerialValue},
+    error::Error,
+    sql_parser::parser::{Column, Expression, SelectQuery, UpdateStatement},
+};
+
+use super::{
+    column::find_column_index,
+    engine::QueryEngine,
+    expression::{check_columns, evaluate},
+    schema_object::SchemaObject,
+    table_writer::TableWriter,
+};
+
+impl QueryEngine {
+    /// Set columns of the rows matching the WHERE clause, or of every row without one, keeping their
+    /// index entries up to date. Returns the number of rows updated
+    pub fn update(&mut self, statement: UpdateStatement) -> Result<usize> {
+        self.write(|engine| engine.update_rows(statement))
+    }
+
+    fn update_rows(&mut self, statement: UpdateStatement) -> Result<usize> {
+        let writer = self.table_writer(&statement.table)?;
+        writer.ensure_no_checks("updating")?;
+        let assignments = statement
+            .assignments
+            .into_iter()
+            .map(|(column, expression)| {
+                let position = find_column_index(&writer.columns, &column)?;
+                check_columns(&expression, &writer.columns)?;
+                Ok((position, expression))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let table = SchemaObject::try_from(self.get_table_rec(&writer.name)?)?;
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: writer.name.clone(),
+            where_clause: statement.where_clause,
+        };
+        // the rows are found before any are changed so the b-trees aren't changed while they're read
+        let rows = self.matching_rows(&table, &query)?;
+        for row in &rows {
+            let values: Vec<SerialValue<'static>> = row
+                .record
+                .values()?
+                .into_iter()
+                .map(SerialValue::into_owned)
+                .collect();
+            let values = table.complete_row(values);
+            self.update_row(&writer, row.row_header.row_id as i64, values, &assignments)?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Give one row, whose values are in table order, the assigned values, which are all computed from
+    /// the values the row had before. The row is rewritten where it is unless its rowid or primary key
+    /// changes, then it's deleted and inserted again
+    fn update_row(
+        &mut self,
+        writer: &TableWriter,
+        row_id: i64,
+        old: Vec<SerialValue<'static>>,
+        assignments: &[(usize, Expression)],
+    ) -> Result<()> {
+        let mut new = old.clone();
+        // the INTEGER PRIMARY KEY column holds NULL in the record, its value is the rowid
+        if let Some(alias) = writer.row_id_alias {
+            new[alias] = SerialValue::Int(row_id);
+        }
+        let values = assignments
+            .iter()
+            .map(|(position, expression)| {
+                let value = evaluate(expression, &writer.columns, &new)?;
+                Ok((*position, writer.affinities[*position].apply(value)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (position, value) in values {
+            new[position] = value;
+        }
+
+        let new_row_id = match writer.row_id_alias.map(|alias| &new[alias]) {
+            Some(SerialValue::Int(new_row_id)) => *new_row_id,
+            None => row_id,
+            // unlike INSERT, sqlite doesn't pick a new rowid for NULL
+            Some(value) => bail!(Error::TypeMismatch {
+                expected: "an integer rowid".into(),
+                found: match value {
+                    SerialValue::Null => "NULL".into(),
+                    value => format!("'{}'", value),
+                },
+            }),
+        };
+        let key_changed = writer
+            .primary_key
+            .as_ref()
+            .is_some_and(|(key, _)| key.iter().any(|position| old[*position] != new[*position]));
+        if new_row_id != row_id || key_changed {
+            self.delete_row(writer, row_id, old)?;
+            self.insert_row(writer, new, 0)?;
+            return Ok(());
+        }
+
+        writer.ensure_not_null(&new)?;
+        if let Some(alias) = writer.row_id_alias {
+            new[alias] = SerialValue::Null;
+        }
+        let old = writer.record_order(old);
+        let new = writer.record_order(new);
+
+        // only indexes on columns that changed need their entry replaced
+        let changed: Vec<_> = writer
+            .indexes
+            .iter()
+            .map(|index| {
+                let old_entry = index.key.entry_for(row_id, &old);
+                let new_entry = index.key.entry_for(row_id, &new);
+                (index, old_entry, new_entry)
+            })
+            .filter(|(_, old_entry, new_entry)| old_entry != new_entry)
+            .collect();
+        for (index, old_entry, _) in &changed {
+            self.delete_index_entry(index, old_entry, row_id)?;
+        }
+        for (index, _, new_entry) in &changed {
+            self.ensure_unique(writer, index, new_entry)?;
+        }
+
+        let record = Record::new(new, self.pager.db_header.text_encoding);
+        let pager = &mut self.pager;
+        let replaced = match &writer.primary_key {
+            // the cell holding the key may be on an interior page, so it's removed and added again
+            Some((key, key_columns)) => {
+                pager.delete_entry(writer.rootpage, &old, key_columns, key.len())?
+                    && pager.insert_entry(writer.rootpage, &record, key_columns, key.len())?
+            }
+            None => pager.insert_row(writer.rootpage, row_id, &record, true)?,
+        };
+        if !replaced {
+            bail!(Error::corrupt_page(
+                writer.rootpage,
+                format!(
+                    "row {} of {} disappeared while updating it",
+                    row_id, writer.name
+                )
+            ));
+        }
+
+        for (index, _, new_entry) in changed {
+            self.insert_index_entry(index, new_entry, row_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod update_tests {
+    use super::*;
+    use crate::vfs::memory::MemoryVfs;
+
+    #[test]
+    fn test_updating_rows_and_their_indexes() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        engine
+            .execute_sql(
+                "UPDATE users SET age = 37, name = 'Ada Lovelace' WHERE email = 'ada@example.com'",
+            )
+            .unwrap();
+        // the INTEGER PRIMARY KEY is the rowid, changing it moves the row
+        engine
+            .execute_sql("UPDATE users SET id = 7 WHERE name = 'Alan'")
+            .unwrap();
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "1|ada@example.com|Ada Lovelace|37\n7|alan@example.com|Alan|41"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE age = '36'")
+                .unwrap(),
+            ""
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE age = '37'")
+                .unwrap(),
+            "Ada Lovelace"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE email = 'alan@example.com'")
+                .unwrap(),
+            "Alan",
+            "the index entry refers to the new rowid"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_setting_columns_from_the_row() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        // every assignment sees the values the row had before the update
+        engine
+            .execute_sql("UPDATE users SET age = age + 1, name = email, email = name")
+            .unwrap();
+        engine
+            .execute_sql("UPDATE users SET id = id * 10 - 1 WHERE email = 'Alan'")
+            .unwrap();
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "1|Ada|ada@example.com|37\n19|Alan|alan@example.com|42"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE age = '37'")
+                .unwrap(),
+            "ada@example.com"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_updating_a_row_selected_by_its_id() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        engine
+            .execute_sql("UPDATE users SET age = 50 WHERE id = '2'")
+            .unwrap();
+        engine
+            .execute_sql("UPDATE users SET id = 5 WHERE id = '2'")
+            .unwrap();
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "1|ada@example.com|Ada|36\n5|alan@example.com|Alan|50"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE id = '5'")
+                .unwrap(),
+            "Alan"
+        );
+        assert_eq!(
+            engine
+                .execute_sql("SELECT name FROM users WHERE id = '2'")
+                .unwrap(),
+            ""
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_updates_change_nothing() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/constraints.db");
+        let error =
+            |engine: &mut QueryEngine, sql| engine.execute_sql(sql).unwrap_err().to_string();
+        assert_eq!(
+            error(&mut engine, "UPDATE users SET email = 'alan@example.com'"),
+            "UNIQUE constraint failed: users.email"
+        );
+        assert_eq!(
+            error(&mut engine, "UPDATE users SET id = 2 WHERE name = 'Ada'"),
+            "UNIQUE constraint failed: users.id"
+        );
+        assert_eq!(
+            error(&mut engine, "UPDATE users SET email = NULL"),
+            "NOT NULL constraint failed: users.email"
+        );
+        assert!(error(&mut engine, "UPDATE users SET id = 'one'").contains("type mismatch"));
+        assert!(error(&mut engine, "UPDATE users SET missing = 1").contains("missing"));
+        assert!(error(&mut engine, "UPDATE users SET age = missing + 1").contains("missing"));
+
+        assert_eq!(
+            engine.execute_sql("SELECT * FROM users").unwrap(),
+            "1|ada@example.com|Ada|36\n2|alan@example.com|Alan|41"
+        );
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_updating_rows_to_and_from_overflow_pages() {
+        let vfs = MemoryVfs::new();
+        let mut engine = QueryEngine::open_fixture(&vfs, "fixtures/overflow.db");
+        let long = "long ".repeat(2000);
+        engine
+            .execute_sql(&format!("UPDATE documents SET body = '{}'", long))
+            .unwrap();
+        let bodies = engine.execute_sql("SELECT body FROM documents").unwrap();
+        assert!(bodies.lines().all(|body| body == long.as_str()));
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+
+        engine
+            .execute_sql("UPDATE documents SET body = 'short'")
+            .unwrap();
+        let bodies = engine.execute_sql("SELECT body FROM documents").unwrap();
+        assert!(bodies.lines().all(|body| body == "short"));
+        // the overflow chains of the long bodies were freed
+        assert!(engine.pager.freelist().unwrap().page_count() > 0);
+        assert!(engine.pager.integrity_check().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::{bail, ensure, Result};
+
+use crate::{
+    data_model::{
+        btree::{
+            index_interior_cell::IndexInteriorCell,
+            index_leaf_cell::IndexLeafCell,
+            page::ensure_depth,
+            page_header::PageType,
+            table_leaf_cell::{RowHeader, TableLeafCell},
+        },
+        table::Table,
+    },
+    error::Error,
+    sql_parser::parser::Comparison,
+};
+
+use super::{
+    column::find_column_index, engine::QueryEngine, filter::create_record_filter,
+    schema_object::SchemaObject,
+};
+
+impl QueryEngine {
+    /// Collect the rows of a WITHOUT ROWID table, which is stored as an index b-tree keyed by its primary key.
+    /// Equality on the first primary key column is answered with a binary search, anything else scans the tree
+    pub fn without_rowid_scan(
+        &self,
+        table: &SchemaObject,
+        comparison: &Option<Comparison>,
+    ) -> Result<Vec<TableLeafCell<'static>>> {
+        let record_columns = table.record_columns();
+        let mut entries: Vec<IndexLeafCell<'static>> = vec![];
+
+        match comparison {
+            Some(cmp) if find_column_index(&record_columns, &cmp.column)? == 0 => {
+                self.index_binary_search(table.rootpage, cmp, &mut entries, table)?;
+            }
+            Some(cmp) => {
+                self.recursive_index_scan(table.rootpage, &mut entries, 0)?;
+                let defaults = table.record_defaults();
+                let record_predicate = create_record_filter(&record_columns, &defaults, cmp)?;
+                let mut matching = vec![];
+                for entry in entries {
+                    if record_predicate(&entry.record)? {
+                        matching.push(entry);
+                    }
+                }
+                entries = matching;
+            }
+            None => self.recursive_index_scan(table.rootpage, &mut entries, 0)?,
+        }
+
+        entries
+            .into_iter()
+            .map(|entry| without_rowid_row(table, &record_columns, entry))
+            .collect()
+    }
+
+    /// Traverses an index BTree collecting every entry in key order
+    fn recursive_index_scan(
+        &self,
+        page_number: u32,
+        entries: &mut Vec<IndexLeafCell<'static>>,
+        depth: usize,
+    ) -> Result<()> {
+        ensure_depth(page_number, depth)?;
+        let (page, buf) = self.pager.read_page(page_number)?;
+        match page.header.page_type {
+            PageType::IndexInterior => {
+                let interior_table = Table::<IndexInteriorCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
+                for cell in interior_table.cells {
+                    self.recursive_index_scan(cell.left_child, entries, depth + 1)?;
+                    entries.push(IndexLeafCell::from(cell).retain(buf.get_ref()));
+                }
+                match page.header.rightmost_pointer {
+                    Some(rightmost_pointer) => {
+                        self.recursive_index_scan(rightmost_pointer, entries, depth + 1)
+                    }
+                    None => bail!(Error::corrupt_page(
+                        page_number,
+                        "interior index page header missing right most pointer"
+                    )),
+                }
+            }
+            PageType::IndexLeaf => {
+                let table = Table::<IndexLeafCell>::new(
+                    page_number,
+                    buf.get_ref(),
+                    &page.cell_pointers,
+                    &self.pager,
+                )?;
+                entries.extend(
+                    table
+                        .cells
+                        .into_iter()
+                        .map(|cell| cell.retain(buf.get_ref())),
+                );
+                Ok(())
+            }
+            page_type => bail!(Error::corrupt_page(
+                page_number,
+                format!("{} page found in an index b-tree", page_type)
+            )),
+        }
+    }
+}
+
+/// Rebuild a row in the declared column order from an entry stored primary key first
+fn without_rowid_row(
+    table: &SchemaObject,
+    record_columns: &[String],
+    entry: IndexLeafCell<'static>,
+) -> Result<TableLeafCell<'static>> {
+    let mut columns = vec![];
+    for column in &table.columns {
+        let idx = find_column_index(record_columns, column)?;
+        ensure!(
+            idx < entry.record.len() || idx >= table.primary_key.len(),
+            Error::corrupt_page(
+                table.rootpage,
+                format!("entry of {} has no value for {}", table.name, column)
+            )
+        );
+        // entries written before a column was added to the table stop short, added columns come last
+        if idx >= entry.record.len() {
+            break;
+        }
+        columns.push(idx);
+    }
+
+    Ok(TableLeafCell {
+        // WITHOUT ROWID tables have no rowid
+        row_header: RowHeader {
+            size: entry.size,
+            row_id: 0,
+        },
+        record: entry.record.select_columns(&columns),
+        overflow_page: entry.overflow_page,
+    })
+}
+
+#[cfg(test)]
+mod without_rowid_tests {
+    use std::path::Path;
+
+    use crate::{
+        pager::pager::Pager,
+        sql_parser::parser::{Column, Operator, SelectQuery},
+    };
+
+    use super::*;
+
+    fn select(engine: &mut QueryEngine, table: &str, column: &str, value: &str) -> String {
+        engine
+            .run_query(SelectQuery {
+                columns: vec![Column::All],
+                table: table.into(),
+                where_clause: Some(Comparison {
+                    column: column.into(),
+                    operator: Operator::Equals,
+                    value: value.into(),
+                }),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_primary_key_lookup() {
+        let path = Path::new("fixtures/without_rowid.db");
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let mut engine = QueryEngine::new(pager);
+
+        // the primary key is stored first but rows come back in declared order
+        assert_eq!(
+            select(&mut engine, "countries", "code", "KCA"),
+            "country 262|KCA|262007"
+        );
+        assert_eq!(select(&mut engine, "countries", "code", "ZZA"), "");
+        assert_eq!(
+            select(&mut engine, "countries", "name", "country 5"),
+            "country 5|AFA|5007"
+        );
+    }
+
+    #[test]
+    fn test_composite_primary_key_scan() {
+        let path = Path::new("fixtures/without_rowid.db");
+        let pager = Pager::open(path).expect("Failed to initialize pager");
+        let mut engine = QueryEngine::new(pager);
+
+        let usd_rates = select(&mut engine, "rates", "currency", "USD");
+        assert_eq!(usd_rates.lines().count(), 28);
+        assert_eq!(usd_rates.lines().next(), Some("USD|2024-02-01|0.25"));
+
+        let table = SchemaObject::try_from(engine.get_table_rec("rates").unwrap()).unwrap();
+        let query = SelectQuery {
+            columns: vec![Column::All],
+            table: "rates".into(),
+            where_clause: None,
+        };
+        assert_eq!(engine.table_db_scan(&table, &query).unwrap().len(), 112);
+    }
+}
@@ -1,5 +1,19 @@
-use std::io::Read;
+use std::io::{Cursor, Read};
 
-pub trait Deserialize {
-    fn deserialize<T: Read>(reader: &mut T) -> Self;
+use anyhow::Result;
+
+use crate::data_model::btree::overflow::PayloadReader;
+
+pub trait Deserialize: Sized {
+    fn deserialize<T: Read>(reader: &mut T) -> Result<Self>;
+}
+
+/// B-Tree cells read from the bytes of page `page_number`, whose payload can continue onto overflow pages.
+/// Payloads that fit on the page are borrowed from it
+pub trait DeserializeCell<'a>: Sized {
+    fn deserialize_cell<O: PayloadReader>(
+        page_number: u32,
+        reader: &mut Cursor<&'a [u8]>,
+        payload_reader: &O,
+    ) -> Result<Self>;
 }
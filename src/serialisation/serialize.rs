@@ -0,0 +1,176 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::data_model::btree::overflow::PayloadReader;
+
+/// The counterpart of `Deserialize`, writes the same bytes it reads
+pub trait Serialize {
+    fn serialize<T: Write>(&self, writer: &mut T) -> Result<()>;
+}
+
+/// The counterpart of `DeserializeCell`. Only the part of the payload that is stored on the page is written,
+/// followed by the first overflow page when the payload doesn't fit
+pub trait SerializeCell {
+    fn serialize_cell<T: Write, O: PayloadReader>(
+        &self,
+        writer: &mut T,
+        payload_reader: &O,
+    ) -> Result<()>;
+}
+
+#[cfg(test)]
+mod serialize_tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        data_model::{
+            btree::{
+                index_interior_cell::IndexInteriorCell, index_leaf_cell::IndexLeafCell, page::Page,
+                page_header::PageType, record::Record, table_interior_cell::TableInteriorCell,
+                table_leaf_cell::TableLeafCell,
+            },
+            db_header::DB_HEADER_SIZE,
+            table::Table,
+        },
+        pager::{page_map::PageOwner, pager::Pager},
+        serialisation::deserialize::DeserializeCell,
+    };
+
+    /// Write every cell of the page at the offset its cell pointer gives,
+    /// checking that re-encoding the decoded values gives the same record as well
+    fn write_cells<'a, C: DeserializeCell<'a> + SerializeCell>(
+        pager: &Pager,
+        page_number: u32,
+        page: &'a [u8],
+        cell_pointers: &[u16],
+        payload: impl Fn(&C) -> Option<&[u8]>,
+        writer: &mut Cursor<Vec<u8>>,
+    ) {
+        let table = Table::<C>::new(page_number, page, cell_pointers, pager).unwrap();
+        for (cell, pointer) in table.cells.iter().zip(cell_pointers) {
+            writer.set_position(*pointer as u64);
+            cell.serialize_cell(writer, pager).unwrap();
+
+            if let Some(payload) = payload(cell) {
+                let encoding = pager.db_header.text_encoding;
+                let record = Record::read(&mut Cursor::new(payload), encoding).unwrap();
+                let mut written = vec![];
+                record.write(&mut written, encoding).unwrap();
+                assert_eq!(written, payload, "record on page {}", page_number);
+            }
+        }
+    }
+
+    /// Copy the unallocated space after the cell pointers and the freeblocks of a page, they can hold
+    /// leftovers of deleted cells that nothing deserialized describes
+    fn copy_free_space(page: &Page, page_number: u32, bytes: &[u8], written: &mut [u8]) {
+        let header_offset = if page_number == 1 { DB_HEADER_SIZE } else { 0 };
+        let header_size = match page.header.rightmost_pointer {
+            Some(_) => 12,
+            None => 8,
+        };
+        let content_start = match page.header.cell_content_offset {
+            0 => 65536,
+            offset => offset as usize,
+        };
+        let unallocated = header_offset + header_size + page.cell_pointers.len() * 2..content_start;
+        written[unallocated.clone()].copy_from_slice(&bytes[unallocated]);
+
+        let read_u16 = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut freeblock = page.header.first_free_block as usize;
+        while freeblock != 0 {
+            let freeblock_end = freeblock + read_u16(freeblock + 2) as usize;
+            written[freeblock..freeblock_end].copy_from_slice(&bytes[freeblock..freeblock_end]);
+            freeblock = read_u16(freeblock) as usize;
+        }
+    }
+
+    /// Deserialize every b-tree page of the database and serialize it again into an empty page,
+    /// keeping the free space of the original page when asked to
+    fn assert_pages_round_trip(path: &str, keep_free_space: bool) {
+        let pager = Pager::open(path).unwrap();
+        let page_map = pager.page_map().unwrap();
+        let btree_pages = (1..=page_map.page_count)
+            .filter(|page| matches!(page_map.owner(*page), PageOwner::BTree(_)));
+
+        for page_number in btree_pages {
+            let (page, buf) = pager.read_page(page_number).unwrap();
+            let bytes = buf.get_ref();
+            let mut writer = Cursor::new(vec![0u8; bytes.len()]);
+            if page_number == 1 {
+                pager.db_header.serialize(&mut writer).unwrap();
+            }
+            page.serialize(&mut writer).unwrap();
+
+            let pointers = &page.cell_pointers;
+            match page.header.page_type {
+                PageType::TableInterior => write_cells::<TableInteriorCell>(
+                    &pager,
+                    page_number,
+                    bytes,
+                    pointers,
+                    |_| None,
+                    &mut writer,
+                ),
+                PageType::TableLeaf => write_cells::<TableLeafCell>(
+                    &pager,
+                    page_number,
+                    bytes,
+                    pointers,
+                    |cell| Some(cell.record.payload()),
+                    &mut writer,
+                ),
+                PageType::IndexInterior => write_cells::<IndexInteriorCell>(
+                    &pager,
+                    page_number,
+                    bytes,
+                    pointers,
+                    |cell| Some(cell.record.payload()),
+                    &mut writer,
+                ),
+                PageType::IndexLeaf => write_cells::<IndexLeafCell>(
+                    &pager,
+                    page_number,
+                    bytes,
+                    pointers,
+                    |cell| Some(cell.record.payload()),
+                    &mut writer,
+                ),
+            }
+
+            let mut written = writer.into_inner();
+            if keep_free_space {
+                copy_free_space(&page, page_number, bytes, &mut written);
+            }
+            if page_number == 1 {
+                assert_eq!(written[..DB_HEADER_SIZE], bytes[..DB_HEADER_SIZE]);
+            }
+            assert!(
+                written[..] == bytes[..],
+                "page {} of {} changed",
+                page_number,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_pages_round_trip() {
+        // sqlite zeroes the free space of pages in this database so every byte is written back
+        assert_pages_round_trip("sample.db", false);
+    }
+
+    #[test]
+    fn test_overflow_and_utf16_pages_round_trip() {
+        for path in [
+            "fixtures/overflow.db",
+            "fixtures/utf16le.db",
+            "fixtures/utf16be.db",
+            "fixtures/without_rowid.db",
+        ] {
+            assert_pages_round_trip(path, true);
+        }
+    }
+}
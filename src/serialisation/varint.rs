@@ -1,54 +1,152 @@
-use std::io::Read;
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+/// The most bytes a varint takes, the 9th byte carries 8 bits rather than 7
+pub const MAX_VARINT_SIZE: usize = 9;
 
 /// read a variable length integer returning a tuple of the value and number of bytes read
-pub fn read_varint<T: Read>(reader: &mut T) -> (u64, u64) {
-    let mut read_more = true;
+pub fn read_varint<T: Read>(reader: &mut T) -> Result<(u64, u64)> {
     let mut result: u64 = 0;
-    let mut bytes_read = 0;
 
-    while read_more {
-        // read first byte
+    for bytes_read in 1..=MAX_VARINT_SIZE {
         let mut buf = [0u8; 1];
-        reader.read_exact(&mut buf).expect("failed to read varint");
-        bytes_read += 1;
-
-        // check high bit for continuation
-        let high_bit = buf[0] & 0b1000_0000;
-        read_more = high_bit != 0;
-
-        // make space for next 7 bits
-        result <<= 7;
-        // isolate low 7 bits and add to result
-        let low_bits = buf[0] & 0b0111_1111;
-        result |= low_bits as u64;
+        reader
+            .read_exact(&mut buf)
+            .context("failed to read varint")?;
+
+        // all 8 bits of the 9th byte are part of the value
+        if bytes_read == MAX_VARINT_SIZE {
+            result = (result << 8) | buf[0] as u64;
+            break;
+        }
+
+        // make space for next 7 bits and add the low 7 bits to the result
+        result = (result << 7) | (buf[0] & 0b0111_1111) as u64;
+
+        // a clear high bit means this is the last byte
+        if buf[0] & 0b1000_0000 == 0 {
+            return Ok((result, bytes_read as u64));
+        }
+    }
+
+    Ok((result, MAX_VARINT_SIZE as u64))
+}
+
+/// Number of bytes `write_varint` takes to write the value
+pub fn varint_size(value: u64) -> usize {
+    // values of up to 56 bits take 7 bits per byte, anything larger takes all 9 bytes
+    if value >> 56 != 0 {
+        return MAX_VARINT_SIZE;
+    }
+    let bits = 64 - value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// write a variable length integer the way sqlite does returning the number of bytes written
+pub fn write_varint<T: Write>(writer: &mut T, value: u64) -> Result<u64> {
+    let size = varint_size(value);
+    let mut buf = [0u8; MAX_VARINT_SIZE];
+
+    let mut remaining = value;
+    let mut groups = size;
+    if size == MAX_VARINT_SIZE {
+        buf[8] = remaining as u8;
+        remaining >>= 8;
+        groups -= 1;
+    }
+    // fill in 7 bits at a time from the end, every byte but the last 7 bit group has its high bit set
+    for i in (0..groups).rev() {
+        let continuation = if i == groups - 1 && size != MAX_VARINT_SIZE {
+            0
+        } else {
+            0b1000_0000
+        };
+        buf[i] = (remaining & 0b0111_1111) as u8 | continuation;
+        remaining >>= 7;
     }
 
-    return (result, bytes_read);
+    writer
+        .write_all(&buf[..size])
+        .context("failed to write varint")?;
+    Ok(size as u64)
 }
 
 #[test]
 fn test_read_varint() {
     let mut buf = std::io::Cursor::new(vec![0b0000_0001, 0b0000_0001]);
-    assert_eq!(read_varint(&mut buf), (1, 1));
-    assert_eq!(read_varint(&mut buf), (1, 1));
+    assert_eq!(read_varint(&mut buf).unwrap(), (1, 1));
+    assert_eq!(read_varint(&mut buf).unwrap(), (1, 1));
 }
 
 #[test]
 fn test_read_varint_reading_zero() {
     let mut buf = std::io::Cursor::new(vec![0b0000]);
-    assert_eq!(read_varint(&mut buf), (0, 1));
+    assert_eq!(read_varint(&mut buf).unwrap(), (0, 1));
 }
 
 #[test]
 fn test_read_varint_reading_continuation_bits() {
     let mut buf = std::io::Cursor::new(vec![0b10000111, 0b01101000]);
-    assert_eq!(read_varint(&mut buf), (1000, 2));
+    assert_eq!(read_varint(&mut buf).unwrap(), (1000, 2));
 }
 
 #[test]
 fn test_read_varint_large_value() {
-    // should be 2^63-1 because one of the 64 bits should be continuation
-    let largest_number: u64 = (2 as u64).pow(63) - 1;
-    let mut buf = std::io::Cursor::new(vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]); // 11111111,..., 01111111
-    assert_eq!(read_varint(&mut buf), (largest_number, 9));
+    // the 9th byte carries all 8 of its bits so 9 bytes can hold any 64 bit value
+    let mut buf = std::io::Cursor::new(vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(read_varint(&mut buf).unwrap(), (u64::MAX, 9));
+
+    let largest_i64 = 2_u64.pow(63) - 1;
+    let mut buf = std::io::Cursor::new(vec![0xBF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(read_varint(&mut buf).unwrap(), (largest_i64, 9));
+}
+
+#[test]
+fn test_read_varint_stops_after_nine_bytes() {
+    let mut buf = std::io::Cursor::new(vec![0x80; 12]);
+    assert_eq!(read_varint(&mut buf).unwrap(), (0x80, 9));
+    assert_eq!(buf.position(), 9);
+}
+
+#[test]
+fn test_write_varint() {
+    for (value, bytes) in [
+        (0, vec![0x00]),
+        (1, vec![0x01]),
+        (127, vec![0x7F]),
+        (128, vec![0x81, 0x00]),
+        (1000, vec![0b10000111, 0b01101000]),
+        (
+            0x00FF_FFFF_FFFF_FFFF,
+            vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+        ),
+        (
+            0x0100_0000_0000_0000,
+            vec![0x80, 0xC0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+        ),
+        (u64::MAX, vec![0xFF; 9]),
+    ] {
+        let mut buf = vec![];
+        assert_eq!(write_varint(&mut buf, value).unwrap(), bytes.len() as u64);
+        assert_eq!(buf, bytes, "{:#x}", value);
+        assert_eq!(varint_size(value), bytes.len());
+    }
+}
+
+#[test]
+fn test_varint_round_trips() {
+    // values either side of every size boundary
+    let values = (0..64)
+        .flat_map(|bit| {
+            let value = 1u64 << bit;
+            [value - 1, value, value + 1]
+        })
+        .chain([u64::MAX, i64::MAX as u64, -1i64 as u64]);
+    for value in values {
+        let mut buf = vec![];
+        let written = write_varint(&mut buf, value).unwrap();
+        let mut reader = std::io::Cursor::new(buf);
+        assert_eq!(read_varint(&mut reader).unwrap(), (value, written));
+    }
 }
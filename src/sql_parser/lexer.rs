@@ -1,81 +1,441 @@
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Select,
     From,
     Where,
+    Insert,
+    Into,
+    Delete,
+    Update,
+    Set,
+    Create,
+    Table,
+    Index,
+    Unique,
+    If,
+    Not,
+    Exists,
+    On,
+    Drop,
+    Alter,
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint,
+    Release,
+    Pragma,
+    Values,
+    Null,
     Identifier(String),
     Count, // TODO: support specifying count
     Equals,
     StringLiteral(String),
+    // kept as written so the parser can tell integers from reals
+    NumericLiteral(String),
+    BlobLiteral(Vec<u8>),
     Comma,
     Asterisk,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    LeftParen,
+    RightParen,
+    Semicolon,
     EOF,
 }
 
-pub fn lexer(input: &str) -> Vec<Token> {
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Select => write!(f, "SELECT"),
+            Self::From => write!(f, "FROM"),
+            Self::Where => write!(f, "WHERE"),
+            Self::Insert => write!(f, "INSERT"),
+            Self::Into => write!(f, "INTO"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Update => write!(f, "UPDATE"),
+            Self::Set => write!(f, "SET"),
+            Self::Create => write!(f, "CREATE"),
+            Self::Table => write!(f, "TABLE"),
+            Self::Index => write!(f, "INDEX"),
+            Self::Unique => write!(f, "UNIQUE"),
+            Self::If => write!(f, "IF"),
+            Self::Not => write!(f, "NOT"),
+            Self::Exists => write!(f, "EXISTS"),
+            Self::On => write!(f, "ON"),
+            Self::Drop => write!(f, "DROP"),
+            Self::Alter => write!(f, "ALTER"),
+            Self::Begin => write!(f, "BEGIN"),
+            Self::Commit => write!(f, "COMMIT"),
+            Self::Rollback => write!(f, "ROLLBACK"),
+            Self::Savepoint => write!(f, "SAVEPOINT"),
+            Self::Release => write!(f, "RELEASE"),
+            Self::Pragma => write!(f, "PRAGMA"),
+            Self::Values => write!(f, "VALUES"),
+            Self::Null => write!(f, "NULL"),
+            Self::Identifier(name) => write!(f, "{}", name),
+            Self::Count => write!(f, "COUNT(*)"),
+            Self::Equals => write!(f, "="),
+            Self::StringLiteral(value) => write!(f, "'{}'", value.replace('\'', "''")),
+            Self::NumericLiteral(value) => write!(f, "{}", value),
+            Self::BlobLiteral(bytes) => {
+                write!(f, "X'")?;
+                for byte in bytes {
+                    write!(f, "{:02X}", byte)?;
+                }
+                write!(f, "'")
+            }
+            Self::Comma => write!(f, ","),
+            Self::Asterisk => write!(f, "*"),
+            Self::Plus => write!(f, "+"),
+            Self::Minus => write!(f, "-"),
+            Self::Slash => write!(f, "/"),
+            Self::Percent => write!(f, "%"),
+            Self::LeftParen => write!(f, "("),
+            Self::RightParen => write!(f, ")"),
+            Self::Semicolon => write!(f, ";"),
+            Self::EOF => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Split SQL into tokens, each paired with its offset in bytes so errors can point at it
+pub fn lexer(input: &str) -> Result<Vec<(usize, Token)>> {
     let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+    let mut chars = input.char_indices().peekable();
 
-    while let Some(&ch) = chars.peek() {
+    while let Some(&(offset, ch)) = chars.peek() {
         match ch {
             // Skip whitespace
             c if c.is_ascii_whitespace() => {
                 chars.next();
             }
             ',' => {
-                tokens.push(Token::Comma);
+                tokens.push((offset, Token::Comma));
                 chars.next();
             }
             '=' => {
-                tokens.push(Token::Equals);
+                tokens.push((offset, Token::Equals));
                 chars.next();
             }
             '\'' => {
                 chars.next(); // Skip the opening quote
-                let literal: String = chars.by_ref().take_while(|&c| c != '\'').collect();
-                tokens.push(Token::StringLiteral(literal));
-                chars.next(); // Skip the closing quote
+                let literal = quoted(&mut chars, input, '\'')?;
+                tokens.push((offset, Token::StringLiteral(literal)));
+            }
+            '"' => {
+                // a quoted identifier can hold spaces and keywords
+                chars.next();
+                let identifier = quoted(&mut chars, input, '"')?;
+                tokens.push((offset, Token::Identifier(identifier)));
             }
             '*' => {
-                tokens.push(Token::Asterisk);
+                tokens.push((offset, Token::Asterisk));
+                chars.next();
+            }
+            '/' => {
+                tokens.push((offset, Token::Slash));
+                chars.next();
+            }
+            '%' => {
+                tokens.push((offset, Token::Percent));
+                chars.next();
+            }
+            '(' => {
+                tokens.push((offset, Token::LeftParen));
+                chars.next();
+            }
+            ')' => {
+                tokens.push((offset, Token::RightParen));
+                chars.next();
+            }
+            ';' => {
+                tokens.push((offset, Token::Semicolon));
+                chars.next();
+            }
+            'x' | 'X' if input[offset + 1..].starts_with('\'') => {
+                chars.next();
+                chars.next();
+                let hex = quoted(&mut chars, input, '\'')?;
+                let blob = (hex.len() % 2 == 0 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                    .then(|| {
+                        (0..hex.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                            .collect::<Result<Vec<u8>, _>>()
+                            .ok()
+                    })
+                    .flatten();
+                let Some(blob) = blob else {
+                    bail!(Error::ParseError {
+                        position: offset,
+                        expected: "an even number of hex digits".into(),
+                        found: format!("X'{}'", hex),
+                    });
+                };
+                tokens.push((offset, Token::BlobLiteral(blob)));
+            }
+            // a sign after a value is an operator, i.e the `-` of `a -1`
+            c if c.is_ascii_digit()
+                || c == '.'
+                || ((c == '-' || c == '+')
+                    && !follows_value(&tokens)
+                    && input[offset + 1..]
+                        .starts_with(|c: char| c.is_ascii_digit() || c == '.')) =>
+            {
+                let mut number = String::new();
+                if c == '-' || c == '+' {
+                    number.push(c);
+                    chars.next();
+                }
+                while let Some((_, ch)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '.')
+                {
+                    number.push(ch);
+                    // the sign of an exponent is part of the number
+                    if ch == 'e' || ch == 'E' {
+                        if let Some((_, sign)) = chars.next_if(|(_, c)| *c == '-' || *c == '+') {
+                            number.push(sign);
+                        }
+                    }
+                }
+                if number
+                    .trim_start_matches(['-', '+'])
+                    .parse::<f64>()
+                    .is_err()
+                {
+                    bail!(Error::ParseError {
+                        position: offset,
+                        expected: "a number".into(),
+                        found: number,
+                    });
+                }
+                tokens.push((offset, Token::NumericLiteral(number)));
+            }
+            '+' => {
+                tokens.push((offset, Token::Plus));
+                chars.next();
+            }
+            '-' => {
+                tokens.push((offset, Token::Minus));
                 chars.next();
             }
             _ => {
-                if ch.is_alphabetic() {
+                if ch.is_alphabetic() || ch == '_' {
                     let mut identifier = String::new();
                     // take while consumes non-matching character
-                    while let Some(ch) = chars.next_if(|c| !c.is_ascii_whitespace() && *c != ',') {
+                    while let Some((_, ch)) = chars
+                        .next_if(|(_, c)| !c.is_ascii_whitespace() && !",()=;'\"+-*/%".contains(*c))
+                    {
                         identifier.push(ch)
                     }
                     let token = match identifier.to_lowercase().as_str() {
                         "select" => Token::Select,
                         "from" => Token::From,
                         "where" => Token::Where,
-                        "count(*)" => Token::Count,
+                        "insert" => Token::Insert,
+                        "into" => Token::Into,
+                        "delete" => Token::Delete,
+                        "update" => Token::Update,
+                        "set" => Token::Set,
+                        "create" => Token::Create,
+                        "table" => Token::Table,
+                        "index" => Token::Index,
+                        "unique" => Token::Unique,
+                        "if" => Token::If,
+                        "not" => Token::Not,
+                        "exists" => Token::Exists,
+                        "on" => Token::On,
+                        "drop" => Token::Drop,
+                        "alter" => Token::Alter,
+                        "begin" => Token::Begin,
+                        "commit" => Token::Commit,
+                        "rollback" => Token::Rollback,
+                        "savepoint" => Token::Savepoint,
+                        "release" => Token::Release,
+                        "pragma" => Token::Pragma,
+                        "values" => Token::Values,
+                        "null" => Token::Null,
+                        "count" if input[offset + identifier.len()..].starts_with("(*)") => {
+                            chars.nth(2);
+                            Token::Count
+                        }
                         _ => Token::Identifier(identifier),
                     };
-                    tokens.push(token);
+                    tokens.push((offset, token));
                 } else {
-                    panic!("Unexpected character: {}", ch);
+                    bail!(Error::ParseError {
+                        position: offset,
+                        expected: "a keyword, identifier or string".into(),
+                        found: format!("'{}'", ch),
+                    });
+                }
+            }
+        }
+    }
+    tokens.push((input.len(), Token::EOF));
+    Ok(tokens)
+}
+
+/// Whether the last token ends a value, so a sign that follows it is an operator
+fn follows_value(tokens: &[(usize, Token)]) -> bool {
+    matches!(
+        tokens.last(),
+        Some((
+            _,
+            Token::Identifier(_)
+                | Token::StringLiteral(_)
+                | Token::NumericLiteral(_)
+                | Token::BlobLiteral(_)
+                | Token::Null
+                | Token::RightParen
+        ))
+    )
+}
+
+/// Read up to the closing quote, the opening one has already been taken.
+/// The quote is written twice to put it in the text, i.e 'it''s'
+fn quoted(
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    input: &str,
+    quote: char,
+) -> Result<String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some((_, c)) if c == quote => {
+                if chars.next_if(|(_, c)| *c == quote).is_none() {
+                    return Ok(text);
                 }
+                text.push(quote);
             }
+            Some((_, c)) => text.push(c),
+            None => bail!(Error::ParseError {
+                position: input.len(),
+                expected: "a closing quote".into(),
+                found: Token::EOF.to_string(),
+            }),
         }
     }
-    tokens.push(Token::EOF);
-    tokens
 }
 
 #[cfg(test)]
 mod lexer_tests {
     use super::{lexer, Token};
 
+    use crate::error::Error;
+
     #[test]
     fn test_tokenizing_multiple_columns() {
-        let tokens = lexer("col1, col2, Count(*)");
+        let tokens: Vec<Token> = lexer("col1, col2, Count(*)")
+            .unwrap()
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect();
         assert_eq!(Token::Identifier("col1".to_string()), tokens[0]);
         assert_eq!(Token::Comma, tokens[1]);
         assert_eq!(Token::Identifier("col2".to_string()), tokens[2]);
         assert_eq!(Token::Comma, tokens[3]);
         assert_eq!(Token::Count, tokens[4]);
     }
+
+    #[test]
+    fn test_string_literal_followed_by_comma() {
+        let tokens = lexer("'a b', c").unwrap();
+        assert_eq!(tokens[0], (0, Token::StringLiteral("a b".to_string())));
+        assert_eq!(tokens[1], (5, Token::Comma));
+        assert_eq!(tokens[2], (7, Token::Identifier("c".to_string())));
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        let error = lexer("select name from t where id = ?").unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::ParseError {
+                position, found, ..
+            }) => {
+                assert_eq!(*position, 30);
+                assert_eq!(found, "'?'");
+            }
+            _ => panic!("expected a parse error, got {:#}", error),
+        }
+        assert!(lexer("select 'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_tokenizing_insert() {
+        let tokens: Vec<Token> =
+            lexer(r#"INSERT INTO t ("a b", c) VALUES (-1.5e3, 'it''s', x'0aFF', NULL, 42);"#)
+                .unwrap()
+                .into_iter()
+                .map(|(_, token)| token)
+                .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Insert,
+                Token::Into,
+                Token::Identifier("t".into()),
+                Token::LeftParen,
+                Token::Identifier("a b".into()),
+                Token::Comma,
+                Token::Identifier("c".into()),
+                Token::RightParen,
+                Token::Values,
+                Token::LeftParen,
+                Token::NumericLiteral("-1.5e3".into()),
+                Token::Comma,
+                Token::StringLiteral("it's".into()),
+                Token::Comma,
+                Token::BlobLiteral(vec![0x0a, 0xff]),
+                Token::Comma,
+                Token::Null,
+                Token::Comma,
+                Token::NumericLiteral("42".into()),
+                Token::RightParen,
+                Token::Semicolon,
+                Token::EOF,
+            ]
+        );
+        assert!(lexer("VALUES (x'abc')").is_err());
+        assert!(lexer("VALUES (12ab)").is_err());
+    }
+
+    #[test]
+    fn test_tokenizing_arithmetic() {
+        let tokens: Vec<Token> = lexer("b = -1 - -b*2/(c+1) % 3")
+            .unwrap()
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Identifier("b".into()),
+                Token::Equals,
+                Token::NumericLiteral("-1".into()),
+                Token::Minus,
+                Token::Minus,
+                Token::Identifier("b".into()),
+                Token::Asterisk,
+                Token::NumericLiteral("2".into()),
+                Token::Slash,
+                Token::LeftParen,
+                Token::Identifier("c".into()),
+                Token::Plus,
+                Token::NumericLiteral("1".into()),
+                Token::RightParen,
+                Token::Percent,
+                Token::NumericLiteral("3".into()),
+                Token::EOF,
+            ]
+        );
+    }
 }
@@ -1,4 +1,8 @@
-use super::lexer::Token;
+use anyhow::Result;
+
+use crate::error::Error;
+
+use super::lexer::{lexer, Token};
 
 #[derive(Debug, PartialEq)]
 pub enum Column {
@@ -31,117 +35,775 @@ pub struct SelectQuery {
     pub where_clause: Option<Comparison>,
 }
 
+/// A constant written in SQL
+#[derive(Debug, PartialEq, Clone)]
+pub enum Literal {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A value computed from the row it's for, i.e the new value of a column in an UPDATE
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    Literal(Literal),
+    Column(String),
+    Negate(Box<Expression>),
+    Arithmetic(Box<Expression>, ArithmeticOperator, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug)]
+pub struct InsertStatement {
+    pub table: String,
+    // None when the values are given for every column in table order
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<Literal>>,
+}
+
+#[derive(Debug)]
+pub struct DeleteStatement {
+    pub table: String,
+    // every row is deleted without one
+    pub where_clause: Option<Comparison>,
+}
+
+#[derive(Debug)]
+pub struct UpdateStatement {
+    pub table: String,
+    // the columns that are set and their new values, in the order they were written
+    pub assignments: Vec<(String, Expression)>,
+    // every row is updated without one
+    pub where_clause: Option<Comparison>,
+}
+
+#[derive(Debug)]
+pub struct CreateTableStatement {
+    pub name: String,
+    pub if_not_exists: bool,
+    // the statement the way sqlite stores it in sqlite_schema, the columns are read from it
+    pub sql: String,
+}
+
+#[derive(Debug)]
+pub struct CreateIndexStatement {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    pub if_not_exists: bool,
+    // the statement the way sqlite stores it in sqlite_schema, the indexed columns are read from it
+    pub sql: String,
+}
+
+#[derive(Debug)]
+pub struct DropStatement {
+    pub name: String,
+    pub if_exists: bool,
+}
+
+/// The change `ALTER TABLE` makes to a table
+#[derive(Debug, PartialEq)]
+pub enum AlterAction {
+    RenameTable(String),
+    RenameColumn {
+        from: String,
+        to: String,
+        // sqlite keeps a new name that was quoted in quotes
+        quoted: bool,
+    },
+    // the definition as it's written, it's added to the stored SQL of the table
+    AddColumn {
+        name: String,
+        definition: String,
+    },
+    DropColumn(String),
+}
+
+/// When `BEGIN` locks the database, https://www.sqlite.org/lang_transaction.html
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    // at the first write
+    Deferred,
+    // straight away, keeping out other writers
+    Immediate,
+    // straight away, keeping out readers as well
+    Exclusive,
+}
+
+/// `PRAGMA name`, `PRAGMA name = value` or `PRAGMA name(value)`, https://www.sqlite.org/pragma.html
+#[derive(Debug)]
+pub struct PragmaStatement {
+    pub name: String,
+    // names like WAL are kept as they're written, like numbers, string literals are unquoted
+    pub value: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AlterTableStatement {
+    pub table: String,
+    pub action: AlterAction,
+}
+
+#[derive(Debug)]
+pub enum Statement {
+    Select(SelectQuery),
+    Insert(InsertStatement),
+    Delete(DeleteStatement),
+    Update(UpdateStatement),
+    CreateTable(CreateTableStatement),
+    CreateIndex(CreateIndexStatement),
+    DropTable(DropStatement),
+    DropIndex(DropStatement),
+    AlterTable(AlterTableStatement),
+    Begin(TransactionKind),
+    Commit,
+    Rollback,
+    Savepoint(String),
+    Release(String),
+    RollbackTo(String),
+    Pragma(PragmaStatement),
+}
+
 pub struct Parser {
-    tokens: Vec<Token>,
+    // tokens with their offsets in the SQL, always ending with EOF
+    tokens: Vec<(usize, Token)>,
     position: usize,
+    // the SQL the tokens came from, empty when the parser was given tokens
+    sql: String,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(mut tokens: Vec<(usize, Token)>) -> Self {
+        if tokens.last().map(|(_, token)| token) != Some(&Token::EOF) {
+            let end = tokens.last().map_or(0, |(offset, _)| *offset);
+            tokens.push((end, Token::EOF));
+        }
         Self {
             tokens,
             position: 0,
+            sql: String::new(),
         }
     }
 
-    pub fn parse(&mut self) -> SelectQuery {
-        self.consume(Token::Select);
-        let columns = self.parse_columns();
-        self.consume(Token::From);
-        let table = self.parse_identifier();
-        let where_clause = if self.matches(Token::Where) {
-            self.consume(Token::Where);
-            Some(self.parse_where_clause())
+    /// Parser for the tokens of `sql`, which is kept for the statements that are stored as
+    /// they're written, i.e `CREATE TABLE`
+    pub fn from_sql(sql: &str) -> Result<Self> {
+        let mut parser = Self::new(lexer(sql)?);
+        parser.sql = sql.to_string();
+        Ok(parser)
+    }
+
+    pub fn parse(&mut self) -> Result<SelectQuery> {
+        self.consume(Token::Select)?;
+        let columns = self.parse_columns()?;
+        self.consume(Token::From)?;
+        let table = self.parse_identifier()?;
+        let where_clause = self.parse_optional_where()?;
+        Ok(SelectQuery {
+            columns,
+            table,
+            where_clause,
+        })
+    }
+
+    /// Parse one statement, optionally ended by a semicolon, that takes up the whole input
+    pub fn parse_statement(&mut self) -> Result<Statement> {
+        let statement = self.parse_one_statement()?;
+        if self.matches(Token::Semicolon) {
+            self.advance();
+        }
+        self.consume(Token::EOF)?;
+        Ok(statement)
+    }
+
+    /// Statements separated by semicolons, i.e a whole transaction
+    pub fn parse_statements(&mut self) -> Result<Vec<Statement>> {
+        let mut statements = vec![];
+        loop {
+            while self.matches(Token::Semicolon) {
+                self.advance();
+            }
+            if self.matches(Token::EOF) {
+                return Ok(statements);
+            }
+            statements.push(self.parse_one_statement()?);
+            if !self.matches(Token::EOF) {
+                self.consume(Token::Semicolon)?;
+            }
+        }
+    }
+
+    fn parse_one_statement(&mut self) -> Result<Statement> {
+        let statement = match self.current().1 {
+            Token::Insert => Statement::Insert(self.parse_insert()?),
+            Token::Delete => Statement::Delete(self.parse_delete()?),
+            Token::Update => Statement::Update(self.parse_update()?),
+            Token::Create => self.parse_create()?,
+            Token::Drop => self.parse_drop()?,
+            Token::Alter => Statement::AlterTable(self.parse_alter()?),
+            Token::Select => Statement::Select(self.parse()?),
+            Token::Begin => Statement::Begin(self.parse_begin()?),
+            Token::Commit => {
+                self.advance();
+                self.skip_word("transaction");
+                Statement::Commit
+            }
+            _ if self.matches_word("end") => {
+                self.advance();
+                self.skip_word("transaction");
+                Statement::Commit
+            }
+            Token::Rollback => self.parse_rollback()?,
+            Token::Savepoint => {
+                self.advance();
+                Statement::Savepoint(self.parse_identifier()?)
+            }
+            Token::Release => {
+                self.advance();
+                if self.matches(Token::Savepoint) {
+                    self.advance();
+                }
+                Statement::Release(self.parse_identifier()?)
+            }
+            Token::Pragma => Statement::Pragma(self.parse_pragma()?),
+            _ => {
+                return Err(self.error(
+                    "SELECT, INSERT, UPDATE, DELETE, CREATE, DROP, ALTER, BEGIN, COMMIT, ROLLBACK, SAVEPOINT, RELEASE or PRAGMA",
+                ));
+            }
+        };
+        Ok(statement)
+    }
+
+    fn parse_begin(&mut self) -> Result<TransactionKind> {
+        self.consume(Token::Begin)?;
+        let kind = [
+            ("deferred", TransactionKind::Deferred),
+            ("immediate", TransactionKind::Immediate),
+            ("exclusive", TransactionKind::Exclusive),
+        ]
+        .into_iter()
+        .find(|(word, _)| self.matches_word(word))
+        .map(|(_, kind)| kind);
+        if kind.is_some() {
+            self.advance();
+        }
+        self.skip_word("transaction");
+        Ok(kind.unwrap_or(TransactionKind::Deferred))
+    }
+
+    /// `ROLLBACK [TRANSACTION] [TO [SAVEPOINT] name]`
+    fn parse_rollback(&mut self) -> Result<Statement> {
+        self.consume(Token::Rollback)?;
+        self.skip_word("transaction");
+        if !self.matches_word("to") {
+            return Ok(Statement::Rollback);
+        }
+        self.advance();
+        if self.matches(Token::Savepoint) {
+            self.advance();
+        }
+        Ok(Statement::RollbackTo(self.parse_identifier()?))
+    }
+
+    fn parse_pragma(&mut self) -> Result<PragmaStatement> {
+        self.consume(Token::Pragma)?;
+        let name = self.parse_identifier()?;
+        let value = match self.current().1 {
+            Token::Equals => {
+                self.advance();
+                Some(self.parse_pragma_value()?)
+            }
+            Token::LeftParen => {
+                self.advance();
+                let value = self.parse_pragma_value()?;
+                self.consume(Token::RightParen)?;
+                Some(value)
+            }
+            _ => None,
+        };
+        Ok(PragmaStatement { name, value })
+    }
+
+    fn parse_pragma_value(&mut self) -> Result<String> {
+        let value = match &self.current().1 {
+            Token::Identifier(value)
+            | Token::StringLiteral(value)
+            | Token::NumericLiteral(value) => value.clone(),
+            // i.e the DELETE of journal_mode=DELETE
+            keyword if keyword.to_string().chars().all(|c| c.is_ascii_alphabetic()) => {
+                keyword.to_string()
+            }
+            _ => return Err(self.error("a pragma value")),
+        };
+        self.advance();
+        Ok(value)
+    }
+
+    fn parse_insert(&mut self) -> Result<InsertStatement> {
+        self.consume(Token::Insert)?;
+        self.consume(Token::Into)?;
+        let table = self.parse_identifier()?;
+        let columns = if self.matches(Token::LeftParen) {
+            self.advance();
+            let mut columns = vec![self.parse_identifier()?];
+            while self.matches(Token::Comma) {
+                self.advance();
+                columns.push(self.parse_identifier()?);
+            }
+            self.consume(Token::RightParen)?;
+            Some(columns)
         } else {
             None
         };
-        SelectQuery {
+
+        self.consume(Token::Values)?;
+        let mut rows = vec![];
+        loop {
+            let (position, _) = *self.current();
+            let row = self.parse_row()?;
+            // like sqlite, every row needs a value for each of the columns
+            let expected = columns
+                .as_ref()
+                .map(Vec::len)
+                .or(rows.first().map(Vec::len));
+            if let Some(expected) = expected.filter(|expected| *expected != row.len()) {
+                return Err(Error::ParseError {
+                    position,
+                    expected: format!("{} values", expected),
+                    found: format!("{} values", row.len()),
+                }
+                .into());
+            }
+            rows.push(row);
+            if !self.matches(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        Ok(InsertStatement {
+            table,
             columns,
+            rows,
+        })
+    }
+
+    fn parse_delete(&mut self) -> Result<DeleteStatement> {
+        self.consume(Token::Delete)?;
+        self.consume(Token::From)?;
+        let table = self.parse_identifier()?;
+        let where_clause = self.parse_optional_where()?;
+        Ok(DeleteStatement {
+            table,
+            where_clause,
+        })
+    }
+
+    fn parse_update(&mut self) -> Result<UpdateStatement> {
+        self.consume(Token::Update)?;
+        let table = self.parse_identifier()?;
+        self.consume(Token::Set)?;
+        let mut assignments = vec![];
+        loop {
+            let column = self.parse_identifier()?;
+            self.consume(Token::Equals)?;
+            assignments.push((column, self.parse_expression()?));
+            if !self.matches(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        let where_clause = self.parse_optional_where()?;
+        Ok(UpdateStatement {
             table,
+            assignments,
             where_clause,
+        })
+    }
+
+    fn parse_create(&mut self) -> Result<Statement> {
+        self.consume(Token::Create)?;
+        let unique = self.matches(Token::Unique);
+        if unique {
+            self.advance();
+        }
+        let is_index = match self.current().1 {
+            Token::Index => true,
+            Token::Table if !unique => false,
+            _ if unique => return Err(self.error("INDEX")),
+            _ => return Err(self.error("TABLE or INDEX")),
+        };
+        self.advance();
+        let if_not_exists = self.matches(Token::If);
+        if if_not_exists {
+            self.advance();
+            self.consume(Token::Not)?;
+            self.consume(Token::Exists)?;
+        }
+
+        let (start, _) = *self.current();
+        let name = self.parse_identifier()?;
+        let table = if is_index {
+            self.consume(Token::On)?;
+            Some(self.parse_identifier()?)
+        } else {
+            None
+        };
+        // the definitions are read from the stored SQL, like those of tables already in the schema
+        self.skip_parenthesized()?;
+        if !is_index && self.matches_word("without") {
+            self.advance();
+            self.consume_word("rowid")?;
+        }
+
+        // like sqlite the stored statement leaves out IF NOT EXISTS and what follows the definition
+        let (end, _) = *self.current();
+        let text = self.text(start, end)?;
+        Ok(match table {
+            Some(table) => Statement::CreateIndex(CreateIndexStatement {
+                name,
+                table,
+                unique,
+                if_not_exists,
+                sql: format!(
+                    "CREATE {}INDEX {}",
+                    if unique { "UNIQUE " } else { "" },
+                    text
+                ),
+            }),
+            None => Statement::CreateTable(CreateTableStatement {
+                name,
+                if_not_exists,
+                sql: format!("CREATE TABLE {}", text),
+            }),
+        })
+    }
+
+    fn parse_drop(&mut self) -> Result<Statement> {
+        self.consume(Token::Drop)?;
+        let is_index = match self.current().1 {
+            Token::Table => false,
+            Token::Index => true,
+            _ => return Err(self.error("TABLE or INDEX")),
+        };
+        self.advance();
+        let if_exists = self.matches(Token::If);
+        if if_exists {
+            self.advance();
+            self.consume(Token::Exists)?;
+        }
+        let drop = DropStatement {
+            name: self.parse_identifier()?,
+            if_exists,
+        };
+        Ok(if is_index {
+            Statement::DropIndex(drop)
+        } else {
+            Statement::DropTable(drop)
+        })
+    }
+
+    fn parse_alter(&mut self) -> Result<AlterTableStatement> {
+        self.consume(Token::Alter)?;
+        self.consume(Token::Table)?;
+        let table = self.parse_identifier()?;
+        let action = if self.matches_word("rename") {
+            self.advance();
+            if self.matches_word("to") {
+                self.advance();
+                AlterAction::RenameTable(self.parse_identifier()?)
+            } else {
+                self.skip_word("column");
+                let from = self.parse_identifier()?;
+                self.consume_word("to")?;
+                let (offset, _) = *self.current();
+                let quoted = self
+                    .sql
+                    .get(offset..)
+                    .is_some_and(|rest| rest.starts_with('"'));
+                let to = self.parse_identifier()?;
+                AlterAction::RenameColumn { from, to, quoted }
+            }
+        } else if self.matches_word("add") {
+            self.advance();
+            self.skip_word("column");
+            let (start, _) = *self.current();
+            let name = self.parse_identifier()?;
+            // like CREATE TABLE, the type and constraints are read from the text of the definition
+            while !matches!(self.current().1, Token::Semicolon | Token::EOF) {
+                self.advance();
+            }
+            let (end, _) = *self.current();
+            let definition = self.text(start, end)?.to_string();
+            AlterAction::AddColumn { name, definition }
+        } else if self.matches(Token::Drop) {
+            self.advance();
+            self.skip_word("column");
+            AlterAction::DropColumn(self.parse_identifier()?)
+        } else {
+            return Err(self.error("RENAME, ADD or DROP"));
+        };
+        Ok(AlterTableStatement { table, action })
+    }
+
+    /// The SQL from one token up to the start of another, without the whitespace before it
+    fn text(&self, start: usize, end: usize) -> Result<&str> {
+        let text = self
+            .sql
+            .get(start..end)
+            .filter(|text| !text.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "the text of CREATE and ALTER statements is kept, see Parser::from_sql"
+                )
+            })?;
+        Ok(text.trim_end())
+    }
+
+    /// Move past a parenthesized list that isn't empty, and the lists nested in it
+    fn skip_parenthesized(&mut self) -> Result<()> {
+        self.consume(Token::LeftParen)?;
+        if self.matches(Token::RightParen) {
+            return Err(self.error("a definition"));
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match self.current().1 {
+                Token::LeftParen => depth += 1,
+                Token::RightParen => depth -= 1,
+                Token::EOF => return Err(self.error(")")),
+                _ => {}
+            }
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn parse_row(&mut self) -> Result<Vec<Literal>> {
+        self.consume(Token::LeftParen)?;
+        let mut row = vec![self.parse_literal()?];
+        while self.matches(Token::Comma) {
+            self.advance();
+            row.push(self.parse_literal()?);
+        }
+        self.consume(Token::RightParen)?;
+        Ok(row)
+    }
+
+    /// Parse a constant, i.e a value of an INSERT or a column's DEFAULT
+    pub fn parse_literal(&mut self) -> Result<Literal> {
+        let literal = match &self.current().1 {
+            Token::Null => Literal::Null,
+            Token::StringLiteral(text) => Literal::Text(text.clone()),
+            Token::BlobLiteral(bytes) => Literal::Blob(bytes.clone()),
+            Token::NumericLiteral(number) => parse_number(number),
+            _ => return Err(self.error("a literal value")),
+        };
+        self.advance();
+        Ok(literal)
+    }
+
+    /// Parse arithmetic on columns and constants, `*`, `/` and `%` before `+` and `-`
+    pub fn parse_expression(&mut self) -> Result<Expression> {
+        let mut expression = self.parse_term()?;
+        loop {
+            let operator = match self.current().1 {
+                Token::Plus => ArithmeticOperator::Add,
+                Token::Minus => ArithmeticOperator::Subtract,
+                _ => return Ok(expression),
+            };
+            self.advance();
+            let right = self.parse_term()?;
+            expression = Expression::Arithmetic(Box::new(expression), operator, Box::new(right));
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expression> {
+        let mut expression = self.parse_operand()?;
+        loop {
+            let operator = match self.current().1 {
+                Token::Asterisk => ArithmeticOperator::Multiply,
+                Token::Slash => ArithmeticOperator::Divide,
+                Token::Percent => ArithmeticOperator::Remainder,
+                _ => return Ok(expression),
+            };
+            self.advance();
+            let right = self.parse_operand()?;
+            expression = Expression::Arithmetic(Box::new(expression), operator, Box::new(right));
         }
     }
 
-    fn parse_columns(&mut self) -> Vec<Column> {
+    fn parse_operand(&mut self) -> Result<Expression> {
+        match &self.current().1 {
+            Token::Identifier(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(Expression::Column(name))
+            }
+            Token::LeftParen => {
+                self.advance();
+                let expression = self.parse_expression()?;
+                self.consume(Token::RightParen)?;
+                Ok(expression)
+            }
+            Token::Minus => {
+                self.advance();
+                Ok(Expression::Negate(Box::new(self.parse_operand()?)))
+            }
+            Token::Plus => {
+                self.advance();
+                self.parse_operand()
+            }
+            Token::Null
+            | Token::StringLiteral(_)
+            | Token::BlobLiteral(_)
+            | Token::NumericLiteral(_) => Ok(Expression::Literal(self.parse_literal()?)),
+            _ => Err(self.error("an expression")),
+        }
+    }
+
+    fn parse_columns(&mut self) -> Result<Vec<Column>> {
         let mut columns = Vec::new();
         loop {
             if self.matches(Token::Count) {
-                self.consume(Token::Count);
+                self.consume(Token::Count)?;
                 columns.push(Column::Aggregation(AggregateFn::CountAll));
             } else if self.matches(Token::Asterisk) {
-                self.consume(Token::Asterisk);
+                self.consume(Token::Asterisk)?;
                 columns.push(Column::All);
             } else {
-                let column = self.parse_identifier();
+                let column = self.parse_identifier()?;
                 columns.push(Column::Regular(column));
             }
             if !self.matches(Token::Comma) {
                 break;
             }
-            self.consume(Token::Comma);
+            self.consume(Token::Comma)?;
         }
-        columns
+        Ok(columns)
     }
 
-    fn parse_identifier(&mut self) -> String {
-        if let Token::Identifier(name) = self.advance() {
-            name
-        } else {
-            panic!("Expected identifier")
+    fn parse_identifier(&mut self) -> Result<String> {
+        let Token::Identifier(name) = self.current().1.clone() else {
+            return Err(self.error("an identifier"));
+        };
+        self.advance();
+        Ok(name)
+    }
+
+    fn parse_optional_where(&mut self) -> Result<Option<Comparison>> {
+        if !self.matches(Token::Where) {
+            return Ok(None);
         }
+        self.advance();
+        Ok(Some(self.parse_where_clause()?))
     }
 
-    fn parse_where_clause(&mut self) -> Comparison {
-        let left = self.parse_identifier();
-        self.consume(Token::Equals);
-        let right = if let Token::StringLiteral(value) = self.advance() {
-            value
-        } else {
-            panic!("Expected string literal")
+    fn parse_where_clause(&mut self) -> Result<Comparison> {
+        let left = self.parse_identifier()?;
+        self.consume(Token::Equals)?;
+        let Token::StringLiteral(right) = self.current().1.clone() else {
+            return Err(self.error("a string literal"));
         };
-        Comparison {
+        self.advance();
+        Ok(Comparison {
             operator: Operator::Equals,
             column: left,
             value: right,
-        }
+        })
+    }
+
+    fn current(&self) -> &(usize, Token) {
+        &self.tokens[self.position]
     }
 
     fn matches(&self, token: Token) -> bool {
-        self.tokens.get(self.position) == Some(&token)
+        self.current().1 == token
+    }
+
+    /// Whether the current token is a word that's only a keyword in some places, i.e `ROWID`
+    fn matches_word(&self, word: &str) -> bool {
+        matches!(&self.current().1, Token::Identifier(name) if name.eq_ignore_ascii_case(word))
+    }
+
+    fn consume_word(&mut self, word: &str) -> Result<()> {
+        if !self.matches_word(word) {
+            return Err(self.error(&word.to_uppercase()));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Move past a word that can be left out, i.e the `COLUMN` of `ALTER TABLE t ADD COLUMN`
+    fn skip_word(&mut self, word: &str) {
+        if self.matches_word(word) {
+            self.advance();
+        }
     }
 
-    fn consume(&mut self, token: Token) {
+    fn consume(&mut self, token: Token) -> Result<()> {
         if self.matches(token.clone()) {
-            self.position += 1;
+            self.advance();
+            Ok(())
         } else {
-            panic!(
-                "Expected token: {:?} recieved {:?}",
-                token,
-                self.tokens
-                    .get(self.position)
-                    .expect("couldn't get current token")
-            );
+            Err(self.error(&token.to_string()))
         }
     }
 
-    fn advance(&mut self) -> Token {
-        self.position += 1;
-        self.tokens.get(self.position - 1).unwrap().clone()
+    /// Move past the current token, staying on EOF once it is reached
+    fn advance(&mut self) {
+        if self.current().1 != Token::EOF {
+            self.position += 1;
+        }
+    }
+
+    /// Syntax error for the current token
+    fn error(&self, expected: &str) -> anyhow::Error {
+        let (position, found) = self.current();
+        Error::ParseError {
+            position: *position,
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+        .into()
+    }
+}
+/// Integers that don't fit in 64 bits and numbers with a fraction or exponent are reals like in sqlite
+pub fn parse_number(number: &str) -> Literal {
+    let number = number.strip_prefix('+').unwrap_or(number);
+    match number.parse::<i64>() {
+        Ok(integer) => Literal::Integer(integer),
+        // the lexer only lets through numbers that parse
+        Err(_) => Literal::Real(number.parse().unwrap_or(f64::NAN)),
     }
 }
+
 #[cfg(test)]
 mod parser_tests {
     use crate::sql_parser::{
         lexer::lexer,
-        parser::{AggregateFn, Column, Comparison, Operator},
+        parser::{
+            AggregateFn, AlterAction, ArithmeticOperator, Column, Comparison, Expression, Literal,
+            Operator, Statement, TransactionKind,
+        },
     };
 
-    use super::{Parser, SelectQuery};
+    use super::{Error, Parser, SelectQuery};
 
     fn parse_sql(query: &str) -> SelectQuery {
-        let tokens = lexer(query);
+        let tokens = lexer(query).unwrap();
         let mut parser = Parser::new(tokens);
-        parser.parse()
+        parser.parse().unwrap()
     }
 
     #[test]
@@ -196,4 +858,370 @@ mod parser_tests {
         assert_eq!(parsed_query.columns[0], Column::All);
         assert_eq!(parsed_query.table, "oranges")
     }
+
+    #[test]
+    fn test_syntax_errors() {
+        let parse_error = |query: &str| {
+            let error = Parser::new(lexer(query).unwrap()).parse().unwrap_err();
+            match error.downcast::<Error>() {
+                Ok(Error::ParseError {
+                    position,
+                    expected,
+                    found,
+                }) => (position, expected, found),
+                error => panic!("expected a parse error, got {:?}", error),
+            }
+        };
+        assert_eq!(
+            parse_error("SELECT name apples"),
+            (12, "FROM".to_string(), "apples".to_string())
+        );
+        assert_eq!(
+            parse_error("SELECT name FROM"),
+            (16, "an identifier".to_string(), "end of input".to_string())
+        );
+        assert_eq!(
+            parse_error("SELECT name FROM apples WHERE color = Yellow"),
+            (38, "a string literal".to_string(), "Yellow".to_string())
+        );
+    }
+
+    #[test]
+    fn test_insert() {
+        let statement = Parser::new(
+            lexer("INSERT INTO apples (name, color) VALUES ('Gala', 'Red'), ('Jazz', NULL);")
+                .unwrap(),
+        )
+        .parse_statement()
+        .unwrap();
+        let Statement::Insert(insert) = statement else {
+            panic!("expected an insert, got {:?}", statement);
+        };
+        assert_eq!(insert.table, "apples");
+        assert_eq!(insert.columns, Some(vec!["name".into(), "color".into()]));
+        assert_eq!(
+            insert.rows,
+            vec![
+                vec![Literal::Text("Gala".into()), Literal::Text("Red".into())],
+                vec![Literal::Text("Jazz".into()), Literal::Null],
+            ]
+        );
+
+        let statement = Parser::new(
+            lexer("insert into t values (1, -2.5, 9223372036854775808, x'00')").unwrap(),
+        )
+        .parse_statement()
+        .unwrap();
+        let Statement::Insert(insert) = statement else {
+            panic!("expected an insert, got {:?}", statement);
+        };
+        assert_eq!(insert.columns, None);
+        assert_eq!(
+            insert.rows[0],
+            vec![
+                Literal::Integer(1),
+                Literal::Real(-2.5),
+                Literal::Real(9223372036854775808.0),
+                Literal::Blob(vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_insert_syntax_errors() {
+        let parse_error = |query: &str| {
+            let error = Parser::new(lexer(query).unwrap())
+                .parse_statement()
+                .unwrap_err();
+            match error.downcast::<Error>() {
+                Ok(Error::ParseError {
+                    position,
+                    expected,
+                    found,
+                }) => (position, expected, found),
+                error => panic!("expected a parse error, got {:?}", error),
+            }
+        };
+        assert_eq!(
+            parse_error("INSERT INTO t (a, b) VALUES (1)"),
+            (28, "2 values".to_string(), "1 values".to_string())
+        );
+        assert_eq!(
+            parse_error("INSERT INTO t VALUES (1), (2, 3)"),
+            (26, "1 values".to_string(), "2 values".to_string())
+        );
+        assert_eq!(
+            parse_error("INSERT INTO t VALUES (a)"),
+            (22, "a literal value".to_string(), "a".to_string())
+        );
+        assert_eq!(
+            parse_error("SELECT a FROM t; SELECT"),
+            (17, "end of input".to_string(), "SELECT".to_string())
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let parse = |query: &str| {
+            Parser::new(lexer(query).unwrap())
+                .parse_statement()
+                .unwrap()
+        };
+        let Statement::Delete(delete) = parse("DELETE FROM apples WHERE color = 'Red';") else {
+            panic!("expected a DELETE");
+        };
+        assert_eq!(delete.table, "apples");
+        assert_eq!(
+            delete.where_clause,
+            Some(Comparison {
+                column: "color".to_string(),
+                value: "Red".to_string(),
+                operator: Operator::Equals,
+            })
+        );
+
+        let Statement::Delete(delete) = parse("delete from apples") else {
+            panic!("expected a DELETE");
+        };
+        assert_eq!(delete.where_clause, None);
+    }
+
+    #[test]
+    fn test_update() {
+        let parse = |query: &str| Parser::new(lexer(query).unwrap()).parse_statement();
+        let Statement::Update(update) =
+            parse("UPDATE apples SET name = 'Fuji', weight = 1.5 WHERE color = 'Red'").unwrap()
+        else {
+            panic!("expected an UPDATE");
+        };
+        assert_eq!(update.table, "apples");
+        assert_eq!(
+            update.assignments,
+            vec![
+                (
+                    "name".to_string(),
+                    Expression::Literal(Literal::Text("Fuji".into()))
+                ),
+                (
+                    "weight".to_string(),
+                    Expression::Literal(Literal::Real(1.5))
+                ),
+            ]
+        );
+        assert_eq!(
+            update.where_clause.map(|comparison| comparison.column),
+            Some("color".to_string())
+        );
+
+        let Statement::Update(update) = parse("update apples set color = NULL;").unwrap() else {
+            panic!("expected an UPDATE");
+        };
+        assert_eq!(
+            update.assignments,
+            vec![("color".to_string(), Expression::Literal(Literal::Null))]
+        );
+        assert_eq!(update.where_clause, None);
+        assert!(parse("UPDATE apples WHERE color = 'Red'").is_err());
+        assert!(parse("UPDATE apples SET weight = (weight").is_err());
+        assert!(parse("UPDATE apples SET weight = weight +").is_err());
+
+        // multiplication binds tighter than addition, operators of the same kind go left to right
+        let Statement::Update(update) =
+            parse("UPDATE apples SET weight = -weight + 2*(count - 1) - 3 % 2").unwrap()
+        else {
+            panic!("expected an UPDATE");
+        };
+        let column = |name: &str| Box::new(Expression::Column(name.into()));
+        let integer = |value: i64| Box::new(Expression::Literal(Literal::Integer(value)));
+        let arithmetic =
+            |left, operator, right| Box::new(Expression::Arithmetic(left, operator, right));
+        assert_eq!(
+            update.assignments[0].1,
+            *arithmetic(
+                arithmetic(
+                    Box::new(Expression::Negate(column("weight"))),
+                    ArithmeticOperator::Add,
+                    arithmetic(
+                        integer(2),
+                        ArithmeticOperator::Multiply,
+                        arithmetic(column("count"), ArithmeticOperator::Subtract, integer(1))
+                    )
+                ),
+                ArithmeticOperator::Subtract,
+                arithmetic(integer(3), ArithmeticOperator::Remainder, integer(2))
+            )
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let parse = |sql: &str| Parser::from_sql(sql).unwrap().parse_statement();
+        let Statement::CreateTable(create) =
+            parse("create table if not exists \"my table\" ( a integer, b text default 'x' collate nocase ) WITHOUT ROWID ;")
+                .unwrap()
+        else {
+            panic!("expected a CREATE TABLE");
+        };
+        assert_eq!(create.name, "my table");
+        assert!(create.if_not_exists);
+        assert_eq!(
+            create.sql,
+            "CREATE TABLE \"my table\" ( a integer, b text default 'x' collate nocase ) WITHOUT ROWID"
+        );
+
+        let Statement::CreateIndex(create) =
+            parse("CREATE UNIQUE INDEX i ON t (a DESC, b)").unwrap()
+        else {
+            panic!("expected a CREATE INDEX");
+        };
+        assert_eq!((create.name.as_str(), create.table.as_str()), ("i", "t"));
+        assert!(create.unique && !create.if_not_exists);
+        assert_eq!(create.sql, "CREATE UNIQUE INDEX i ON t (a DESC, b)");
+
+        assert!(parse("CREATE UNIQUE TABLE t (a)").is_err());
+        assert!(parse("CREATE TABLE t ()").is_err());
+        assert!(parse("CREATE TABLE t (a").is_err());
+        assert!(parse("CREATE INDEX i ON t (a) WHERE a = 'x'").is_err());
+        // the stored text comes from the SQL, tokens alone don't have it
+        assert!(Parser::new(lexer("CREATE TABLE t (a)").unwrap())
+            .parse_statement()
+            .is_err());
+    }
+
+    #[test]
+    fn test_drop_and_alter() {
+        let parse = |sql: &str| Parser::from_sql(sql).unwrap().parse_statement();
+        let Statement::DropTable(drop) = parse("DROP TABLE IF EXISTS \"my table\";").unwrap()
+        else {
+            panic!("expected a DROP TABLE");
+        };
+        assert_eq!(drop.name, "my table");
+        assert!(drop.if_exists);
+        let Statement::DropIndex(drop) = parse("drop index i").unwrap() else {
+            panic!("expected a DROP INDEX");
+        };
+        assert_eq!(drop.name, "i");
+        assert!(!drop.if_exists);
+
+        let action = |sql: &str| match parse(sql).unwrap() {
+            Statement::AlterTable(alter) => {
+                assert_eq!(alter.table, "t");
+                alter.action
+            }
+            statement => panic!("expected an ALTER TABLE, got {:?}", statement),
+        };
+        assert_eq!(
+            action("ALTER TABLE t RENAME TO \"new t\""),
+            AlterAction::RenameTable("new t".into())
+        );
+        assert_eq!(
+            action("alter table t rename column a to b"),
+            action("ALTER TABLE t RENAME a TO b")
+        );
+        assert_eq!(
+            action("ALTER TABLE t RENAME COLUMN a TO \"b c\""),
+            AlterAction::RenameColumn {
+                from: "a".into(),
+                to: "b c".into(),
+                quoted: true,
+            }
+        );
+        assert_eq!(
+            action("ALTER TABLE t ADD COLUMN c integer not null default (1) ;"),
+            AlterAction::AddColumn {
+                name: "c".into(),
+                definition: "c integer not null default (1)".into(),
+            }
+        );
+        assert_eq!(
+            action("ALTER TABLE t DROP c"),
+            AlterAction::DropColumn("c".into())
+        );
+
+        assert!(parse("DROP VIEW v").is_err());
+        assert!(parse("ALTER TABLE t RENAME a b").is_err());
+        assert!(parse("ALTER TABLE t ADD").is_err());
+    }
+
+    #[test]
+    fn test_transactions() {
+        let parse = |sql: &str| Parser::from_sql(sql).unwrap().parse_statements().unwrap();
+        let statements = parse(
+            "BEGIN IMMEDIATE TRANSACTION; insert into t values (1); COMMIT; begin; rollback transaction;",
+        );
+        assert!(matches!(
+            statements[..],
+            [
+                Statement::Begin(TransactionKind::Immediate),
+                Statement::Insert(_),
+                Statement::Commit,
+                Statement::Begin(TransactionKind::Deferred),
+                Statement::Rollback,
+            ]
+        ));
+        assert!(matches!(parse("end")[..], [Statement::Commit]));
+        assert!(matches!(
+            parse(";; Begin Exclusive")[..],
+            [Statement::Begin(TransactionKind::Exclusive)]
+        ));
+        assert!(Parser::from_sql("begin commit")
+            .unwrap()
+            .parse_statements()
+            .is_err());
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let parse = |sql: &str| Parser::from_sql(sql).unwrap().parse_statements().unwrap();
+        let statements = parse(
+            "SAVEPOINT a; RELEASE a; release savepoint \"b c\"; ROLLBACK TO a; rollback transaction to savepoint a",
+        );
+        assert!(matches!(
+            &statements[..],
+            [
+                Statement::Savepoint(a),
+                Statement::Release(a2),
+                Statement::Release(b),
+                Statement::RollbackTo(a3),
+                Statement::RollbackTo(a4),
+            ] if a == "a" && a2 == "a" && b == "b c" && a3 == "a" && a4 == "a"
+        ));
+        assert!(Parser::from_sql("ROLLBACK TO")
+            .unwrap()
+            .parse_statement()
+            .is_err());
+        assert!(Parser::from_sql("SAVEPOINT")
+            .unwrap()
+            .parse_statement()
+            .is_err());
+    }
+
+    #[test]
+    fn test_pragmas() {
+        let parse = |sql: &str| match Parser::from_sql(sql).unwrap().parse_statement() {
+            Ok(Statement::Pragma(pragma)) => (pragma.name, pragma.value),
+            other => panic!("{:?} isn't a pragma", other),
+        };
+        assert_eq!(parse("PRAGMA journal_mode"), ("journal_mode".into(), None));
+        assert_eq!(
+            parse("pragma journal_mode = WAL"),
+            ("journal_mode".into(), Some("WAL".into()))
+        );
+        assert_eq!(
+            parse("PRAGMA wal_checkpoint(truncate);"),
+            ("wal_checkpoint".into(), Some("truncate".into()))
+        );
+        assert_eq!(
+            parse("PRAGMA journal_mode='delete'"),
+            ("journal_mode".into(), Some("delete".into()))
+        );
+        assert_eq!(
+            parse("PRAGMA journal_mode=delete"),
+            ("journal_mode".into(), Some("DELETE".into()))
+        );
+        assert!(Parser::from_sql("PRAGMA wal_checkpoint(full")
+            .unwrap()
+            .parse_statement()
+            .is_err());
+    }
 }
@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use anyhow::Result;
+
+use super::vfs::{LockLevel, OpenMode, Vfs, VfsFile};
+use crate::error::Error;
+
+/// Something that goes wrong when a file is read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The nth read of the file fails, reads of every handle to the file are counted from 1
+    FailRead(u64),
+    /// Reads return at most this many bytes. Like sqlite's VFS the rest of the buffer is zeroed and the read fails
+    ShortRead(usize),
+    /// The bits of `mask` are flipped in the byte at `offset` whenever it is read
+    FlipBits { offset: u64, mask: u8 },
+    /// The file ends at this offset
+    Truncate(u64),
+}
+
+impl Fault {
+    /// Flip bits of the byte at `offset` in a 1-indexed page
+    pub fn flip_page_bits(page_size: u32, page_number: u32, offset: usize, mask: u8) -> Self {
+        Fault::FlipBits {
+            offset: (page_number - 1) as u64 * page_size as u64 + offset as u64,
+            mask,
+        }
+    }
+}
+
+/// The process dying part way through writing, see `FaultyVfs::crash`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crash {
+    /// The write the process dies during, writes and truncations of every file are counted from 1
+    pub write: u64,
+    /// Half of that write reaches the file like a torn sector, otherwise none of it does
+    pub torn: bool,
+    /// What was written to a file since it was last synced is lost on restart, like after a power cut
+    pub lose_unsynced: bool,
+}
+
+/// Writes through the whole VFS and the crash they lead to
+#[derive(Default)]
+struct CrashState {
+    crash: Mutex<Option<Crash>>,
+    writes: AtomicU64,
+    crashed: AtomicBool,
+}
+
+impl CrashState {
+    fn crash(&self) -> MutexGuard<'_, Option<Crash>> {
+        self.crash.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count a write, failing once the process has crashed. Some when the process crashes during this write
+    fn next_write(&self) -> Result<Option<Crash>> {
+        if self.crashed.load(Ordering::SeqCst) {
+            return Err(crashed());
+        }
+        let write = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        let crash = self.crash().filter(|crash| crash.write == write);
+        if crash.is_some() {
+            self.crashed.store(true, Ordering::SeqCst);
+        }
+        Ok(crash)
+    }
+}
+
+fn crashed() -> anyhow::Error {
+    Error::Io(io::Error::other("injected crash")).into()
+}
+
+/// Bytes a write replaced, to put back when the write is lost
+struct Undo {
+    offset: u64,
+    bytes: Vec<u8>,
+    // size of the file before the write
+    size: u64,
+}
+
+/// Faults of one path, the number of times it has been read and the writes since it was last synced
+#[derive(Default)]
+struct FaultState {
+    faults: Mutex<Vec<Fault>>,
+    reads: AtomicU64,
+    unsynced: Mutex<Vec<Undo>>,
+}
+
+impl FaultState {
+    fn faults(&self) -> MutexGuard<'_, Vec<Fault>> {
+        self.faults.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn unsynced(&self) -> MutexGuard<'_, Vec<Undo>> {
+        self.unsynced.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Wraps another VFS to inject faults into the reads of chosen files, for testing how corrupt
+/// or unreadable files are handled, and to crash part way through writing, for testing how a database
+/// is recovered. Syncs and locks go straight to the wrapped file
+pub struct FaultyVfs<V: Vfs> {
+    inner: Arc<V>,
+    files: Arc<Mutex<HashMap<PathBuf, Arc<FaultState>>>>,
+    crash: Arc<CrashState>,
+}
+
+impl<V: Vfs> FaultyVfs<V> {
+    pub fn new(inner: V) -> Self {
+        FaultyVfs {
+            inner: Arc::new(inner),
+            files: Arc::default(),
+            crash: Arc::default(),
+        }
+    }
+
+    /// Crash part way through a later write, every write, truncation and sync after it fails until `restart`
+    pub fn crash(&self, crash: Crash) {
+        *self.crash.crash() = Some(crash);
+    }
+
+    /// Number of writes and truncations of every file since the VFS was made or restarted
+    pub fn writes(&self) -> u64 {
+        self.crash.writes.load(Ordering::SeqCst)
+    }
+
+    /// Start again after a crash, the files are left as the crash left them for the database to be recovered from
+    pub fn restart(&self) -> Result<()> {
+        let lose_unsynced = self.crash.crash().is_some_and(|crash| crash.lose_unsynced);
+        let files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        for (path, state) in files.iter() {
+            let undo = std::mem::take(&mut *state.unsynced());
+            if !lose_unsynced || undo.is_empty() || !self.inner.exists(path)? {
+                continue;
+            }
+            let file = self.inner.open(path, OpenMode::ReadWrite)?;
+            for write in undo.iter().rev() {
+                file.write_at(&write.bytes, write.offset)?;
+                file.set_len(write.size)?;
+            }
+        }
+        *self.crash.crash() = None;
+        self.crash.writes.store(0, Ordering::SeqCst);
+        self.crash.crashed.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Inject a fault into the file at `path`, handles that are already open see it as well
+    pub fn inject(&self, path: impl AsRef<Path>, fault: Fault) {
+        self.state(path.as_ref()).faults().push(fault);
+    }
+
+    /// Remove every fault of the file at `path`
+    pub fn clear(&self, path: impl AsRef<Path>) {
+        self.state(path.as_ref()).faults().clear();
+    }
+
+    /// Number of reads of the file at `path` through any handle
+    pub fn reads(&self, path: impl AsRef<Path>) -> u64 {
+        self.state(path.as_ref()).reads.load(Ordering::SeqCst)
+    }
+
+    fn state(&self, path: &Path) -> Arc<FaultState> {
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        files.entry(path.to_path_buf()).or_default().clone()
+    }
+}
+
+impl<V: Vfs + 'static> Vfs for FaultyVfs<V> {
+    fn open(&self, path: &Path, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.open(path, mode)?,
+            state: self.state(path),
+            crash: self.crash.clone(),
+        }))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn share(&self) -> Box<dyn Vfs> {
+        Box::new(FaultyVfs {
+            inner: self.inner.clone(),
+            files: self.files.clone(),
+            crash: self.crash.clone(),
+        })
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn VfsFile>,
+    state: Arc<FaultState>,
+    crash: Arc<CrashState>,
+}
+
+impl FaultyFile {
+    /// Keep the bytes from `offset` to `end` that are about to be replaced while a crash that loses them is coming
+    fn remember(&self, offset: u64, end: u64) -> Result<()> {
+        if !self.crash.crash().is_some_and(|crash| crash.lose_unsynced) {
+            return Ok(());
+        }
+        let size = self.inner.size()?;
+        let mut bytes = vec![0u8; (end.min(size).saturating_sub(offset)) as usize];
+        if !bytes.is_empty() {
+            self.inner.read_at(&mut bytes, offset)?;
+        }
+        self.state.unsynced().push(Undo {
+            offset,
+            bytes,
+            size,
+        });
+        Ok(())
+    }
+
+    /// Fill `buf` with as much of the file as there is before `available`, zeroing the rest like a short read
+    fn read_short(&self, buf: &mut [u8], offset: u64, available: u64) -> Result<()> {
+        let read = available.saturating_sub(offset).min(buf.len() as u64) as usize;
+        self.inner.read_at(&mut buf[..read], offset)?;
+        buf[read..].fill(0);
+        let error = io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "short read of {} out of {} bytes at offset {}",
+                read,
+                buf.len(),
+                offset
+            ),
+        );
+        Err(Error::Io(error).into())
+    }
+}
+
+impl VfsFile for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let read_number = self.state.reads.fetch_add(1, Ordering::SeqCst) + 1;
+        let faults = self.state.faults().clone();
+        let end = offset + buf.len() as u64;
+
+        for fault in &faults {
+            match *fault {
+                Fault::FailRead(n) if n == read_number => {
+                    let error = io::Error::other(format!("injected failure of read {}", n));
+                    return Err(Error::Io(error).into());
+                }
+                Fault::ShortRead(max) if buf.len() > max => {
+                    return self.read_short(buf, offset, offset + max as u64);
+                }
+                Fault::Truncate(size) if end > size => {
+                    return self.read_short(buf, offset, size);
+                }
+                _ => {}
+            }
+        }
+
+        self.inner.read_at(buf, offset)?;
+        for fault in &faults {
+            if let Fault::FlipBits { offset: at, mask } = *fault {
+                if (offset..end).contains(&at) {
+                    buf[(at - offset) as usize] ^= mask;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let crash = self.crash.next_write()?;
+        let written = match crash {
+            Some(crash) if crash.torn => &buf[..buf.len() / 2],
+            Some(_) => &[],
+            None => buf,
+        };
+        if !written.is_empty() {
+            self.remember(offset, offset + written.len() as u64)?;
+            self.inner.write_at(written, offset)?;
+        }
+        match crash {
+            Some(_) => Err(crashed()),
+            None => Ok(()),
+        }
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        if self.crash.next_write()?.is_some() {
+            return Err(crashed());
+        }
+        self.remember(size, u64::MAX)?;
+        self.inner.set_len(size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        if self.crash.crashed.load(Ordering::SeqCst) {
+            return Err(crashed());
+        }
+        self.state.unsynced().clear();
+        self.inner.sync()
+    }
+
+    fn size(&self) -> Result<u64> {
+        let size = self.inner.size()?;
+        let truncated = self
+            .state
+            .faults()
+            .iter()
+            .fold(size, |size, fault| match fault {
+                Fault::Truncate(at) => size.min(*at),
+                _ => size,
+            });
+        Ok(truncated)
+    }
+
+    fn lock(&self, level: LockLevel) -> Result<()> {
+        self.inner.lock(level)
+    }
+
+    fn unlock(&self, level: LockLevel) -> Result<()> {
+        self.inner.unlock(level)
+    }
+}
+
+#[cfg(test)]
+mod fault_vfs_tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use anyhow::ensure;
+
+    use super::*;
+    use crate::{
+        pager::pager::Pager,
+        query_engine::engine::QueryEngine,
+        sql_parser::{lexer::lexer, parser::Parser},
+        vfs::memory::MemoryVfs,
+    };
+
+    const SAMPLE: &[u8] = include_bytes!("../../sample.db");
+    const PAGE_SIZE: u32 = 4096;
+
+    fn sample_vfs(faults: &[Fault]) -> FaultyVfs<MemoryVfs> {
+        let memory = MemoryVfs::new();
+        memory.insert("sample.db", SAMPLE.to_vec());
+        let vfs = FaultyVfs::new(memory);
+        for fault in faults {
+            vfs.inject("sample.db", *fault);
+        }
+        vfs
+    }
+
+    /// Open the database and read every page of it through queries, the integrity check and the page map.
+    /// Returns the problems the integrity check found
+    fn read_everything(vfs: &dyn Vfs) -> Result<Vec<String>> {
+        let engine = QueryEngine::new(Pager::open_with(vfs, "sample.db")?);
+        for sql in [
+            "select * from apples",
+            "select count(*) from oranges",
+            "select name, description from oranges where name = 'Tangelo'",
+        ] {
+            engine.run_query(Parser::new(lexer(sql)?).parse()?)?;
+        }
+        let problems = engine.pager.integrity_check()?;
+        engine.pager.page_map()?;
+        Ok(problems)
+    }
+
+    /// Read the database with the faults injected, panicking with the faults when reading it panicked
+    /// or failed with an error that doesn't say what kind of failure it was. Problems found by the
+    /// integrity check are an error as well
+    fn read_with_faults(faults: &[Fault]) -> Result<()> {
+        let vfs = sample_vfs(faults);
+        let problems = catch_unwind(AssertUnwindSafe(|| read_everything(&vfs)))
+            .unwrap_or_else(|_| panic!("reading with {:?} panicked", faults))
+            .inspect_err(|error| {
+                assert!(
+                    error.downcast_ref::<Error>().is_some(),
+                    "reading with {:?} failed with an untyped error: {:#}",
+                    faults,
+                    error
+                )
+            })?;
+        ensure!(problems.is_empty(), "{}", problems.join("\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reading_without_faults() {
+        let vfs = sample_vfs(&[]);
+        assert!(read_everything(&vfs).unwrap().is_empty());
+        assert!(vfs.reads("sample.db") > 4);
+    }
+
+    #[test]
+    fn test_every_failed_read_is_an_error() {
+        let vfs = sample_vfs(&[]);
+        read_everything(&vfs).unwrap();
+        for n in 1..=vfs.reads("sample.db") {
+            let error = read_with_faults(&[Fault::FailRead(n)]).unwrap_err();
+            assert!(
+                format!("{:#}", error).contains(&format!("injected failure of read {}", n)),
+                "{:#}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_short_reads_are_errors() {
+        for max in [0, 1, 99, 100, 101, 4095] {
+            assert!(read_with_faults(&[Fault::ShortRead(max)]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_truncated_file_is_an_error() {
+        for size in (0..SAMPLE.len() as u64).step_by(97) {
+            assert!(
+                read_with_faults(&[Fault::Truncate(size)]).is_err(),
+                "truncated at {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn test_flipped_bits_never_panic() {
+        // changing a value of a row can go unnoticed, anything else has to come back as an error.
+        // Besides the headers of the file and its pages only the non-zero bytes are used, the rest is free space
+        let headers = |offset: usize| offset < 112 || offset % PAGE_SIZE as usize <= 12;
+        let offsets = (0..SAMPLE.len()).filter(|offset| SAMPLE[*offset] != 0 || headers(*offset));
+        for offset in offsets {
+            for mask in (0..8).map(|bit| 1 << bit).chain([0xff]) {
+                let fault = Fault::FlipBits {
+                    offset: offset as u64,
+                    mask,
+                };
+                let _ = read_with_faults(&[fault]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupt_page_headers_are_errors() {
+        for page_number in 2..=4 {
+            // the page type, the first freeblock and the cell count
+            for offset in [0, 1, 3] {
+                let fault = Fault::flip_page_bits(PAGE_SIZE, page_number, offset, 0xff);
+                assert!(
+                    read_with_faults(&[fault]).is_err(),
+                    "flipped byte {} of page {}",
+                    offset,
+                    page_number
+                );
+            }
+            // an invalid page type is reported as corruption of that page
+            let fault = Fault::flip_page_bits(PAGE_SIZE, page_number, 0, 0xff);
+            let error = read_everything(&sample_vfs(&[fault])).unwrap_err();
+            assert!(
+                matches!(error.downcast_ref(), Some(Error::CorruptPage { page, .. }) if *page == page_number),
+                "{:#}",
+                error
+            );
+        }
+        // the b-tree page header of page 1 comes after the database header
+        let fault = Fault::flip_page_bits(PAGE_SIZE, 1, 100, 0x08);
+        assert!(read_with_faults(&[fault]).is_err());
+    }
+
+    #[test]
+    fn test_crashing_part_way_through_writes() {
+        let vfs = FaultyVfs::new(MemoryVfs::new());
+        let file = vfs.open(Path::new("a"), OpenMode::Create).unwrap();
+        file.write_at(b"synced", 0).unwrap();
+        file.sync().unwrap();
+        vfs.crash(Crash {
+            write: 4,
+            torn: true,
+            lose_unsynced: false,
+        });
+        file.write_at(b"!", 6).unwrap();
+        file.set_len(8).unwrap();
+        assert!(file.write_at(b"torn", 8).is_err());
+        assert!(file.write_at(b"after", 0).is_err());
+        assert!(file.sync().is_err());
+        assert_eq!(vfs.writes(), 4);
+
+        vfs.restart().unwrap();
+        let read = |len| {
+            let mut buf = vec![0u8; len];
+            file.read_at(&mut buf, 0).unwrap();
+            buf
+        };
+        assert_eq!(read(10), b"synced!\0to");
+
+        // writes since the last sync are undone on restart
+        vfs.crash(Crash {
+            write: 3,
+            torn: false,
+            lose_unsynced: true,
+        });
+        file.set_len(2).unwrap();
+        file.write_at(b"lost", 20).unwrap();
+        assert!(file.write_at(b"never", 0).is_err());
+        vfs.restart().unwrap();
+        assert_eq!(file.size().unwrap(), 10);
+        assert_eq!(read(10), b"synced!\0to");
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+};
+
+use anyhow::{ensure, Context, Result};
+
+use super::vfs::{LockLevel, OpenMode, Vfs, VfsFile};
+use crate::error::Error;
+
+/// Files kept in memory under a path, i.e a database and its journal that never touch the disk.
+/// Clones share the files
+#[derive(Default, Clone)]
+pub struct MemoryVfs {
+    files: Arc<Mutex<HashMap<PathBuf, Arc<Contents>>>>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file holding `bytes`, replacing any file at the same path
+    pub fn insert(&self, path: impl Into<PathBuf>, bytes: Vec<u8>) {
+        self.files()
+            .insert(path.into(), Arc::new(Contents::new(bytes)));
+    }
+
+    /// Copy of the bytes of a file
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        let contents = self.files().get(path)?.clone();
+        let bytes = contents
+            .bytes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Some(bytes.clone())
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<Contents>>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+        let mut files = self.files();
+        let contents = match files.get(path) {
+            Some(contents) => contents.clone(),
+            None if mode == OpenMode::Create => files
+                .entry(path.to_path_buf())
+                .or_insert_with(|| Arc::new(Contents::new(vec![])))
+                .clone(),
+            None => {
+                return Err(Error::Io(io::ErrorKind::NotFound.into()))
+                    .with_context(|| format!("couldn't open {}", path.display()))
+            }
+        };
+        Ok(Box::new(MemoryFile::open(contents, mode)))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.files().contains_key(path))
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.files()
+            .remove(path)
+            .map(|_| ())
+            .ok_or(Error::Io(io::ErrorKind::NotFound.into()))
+            .with_context(|| format!("couldn't delete {}", path.display()))
+    }
+
+    fn share(&self) -> Box<dyn Vfs> {
+        Box::new(self.clone())
+    }
+}
+
+/// Locks held on a file by all of its handles
+#[derive(Default)]
+struct Locks {
+    // handles holding a shared lock or more
+    shared: usize,
+    // the single reserved, pending or exclusive lock
+    writer: Option<LockLevel>,
+}
+
+/// Bytes of a file shared by every handle that opened it
+struct Contents {
+    bytes: RwLock<Vec<u8>>,
+    locks: Mutex<Locks>,
+}
+
+impl Contents {
+    fn new(bytes: Vec<u8>) -> Self {
+        Contents {
+            bytes: RwLock::new(bytes),
+            locks: Mutex::new(Locks::default()),
+        }
+    }
+}
+
+/// A handle to a file in memory. Handles opened from the same `MemoryVfs` path see each other's writes
+/// and lock each other out like connections to a file on disk
+pub struct MemoryFile {
+    contents: Arc<Contents>,
+    writable: bool,
+    lock: Mutex<LockLevel>,
+}
+
+impl MemoryFile {
+    fn open(contents: Arc<Contents>, mode: OpenMode) -> Self {
+        MemoryFile {
+            contents,
+            writable: mode.writable(),
+            lock: Mutex::new(LockLevel::None),
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if !self.writable {
+            let error = io::Error::new(io::ErrorKind::PermissionDenied, "file is open read-only");
+            return Err(Error::Io(error).into());
+        }
+        Ok(())
+    }
+
+    /// Copy of the file's bytes
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.contents
+            .bytes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// A writable file that isn't reachable through a `MemoryVfs`, i.e database bytes from a blob store
+impl From<Vec<u8>> for MemoryFile {
+    fn from(bytes: Vec<u8>) -> Self {
+        MemoryFile::open(Arc::new(Contents::new(bytes)), OpenMode::ReadWrite)
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let bytes = self
+            .contents
+            .bytes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let start = offset as usize;
+        let Some(src) = bytes.get(start..start + buf.len()) else {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into())).with_context(|| {
+                format!(
+                    "couldn't read {} bytes at {} from a file of {} bytes",
+                    buf.len(),
+                    offset,
+                    bytes.len()
+                )
+            });
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        self.ensure_writable()?;
+        let mut bytes = self
+            .contents
+            .bytes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (start, end) = (offset as usize, offset as usize + buf.len());
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.ensure_writable()?;
+        self.contents
+            .bytes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        let bytes = self
+            .contents
+            .bytes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(bytes.len() as u64)
+    }
+
+    fn lock(&self, level: LockLevel) -> Result<()> {
+        let mut held = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if level <= *held {
+            return Ok(());
+        }
+        let mut locks = self
+            .contents
+            .locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if *held == LockLevel::None {
+            // a writer waiting on readers or writing keeps new readers out
+            ensure!(locks.writer < Some(LockLevel::Pending), Error::Locked);
+            if level == LockLevel::Shared {
+                locks.shared += 1;
+                *held = level;
+                return Ok(());
+            }
+        }
+        if *held < LockLevel::Reserved {
+            ensure!(locks.writer.is_none(), Error::Locked);
+        }
+        // this handle is the only writer now, it can only be stopped by other readers
+        let readers = locks.shared - (*held >= LockLevel::Shared) as usize;
+        if level == LockLevel::Exclusive {
+            ensure!(readers == 0, Error::Locked);
+        }
+        if *held == LockLevel::None {
+            locks.shared += 1;
+        }
+        locks.writer = Some(level);
+        *held = level;
+        Ok(())
+    }
+
+    fn unlock(&self, level: LockLevel) -> Result<()> {
+        ensure!(
+            level <= LockLevel::Shared,
+            "can only unlock down to a shared lock"
+        );
+        let mut held = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if level >= *held {
+            return Ok(());
+        }
+        let mut locks = self
+            .contents
+            .locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *held > LockLevel::Shared {
+            locks.writer = None;
+        }
+        if level == LockLevel::None {
+            locks.shared -= 1;
+        }
+        *held = level;
+        Ok(())
+    }
+}
+
+impl Drop for MemoryFile {
+    // a closed handle gives up its locks like a file descriptor being closed
+    fn drop(&mut self) {
+        let _ = self.unlock(LockLevel::None);
+    }
+}
+
+#[cfg(test)]
+mod memory_vfs_tests {
+    use super::*;
+    use crate::{
+        pager::pager::Pager,
+        query_engine::engine::QueryEngine,
+        sql_parser::parser::{AggregateFn, Column, SelectQuery},
+    };
+
+    #[test]
+    fn test_opening_database_bytes() {
+        let bytes = include_bytes!("../../sample.db").to_vec();
+        let pager = Pager::new(Box::new(MemoryFile::from(bytes))).unwrap();
+        let engine = QueryEngine::new(pager);
+        let query = SelectQuery {
+            table: "apples".into(),
+            columns: vec![Column::Aggregation(AggregateFn::CountAll)],
+            where_clause: None,
+        };
+        assert_eq!(engine.run_query(query).unwrap(), "4");
+    }
+
+    #[test]
+    fn test_wal_next_to_the_database() {
+        let vfs = MemoryVfs::new();
+        vfs.insert("wal.db", include_bytes!("../../fixtures/wal.db").to_vec());
+        vfs.insert(
+            "wal.db-wal",
+            include_bytes!("../../fixtures/wal.db-wal").to_vec(),
+        );
+        let engine = QueryEngine::new(Pager::open_with(&vfs, "wal.db").unwrap());
+        let query = SelectQuery {
+            table: "extra".into(),
+            columns: vec![Column::Regular("note".into())],
+            where_clause: None,
+        };
+        assert_eq!(engine.run_query(query).unwrap(), "only in the wal");
+        assert!(Pager::open_with(&vfs, "missing.db").is_err());
+    }
+
+    #[test]
+    fn test_handles_share_the_file() {
+        let vfs = MemoryVfs::new();
+        let writer = vfs.open(Path::new("db"), OpenMode::Create).unwrap();
+        let reader = vfs.open(Path::new("db"), OpenMode::ReadOnly).unwrap();
+        writer.write_at(b"hello", 3).unwrap();
+
+        let mut buf = [0u8; 8];
+        reader.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"\0\0\0hello");
+        assert!(reader.read_at(&mut buf, 1).is_err());
+        assert!(reader.write_at(b"x", 0).is_err());
+
+        writer.set_len(4).unwrap();
+        assert_eq!(reader.size().unwrap(), 4);
+        vfs.delete(Path::new("db")).unwrap();
+        assert!(!vfs.exists(Path::new("db")).unwrap());
+    }
+
+    #[test]
+    fn test_locking() {
+        let vfs = MemoryVfs::new();
+        vfs.insert("db", vec![]);
+        let open = || vfs.open(Path::new("db"), OpenMode::ReadWrite).unwrap();
+        let (a, b) = (open(), open());
+
+        a.lock(LockLevel::Shared).unwrap();
+        b.lock(LockLevel::Shared).unwrap();
+        a.lock(LockLevel::Reserved).unwrap();
+        // only one connection can intend to write and it can't write while the other is reading
+        assert!(b.lock(LockLevel::Reserved).is_err());
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+
+        b.unlock(LockLevel::None).unwrap();
+        a.lock(LockLevel::Exclusive).unwrap();
+        assert!(b.lock(LockLevel::Shared).is_err());
+
+        a.unlock(LockLevel::Shared).unwrap();
+        b.lock(LockLevel::Shared).unwrap();
+        // dropping a handle releases its locks
+        drop(a);
+        b.lock(LockLevel::Exclusive).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::{fs::File, ops::Deref};
+
+use anyhow::Result;
+
+/// The start of a file mapped read-only into memory, unmapped once dropped.
+/// Reading the mapping after another process truncated the file kills the process with SIGBUS,
+/// like sqlite the pager remaps when it notices the file has changed size
+pub struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is read-only and lives until it is dropped
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    // the same on Linux and the BSDs including macOS
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_SHARED: c_int = 1;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    /// Map the first `len` bytes of the file, None where memory mapping isn't supported
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub fn map(file: &File, len: usize) -> Result<Option<Self>> {
+        use std::{io, os::fd::AsRawFd, ptr};
+
+        use crate::error::Error;
+
+        if len == 0 {
+            return Ok(None);
+        }
+        // a new mapping chosen by the kernel so it can't alias memory rust already owns
+        let ptr = unsafe {
+            sys::mmap(
+                ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(Error::Io(io::Error::last_os_error()).into());
+        }
+        Ok(Some(Mmap {
+            ptr: ptr.cast(),
+            len,
+        }))
+    }
+
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub fn map(_file: &File, _len: usize) -> Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // the mapping is valid for `len` bytes until it is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        unsafe {
+            sys::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
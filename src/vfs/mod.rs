@@ -0,0 +1,6 @@
+pub mod fault;
+pub mod memory;
+pub mod mmap;
+pub mod os;
+#[allow(clippy::module_inception)]
+pub mod vfs;
@@ -0,0 +1,211 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{ensure, Context, Result};
+
+use super::{
+    mmap::Mmap,
+    vfs::{LockLevel, OpenMode, Vfs, VfsFile},
+};
+use crate::error::Error;
+
+/// Files on disk opened through the standard library
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsVfs;
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode.writable())
+            .create(mode == OpenMode::Create)
+            .truncate(false)
+            .open(path)
+            .map_err(Error::Io)
+            .with_context(|| format!("couldn't open {}", path.display()))?;
+        Ok(Box::new(OsFile::from(file)))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(path.try_exists()?)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+            .map_err(Error::Io)
+            .with_context(|| format!("couldn't delete {}", path.display()))
+    }
+
+    fn share(&self) -> Box<dyn Vfs> {
+        Box::new(*self)
+    }
+}
+
+/// A file on disk read and written at offsets so the file's cursor is never shared between threads.
+/// Locks are the standard library's whole-file locks, they keep out other connections going through
+/// this VFS but not sqlite itself, which locks byte ranges of the file
+pub struct OsFile {
+    file: File,
+    lock: Mutex<LockLevel>,
+}
+
+impl From<File> for OsFile {
+    fn from(file: File) -> Self {
+        OsFile {
+            file,
+            lock: Mutex::new(LockLevel::None),
+        }
+    }
+}
+
+impl VfsFile for OsFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        #[cfg(unix)]
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset).map_err(Error::Io)?;
+        #[cfg(windows)]
+        {
+            let (mut buf, mut offset) = (buf, offset);
+            while !buf.is_empty() {
+                match std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset) {
+                    Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()).into()),
+                    Ok(n) => {
+                        buf = &mut buf[n..];
+                        offset += n as u64;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(Error::Io(e).into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        #[cfg(unix)]
+        std::os::unix::fs::FileExt::write_all_at(&self.file, buf, offset).map_err(Error::Io)?;
+        #[cfg(windows)]
+        {
+            let (mut buf, mut offset) = (buf, offset);
+            while !buf.is_empty() {
+                match std::os::windows::fs::FileExt::seek_write(&self.file, buf, offset) {
+                    Ok(0) => return Err(Error::Io(io::ErrorKind::WriteZero.into()).into()),
+                    Ok(n) => {
+                        buf = &buf[n..];
+                        offset += n as u64;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(Error::Io(e).into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size).map_err(Error::Io)?)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.file.sync_all().map_err(Error::Io)?)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata().map_err(Error::Io)?.len())
+    }
+
+    fn lock(&self, level: LockLevel) -> Result<()> {
+        let mut held = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if level <= *held {
+            return Ok(());
+        }
+        // there are only shared and exclusive whole-file locks, a reserved or pending lock is taken as exclusive
+        let locked = match level {
+            LockLevel::Shared => self.file.try_lock_shared(),
+            _ => self.file.try_lock(),
+        };
+        match locked {
+            Ok(()) => {
+                *held = level;
+                Ok(())
+            }
+            Err(TryLockError::WouldBlock) => Err(Error::Locked.into()),
+            Err(TryLockError::Error(e)) => Err(Error::Io(e).into()),
+        }
+    }
+
+    fn unlock(&self, level: LockLevel) -> Result<()> {
+        ensure!(
+            level <= LockLevel::Shared,
+            "can only unlock down to a shared lock"
+        );
+        let mut held = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if level >= *held {
+            return Ok(());
+        }
+        match level {
+            LockLevel::None => self.file.unlock().map_err(Error::Io)?,
+            // taking a shared lock converts the exclusive lock this handle holds
+            _ => self
+                .file
+                .try_lock_shared()
+                .map_err(|e| Error::Io(io::Error::from(e)))?,
+        }
+        *held = level;
+        Ok(())
+    }
+
+    fn map(&self, len: u64) -> Result<Option<Mmap>> {
+        Mmap::map(&self.file, len as usize)
+    }
+}
+
+#[cfg(test)]
+mod os_vfs_tests {
+    use super::*;
+
+    #[test]
+    fn test_reading_and_writing_at_offsets() {
+        let path = std::env::temp_dir().join(format!("os_vfs_{}.db", std::process::id()));
+        let file = OsVfs.open(&path, OpenMode::Create).unwrap();
+        assert!(OsVfs.exists(&path).unwrap());
+        file.write_at(b"world", 6).unwrap();
+        file.write_at(b"hello", 0).unwrap();
+
+        let mut buf = [0u8; 11];
+        file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello\0world");
+        assert!(file.read_at(&mut buf, 1).is_err());
+
+        file.set_len(5).unwrap();
+        file.sync().unwrap();
+        assert_eq!(file.size().unwrap(), 5);
+
+        let read_only = OsVfs.open(&path, OpenMode::ReadOnly).unwrap();
+        assert!(read_only.write_at(b"x", 0).is_err());
+        OsVfs.delete(&path).unwrap();
+        assert!(!OsVfs.exists(&path).unwrap());
+    }
+
+    #[test]
+    fn test_locking() {
+        let path = std::env::temp_dir().join(format!("os_vfs_lock_{}.db", std::process::id()));
+        let a = OsVfs.open(&path, OpenMode::Create).unwrap();
+        let b = OsVfs.open(&path, OpenMode::ReadWrite).unwrap();
+
+        a.lock(LockLevel::Shared).unwrap();
+        b.lock(LockLevel::Shared).unwrap();
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+
+        b.unlock(LockLevel::None).unwrap();
+        a.lock(LockLevel::Exclusive).unwrap();
+        assert!(b.lock(LockLevel::Shared).is_err());
+
+        a.unlock(LockLevel::None).unwrap();
+        b.lock(LockLevel::Shared).unwrap();
+        OsVfs.delete(&path).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::mmap::Mmap;
+
+/// How a file is opened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    ReadOnly,
+    ReadWrite,
+    // open for writing, creating an empty file when it doesn't exist
+    Create,
+}
+
+impl OpenMode {
+    pub fn writable(self) -> bool {
+        self != OpenMode::ReadOnly
+    }
+}
+
+/// sqlite's file locks, holding a lock includes every level below it
+/// https://www.sqlite.org/lockingv3.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    None,
+    // any number of connections can read
+    Shared,
+    // one connection intends to write, new readers are still let in
+    Reserved,
+    // a writer is waiting for the readers to finish, new readers are kept out
+    Pending,
+    // one connection is writing to the file
+    Exclusive,
+}
+
+/// Where the pager gets its files from, i.e the operating system or memory
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &Path, mode: OpenMode) -> Result<Box<dyn VfsFile>>;
+    fn exists(&self, path: &Path) -> Result<bool>;
+    fn delete(&self, path: &Path) -> Result<()>;
+    /// Another handle to the same files, a pager keeps one to create and delete its log when the journal mode changes
+    fn share(&self) -> Box<dyn Vfs>;
+}
+
+/// An open file, every method takes `&self` so one file can be read from several threads at once
+pub trait VfsFile: Send + Sync {
+    /// Fill `buf` with the bytes starting at `offset`, failing when the file ends first
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+    /// Write all of `buf` at `offset`, growing the file when it is written past its end
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+    /// Truncate or extend the file to `size` bytes
+    fn set_len(&self, size: u64) -> Result<()>;
+    /// Wait for every write to reach the disk
+    fn sync(&self) -> Result<()>;
+    /// Size of the file in bytes
+    fn size(&self) -> Result<u64>;
+    /// Raise this handle's lock to `level`, fails with the lock unchanged when another handle's lock is in the way
+    fn lock(&self, level: LockLevel) -> Result<()>;
+    /// Lower this handle's lock to `level`, only `LockLevel::Shared` or `LockLevel::None`
+    fn unlock(&self, level: LockLevel) -> Result<()>;
+    /// Map the first `len` bytes of the file into memory for reading, None when the file can't be mapped
+    fn map(&self, _len: u64) -> Result<Option<Mmap>> {
+        Ok(None)
+    }
+}